@@ -14,4 +14,10 @@ pub enum FerrisError{
     DoubleFault,
     #[error(display = "TripleFault")]
     TripleFault,
+    #[error(display = "InvalidInstruction")]
+    InvalidInstruction,
+    #[error(display = "DivideByZero")]
+    DivideByZero,
+    #[error(display = "InputExhausted")]
+    InputExhausted,
 }
\ No newline at end of file
@@ -11,6 +11,245 @@ pub mod error;
 pub mod opcode;
 
 use error::FerrisError;
+use opcode::Opcode;
+use std::collections::VecDeque;
+
+/// maximum number of values on the stack
+pub const STACK_SIZE: usize = 4096;
+
+/// simple stack machine, runs one decoded program
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Machine {
+    program: Vec<Opcode>,
+    pc: usize,
+    stack: Vec<i64>,
+    memory: Vec<i64>,
+    input: VecDeque<i64>,
+    output: Vec<i64>,
+    halted: bool,
+    executed: u64,
+    stack_peak: usize,
+}
+impl Machine {
+    /// # Arguments
+    ///
+    /// code: bytecode, see Opcode::encode
+    /// memory: number of addressable memory cells
+    pub fn new(code: &[u8], memory: usize) -> Result<Self, FerrisError> {
+        Ok(Self {
+            program: Opcode::decode(code)?,
+            pc: 0,
+            stack: Vec::new(),
+            memory: vec![0; memory],
+            input: VecDeque::new(),
+            output: Vec::new(),
+            halted: false,
+            executed: 0,
+            stack_peak: 0,
+        })
+    }
+    /// values returned by the Input instruction, in order
+    pub fn input(&mut self, values: &[i64]) {
+        self.input.extend(values.iter());
+    }
+    pub fn output(&self) -> &[i64] {
+        &self.output
+    }
+    pub fn into_output(self) -> Vec<i64> {
+        self.output
+    }
+    pub fn halted(&self) -> bool {
+        self.halted
+    }
+    /// number of instructions executed so far, used as fuel when accounting for a job
+    pub fn executed(&self) -> u64 {
+        self.executed
+    }
+    /// bytes of memory cells, plus the most the stack has held
+    pub fn memory_peak(&self) -> usize {
+        (self.memory.len() + self.stack_peak) * std::mem::size_of::<i64>()
+    }
+    fn pop(&mut self) -> Result<i64, FerrisError> {
+        self.stack.pop().ok_or(FerrisError::StackUnderflow)
+    }
+    fn push(&mut self, value: i64) -> Result<(), FerrisError> {
+        if self.stack.len() >= STACK_SIZE {
+            return Err(FerrisError::StackOverflow);
+        }
+        self.stack.push(value);
+        self.stack_peak = self.stack_peak.max(self.stack.len());
+        Ok(())
+    }
+    fn cell(&mut self, address: u32) -> Result<&mut i64, FerrisError> {
+        self.memory
+            .get_mut(address as usize)
+            .ok_or(FerrisError::PageFault)
+    }
+    fn binary<F: Fn(i64, i64) -> Result<i64, FerrisError>>(&mut self, f: F) -> Result<(), FerrisError> {
+        let right = self.pop()?;
+        let left = self.pop()?;
+        let value = f(left, right)?;
+        self.push(value)
+    }
+    /// executes a single instruction, returns true once the machine has halted
+    /// running past the end of the program halts the machine
+    pub fn step(&mut self) -> Result<bool, FerrisError> {
+        if self.halted {
+            return Ok(true);
+        }
+        let op = match self.program.get(self.pc) {
+            Some(op) => *op,
+            None => {
+                self.halted = true;
+                return Ok(true);
+            }
+        };
+        self.pc += 1;
+        self.executed += 1;
+        match op {
+            Opcode::Halt => self.halted = true,
+            Opcode::Push(value) => self.push(value)?,
+            Opcode::Pop => {
+                self.pop()?;
+            }
+            Opcode::Dup => {
+                let value = self.pop()?;
+                self.push(value)?;
+                self.push(value)?;
+            }
+            Opcode::Swap => {
+                let top = self.pop()?;
+                let second = self.pop()?;
+                self.push(top)?;
+                self.push(second)?;
+            }
+            Opcode::Add => self.binary(|l, r| Ok(l.wrapping_add(r)))?,
+            Opcode::Sub => self.binary(|l, r| Ok(l.wrapping_sub(r)))?,
+            Opcode::Mul => self.binary(|l, r| Ok(l.wrapping_mul(r)))?,
+            Opcode::Div => self.binary(|l, r| l.checked_div(r).ok_or(FerrisError::DivideByZero))?,
+            Opcode::Rem => self.binary(|l, r| l.checked_rem(r).ok_or(FerrisError::DivideByZero))?,
+            Opcode::Eq => self.binary(|l, r| Ok((l == r) as i64))?,
+            Opcode::Lt => self.binary(|l, r| Ok((l < r) as i64))?,
+            Opcode::Jump(index) => self.jump(index)?,
+            Opcode::JumpIf(index) => {
+                if self.pop()? != 0 {
+                    self.jump(index)?;
+                }
+            }
+            Opcode::Load(address) => {
+                let value = *self.cell(address)?;
+                self.push(value)?;
+            }
+            Opcode::Store(address) => {
+                let value = self.pop()?;
+                *self.cell(address)? = value;
+            }
+            Opcode::Input => {
+                let value = self.input.pop_front().ok_or(FerrisError::InputExhausted)?;
+                self.push(value)?;
+            }
+            Opcode::Output => {
+                let value = self.pop()?;
+                self.output.push(value);
+            }
+        }
+        Ok(self.halted)
+    }
+    fn jump(&mut self, index: u32) -> Result<(), FerrisError> {
+        if index as usize > self.program.len() {
+            return Err(FerrisError::SegFault);
+        }
+        self.pc = index as usize;
+        Ok(())
+    }
+    /// runs until the machine halts
+    pub fn run(&mut self) -> Result<(), FerrisError> {
+        while !self.step()? {}
+        Ok(())
+    }
+    /// runs until the machine halts, or until running returns false
+    /// running is checked before every instruction, returns true if the machine halted
+    pub fn run_while<F: FnMut() -> bool>(&mut self, mut running: F) -> Result<bool, FerrisError> {
+        while running() {
+            if self.step()? {
+                return Ok(true);
+            }
+        }
+        Ok(self.halted)
+    }
+}
+#[test]
+fn sum_input() {
+    // output the sum of every input value until a 0 is read
+    let program = [
+        Opcode::Push(0),
+        Opcode::Store(0),
+        Opcode::Input,
+        Opcode::Dup,
+        Opcode::JumpIf(7),
+        Opcode::Load(0),
+        Opcode::Jump(11),
+        Opcode::Load(0),
+        Opcode::Add,
+        Opcode::Store(0),
+        Opcode::Jump(2),
+        Opcode::Output,
+        Opcode::Halt,
+    ];
+    let code = Opcode::encode(&program);
+    assert_eq!(Opcode::decode(&code).unwrap(), program.to_vec());
+    let mut machine = Machine::new(&code, 1).unwrap();
+    machine.input(&[3, 4, 5, 0]);
+    machine.run().unwrap();
+    assert_eq!(machine.output(), &[12]);
+}
+#[test]
+fn interrupt() {
+    let code = Opcode::encode(&[Opcode::Jump(0)]);
+    let mut machine = Machine::new(&code, 0).unwrap();
+    let mut steps = 0;
+    let halted = machine
+        .run_while(|| {
+            steps += 1;
+            steps <= 100
+        })
+        .unwrap();
+    assert!(!halted);
+    assert_eq!(steps, 101);
+    assert_eq!(machine.executed(), 100);
+}
+#[test]
+fn faults() {
+    let run = |program: &[Opcode], memory: usize| {
+        let mut machine = Machine::new(&Opcode::encode(program), memory)?;
+        machine.run().map(|_| machine.into_output())
+    };
+    assert_eq!(run(&[Opcode::Add], 0), Err(FerrisError::StackUnderflow));
+    let divide = [Opcode::Push(1), Opcode::Push(0), Opcode::Div];
+    assert_eq!(run(&divide, 0), Err(FerrisError::DivideByZero));
+    assert_eq!(run(&[Opcode::Load(1)], 1), Err(FerrisError::PageFault));
+    assert_eq!(run(&[Opcode::Jump(5)], 0), Err(FerrisError::SegFault));
+    assert_eq!(run(&[Opcode::Input], 0), Err(FerrisError::InputExhausted));
+    let overflow = [Opcode::Push(1), Opcode::Dup, Opcode::Jump(1)];
+    assert_eq!(run(&overflow, 0), Err(FerrisError::StackOverflow));
+    // running off the end halts, like Halt
+    let output = [Opcode::Push(7), Opcode::Output];
+    assert_eq!(run(&output, 0), Ok(vec![7]));
+}
+#[test]
+fn decoding() {
+    assert_eq!(Opcode::decode(&[0xff]), Err(FerrisError::InvalidInstruction));
+    // Push with a truncated operand
+    assert_eq!(Opcode::decode(&[0x01, 0, 0]), Err(FerrisError::InvalidInstruction));
+    let program = [
+        Opcode::Push(-2),
+        Opcode::Store(3),
+        Opcode::JumpIf(u32::MAX),
+        Opcode::Rem,
+        Opcode::Lt,
+    ];
+    assert_eq!(Opcode::decode(&Opcode::encode(&program)).unwrap(), program.to_vec());
+}
 
 /*pub struct HeteroMorphicList {
     length: usize,
@@ -0,0 +1,125 @@
+use crate::error::FerrisError;
+use std::convert::TryInto;
+
+/// instructions understood by the Machine
+/// encoded as one byte, followed by a little endian operand for instructions that have one
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub enum Opcode {
+    /// stop execution
+    Halt,
+    /// push a constant
+    Push(i64),
+    Pop,
+    /// push a copy of the top of the stack
+    Dup,
+    /// swap the two values on top of the stack
+    Swap,
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Rem,
+    /// push 1 if the two values on top are equal, otherwise 0
+    Eq,
+    /// push 1 if the second value is less then the top value, otherwise 0
+    Lt,
+    /// continue at instruction index
+    Jump(u32),
+    /// pop, and continue at instruction index if the value isn't 0
+    JumpIf(u32),
+    /// push the value at memory address
+    Load(u32),
+    /// pop into memory address
+    Store(u32),
+    /// push the next input value
+    Input,
+    /// pop into the output
+    Output,
+}
+impl Opcode {
+    fn byte(&self) -> u8 {
+        match self {
+            Self::Halt => 0x00,
+            Self::Push(_) => 0x01,
+            Self::Pop => 0x02,
+            Self::Dup => 0x03,
+            Self::Swap => 0x04,
+            Self::Add => 0x10,
+            Self::Sub => 0x11,
+            Self::Mul => 0x12,
+            Self::Div => 0x13,
+            Self::Rem => 0x14,
+            Self::Eq => 0x15,
+            Self::Lt => 0x16,
+            Self::Jump(_) => 0x20,
+            Self::JumpIf(_) => 0x21,
+            Self::Load(_) => 0x30,
+            Self::Store(_) => 0x31,
+            Self::Input => 0x40,
+            Self::Output => 0x41,
+        }
+    }
+    /// converts a program to bytecode
+    pub fn encode(program: &[Opcode]) -> Vec<u8> {
+        let mut code = Vec::new();
+        for op in program.iter() {
+            code.push(op.byte());
+            match op {
+                Self::Push(value) => code.extend_from_slice(&value.to_le_bytes()),
+                Self::Jump(index) | Self::JumpIf(index) | Self::Load(index) | Self::Store(index) => {
+                    code.extend_from_slice(&index.to_le_bytes())
+                }
+                _ => {}
+            }
+        }
+        code
+    }
+    /// converts bytecode to a program, fails on unknown instructions or truncated operands
+    pub fn decode(code: &[u8]) -> Result<Vec<Opcode>, FerrisError> {
+        let mut program = Vec::new();
+        let mut offset = 0;
+        while offset < code.len() {
+            let byte = code[offset];
+            offset += 1;
+            let op = match byte {
+                0x00 => Self::Halt,
+                0x01 => {
+                    let operand = operand::<8>(code, offset)?;
+                    offset += 8;
+                    Self::Push(i64::from_le_bytes(operand))
+                }
+                0x02 => Self::Pop,
+                0x03 => Self::Dup,
+                0x04 => Self::Swap,
+                0x10 => Self::Add,
+                0x11 => Self::Sub,
+                0x12 => Self::Mul,
+                0x13 => Self::Div,
+                0x14 => Self::Rem,
+                0x15 => Self::Eq,
+                0x16 => Self::Lt,
+                0x20 | 0x21 | 0x30 | 0x31 => {
+                    let index = u32::from_le_bytes(operand::<4>(code, offset)?);
+                    offset += 4;
+                    match byte {
+                        0x20 => Self::Jump(index),
+                        0x21 => Self::JumpIf(index),
+                        0x30 => Self::Load(index),
+                        _ => Self::Store(index),
+                    }
+                }
+                0x40 => Self::Input,
+                0x41 => Self::Output,
+                _ => return Err(FerrisError::InvalidInstruction),
+            };
+            program.push(op);
+        }
+        Ok(program)
+    }
+}
+fn operand<const N: usize>(code: &[u8], offset: usize) -> Result<[u8; N], FerrisError> {
+    match code.get(offset..offset + N) {
+        Some(bytes) => Ok(bytes.try_into().unwrap()),
+        None => Err(FerrisError::InvalidInstruction),
+    }
+}
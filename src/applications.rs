@@ -15,6 +15,19 @@ impl AppIdentity {
             api_key,
         }
     }
+    /// jobs submitted on this host without an application, has no api key
+    pub fn local() -> Self {
+        Self::new(String::from("local"), String::new(), String::new())
+    }
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+    pub fn version(&self) -> &str {
+        &self.version
+    }
+    pub fn api_key(&self) -> &str {
+        &self.api_key
+    }
 }
 impl fmt::Display for AppIdentity {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
@@ -67,6 +80,10 @@ impl Application {
         self.repository = Some(repo);
         self
     }
+    /// resources the application asks for, these still have to be granted
+    pub fn requested(&self) -> &[Resource] {
+        &self.permissions
+    }
     pub fn identity(&self) -> AppIdentity {
         AppIdentity::new(
             self.name.clone(),
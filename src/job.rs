@@ -0,0 +1,242 @@
+use crate::ledger::Usage;
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+/// identifies a job, chosen by whoever submits it so it can be cancelled or queried while running
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub struct JobId(u64);
+impl JobId {
+    pub fn new(id: u64) -> Self {
+        Self(id)
+    }
+    pub fn value(&self) -> u64 {
+        self.0
+    }
+}
+impl fmt::Display for JobId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "job-{}", self.0)
+    }
+}
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum JobStatus {
+    Running,
+    Completed,
+    Failed,
+    Cancelled,
+    /// stopped because its deadline passed
+    TimedOut,
+    /// never submitted to this environment, or already forgotten
+    Unknown,
+}
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum JobError {
+    /// a job with the same id is still running
+    Duplicate(JobId),
+    Unknown(JobId),
+}
+impl fmt::Display for JobError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Duplicate(id) => write!(f, "{} is already running", id),
+            Self::Unknown(id) => write!(f, "{} is unknown", id),
+        }
+    }
+}
+impl Error for JobError {}
+
+/// how a job that didn't fail ended
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum JobOutcome {
+    Completed,
+    Cancelled,
+    TimedOut,
+}
+impl From<JobOutcome> for JobStatus {
+    fn from(outcome: JobOutcome) -> Self {
+        match outcome {
+            JobOutcome::Completed => JobStatus::Completed,
+            JobOutcome::Cancelled => JobStatus::Cancelled,
+            JobOutcome::TimedOut => JobStatus::TimedOut,
+        }
+    }
+}
+/// returned by ExecEnv::execute, output is whatever the job produced before it stopped,
+/// and can be None if the environment can't recover output of a job that was stopped
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct JobResult<T> {
+    pub id: JobId,
+    pub outcome: JobOutcome,
+    pub output: Option<T>,
+    /// measured by the environment, whether or not the job completed
+    pub usage: Usage,
+}
+impl<T> JobResult<T> {
+    pub fn completed(id: JobId, output: T, usage: Usage) -> Self {
+        Self {
+            id,
+            outcome: JobOutcome::Completed,
+            output: Some(output),
+            usage,
+        }
+    }
+    pub fn is_completed(&self) -> bool {
+        self.outcome == JobOutcome::Completed
+    }
+}
+
+const RUNNING: u8 = 0;
+const CANCELLED: u8 = 1;
+const TIMED_OUT: u8 = 2;
+/// handed to a running job, which checks it to see if it should stop
+/// clones share the same state, the first of cancel or the deadline passing wins
+#[derive(Debug, Clone)]
+pub struct CancelToken {
+    state: Arc<AtomicU8>,
+    deadline: Option<Instant>,
+}
+impl CancelToken {
+    pub fn new(deadline: Option<Instant>) -> Self {
+        Self {
+            state: Arc::new(AtomicU8::new(RUNNING)),
+            deadline,
+        }
+    }
+    pub fn deadline(&self) -> Option<Instant> {
+        self.deadline
+    }
+    pub fn cancel(&self) {
+        self.stop(CANCELLED);
+    }
+    /// used by environments that wait for the deadline themselves, rather then polling stopped
+    pub fn expire(&self) {
+        self.stop(TIMED_OUT);
+    }
+    fn stop(&self, reason: u8) {
+        let _ = self
+            .state
+            .compare_exchange(RUNNING, reason, Ordering::SeqCst, Ordering::SeqCst);
+    }
+    /// only checks the flag, cheap enough to call between every instruction
+    pub fn is_stopped(&self) -> bool {
+        self.state.load(Ordering::Relaxed) != RUNNING
+    }
+    /// why the job has to stop, also checks the deadline, None while it may keep running
+    pub fn stopped(&self) -> Option<JobOutcome> {
+        if let Some(deadline) = self.deadline {
+            if Instant::now() >= deadline {
+                self.expire();
+            }
+        }
+        match self.state.load(Ordering::SeqCst) {
+            CANCELLED => Some(JobOutcome::Cancelled),
+            TIMED_OUT => Some(JobOutcome::TimedOut),
+            _ => None,
+        }
+    }
+    /// outcome of a job that ran until it returned, or until it was stopped
+    pub fn outcome(&self) -> JobOutcome {
+        self.stopped().unwrap_or(JobOutcome::Completed)
+    }
+}
+
+#[derive(Debug)]
+struct JobEntry {
+    status: JobStatus,
+    token: CancelToken,
+    pid: Option<u32>,
+}
+/// book keeping shared by ExecEnv implementations, tracks the status of every job,
+/// and the token a running job checks to see if it has been cancelled
+#[derive(Debug, Default)]
+pub struct JobTable {
+    jobs: Mutex<HashMap<JobId, JobEntry>>,
+}
+impl JobTable {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// marks a job as running, the returned token stops once the job is cancelled, or deadline passes
+    pub fn start(&self, id: JobId, deadline: Option<Instant>) -> Result<CancelToken, JobError> {
+        let mut jobs = self.jobs.lock().unwrap();
+        if let Some(entry) = jobs.get(&id) {
+            if entry.status == JobStatus::Running {
+                return Err(JobError::Duplicate(id));
+            }
+        }
+        let token = CancelToken::new(deadline);
+        jobs.insert(
+            id,
+            JobEntry {
+                status: JobStatus::Running,
+                token: token.clone(),
+                pid: None,
+            },
+        );
+        Ok(token)
+    }
+    /// records the process that runs a job, so that it can be killed
+    pub fn set_pid(&self, id: JobId, pid: u32) {
+        if let Some(entry) = self.jobs.lock().unwrap().get_mut(&id) {
+            entry.pid = Some(pid);
+        }
+    }
+    pub fn pid(&self, id: JobId) -> Option<u32> {
+        self.jobs.lock().unwrap().get(&id).and_then(|entry| entry.pid)
+    }
+    /// status should match the JobResult returned for the job
+    pub fn finish(&self, id: JobId, status: JobStatus) {
+        if let Some(entry) = self.jobs.lock().unwrap().get_mut(&id) {
+            entry.status = status;
+            entry.pid = None;
+        }
+    }
+    /// stops the token of a running job, and returns the process set_pid recorded for it
+    /// both happen under one lock, so a job that records its pid after this sees its token stopped,
+    /// and one that recorded it before has its pid returned here
+    pub fn cancel(&self, id: JobId) -> Result<Option<u32>, JobError> {
+        match self.jobs.lock().unwrap().get_mut(&id) {
+            Some(entry) if entry.status == JobStatus::Running => {
+                entry.token.cancel();
+                entry.status = JobStatus::Cancelled;
+                Ok(entry.pid)
+            }
+            _ => Err(JobError::Unknown(id)),
+        }
+    }
+    pub fn status(&self, id: JobId) -> JobStatus {
+        match self.jobs.lock().unwrap().get(&id) {
+            Some(entry) => entry.status,
+            None => JobStatus::Unknown,
+        }
+    }
+    /// drop every job that isn't running anymore
+    pub fn clear_finished(&self) {
+        self.jobs
+            .lock()
+            .unwrap()
+            .retain(|_, entry| entry.status == JobStatus::Running);
+    }
+}
+
+#[test]
+fn cancel_returns_pid() {
+    let table = JobTable::new();
+    let (first, second) = (JobId::new(1), JobId::new(2));
+    // cancelled before the process was started, the job sees its token stopped once it records the pid
+    let token = table.start(first, None).unwrap();
+    assert_eq!(table.cancel(first), Ok(None));
+    table.set_pid(first, 100);
+    assert!(token.is_stopped());
+    assert_eq!(token.outcome(), JobOutcome::Cancelled);
+    // cancelled after, the pid is returned so cancel can kill it
+    table.start(second, None).unwrap();
+    table.set_pid(second, 200);
+    assert_eq!(table.cancel(second), Ok(Some(200)));
+    assert_eq!(table.status(second), JobStatus::Cancelled);
+    assert_eq!(table.cancel(second), Err(JobError::Unknown(second)));
+}
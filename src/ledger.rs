@@ -0,0 +1,379 @@
+/*
+// local record of who used what
+// every job run for a peer is recorded with its usage, tagged with the peer and the application it
+// was submitted for, hosts that share resources can query it to check fairness, or investigate abuse
+// records are appended to a file as json lines, so a ledger survives restarts and can be read by other tools
+*/
+use crate::applications::AppIdentity;
+use crate::job::{JobId, JobOutcome};
+use crate::EnvType;
+use networking::NetworkHash;
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// peer tag of jobs run through Distributor::execute, which come from this host rather then a peer
+pub const LOCAL_PEER: NetworkHash = [0; 32];
+
+/// resources used by a single job, or the total of several jobs
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Usage {
+    /// None when the backend can't measure it, such as native jobs that don't run in a cgroup
+    pub cpu_time: Option<Duration>,
+    /// bytes, the highest peak for a total
+    pub memory_peak: Option<u64>,
+    pub wall_time: Duration,
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+    /// instructions executed, only ferrisvm jobs use fuel
+    pub fuel: Option<u64>,
+}
+impl Usage {
+    /// adds other to this usage, used for totals
+    pub fn combine(&mut self, other: &Usage) {
+        fn sum<T: std::ops::Add<Output = T>>(a: Option<T>, b: Option<T>) -> Option<T> {
+            match (a, b) {
+                (Some(a), Some(b)) => Some(a + b),
+                (a, None) => a,
+                (None, b) => b,
+            }
+        }
+        self.cpu_time = sum(self.cpu_time, other.cpu_time);
+        self.memory_peak = match (self.memory_peak, other.memory_peak) {
+            (Some(a), Some(b)) => Some(a.max(b)),
+            (a, b) => a.or(b),
+        };
+        self.wall_time += other.wall_time;
+        self.bytes_in += other.bytes_in;
+        self.bytes_out += other.bytes_out;
+        self.fuel = sum(self.fuel, other.fuel);
+    }
+}
+
+/// usage of one job, along with who submitted it
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct UsageRecord {
+    pub id: JobId,
+    pub peer: NetworkHash,
+    /// the api key of the application is never recorded
+    pub app_name: String,
+    pub app_version: String,
+    pub env_type: EnvType,
+    /// None if the job failed
+    pub outcome: Option<JobOutcome>,
+    /// seconds since the unix epoch
+    pub started: u64,
+    pub usage: Usage,
+}
+impl UsageRecord {
+    pub fn new(id: JobId, peer: &NetworkHash, app: &AppIdentity, env_type: &EnvType) -> Self {
+        Self {
+            id,
+            peer: *peer,
+            app_name: app.name().to_string(),
+            app_version: app.version().to_string(),
+            env_type: env_type.clone(),
+            outcome: None,
+            started: unix_time(SystemTime::now()),
+            usage: Usage::default(),
+        }
+    }
+}
+
+/// filter for records, every field that is set has to match
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Query {
+    peer: Option<NetworkHash>,
+    app_name: Option<String>,
+    since: Option<u64>,
+    until: Option<u64>,
+    failed: Option<bool>,
+}
+impl Query {
+    pub fn peer(mut self, peer: &NetworkHash) -> Self {
+        self.peer = Some(*peer);
+        self
+    }
+    pub fn app(mut self, name: &str) -> Self {
+        self.app_name = Some(name.to_string());
+        self
+    }
+    /// jobs started at or after time
+    pub fn since(mut self, time: SystemTime) -> Self {
+        self.since = Some(unix_time(time));
+        self
+    }
+    /// jobs started before time
+    pub fn until(mut self, time: SystemTime) -> Self {
+        self.until = Some(unix_time(time));
+        self
+    }
+    /// only jobs that failed, or only jobs that didn't
+    pub fn failed(mut self, failed: bool) -> Self {
+        self.failed = Some(failed);
+        self
+    }
+    pub fn matches(&self, record: &UsageRecord) -> bool {
+        if let Some(peer) = &self.peer {
+            if *peer != record.peer {
+                return false;
+            }
+        }
+        if let Some(name) = &self.app_name {
+            if *name != record.app_name {
+                return false;
+            }
+        }
+        if let Some(since) = self.since {
+            if record.started < since {
+                return false;
+            }
+        }
+        if let Some(until) = self.until {
+            if record.started >= until {
+                return false;
+            }
+        }
+        match self.failed {
+            Some(failed) => failed == record.outcome.is_none(),
+            None => true,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum LedgerError {
+    Io(io::Error),
+    /// a record couldn't be written as json
+    Format(serde_json::Error),
+}
+impl fmt::Display for LedgerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Io(e) => write!(f, "{}", e),
+            Self::Format(e) => write!(f, "invalid ledger record: {}", e),
+        }
+    }
+}
+impl Error for LedgerError {}
+impl From<io::Error> for LedgerError {
+    fn from(error: io::Error) -> Self {
+        LedgerError::Io(error)
+    }
+}
+impl From<serde_json::Error> for LedgerError {
+    fn from(error: serde_json::Error) -> Self {
+        LedgerError::Format(error)
+    }
+}
+
+#[derive(Debug)]
+struct LedgerFile {
+    file: File,
+    /// lines of records that couldn't be written yet, in order
+    unsaved: Vec<String>,
+}
+impl LedgerFile {
+    /// writes the unsaved lines, those after the first that fails stay unsaved
+    fn flush(&mut self) -> Result<(), LedgerError> {
+        while let Some(line) = self.unsaved.first() {
+            writeln!(self.file, "{}", line)?;
+            self.unsaved.remove(0);
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Ledger {
+    records: Mutex<Vec<UsageRecord>>,
+    file: Option<Mutex<LedgerFile>>,
+    /// lines of the file that weren't records when it was opened
+    skipped: usize,
+}
+impl Ledger {
+    /// records are lost once the ledger is dropped
+    pub fn memory() -> Self {
+        Self::default()
+    }
+    /// loads the records already in the file, and appends new ones to it
+    /// lines that aren't records are skipped and counted in skipped, a torn last line,
+    /// such as one left by a crash while it was written, is also cut off the file
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, LedgerError> {
+        let mut file = OpenOptions::new()
+            .create(true)
+            .read(true)
+            .append(true)
+            .open(path)?;
+        let mut contents = Vec::new();
+        file.read_to_end(&mut contents)?;
+        // whatever follows the last newline was still being written, or was written by something else
+        let end = contents
+            .iter()
+            .rposition(|byte| *byte == b'\n')
+            .map_or(0, |index| index + 1);
+        let mut records = Vec::new();
+        let mut skipped = 0;
+        for line in contents[..end].split(|byte| *byte == b'\n') {
+            if line.iter().all(u8::is_ascii_whitespace) {
+                continue;
+            }
+            match serde_json::from_slice(line) {
+                Ok(record) => records.push(record),
+                Err(_) => skipped += 1,
+            }
+        }
+        let last = &contents[end..];
+        if !last.iter().all(u8::is_ascii_whitespace) {
+            match serde_json::from_slice(last) {
+                // the next record needs a line of its own
+                Ok(record) => {
+                    records.push(record);
+                    writeln!(file)?;
+                }
+                Err(_) => {
+                    skipped += 1;
+                    file.set_len(end as u64)?;
+                }
+            }
+        }
+        Ok(Self {
+            records: Mutex::new(records),
+            file: Some(Mutex::new(LedgerFile {
+                file,
+                unsaved: Vec::new(),
+            })),
+            skipped,
+        })
+    }
+    /// the record is kept in memory even if writing it to the file fails,
+    /// it's then written along with the next record, or by flush
+    pub fn record(&self, record: UsageRecord) -> Result<(), LedgerError> {
+        let line = serde_json::to_string(&record)?;
+        self.records.lock().unwrap().push(record);
+        match &self.file {
+            Some(file) => {
+                let mut file = file.lock().unwrap();
+                file.unsaved.push(line);
+                file.flush()
+            }
+            None => Ok(()),
+        }
+    }
+    /// writes the records that couldn't be written when they were recorded
+    pub fn flush(&self) -> Result<(), LedgerError> {
+        match &self.file {
+            Some(file) => file.lock().unwrap().flush(),
+            None => Ok(()),
+        }
+    }
+    /// number of records that are only in memory, since writing them to the file failed
+    pub fn unsaved(&self) -> usize {
+        match &self.file {
+            Some(file) => file.lock().unwrap().unsaved.len(),
+            None => 0,
+        }
+    }
+    /// number of lines of the file that weren't records when it was opened
+    pub fn skipped(&self) -> usize {
+        self.skipped
+    }
+    pub fn query(&self, query: &Query) -> Vec<UsageRecord> {
+        self.records
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|record| query.matches(record))
+            .cloned()
+            .collect()
+    }
+    /// combined usage of every record that matches
+    pub fn total(&self, query: &Query) -> Usage {
+        let mut total = Usage::default();
+        for record in self.query(query).iter() {
+            total.combine(&record.usage);
+        }
+        total
+    }
+    /// combined usage of every peer, for records that match
+    pub fn by_peer(&self, query: &Query) -> HashMap<NetworkHash, Usage> {
+        let mut peers: HashMap<NetworkHash, Usage> = HashMap::new();
+        for record in self.query(query).iter() {
+            peers.entry(record.peer).or_default().combine(&record.usage);
+        }
+        peers
+    }
+}
+
+fn unix_time(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|since| since.as_secs())
+        .unwrap_or(0)
+}
+
+#[test]
+fn ledger_file() {
+    let path = std::env::temp_dir().join(format!("artifice-ledger-{}", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let app = AppIdentity::new("sum".into(), "0.1".into(), "secret".into());
+    let ledger = Ledger::open(&path).unwrap();
+    for (id, peer) in [(0, [1; 32]), (1, [2; 32]), (2, [1; 32])].iter() {
+        let mut record = UsageRecord::new(JobId::new(*id), peer, &app, &EnvType::Inherit);
+        record.outcome = Some(JobOutcome::Completed);
+        record.usage = Usage {
+            cpu_time: Some(Duration::from_millis(10)),
+            memory_peak: Some(100 * (*id + 1)),
+            wall_time: Duration::from_millis(20),
+            bytes_in: 8,
+            bytes_out: 16,
+            fuel: None,
+        };
+        ledger.record(record).unwrap();
+    }
+    drop(ledger);
+    assert!(!std::fs::read_to_string(&path).unwrap().contains("secret"));
+
+    let ledger = Ledger::open(&path).unwrap();
+    let total = ledger.total(&Query::default().peer(&[1; 32]));
+    assert_eq!(total.cpu_time, Some(Duration::from_millis(20)));
+    assert_eq!(total.memory_peak, Some(300));
+    assert_eq!(total.bytes_out, 32);
+    assert_eq!(ledger.by_peer(&Query::default()).len(), 2);
+    assert_eq!(ledger.query(&Query::default().app("sum")).len(), 3);
+    assert!(ledger.query(&Query::default().failed(true)).is_empty());
+    assert!(ledger.query(&Query::default().app("other")).is_empty());
+    std::fs::remove_file(&path).unwrap();
+}
+#[test]
+fn torn_ledger() {
+    let path = std::env::temp_dir().join(format!("artifice-torn-ledger-{}", std::process::id()));
+    let app = AppIdentity::new("sum".into(), "0.1".into(), "secret".into());
+    let line = |id: u64| {
+        let record = UsageRecord::new(JobId::new(id), &[1; 32], &app, &EnvType::Inherit);
+        serde_json::to_string(&record).unwrap()
+    };
+    // a corrupt line in the middle, and a crash half way through the last one
+    let torn = line(2);
+    let contents = format!("{}\nnot a record\n{}\n{}", line(0), line(1), &torn[..torn.len() / 2]);
+    std::fs::write(&path, &contents).unwrap();
+    let ledger = Ledger::open(&path).unwrap();
+    assert_eq!(ledger.skipped(), 2);
+    assert_eq!(ledger.query(&Query::default()).len(), 2);
+    ledger.record(UsageRecord::new(JobId::new(3), &[1; 32], &app, &EnvType::Inherit)).unwrap();
+    drop(ledger);
+
+    let ledger = Ledger::open(&path).unwrap();
+    assert_eq!(ledger.skipped(), 1);
+    let ids = ledger
+        .query(&Query::default())
+        .iter()
+        .map(|record| record.id)
+        .collect::<Vec<_>>();
+    assert_eq!(ids, vec![JobId::new(0), JobId::new(1), JobId::new(3)]);
+    std::fs::remove_file(&path).unwrap();
+}
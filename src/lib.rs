@@ -7,13 +7,28 @@ pub mod database;
 #[cfg(target_os = "Windows")]
 unimplemented!();
 pub mod applications;
+pub mod job;
+pub mod ledger;
 pub mod permissions;
+pub mod protocol;
 pub mod runtime;
+pub mod scheduler;
+use async_trait::async_trait;
+use applications::AppIdentity;
+use job::{JobId, JobResult, JobStatus};
+use ledger::{Ledger, UsageRecord};
+use protocol::{Channel, Message, ProtocolError, RemoteJob};
+use runtime::{Quantity, Unit};
+use scheduler::{Placement, Requirements, Scheduler};
 use networking::{
     asyncronous::AsyncStream, syncronous::SyncStream, ArtificeConfig, ArtificePeer, LongHash,
     NetworkError, NetworkHash,
 };
+use serde::de::DeserializeOwned;
+use serde::Serialize;
 use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
 
 /// the first two environemnts that will be implemented are MeSHE using Paillier, and Trusted, or execution directly on the host
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
@@ -36,9 +51,13 @@ pub trait EnvData {
     /// because any default would be based on the local system, not the remote system
     fn os_name(&self) -> &str;
     fn arch_name(&self) -> &str;
+    /// in KiB, as reported by sys_info
     fn total_mem(&self) -> u64;
     fn cpu_count(&self) -> u16;
     fn cpu_speed(&self) -> u16;
+    fn total_memory(&self) -> Quantity {
+        Quantity::new(self.total_mem(), Unit::KiB)
+    }
 }
 /// struct that implements EnvData, and can be used to store info on remote hosts
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
@@ -221,15 +240,20 @@ impl EnvData for RemoteEnv {
         self.total_mem
     }
     fn cpu_count(&self) -> u16 {
-        self.cpu_speed
+        self.cpu_count
     }
     fn cpu_speed(&self) -> u16 {
         self.cpu_speed
     }
 }
 
-pub trait ExecEnv: EnvData {
-    type Error: std::error::Error;
+/// an environment jobs can be executed in, such as ferrisvm (VirtualEnv) or the host (NativeEnv)
+#[async_trait]
+pub trait ExecEnv: EnvData + Send + Sync {
+    type Error: std::error::Error + Send;
+    /// description of the work to run, such as a program and its arguments
+    type Job: Send;
+    type Output: Send;
     /// check available memory count
     fn current_mem(&self) -> Result<u64, Self::Error>
     where
@@ -250,12 +274,26 @@ pub trait ExecEnv: EnvData {
             Err(e) => Err(e.into()),
         }
     }
+    /// runs a job until it completes, is cancelled, or deadline passes
+    /// id is used to cancel, or query the job while it runs
+    async fn execute(
+        &self,
+        id: JobId,
+        job: Self::Job,
+        deadline: Option<Instant>,
+    ) -> Result<JobResult<Self::Output>, Self::Error>;
+    /// stops a running job, its result has JobOutcome::Cancelled
+    fn cancel(&self, id: JobId) -> Result<(), Self::Error>;
+    fn status(&self, id: JobId) -> JobStatus;
 }
 /// this is the main struct of this library, and is used to run code on remote systems
 pub struct Distributor<E: ExecEnv> {
     database: HashMap<NetworkHash, RemoteHost>,
-    connections: HashMap<NetworkHash, AsyncStream>,
+    connections: HashMap<NetworkHash, Channel<AsyncStream>>,
     env: Option<E>,
+    next_job: AtomicU64,
+    ledger: Ledger,
+    scheduler: Scheduler,
 }
 impl<E: ExecEnv> Distributor<E> {
     pub fn empty() -> Self {
@@ -263,6 +301,9 @@ impl<E: ExecEnv> Distributor<E> {
             database: HashMap::new(),
             env: None,
             connections: HashMap::new(),
+            next_job: AtomicU64::new(0),
+            ledger: Ledger::memory(),
+            scheduler: Scheduler::default(),
         }
     }
     /// # Arguments
@@ -274,6 +315,9 @@ impl<E: ExecEnv> Distributor<E> {
             database,
             env: Some(env),
             connections: HashMap::new(),
+            next_job: AtomicU64::new(0),
+            ledger: Ledger::memory(),
+            scheduler: Scheduler::default(),
         }
     }
     /// load peers
@@ -288,6 +332,33 @@ impl<E: ExecEnv> Distributor<E> {
         self.env = Some(env);
         self
     }
+    /// record usage of jobs in ledger, rather then only in memory
+    pub fn ledger(mut self, ledger: Ledger) -> Self {
+        self.ledger = ledger;
+        self
+    }
+    /// records of every job run, Ledger::unsaved and Ledger::flush report records that couldn't be written
+    pub fn usage(&self) -> &Ledger {
+        &self.ledger
+    }
+    /// place jobs with scheduler, rather then by least loaded peer
+    pub fn scheduler(mut self, scheduler: Scheduler) -> Self {
+        self.scheduler = scheduler;
+        self
+    }
+    /// used to record latency, and to assign and release jobs sent to the peers placement chose
+    pub fn mut_scheduler(&mut self) -> &mut Scheduler {
+        &mut self.scheduler
+    }
+    /// decides which peer in the database a job with requirements should be submitted to
+    /// the job isn't assigned to the peer, so it isn't counted until mut_scheduler().assign is called
+    pub fn place(&mut self, requirements: &Requirements) -> Placement {
+        let peers = self
+            .database
+            .iter()
+            .map(|(hash, host)| (hash, host.env_data()));
+        self.scheduler.place(peers, requirements)
+    }
     /// return peers, and select execution environment
     pub fn collapse(self) -> (HashMap<NetworkHash, RemoteHost>, E) {
         (self.database, self.env.unwrap())
@@ -298,7 +369,7 @@ impl<E: ExecEnv> Distributor<E> {
             Some(host) => host.async_connect().await?,
             None => return Err(NetworkError::UnSet(String::from("Couldn't Find Peer"))),
         };
-        self.connections.insert(*hash, stream);
+        self.connections.insert(*hash, Channel::new(stream));
         Ok(())
     }
     /// connect to list of peers
@@ -309,6 +380,174 @@ impl<E: ExecEnv> Distributor<E> {
         Ok(())
     }
     pub fn append_incoming(&mut self, stream: AsyncStream) {
-        self.connections.insert(*stream.hash(), stream);
+        self.connections.insert(*stream.hash(), Channel::new(stream));
+    }
+    async fn channel(&mut self, hash: &NetworkHash) -> Result<&mut Channel<AsyncStream>, NetworkError> {
+        if !self.connections.contains_key(hash) {
+            self.connect(hash).await?;
+        }
+        Ok(self.connections.get_mut(hash).unwrap())
+    }
+    /// asks a peer to run a job, connecting to it first if needed
+    /// returns once the peer has accepted it, its progress and result are read with remote_update
+    pub async fn submit(
+        &mut self,
+        hash: &NetworkHash,
+        job: E::Job,
+        timeout: Option<Duration>,
+    ) -> Result<RemoteJob, ProtocolError>
+    where
+        E::Job: Serialize + DeserializeOwned,
+        E::Output: Serialize + DeserializeOwned,
+    {
+        let channel = self.channel(hash).await?;
+        let submit = Message::<E::Job, E::Output>::SubmitJob { job, timeout };
+        match channel.request(submit).await? {
+            (request, Message::JobAccepted { id }) => Ok(RemoteJob {
+                host: *hash,
+                request,
+                id,
+            }),
+            (_, Message::JobRejected { reason }) => Err(ProtocolError::Rejected(reason)),
+            (_, other) => Err(ProtocolError::Unexpected(other.kind().to_string())),
+        }
+    }
+    /// next Progress or Result the peer sent for a submitted job, Result is the last
+    pub async fn remote_update(
+        &mut self,
+        job: &RemoteJob,
+    ) -> Result<Message<E::Job, E::Output>, ProtocolError>
+    where
+        E::Job: DeserializeOwned,
+        E::Output: DeserializeOwned,
+    {
+        match self.connections.get_mut(&job.host) {
+            Some(channel) => channel.recv_for(job.request).await,
+            None => Err(ProtocolError::Closed),
+        }
+    }
+    /// stops a job running on a peer, returns its status once the peer has cancelled it
+    pub async fn cancel_remote(&mut self, job: &RemoteJob) -> Result<JobStatus, ProtocolError>
+    where
+        E::Job: Serialize + DeserializeOwned,
+        E::Output: Serialize + DeserializeOwned,
+    {
+        let channel = match self.connections.get_mut(&job.host) {
+            Some(channel) => channel,
+            None => return Err(ProtocolError::Closed),
+        };
+        let cancel = Message::<E::Job, E::Output>::Cancel { id: job.id };
+        match channel.request(cancel).await? {
+            (_, Message::Progress { status, .. }) => Ok(status),
+            (_, other) => Err(ProtocolError::Unexpected(other.kind().to_string())),
+        }
+    }
+    /// asks a peer for its environment data, connecting to it first if needed
+    pub async fn query_env(&mut self, hash: &NetworkHash) -> Result<RemoteEnv, ProtocolError>
+    where
+        E::Job: Serialize + DeserializeOwned,
+        E::Output: Serialize + DeserializeOwned,
+    {
+        let channel = self.channel(hash).await?;
+        match channel.request(Message::<E::Job, E::Output>::EnvQuery).await? {
+            (_, Message::Env(env)) => Ok(env),
+            (_, other) => Err(ProtocolError::Unexpected(other.kind().to_string())),
+        }
     }
+    /// moves every running domain of qemu this crate manages to a peer, connecting to it first if needed
+    /// the peer has to answer the Migrate request by running runtime::migration::receive
+    /// on Channel::accept_migration
+    #[cfg(feature = "kvm")]
+    pub async fn evacuate(
+        &mut self,
+        hash: &NetworkHash,
+        qemu: &mut runtime::kvm::QEMU,
+        dir: &std::path::Path,
+    ) -> Result<Vec<String>, runtime::migration::MigrationError> {
+        let mut migration = self.channel(hash).await?.migrate().await?;
+        runtime::migration::evacuate(qemu, dir, &mut migration).await
+    }
+    /// new id for a job submitted through this distributor
+    pub fn job_id(&self) -> JobId {
+        JobId::new(self.next_job.fetch_add(1, Ordering::SeqCst))
+    }
+    /// run a job on the selected execution environment, stopping it once deadline passes
+    /// usage is recorded in the ledger as a local job, see ledger::LOCAL_PEER
+    pub async fn execute(
+        &self,
+        id: JobId,
+        job: E::Job,
+        deadline: Option<Instant>,
+    ) -> Result<JobResult<E::Output>, E::Error>
+    where
+        E::Error: From<NetworkError>,
+    {
+        self.execute_for(&ledger::LOCAL_PEER, &AppIdentity::local(), id, job, deadline)
+            .await
+    }
+    /// same as execute, but the usage of the job is recorded tagged with peer and app
+    /// a record that couldn't be written to the ledger file is still kept in memory, and the result returned
+    pub async fn execute_for(
+        &self,
+        peer: &NetworkHash,
+        app: &AppIdentity,
+        id: JobId,
+        job: E::Job,
+        deadline: Option<Instant>,
+    ) -> Result<JobResult<E::Output>, E::Error>
+    where
+        E::Error: From<NetworkError>,
+    {
+        let env = match &self.env {
+            Some(env) => env,
+            None => return Err(NetworkError::UnSet(String::from("No Execution Environment")).into()),
+        };
+        let mut record = UsageRecord::new(id, peer, app, env.env_type());
+        let started = Instant::now();
+        let result = env.execute(id, job, deadline).await;
+        match &result {
+            Ok(result) => {
+                record.outcome = Some(result.outcome);
+                record.usage = result.usage;
+            }
+            Err(_) => record.usage.wall_time = started.elapsed(),
+        }
+        // a record that can't be written stays in the ledger, counted by unsaved, until a later write succeeds
+        let _ = self.ledger.record(record);
+        result
+    }
+    pub fn cancel(&self, id: JobId) -> Result<(), E::Error>
+    where
+        E::Error: From<NetworkError>,
+    {
+        match &self.env {
+            Some(env) => env.cancel(id),
+            None => Err(NetworkError::UnSet(String::from("No Execution Environment")).into()),
+        }
+    }
+    pub fn status(&self, id: JobId) -> JobStatus {
+        match &self.env {
+            Some(env) => env.status(id),
+            None => JobStatus::Unknown,
+        }
+    }
+}
+
+#[test]
+fn local_usage() {
+    use runtime::{NativeEnv, NativeJob};
+    let env = NativeEnv::new(RemoteEnv::init(EnvType::Inherit).unwrap());
+    let distributor = Distributor::load(HashMap::new(), env);
+    let mut runtime = runtime::test_runtime();
+    let id = distributor.job_id();
+    let result = runtime
+        .block_on(distributor.execute(id, NativeJob::new("/bin/true"), None))
+        .unwrap();
+    assert!(result.is_completed());
+    let records = distributor
+        .usage()
+        .query(&ledger::Query::default().peer(&ledger::LOCAL_PEER));
+    assert_eq!(records.len(), 1);
+    assert_eq!(records[0].id, id);
+    assert_eq!(records[0].app_name, "local");
 }
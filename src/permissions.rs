@@ -1,6 +1,7 @@
+use crate::applications::AppIdentity;
 use ipnetwork::IpNetwork;
 use std::fmt;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 #[derive(Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord, Hash, Debug, Clone)]
 pub enum Resource {
     Read(PathBuf),
@@ -12,6 +13,42 @@ pub enum Resource {
     ReadWriteExecute(PathBuf),
     Network(IpNetwork),
 }
+impl Resource {
+    /// the path this resource refers to, None for network resources
+    pub fn path(&self) -> Option<&Path> {
+        match self {
+            Self::Read(path)
+            | Self::Write(path)
+            | Self::Execute(path)
+            | Self::ReadWrite(path)
+            | Self::WriteExecute(path)
+            | Self::ReadExecute(path)
+            | Self::ReadWriteExecute(path) => Some(path),
+            Self::Network(_) => None,
+        }
+    }
+    pub fn readable(&self) -> bool {
+        matches!(
+            self,
+            Self::Read(_) | Self::ReadWrite(_) | Self::ReadExecute(_) | Self::ReadWriteExecute(_)
+        )
+    }
+    pub fn writable(&self) -> bool {
+        matches!(
+            self,
+            Self::Write(_) | Self::ReadWrite(_) | Self::WriteExecute(_) | Self::ReadWriteExecute(_)
+        )
+    }
+    pub fn executable(&self) -> bool {
+        matches!(
+            self,
+            Self::Execute(_)
+                | Self::WriteExecute(_)
+                | Self::ReadExecute(_)
+                | Self::ReadWriteExecute(_)
+        )
+    }
+}
 impl fmt::Display for Resource {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         let value = match self {
@@ -49,8 +86,40 @@ pub enum RequestResult {
     Denied,
 }
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
-struct Permission {
+pub struct Permission {
     resource: Resource,
     // peer hash, application key
     granted: Vec<(String, String)>,
 }
+impl Permission {
+    pub fn new(resource: Resource) -> Self {
+        Self {
+            resource,
+            granted: Vec::new(),
+        }
+    }
+    pub fn resource(&self) -> &Resource {
+        &self.resource
+    }
+    /// grant resource to an application submitted by a peer
+    pub fn grant(&mut self, peer: String, app: &AppIdentity) {
+        let entry = (peer, app.api_key().to_string());
+        if !self.granted.contains(&entry) {
+            self.granted.push(entry);
+        }
+    }
+    pub fn revoke(&mut self, peer: &str, app: &AppIdentity) {
+        self.granted.retain(|(p, key)| !(p == peer && key == app.api_key()));
+    }
+    pub fn is_granted(&self, peer: &str, app: &AppIdentity) -> bool {
+        self.granted.iter().any(|(p, key)| p == peer && key == app.api_key())
+    }
+}
+/// collects every resource granted to an application submitted by peer
+pub fn granted(permissions: &[Permission], peer: &str, app: &AppIdentity) -> Vec<Resource> {
+    permissions
+        .iter()
+        .filter(|permission| permission.is_granted(peer, app))
+        .map(|permission| permission.resource.clone())
+        .collect()
+}
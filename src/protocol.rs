@@ -0,0 +1,595 @@
+/*
+// messages peers exchange to run jobs on each other's environments
+// every message is sent as a frame, a big endian u32 length followed by the json of the frame,
+// so frames can be read back from streams that split or merge what was sent
+// each frame carries the protocol version and the id of the request it belongs to, replies and
+// updates to a request reuse its id, so several requests can be in flight on one stream
+// both peers number their own requests, so replies are marked as such to tell them apart from
+// a request of the peer that happens to have the same id
+*/
+use crate::job::{JobId, JobResult, JobStatus};
+use crate::RemoteEnv;
+use async_trait::async_trait;
+use networking::asyncronous::AsyncStream;
+use networking::{NetworkError, NetworkHash};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::convert::TryInto;
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::UnixStream;
+
+/// bumped whenever a message changes, frames of another version are refused
+pub const PROTOCOL_VERSION: u32 = 3;
+/// largest frame that is read, larger lengths mean the stream is out of step or the peer misbehaves
+pub const MAX_FRAME: usize = 16 * 1024 * 1024;
+const LENGTH_SIZE: usize = 4;
+
+#[derive(Debug)]
+pub enum ProtocolError {
+    Network(NetworkError),
+    Io(io::Error),
+    /// the peer sent a frame of another protocol version
+    Version(u32),
+    TooLarge(usize),
+    /// the frame couldn't be parsed
+    Malformed(String),
+    /// the peer refused the request
+    Rejected(String),
+    /// the peer replied with a message that doesn't answer the request
+    Unexpected(String),
+    /// the peer closed the stream
+    Closed,
+}
+impl fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Network(e) => write!(f, "{}", e),
+            Self::Io(e) => write!(f, "{}", e),
+            Self::Version(version) => write!(
+                f,
+                "peer uses protocol version {}, expected {}",
+                version, PROTOCOL_VERSION
+            ),
+            Self::TooLarge(len) => write!(f, "{} byte frame is larger than {}", len, MAX_FRAME),
+            Self::Malformed(reason) => write!(f, "malformed frame: {}", reason),
+            Self::Rejected(reason) => write!(f, "request rejected: {}", reason),
+            Self::Unexpected(message) => write!(f, "unexpected reply: {}", message),
+            Self::Closed => write!(f, "stream closed by peer"),
+        }
+    }
+}
+impl Error for ProtocolError {}
+impl From<NetworkError> for ProtocolError {
+    fn from(error: NetworkError) -> Self {
+        Self::Network(error)
+    }
+}
+impl From<io::Error> for ProtocolError {
+    fn from(error: io::Error) -> Self {
+        Self::Io(error)
+    }
+}
+impl From<serde_json::Error> for ProtocolError {
+    fn from(error: serde_json::Error) -> Self {
+        Self::Malformed(error.to_string())
+    }
+}
+
+/// correlates replies with the request they answer, unique per channel and side,
+/// so a request and a reply can share an id when they come from different sides
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub struct RequestId(u64);
+impl RequestId {
+    pub fn new(id: u64) -> Self {
+        Self(id)
+    }
+    pub fn value(&self) -> u64 {
+        self.0
+    }
+}
+impl fmt::Display for RequestId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// a job submitted to a peer, and accepted by it
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct RemoteJob {
+    pub host: NetworkHash,
+    /// the SubmitJob request, the job's progress and result are sent under its id
+    pub request: RequestId,
+    /// the peer's id for the job
+    pub id: JobId,
+}
+
+/// J is the job description of the environment jobs are submitted to, and O its output
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Message<J, O> {
+    /// asks the peer to run a job, answered by JobAccepted or JobRejected,
+    /// then Progress updates and a Result under the same request id
+    SubmitJob {
+        job: J,
+        /// how long the job may run once it starts
+        timeout: Option<Duration>,
+    },
+    /// id is the peer's id for the job, used to cancel it
+    JobAccepted {
+        id: JobId,
+    },
+    JobRejected {
+        reason: String,
+    },
+    Progress {
+        id: JobId,
+        status: JobStatus,
+    },
+    Result(JobResult<O>),
+    /// answered by Progress with the job's status after cancelling it
+    Cancel {
+        id: JobId,
+    },
+    /// answered by Env
+    EnvQuery,
+    Env(RemoteEnv),
+    /// asks the peer to take over domains, see runtime::migration,
+    /// the migration then goes both ways as MigrationData under the same request id
+    Migrate,
+    /// one message of a migration
+    MigrationData {
+        #[serde(with = "base64_bytes")]
+        data: Vec<u8>,
+    },
+}
+impl<J, O> Message<J, O> {
+    /// name of the message, for errors, without its contents
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Self::SubmitJob { .. } => "SubmitJob",
+            Self::JobAccepted { .. } => "JobAccepted",
+            Self::JobRejected { .. } => "JobRejected",
+            Self::Progress { .. } => "Progress",
+            Self::Result(_) => "Result",
+            Self::Cancel { .. } => "Cancel",
+            Self::EnvQuery => "EnvQuery",
+            Self::Env(_) => "Env",
+            Self::Migrate => "Migrate",
+            Self::MigrationData { .. } => "MigrationData",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Frame<J, O> {
+    pub version: u32,
+    pub id: RequestId,
+    /// the frame answers a request of the receiving side, rather then being a request of the sender
+    pub reply: bool,
+    pub message: Message<J, O>,
+}
+impl<J: Serialize, O: Serialize> Frame<J, O> {
+    /// a new request of the sender
+    pub fn new(id: RequestId, message: Message<J, O>) -> Self {
+        Self {
+            version: PROTOCOL_VERSION,
+            id,
+            reply: false,
+            message,
+        }
+    }
+    /// a reply or update to the request of the receiver with id
+    pub fn reply(id: RequestId, message: Message<J, O>) -> Self {
+        Self {
+            reply: true,
+            ..Self::new(id, message)
+        }
+    }
+    /// length prefixed bytes of the frame, as they are sent
+    pub fn encode(&self) -> Result<Vec<u8>, ProtocolError> {
+        let body = serde_json::to_vec(self)?;
+        if body.len() > MAX_FRAME {
+            return Err(ProtocolError::TooLarge(body.len()));
+        }
+        let mut bytes = Vec::with_capacity(LENGTH_SIZE + body.len());
+        bytes.extend_from_slice(&(body.len() as u32).to_be_bytes());
+        bytes.extend_from_slice(&body);
+        Ok(bytes)
+    }
+}
+impl<J: DeserializeOwned, O: DeserializeOwned> Frame<J, O> {
+    /// parses the body of a frame, without its length
+    /// the version is checked first, so frames of other versions are refused even if they don't parse
+    pub fn decode(body: &[u8]) -> Result<Self, ProtocolError> {
+        let header: Header = serde_json::from_slice(body)?;
+        if header.version != PROTOCOL_VERSION {
+            return Err(ProtocolError::Version(header.version));
+        }
+        Ok(serde_json::from_slice(body)?)
+    }
+}
+/// the part of a frame every version has to keep
+#[derive(Deserialize)]
+struct Header {
+    version: u32,
+}
+
+/// a stream frames can be sent over, which may split or merge what was sent
+#[async_trait]
+pub trait Transport {
+    async fn send_bytes(&mut self, bytes: &[u8]) -> Result<(), ProtocolError>;
+    /// appends whatever has arrived to buffer, returns 0 once the stream is closed
+    async fn recv_bytes(&mut self, buffer: &mut Vec<u8>) -> Result<usize, ProtocolError>;
+}
+#[async_trait]
+impl Transport for AsyncStream {
+    async fn send_bytes(&mut self, bytes: &[u8]) -> Result<(), ProtocolError> {
+        self.send(bytes).await?;
+        Ok(())
+    }
+    async fn recv_bytes(&mut self, buffer: &mut Vec<u8>) -> Result<usize, ProtocolError> {
+        let mut received = Vec::new();
+        let len = self.recv(&mut received).await?;
+        received.truncate(len);
+        buffer.extend_from_slice(&received);
+        Ok(len)
+    }
+}
+#[async_trait]
+impl Transport for UnixStream {
+    async fn send_bytes(&mut self, bytes: &[u8]) -> Result<(), ProtocolError> {
+        self.write_all(bytes).await?;
+        Ok(())
+    }
+    async fn recv_bytes(&mut self, buffer: &mut Vec<u8>) -> Result<usize, ProtocolError> {
+        let mut received = [0; 8192];
+        let len = self.read(&mut received).await?;
+        buffer.extend_from_slice(&received[..len]);
+        Ok(len)
+    }
+}
+
+/// sends and receives frames over a transport, and hands out request ids
+/// frames received while waiting for a reply to another request are kept, and returned by recv
+#[derive(Debug)]
+pub struct Channel<S> {
+    stream: S,
+    buffer: Vec<u8>,
+    pending: VecDeque<Vec<u8>>,
+    next_request: u64,
+}
+impl<S: Transport + Send> Channel<S> {
+    pub fn new(stream: S) -> Self {
+        Self {
+            stream,
+            buffer: Vec::new(),
+            pending: VecDeque::new(),
+            next_request: 0,
+        }
+    }
+    pub fn stream(&self) -> &S {
+        &self.stream
+    }
+    /// the stream without framing, anything written to it directly has to be understood by the peer
+    pub fn stream_mut(&mut self) -> &mut S {
+        &mut self.stream
+    }
+    pub fn into_inner(self) -> S {
+        self.stream
+    }
+    /// sends message as a new request, replies to it have the returned id
+    pub async fn send<J, O>(&mut self, message: Message<J, O>) -> Result<RequestId, ProtocolError>
+    where
+        J: Serialize + Send,
+        O: Serialize + Send,
+    {
+        let id = RequestId::new(self.next_request);
+        self.next_request += 1;
+        self.send_frame(Frame::new(id, message)).await?;
+        Ok(id)
+    }
+    /// sends message under the id of a request from the peer
+    pub async fn reply<J, O>(
+        &mut self,
+        id: RequestId,
+        message: Message<J, O>,
+    ) -> Result<(), ProtocolError>
+    where
+        J: Serialize + Send,
+        O: Serialize + Send,
+    {
+        self.send_frame(Frame::reply(id, message)).await
+    }
+    async fn send_frame<J, O>(&mut self, frame: Frame<J, O>) -> Result<(), ProtocolError>
+    where
+        J: Serialize + Send,
+        O: Serialize + Send,
+    {
+        let bytes = frame.encode()?;
+        self.stream.send_bytes(&bytes).await
+    }
+    /// next frame from the peer, frames kept by recv_for come first
+    pub async fn recv<J, O>(&mut self) -> Result<Frame<J, O>, ProtocolError>
+    where
+        J: DeserializeOwned,
+        O: DeserializeOwned,
+    {
+        let body = match self.pending.pop_front() {
+            Some(body) => body,
+            None => self.recv_body().await?,
+        };
+        Frame::decode(&body)
+    }
+    /// next reply with the id of request, other frames, including requests of the peer
+    /// with the same id, are kept for recv
+    pub async fn recv_for<J, O>(
+        &mut self,
+        request: RequestId,
+    ) -> Result<Message<J, O>, ProtocolError>
+    where
+        J: DeserializeOwned,
+        O: DeserializeOwned,
+    {
+        self.recv_matching(request, true).await
+    }
+    /// asks the peer to take over domains, the migration is carried by frames of the returned request
+    pub async fn migrate(&mut self) -> Result<Migration<'_, S>, ProtocolError> {
+        let request = self.send(Message::<(), ()>::Migrate).await?;
+        Ok(Migration {
+            channel: self,
+            request,
+            reply: false,
+        })
+    }
+    /// the migration the peer asked for with request
+    pub fn accept_migration(&mut self, request: RequestId) -> Migration<'_, S> {
+        Migration {
+            channel: self,
+            request,
+            reply: true,
+        }
+    }
+    async fn recv_matching<J, O>(
+        &mut self,
+        request: RequestId,
+        reply: bool,
+    ) -> Result<Message<J, O>, ProtocolError>
+    where
+        J: DeserializeOwned,
+        O: DeserializeOwned,
+    {
+        let position = self
+            .pending
+            .iter()
+            .position(|body| matches!(is_frame_of(body, request, reply), Ok(true)));
+        if let Some(position) = position {
+            let body = self.pending.remove(position).unwrap();
+            return Ok(Frame::decode(&body)?.message);
+        }
+        loop {
+            let body = self.recv_body().await?;
+            if is_frame_of(&body, request, reply)? {
+                return Ok(Frame::decode(&body)?.message);
+            }
+            self.pending.push_back(body);
+        }
+    }
+    /// sends message as a new request, and waits for the first reply to it
+    pub async fn request<J, O>(
+        &mut self,
+        message: Message<J, O>,
+    ) -> Result<(RequestId, Message<J, O>), ProtocolError>
+    where
+        J: Serialize + DeserializeOwned + Send,
+        O: Serialize + DeserializeOwned + Send,
+    {
+        let id = self.send(message).await?;
+        Ok((id, self.recv_for(id).await?))
+    }
+    async fn recv_body(&mut self) -> Result<Vec<u8>, ProtocolError> {
+        loop {
+            if let Some(body) = take_frame(&mut self.buffer)? {
+                return Ok(body);
+            }
+            if self.stream.recv_bytes(&mut self.buffer).await? == 0 {
+                return Err(ProtocolError::Closed);
+            }
+        }
+    }
+}
+/// removes the first whole frame from the front of buffer, and returns its body
+fn take_frame(buffer: &mut Vec<u8>) -> Result<Option<Vec<u8>>, ProtocolError> {
+    if buffer.len() < LENGTH_SIZE {
+        return Ok(None);
+    }
+    let len = u32::from_be_bytes(buffer[..LENGTH_SIZE].try_into().unwrap()) as usize;
+    if len > MAX_FRAME {
+        return Err(ProtocolError::TooLarge(len));
+    }
+    if buffer.len() < LENGTH_SIZE + len {
+        return Ok(None);
+    }
+    let body = buffer[LENGTH_SIZE..LENGTH_SIZE + len].to_vec();
+    buffer.drain(..LENGTH_SIZE + len);
+    Ok(Some(body))
+}
+fn is_frame_of(body: &[u8], request: RequestId, reply: bool) -> Result<bool, ProtocolError> {
+    #[derive(Deserialize)]
+    struct Id {
+        id: RequestId,
+        reply: bool,
+    }
+    let frame: Id = serde_json::from_slice(body)?;
+    Ok(frame.reply == reply && frame.id == request)
+}
+
+/// bytes as a base64 string, json would write them as an array of numbers, several times their size
+mod base64_bytes {
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(bytes: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&base64::encode(bytes))
+    }
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+        let text = String::deserialize(deserializer)?;
+        base64::decode(&text).map_err(serde::de::Error::custom)
+    }
+}
+
+/// one side of a migration over a channel, every message is a MigrationData frame of the request
+/// that started it, so other requests can still be answered while it runs
+pub struct Migration<'a, S> {
+    channel: &'a mut Channel<S>,
+    request: RequestId,
+    /// the peer started the migration, so this side's frames are replies
+    reply: bool,
+}
+impl<S: Transport + Send> Migration<'_, S> {
+    pub fn request(&self) -> RequestId {
+        self.request
+    }
+    pub async fn send(&mut self, data: &[u8]) -> Result<(), ProtocolError> {
+        let message = Message::<(), ()>::MigrationData {
+            data: data.to_vec(),
+        };
+        let frame = match self.reply {
+            true => Frame::reply(self.request, message),
+            false => Frame::new(self.request, message),
+        };
+        self.channel.send_frame(frame).await
+    }
+    pub async fn recv(&mut self) -> Result<Vec<u8>, ProtocolError> {
+        match self
+            .channel
+            .recv_matching::<(), ()>(self.request, !self.reply)
+            .await?
+        {
+            Message::MigrationData { data } => Ok(data),
+            other => Err(ProtocolError::Unexpected(other.kind().to_string())),
+        }
+    }
+}
+
+#[test]
+fn framing() {
+    let frame: Frame<String, String> =
+        Frame::new(RequestId::new(3), Message::Cancel { id: JobId::new(7) });
+    let bytes = frame.encode().unwrap();
+    // a stream may deliver a frame in pieces, or several at once
+    let mut buffer = bytes[..5].to_vec();
+    assert_eq!(take_frame(&mut buffer).unwrap(), None);
+    buffer.extend_from_slice(&bytes[5..]);
+    buffer.extend_from_slice(&bytes);
+    let body = take_frame(&mut buffer).unwrap().unwrap();
+    assert_eq!(Frame::decode(&body).unwrap(), frame);
+    assert_eq!(buffer, bytes);
+
+    let next = PROTOCOL_VERSION + 1;
+    let other = format!(r#"{{"version":{},"id":3,"message":{{"Renamed":{{}}}}}}"#, next);
+    match Frame::<String, String>::decode(other.as_bytes()) {
+        Err(ProtocolError::Version(version)) if version == next => (),
+        other => panic!("expected version error, got {:?}", other),
+    }
+    let mut oversized = (MAX_FRAME as u32 + 1).to_be_bytes().to_vec();
+    assert!(matches!(
+        take_frame(&mut oversized),
+        Err(ProtocolError::TooLarge(_))
+    ));
+
+    let data = Message::<(), ()>::MigrationData {
+        data: vec![0xff; 48],
+    };
+    let json = serde_json::to_string(&data).unwrap();
+    assert!(json.len() < 100, "{}", json);
+    let data: Message<(), ()> = serde_json::from_str(&json).unwrap();
+    assert!(matches!(data, Message::MigrationData { data } if data == vec![0xff; 48]));
+}
+#[test]
+fn correlation() {
+    let mut runtime = crate::runtime::test_runtime();
+    runtime.block_on(async {
+        let (client, server) = UnixStream::pair().unwrap();
+        let mut client = Channel::new(client);
+        let mut server = Channel::new(server);
+        let submit = Message::<String, String>::SubmitJob {
+            job: String::from("echo"),
+            timeout: Some(Duration::from_secs(5)),
+        };
+        let first = client.send(submit.clone()).await.unwrap();
+        let second = client.send(submit.clone()).await.unwrap();
+        assert_ne!(first, second);
+
+        let frame = server.recv::<String, String>().await.unwrap();
+        assert_eq!((frame.id, frame.message), (first, submit));
+        assert_eq!(server.recv::<String, String>().await.unwrap().id, second);
+        // replies can arrive in another order then the requests were sent
+        let accepted = Message::<String, String>::JobAccepted { id: JobId::new(1) };
+        let rejected = Message::<String, String>::JobRejected {
+            reason: String::from("busy"),
+        };
+        server.reply(second, rejected.clone()).await.unwrap();
+        server.reply(first, accepted.clone()).await.unwrap();
+
+        assert_eq!(
+            client.recv_for::<String, String>(first).await.unwrap(),
+            accepted
+        );
+        let frame = client.recv::<String, String>().await.unwrap();
+        assert_eq!((frame.id, frame.message), (second, rejected));
+    });
+}
+#[test]
+fn two_way() {
+    let mut runtime = crate::runtime::test_runtime();
+    runtime.block_on(async {
+        let (left, right) = UnixStream::pair().unwrap();
+        let mut left = Channel::new(left);
+        let mut right = Channel::new(right);
+        // both sides number their requests from 0, so the ids are the same
+        let ours = left
+            .send(Message::<String, String>::EnvQuery)
+            .await
+            .unwrap();
+        let theirs = right
+            .send(Message::<String, String>::Cancel { id: JobId::new(4) })
+            .await
+            .unwrap();
+        assert_eq!(ours, theirs);
+        let frame = right.recv::<String, String>().await.unwrap();
+        assert!(!frame.reply);
+        assert_eq!(frame.message, Message::EnvQuery);
+        let progress = Message::<String, String>::Progress {
+            id: JobId::new(4),
+            status: JobStatus::Running,
+        };
+        right.reply(ours, progress.clone()).await.unwrap();
+
+        // the peer's request with the same id arrived first, but isn't the reply
+        assert_eq!(
+            left.recv_for::<String, String>(ours).await.unwrap(),
+            progress
+        );
+        let frame = left.recv::<String, String>().await.unwrap();
+        assert!(!frame.reply);
+        assert_eq!(frame.message, Message::Cancel { id: JobId::new(4) });
+        left.reply(frame.id, progress.clone()).await.unwrap();
+        assert_eq!(
+            right.recv_for::<String, String>(theirs).await.unwrap(),
+            progress
+        );
+
+        // a migration goes both ways under the id of the request that started it
+        let mut outgoing = left.migrate().await.unwrap();
+        outgoing.send(b"domain").await.unwrap();
+        let frame = right.recv::<String, String>().await.unwrap();
+        assert_eq!((frame.reply, frame.message), (false, Message::Migrate));
+        let mut incoming = right.accept_migration(frame.id);
+        assert_eq!(incoming.recv().await.unwrap(), b"domain");
+        incoming.send(b"restored").await.unwrap();
+        assert_eq!(outgoing.recv().await.unwrap(), b"restored");
+    });
+}
@@ -0,0 +1,304 @@
+/*
+// cgroup v2 limits for native jobs
+// every job gets its own leaf under a delegated cgroup, the limits are written before the job
+// is started and the job joins the leaf from its pre_exec hook, so nothing escapes accounting
+// usage is read back from the leaf after the job exits, then the leaf is removed
+*/
+use crate::runtime::ResourceLimits;
+use std::error::Error;
+use std::ffi::CString;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+/// controllers that have to be delegated to the root for limits to be applied
+pub const CONTROLLERS: [&str; 3] = ["memory", "cpu", "pids"];
+/// period used for cpu.max, in microseconds
+pub const CPU_PERIOD: u64 = 100_000;
+
+#[derive(Debug)]
+pub enum CgroupError {
+    /// no cgroup2 mount, or the cgroup isn't delegated to this user
+    Unavailable(String),
+    /// the cgroup exists but a controller isn't enabled in it
+    MissingController(String),
+    Io(io::Error),
+}
+impl fmt::Display for CgroupError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Unavailable(reason) => write!(f, "cgroup unavailable: {}", reason),
+            Self::MissingController(name) => write!(f, "cgroup controller {} not available", name),
+            Self::Io(error) => write!(f, "{}", error),
+        }
+    }
+}
+impl Error for CgroupError {}
+impl From<io::Error> for CgroupError {
+    fn from(error: io::Error) -> Self {
+        CgroupError::Io(error)
+    }
+}
+
+/// usage read back from a job's leaf after it exited
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub struct CgroupUsage {
+    /// memory.peak, None on kernels older than 5.19
+    pub memory_peak: Option<u64>,
+    pub cpu_usage: Duration,
+    pub cpu_user: Duration,
+    pub cpu_system: Duration,
+}
+
+/// a delegated cgroup that job leaves are created under
+#[derive(Debug)]
+pub struct CgroupRoot {
+    path: PathBuf,
+    counter: AtomicU64,
+}
+impl CgroupRoot {
+    /// # Arguments
+    ///
+    /// path: directory of a cgroup2 cgroup that is writable by this user, and that
+    /// has no processes of its own, so that controllers can be enabled for its children
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, CgroupError> {
+        let path = path.as_ref().to_path_buf();
+        check_controllers(&path)?;
+        let enable: Vec<String> = CONTROLLERS.iter().map(|c| format!("+{}", c)).collect();
+        if let Err(e) = fs::write(path.join("cgroup.subtree_control"), enable.join(" ")) {
+            return Err(CgroupError::Unavailable(format!(
+                "can't enable controllers in {}: {}",
+                path.display(),
+                e
+            )));
+        }
+        Ok(Self {
+            path,
+            counter: AtomicU64::new(0),
+        })
+    }
+    /// opens the cgroup in $ARTIFICE_CGROUP
+    pub fn from_env() -> Result<Self, CgroupError> {
+        match std::env::var("ARTIFICE_CGROUP") {
+            Ok(path) => Self::open(path),
+            Err(_) => Err(CgroupError::Unavailable(String::from(
+                "ARTIFICE_CGROUP isn't set",
+            ))),
+        }
+    }
+    /// uses the cgroup this process was started in, which has to be delegated to this user,
+    /// systemd users can get one with `systemd-run --user -p Delegate=yes`
+    /// this moves the whole process into a "manager" child of it,
+    /// because controllers can't be enabled for a cgroup that has processes of its own
+    pub fn delegate_self() -> Result<Self, CgroupError> {
+        let mount = cgroup2_mount()?;
+        let own = fs::read_to_string("/proc/self/cgroup")?;
+        let relative = match own.lines().find_map(|line| line.strip_prefix("0::")) {
+            Some(relative) => relative.trim_start_matches('/').to_string(),
+            None => {
+                return Err(CgroupError::Unavailable(String::from(
+                    "not in a cgroup2 hierarchy",
+                )))
+            }
+        };
+        let path = mount.join(relative);
+        check_controllers(&path)?;
+        let manager = path.join("manager");
+        if !manager.exists() {
+            if let Err(e) = fs::create_dir(&manager) {
+                return Err(CgroupError::Unavailable(format!(
+                    "{} is not delegated: {}",
+                    path.display(),
+                    e
+                )));
+            }
+        }
+        fs::write(manager.join("cgroup.procs"), std::process::id().to_string())?;
+        Self::open(path)
+    }
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+    /// create a new leaf for a job, and write the limits to it
+    pub fn leaf(&self, limits: &ResourceLimits) -> Result<Cgroup, CgroupError> {
+        let name = format!(
+            "job-{}-{}",
+            std::process::id(),
+            self.counter.fetch_add(1, Ordering::SeqCst)
+        );
+        let path = self.path.join(name);
+        fs::create_dir(&path)?;
+        let cgroup = Cgroup { path };
+        cgroup.limit(limits)?;
+        Ok(cgroup)
+    }
+}
+
+/// leaf cgroup of a single job, removed on drop
+#[derive(Debug)]
+pub struct Cgroup {
+    path: PathBuf,
+}
+impl Cgroup {
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+    fn limit(&self, limits: &ResourceLimits) -> io::Result<()> {
+        if let Some(memory) = limits.memory_limit() {
+            fs::write(self.path.join("memory.max"), memory.to_string())?;
+            // without this the job can keep going by swapping, not all kernels have swap accounting
+            let _ = fs::write(self.path.join("memory.swap.max"), "0");
+        }
+        if let Some(millis) = limits.cpu_limit() {
+            let quota = u64::from(millis) * CPU_PERIOD / 1000;
+            fs::write(
+                self.path.join("cpu.max"),
+                format!("{} {}", quota.max(1000), CPU_PERIOD),
+            )?;
+        }
+        if let Some(pids) = limits.pid_limit() {
+            fs::write(self.path.join("pids.max"), pids.to_string())?;
+        }
+        Ok(())
+    }
+    /// move the process started by command into this cgroup before it execs
+    pub fn attach(&self, command: &mut Command) -> Result<(), CgroupError> {
+        use std::os::unix::process::CommandExt;
+        let procs = match CString::new(self.path.join("cgroup.procs").as_os_str().as_bytes()) {
+            Ok(procs) => procs,
+            Err(_) => return Err(CgroupError::Unavailable(String::from("invalid cgroup path"))),
+        };
+        unsafe {
+            command.pre_exec(move || join(&procs));
+        }
+        Ok(())
+    }
+    /// read usage of all processes that have been in this cgroup
+    pub fn usage(&self) -> Result<CgroupUsage, CgroupError> {
+        let memory_peak = match fs::read_to_string(self.path.join("memory.peak")) {
+            Ok(peak) => peak.trim().parse().ok(),
+            Err(_) => None,
+        };
+        let mut usage = CgroupUsage {
+            memory_peak,
+            ..CgroupUsage::default()
+        };
+        for line in fs::read_to_string(self.path.join("cpu.stat"))?.lines() {
+            let mut parts = line.split_whitespace();
+            let (key, value) = match (parts.next(), parts.next().and_then(|v| v.parse().ok())) {
+                (Some(key), Some(value)) => (key, Duration::from_micros(value)),
+                _ => continue,
+            };
+            match key {
+                "usage_usec" => usage.cpu_usage = value,
+                "user_usec" => usage.cpu_user = value,
+                "system_usec" => usage.cpu_system = value,
+                _ => {}
+            }
+        }
+        Ok(usage)
+    }
+    /// kill every process left in the cgroup, cgroup.kill needs linux 5.14
+    pub fn kill(&self) -> io::Result<()> {
+        fs::write(self.path.join("cgroup.kill"), "1")
+    }
+}
+impl Drop for Cgroup {
+    fn drop(&mut self) {
+        // fails if processes are still alive, nothing more can be done from drop
+        let _ = self.kill();
+        let _ = fs::remove_dir(&self.path);
+    }
+}
+
+fn check_controllers(path: &Path) -> Result<(), CgroupError> {
+    let controllers = match fs::read_to_string(path.join("cgroup.controllers")) {
+        Ok(controllers) => controllers,
+        Err(_) => {
+            return Err(CgroupError::Unavailable(format!(
+                "{} is not a cgroup2 directory",
+                path.display()
+            )))
+        }
+    };
+    let available: Vec<&str> = controllers.split_whitespace().collect();
+    for controller in CONTROLLERS.iter() {
+        if !available.contains(controller) {
+            return Err(CgroupError::MissingController(controller.to_string()));
+        }
+    }
+    Ok(())
+}
+
+/// finds where cgroup2 is mounted, /sys/fs/cgroup on unified systems
+fn cgroup2_mount() -> Result<PathBuf, CgroupError> {
+    let mountinfo = fs::read_to_string("/proc/self/mountinfo")?;
+    for line in mountinfo.lines() {
+        // optional fields end with a lone -, the filesystem type comes right after
+        let mut halves = line.splitn(2, " - ");
+        let (mount, fs_type) = match (halves.next(), halves.next()) {
+            (Some(mount), Some(fs_type)) => (mount, fs_type),
+            _ => continue,
+        };
+        if fs_type.split_whitespace().next() == Some("cgroup2") {
+            if let Some(point) = mount.split_whitespace().nth(4) {
+                return Ok(PathBuf::from(point));
+            }
+        }
+    }
+    Err(CgroupError::Unavailable(String::from("cgroup2 is not mounted")))
+}
+
+/// runs in the forked child, writes its own pid (0) into cgroup.procs
+fn join(procs: &CString) -> io::Result<()> {
+    let fd = unsafe { libc::open(procs.as_ptr(), libc::O_WRONLY) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let written = unsafe { libc::write(fd, b"0".as_ptr() as *const libc::c_void, 1) };
+    unsafe { libc::close(fd) };
+    if written < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[test]
+fn job_leaf() {
+    let delegated = match CgroupRoot::from_env() {
+        Ok(root) => root,
+        Err(e) => {
+            println!("skipping, no delegated cgroup: {}", e);
+            return;
+        }
+    };
+    // the leaves go in a scratch subtree, so the test leaves nothing behind in the delegated cgroup
+    let scratch = delegated.path().join(format!("test-{}", std::process::id()));
+    fs::create_dir(&scratch).unwrap();
+    let root = CgroupRoot::open(&scratch).unwrap();
+    let limits = ResourceLimits::default()
+        .memory(64 * 1024 * 1024)
+        .cpu_millis(500)
+        .pids(16);
+    let leaf = root.leaf(&limits).unwrap();
+    let read = |file: &str| fs::read_to_string(leaf.path().join(file)).unwrap();
+    assert_eq!(read("memory.max").trim(), "67108864");
+    assert_eq!(read("cpu.max").trim(), "50000 100000");
+    assert_eq!(read("pids.max").trim(), "16");
+
+    let mut command = Command::new("/bin/sh");
+    command.args(["-c", "head -c 4000000 /dev/zero | tail -c 1 > /dev/null"]);
+    leaf.attach(&mut command).unwrap();
+    assert!(command.status().unwrap().success());
+    let usage = leaf.usage().unwrap();
+    assert!(usage.cpu_usage > Duration::from_micros(0));
+    let path = leaf.path().to_path_buf();
+    drop(leaf);
+    assert!(!path.exists());
+    fs::remove_dir(&scratch).unwrap();
+}
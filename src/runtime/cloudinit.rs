@@ -0,0 +1,421 @@
+/*
+// cloud-init NoCloud seeds for kvm jobs
+// a fresh guest finds its seed by the volume label cidata, and reads meta-data, user-data and
+// network-config from it on first boot, the user-data writes the job's files and a script
+// templated from the job, which runs the job, sends its output to the host over virtio-serial and powers off
+// user-data is written as json, which cloud-init accepts since json is a subset of yaml
+*/
+use crate::runtime::kvm::{driver, Disk, DomainConfig, Source, Target};
+use crate::runtime::ResourceLimits;
+use serde_json::{json, Value};
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// virtio-serial port the job's stdout is written to
+pub const OUTPUT_PORT: &str = "org.artifice.output";
+/// virtio-serial port the job's exit code is written to once it finishes
+pub const STATUS_PORT: &str = "org.artifice.status";
+/// directory the job's files are written to, and that the job is run in
+pub const JOB_DIR: &str = "/var/lib/artifice";
+
+/// program, arguments, environment and files of a job that is run inside a vm
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct VmJob {
+    program: String,
+    args: Vec<String>,
+    envs: Vec<(String, String)>,
+    files: Vec<(String, Vec<u8>)>,
+    limits: ResourceLimits,
+}
+impl VmJob {
+    /// program is run inside the guest, relative paths are relative to JOB_DIR
+    pub fn new(program: &str) -> Self {
+        Self {
+            program: program.to_string(),
+            args: Vec::new(),
+            envs: Vec::new(),
+            files: Vec::new(),
+            limits: ResourceLimits::default(),
+        }
+    }
+    /// limits of the vm the job runs in, see DomainConfig::tune
+    pub fn limits(mut self, limits: ResourceLimits) -> Self {
+        self.limits = limits;
+        self
+    }
+    pub fn arg(mut self, arg: String) -> Self {
+        self.args.push(arg);
+        self
+    }
+    pub fn args(mut self, args: &[String]) -> Self {
+        self.args.extend_from_slice(args);
+        self
+    }
+    pub fn env(mut self, key: String, value: String) -> Self {
+        self.envs.push((key, value));
+        self
+    }
+    /// file written to JOB_DIR before the job starts, such as a script or its input
+    pub fn file(mut self, name: &str, contents: Vec<u8>) -> Self {
+        self.files.push((name.to_string(), contents));
+        self
+    }
+    pub fn program(&self) -> &str {
+        &self.program
+    }
+    pub fn resource_limits(&self) -> &ResourceLimits {
+        &self.limits
+    }
+    /// bytes of arguments, environment and files delivered to the guest
+    pub fn payload_len(&self) -> u64 {
+        let args: usize = self.args.iter().map(String::len).sum();
+        let envs: usize = self.envs.iter().map(|(k, v)| k.len() + v.len()).sum();
+        let files: usize = self.files.iter().map(|(_, contents)| contents.len()).sum();
+        (args + envs + files) as u64
+    }
+    /// shell script that runs the job inside the guest
+    pub fn script(&self) -> Result<String, SeedError> {
+        let mut script = String::from("#!/bin/sh\n");
+        for (key, value) in self.envs.iter() {
+            let valid = key
+                .chars()
+                .enumerate()
+                .all(|(i, c)| c == '_' || c.is_ascii_alphabetic() || (i > 0 && c.is_ascii_digit()));
+            if !valid || key.is_empty() {
+                return Err(SeedError::InvalidEnv(key.clone()));
+            }
+            script.push_str(&format!("export {}={}\n", key, quote(value)));
+        }
+        script.push_str(&format!("cd {}\n", JOB_DIR));
+        let mut command = vec![quote(&self.program)];
+        command.extend(self.args.iter().map(|arg| quote(arg)));
+        script.push_str(&format!(
+            "{} > /dev/virtio-ports/{} 2> {}/stderr\n",
+            command.join(" "),
+            OUTPUT_PORT,
+            JOB_DIR
+        ));
+        script.push_str(&format!(
+            "echo $? > /dev/virtio-ports/{}\npoweroff\n",
+            STATUS_PORT
+        ));
+        Ok(script)
+    }
+}
+/// quotes value for sh, nothing inside single quotes is expanded
+fn quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', "'\\''"))
+}
+fn valid_file_name(name: &str) -> bool {
+    !name.is_empty() && name != "." && name != ".." && !name.contains('/')
+}
+
+#[derive(Debug)]
+pub enum SeedError {
+    Io(io::Error),
+    /// the tool that builds the image failed, with what it wrote to stderr
+    Tool(String),
+    /// environment variable names can only contain letters, digits and underscores
+    InvalidEnv(String),
+    /// job files are written to JOB_DIR, so their names can't contain a path
+    InvalidFile(String),
+}
+impl fmt::Display for SeedError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Io(e) => write!(f, "{}", e),
+            Self::Tool(e) => write!(f, "couldn't build seed image: {}", e),
+            Self::InvalidEnv(key) => write!(f, "invalid environment variable {}", key),
+            Self::InvalidFile(name) => write!(f, "invalid job file name {}", name),
+        }
+    }
+}
+impl Error for SeedError {}
+impl From<io::Error> for SeedError {
+    fn from(error: io::Error) -> Self {
+        SeedError::Io(error)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum SeedFormat {
+    /// iso9660, attached as a cdrom, built with genisoimage
+    Iso,
+    /// vfat, attached as a disk, built with mkfs.vfat and mcopy
+    Fat,
+}
+
+/// contents of a NoCloud seed
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CloudInit {
+    instance_id: String,
+    hostname: String,
+    ssh_keys: Vec<String>,
+    job: Option<VmJob>,
+}
+impl CloudInit {
+    /// cloud-init only runs again in a guest that sees a new instance id
+    pub fn new(instance_id: &str) -> Self {
+        Self {
+            instance_id: instance_id.to_string(),
+            hostname: instance_id.to_string(),
+            ssh_keys: Vec::new(),
+            job: None,
+        }
+    }
+    /// the instance id by default
+    pub fn hostname(mut self, hostname: &str) -> Self {
+        self.hostname = hostname.to_string();
+        self
+    }
+    /// public key allowed to log in as the default user
+    pub fn ssh_key(mut self, key: &str) -> Self {
+        self.ssh_keys.push(key.to_string());
+        self
+    }
+    /// run job once the guest has booted
+    pub fn job(mut self, job: VmJob) -> Self {
+        self.job = Some(job);
+        self
+    }
+    pub fn meta_data(&self) -> String {
+        json!({
+            "instance-id": self.instance_id,
+            "local-hostname": self.hostname,
+        })
+        .to_string()
+    }
+    pub fn user_data(&self) -> Result<String, SeedError> {
+        let mut config = json!({ "hostname": self.hostname });
+        if !self.ssh_keys.is_empty() {
+            config["ssh_authorized_keys"] = json!(self.ssh_keys);
+        }
+        if let Some(job) = &self.job {
+            let mut files = Vec::new();
+            for (name, contents) in job.files.iter() {
+                if !valid_file_name(name) {
+                    return Err(SeedError::InvalidFile(name.clone()));
+                }
+                files.push(write_file(
+                    &format!("{}/{}", JOB_DIR, name),
+                    contents,
+                    "0644",
+                ));
+            }
+            let script = format!("{}/job.sh", JOB_DIR);
+            files.push(write_file(&script, job.script()?.as_bytes(), "0755"));
+            config["write_files"] = Value::Array(files);
+            config["runcmd"] = json!([[script]]);
+        }
+        Ok(format!("#cloud-config\n{}\n", config))
+    }
+    /// dhcp on every ethernet interface
+    pub fn network_config(&self) -> String {
+        json!({
+            "version": 2,
+            "ethernets": {
+                "all": {
+                    "match": { "name": "e*" },
+                    "dhcp4": true,
+                },
+            },
+        })
+        .to_string()
+    }
+    /// writes the seed image to path, using tools from PATH
+    pub fn write<P: AsRef<Path>>(&self, path: P, format: SeedFormat) -> Result<Seed, SeedError> {
+        let path = path.as_ref().to_path_buf();
+        // the files are staged in a directory next to the image, since the tools take files
+        let staging = path.with_extension("d");
+        fs::create_dir_all(&staging)?;
+        let result = self.stage(&staging).and_then(|files| match format {
+            SeedFormat::Iso => build_iso(&path, &files),
+            SeedFormat::Fat => build_fat(&path, &files),
+        });
+        fs::remove_dir_all(&staging)?;
+        result?;
+        Ok(Seed { path, format })
+    }
+    fn stage(&self, dir: &Path) -> Result<Vec<PathBuf>, SeedError> {
+        let files = vec![
+            ("meta-data", self.meta_data()),
+            ("user-data", self.user_data()?),
+            ("network-config", self.network_config()),
+        ];
+        let mut paths = Vec::new();
+        for (name, contents) in files {
+            let path = dir.join(name);
+            fs::write(&path, contents)?;
+            paths.push(path);
+        }
+        Ok(paths)
+    }
+}
+fn write_file(path: &str, contents: &[u8], permissions: &str) -> Value {
+    json!({
+        "path": path,
+        "permissions": permissions,
+        "encoding": "b64",
+        "content": base64::encode(contents),
+    })
+}
+fn build_iso(path: &Path, files: &[PathBuf]) -> Result<(), SeedError> {
+    let mut command = Command::new("genisoimage");
+    command
+        .arg("-output")
+        .arg(path)
+        .args(["-volid", "cidata", "-joliet", "-rock", "-quiet"])
+        .args(files);
+    run(&mut command)
+}
+fn build_fat(path: &Path, files: &[PathBuf]) -> Result<(), SeedError> {
+    // size in KiB, far more than the seed needs
+    let mut command = Command::new("mkfs.vfat");
+    command.args(["-n", "CIDATA", "-C"]).arg(path).arg("1024");
+    run(&mut command)?;
+    let mut command = Command::new("mcopy");
+    command.arg("-oi").arg(path).args(files).arg("::");
+    run(&mut command)
+}
+fn run(command: &mut Command) -> Result<(), SeedError> {
+    let output = command.output()?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(SeedError::Tool(stderr.trim().to_string()));
+    }
+    Ok(())
+}
+
+/// seed image written by CloudInit::write
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Seed {
+    path: PathBuf,
+    format: SeedFormat,
+}
+impl Seed {
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+    pub fn format(&self) -> SeedFormat {
+        self.format
+    }
+    /// iso seeds are a cdrom on the sata bus, fat seeds a virtio disk
+    pub fn disk(&self, dev: &str) -> Disk {
+        let (device, bus) = match self.format {
+            SeedFormat::Iso => ("cdrom", "sata"),
+            SeedFormat::Fat => ("disk", "virtio"),
+        };
+        Disk {
+            r#type: Some(String::from("file")),
+            device: Some(device.to_string()),
+            driver: Some(driver {
+                name: Some(String::from("qemu")),
+                r#type: Some(String::from("raw")),
+            }),
+            source: Some(Source {
+                file: Some(self.path.to_string_lossy().into_owned()),
+            }),
+            target: Some(Target {
+                dev: Some(dev.to_string()),
+                bus: Some(bus.to_string()),
+            }),
+            address: None,
+        }
+    }
+    /// adds the seed to config, as the first target dev on its bus that isn't taken
+    pub fn attach(&self, config: &mut DomainConfig) {
+        let prefix = match self.format {
+            SeedFormat::Iso => "sd",
+            SeedFormat::Fat => "vd",
+        };
+        let devices = config.devices.get_or_insert_with(Default::default);
+        let taken: Vec<&str> = devices
+            .disks
+            .iter()
+            .filter_map(|disk| disk.target.as_ref())
+            .filter_map(|target| target.dev.as_deref())
+            .collect();
+        let dev = (b'a'..=b'z')
+            .map(|letter| format!("{}{}", prefix, letter as char))
+            .find(|dev| !taken.contains(&dev.as_str()))
+            .unwrap_or_else(|| format!("{}zz", prefix));
+        devices.disks.push(self.disk(&dev));
+    }
+}
+
+#[test]
+fn seed() {
+    let job = VmJob::new("python3")
+        .arg(String::from("main.py"))
+        .arg(String::from("it's"))
+        .env(String::from("MODE"), String::from("fast $HOME"))
+        .file("main.py", b"print('hello')".to_vec());
+    let script = job.script().unwrap();
+    assert!(script.contains("export MODE='fast $HOME'\n"));
+    assert!(
+        script.contains("'python3' 'main.py' 'it'\\''s' > /dev/virtio-ports/org.artifice.output")
+    );
+    assert!(script.ends_with("poweroff\n"));
+    let invalid = VmJob::new("true").env(String::from("A B"), String::new());
+    assert!(matches!(invalid.script(), Err(SeedError::InvalidEnv(_))));
+
+    let cloud = CloudInit::new("job-7")
+        .ssh_key("ssh-ed25519 AAAA host")
+        .job(job);
+    let meta: Value = serde_json::from_str(&cloud.meta_data()).unwrap();
+    assert_eq!(meta["instance-id"], "job-7");
+    let user_data = cloud.user_data().unwrap();
+    assert!(user_data.starts_with("#cloud-config\n"));
+    let user: Value = serde_json::from_str(&user_data["#cloud-config\n".len()..]).unwrap();
+    assert_eq!(user["ssh_authorized_keys"][0], "ssh-ed25519 AAAA host");
+    assert_eq!(user["write_files"][0]["path"], "/var/lib/artifice/main.py");
+    assert_eq!(
+        base64::decode(user["write_files"][0]["content"].as_str().unwrap()).unwrap(),
+        b"print('hello')"
+    );
+    assert_eq!(user["runcmd"][0][0], "/var/lib/artifice/job.sh");
+    let escape = CloudInit::new("job-8").job(VmJob::new("true").file("../etc/passwd", Vec::new()));
+    assert!(matches!(escape.user_data(), Err(SeedError::InvalidFile(_))));
+
+    let mut config = DomainConfig::builder("job-7")
+        .uuid("4d6e0f4e-8bd1-4a2a-9d43-0c3f6bd1a006")
+        .boot_disk("/images/job-7.qcow2", "qcow2")
+        .build()
+        .unwrap();
+    let seed = Seed {
+        path: PathBuf::from("/images/job-7-seed.iso"),
+        format: SeedFormat::Iso,
+    };
+    seed.attach(&mut config);
+    seed.attach(&mut config);
+    let disks = &config.devices.as_ref().unwrap().disks;
+    assert_eq!(disks[1].device.as_deref(), Some("cdrom"));
+    assert_eq!(
+        disks[1].target.as_ref().unwrap().dev.as_deref(),
+        Some("sda")
+    );
+    assert_eq!(
+        disks[2].target.as_ref().unwrap().dev.as_deref(),
+        Some("sdb")
+    );
+    assert_eq!(config.validate(), Ok(()));
+}
+#[test]
+fn seed_image() {
+    let path = std::env::temp_dir().join(format!("artifice-seed-{}.iso", std::process::id()));
+    let seed = match CloudInit::new("job-9").write(&path, SeedFormat::Iso) {
+        Ok(seed) => seed,
+        Err(SeedError::Io(e)) if e.kind() == io::ErrorKind::NotFound => {
+            println!("skipping, genisoimage isn't installed");
+            return;
+        }
+        Err(e) => panic!("{}", e),
+    };
+    assert!(fs::metadata(seed.path()).unwrap().len() > 0);
+    assert!(!path.with_extension("d").exists());
+    fs::remove_file(&path).unwrap();
+}
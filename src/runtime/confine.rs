@@ -0,0 +1,454 @@
+/*
+// kernel enforced permissions for native jobs
+// the resources granted to an application are turned into a landlock ruleset for file access
+// and a seccomp filter that refuses to create network sockets unless a Network grant exists
+// seccomp can't see addresses, so a Network grant allows all of the network,
+// the IpNetwork in the grant is not enforced here
+*/
+use crate::permissions::Resource;
+use std::error::Error;
+use std::ffi::CString;
+use std::fmt;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+// landlock uapi, include/uapi/linux/landlock.h
+// the syscall numbers are the same on every architecture
+const SYS_LANDLOCK_CREATE_RULESET: libc::c_long = 444;
+const SYS_LANDLOCK_ADD_RULE: libc::c_long = 445;
+const SYS_LANDLOCK_RESTRICT_SELF: libc::c_long = 446;
+const CREATE_RULESET_VERSION: u32 = 1;
+const RULE_PATH_BENEATH: u32 = 1;
+const ACCESS_EXECUTE: u64 = 1;
+const ACCESS_WRITE_FILE: u64 = 1 << 1;
+const ACCESS_READ_FILE: u64 = 1 << 2;
+const ACCESS_READ_DIR: u64 = 1 << 3;
+const ACCESS_REMOVE_DIR: u64 = 1 << 4;
+const ACCESS_REMOVE_FILE: u64 = 1 << 5;
+const ACCESS_MAKE_CHAR: u64 = 1 << 6;
+const ACCESS_MAKE_DIR: u64 = 1 << 7;
+const ACCESS_MAKE_REG: u64 = 1 << 8;
+const ACCESS_MAKE_SOCK: u64 = 1 << 9;
+const ACCESS_MAKE_FIFO: u64 = 1 << 10;
+const ACCESS_MAKE_BLOCK: u64 = 1 << 11;
+const ACCESS_MAKE_SYM: u64 = 1 << 12;
+const ACCESS_REFER: u64 = 1 << 13;
+const ACCESS_TRUNCATE: u64 = 1 << 14;
+/// rights that can be given on a file, everything else only applies to directories
+const FILE_ACCESS: u64 = ACCESS_EXECUTE | ACCESS_WRITE_FILE | ACCESS_READ_FILE | ACCESS_TRUNCATE;
+
+#[repr(C)]
+struct RulesetAttr {
+    handled_access_fs: u64,
+}
+#[repr(C, packed)]
+struct PathBeneathAttr {
+    allowed_access: u64,
+    parent_fd: i32,
+}
+
+/// None where the network filter hasn't been written for the architecture
+#[cfg(target_arch = "x86_64")]
+const AUDIT_ARCH: Option<u32> = Some(0xC000_003E);
+#[cfg(target_arch = "aarch64")]
+const AUDIT_ARCH: Option<u32> = Some(0xC000_00B7);
+#[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+const AUDIT_ARCH: Option<u32> = None;
+/// x32 syscalls share the x86_64 audit arch, they are refused outright
+const X32_SYSCALL_BIT: u32 = 0x4000_0000;
+
+/// file access a job is allowed, combined the same way as permissions::Resource
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct Access {
+    pub read: bool,
+    pub write: bool,
+    pub execute: bool,
+}
+impl Access {
+    pub const READ: Access = Access {
+        read: true,
+        write: false,
+        execute: false,
+    };
+    pub const READ_WRITE: Access = Access {
+        read: true,
+        write: true,
+        execute: false,
+    };
+    pub const READ_EXECUTE: Access = Access {
+        read: true,
+        write: false,
+        execute: true,
+    };
+    /// landlock rights for this access on the given abi version
+    fn landlock(self, abi: u32) -> u64 {
+        let mut rights = 0;
+        if self.read {
+            rights |= ACCESS_READ_FILE | ACCESS_READ_DIR;
+        }
+        if self.write {
+            rights |= ACCESS_WRITE_FILE
+                | ACCESS_REMOVE_DIR
+                | ACCESS_REMOVE_FILE
+                | ACCESS_MAKE_DIR
+                | ACCESS_MAKE_REG
+                | ACCESS_MAKE_SOCK
+                | ACCESS_MAKE_FIFO
+                | ACCESS_MAKE_SYM;
+            if abi >= 2 {
+                rights |= ACCESS_REFER;
+            }
+            if abi >= 3 {
+                rights |= ACCESS_TRUNCATE;
+            }
+        }
+        if self.execute {
+            rights |= ACCESS_EXECUTE;
+        }
+        rights
+    }
+}
+impl From<&Resource> for Access {
+    fn from(resource: &Resource) -> Self {
+        Self {
+            read: resource.readable(),
+            write: resource.writable(),
+            execute: resource.executable(),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ConfineError {
+    /// landlock or seccomp is missing or disabled on this host
+    Unsupported(String),
+    InvalidPath(PathBuf),
+    Io(io::Error),
+}
+impl fmt::Display for ConfineError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Unsupported(reason) => write!(f, "confinement unsupported: {}", reason),
+            Self::InvalidPath(path) => write!(f, "invalid path: {}", path.display()),
+            Self::Io(error) => write!(f, "{}", error),
+        }
+    }
+}
+impl Error for ConfineError {}
+impl From<io::Error> for ConfineError {
+    fn from(error: io::Error) -> Self {
+        ConfineError::Io(error)
+    }
+}
+
+/// landlock rules and network policy for a single job
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Confinement {
+    rules: Vec<(PathBuf, Access)>,
+    network: bool,
+}
+impl Confinement {
+    /// builds rules from the resources granted to an application, anything not listed is denied
+    pub fn new(grants: &[Resource]) -> Self {
+        let mut confinement = Self::default();
+        for grant in grants.iter() {
+            match grant.path() {
+                Some(path) => confinement.rules.push((path.to_path_buf(), grant.into())),
+                None => confinement.network = true,
+            }
+        }
+        confinement
+    }
+    /// allow access beneath path in addition to the grants, used for system directories
+    pub fn allow<P: AsRef<Path>>(mut self, path: P, access: Access) -> Self {
+        self.rules.push((path.as_ref().to_path_buf(), access));
+        self
+    }
+    pub fn rules(&self) -> &[(PathBuf, Access)] {
+        &self.rules
+    }
+    /// true if a Network grant exists
+    pub fn network(&self) -> bool {
+        self.network
+    }
+    /// returns the landlock abi version of the running kernel
+    pub fn probe() -> Result<u32, ConfineError> {
+        let version = unsafe {
+            libc::syscall(
+                SYS_LANDLOCK_CREATE_RULESET,
+                std::ptr::null::<RulesetAttr>(),
+                0usize,
+                CREATE_RULESET_VERSION,
+            )
+        };
+        if version < 1 {
+            return Err(ConfineError::Unsupported(format!(
+                "landlock: {}",
+                io::Error::last_os_error()
+            )));
+        }
+        Ok(version as u32)
+    }
+    /// restrict the process started by command before it execs
+    /// paths are opened in the child, so this has to be applied after any sandbox
+    pub fn apply(&self, command: &mut Command) -> Result<(), ConfineError> {
+        use std::os::unix::process::CommandExt;
+        let abi = Self::probe()?;
+        let mut handled = Access {
+            read: true,
+            write: true,
+            execute: true,
+        }
+        .landlock(abi);
+        handled |= ACCESS_MAKE_CHAR | ACCESS_MAKE_BLOCK;
+        let mut rules = Vec::with_capacity(self.rules.len());
+        for (path, access) in self.rules.iter() {
+            let c_path = match CString::new(path.as_os_str().as_bytes()) {
+                Ok(c_path) => c_path,
+                Err(_) => return Err(ConfineError::InvalidPath(path.clone())),
+            };
+            rules.push((c_path, access.landlock(abi)));
+        }
+        let filter = if self.network {
+            None
+        } else {
+            Some(network_filter()?)
+        };
+        unsafe {
+            command.pre_exec(move || restrict(handled, &rules, filter.as_deref()));
+        }
+        Ok(())
+    }
+}
+
+fn check(result: libc::c_long) -> io::Result<libc::c_long> {
+    if result < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(result)
+}
+
+/// runs in the forked child, nothing in here allocates
+fn restrict(
+    handled: u64,
+    rules: &[(CString, u64)],
+    filter: Option<&[libc::sock_filter]>,
+) -> io::Result<()> {
+    let attr = RulesetAttr {
+        handled_access_fs: handled,
+    };
+    let ruleset = check(unsafe {
+        libc::syscall(
+            SYS_LANDLOCK_CREATE_RULESET,
+            &attr as *const RulesetAttr,
+            std::mem::size_of::<RulesetAttr>(),
+            0u32,
+        )
+    })? as libc::c_int;
+    for (path, access) in rules.iter() {
+        let fd = unsafe { libc::open(path.as_ptr(), libc::O_PATH | libc::O_CLOEXEC) };
+        if fd < 0 {
+            let error = io::Error::last_os_error();
+            // grants for paths that don't exist (or aren't visible in the sandbox) are skipped
+            if error.raw_os_error() == Some(libc::ENOENT) {
+                continue;
+            }
+            return Err(error);
+        }
+        let mut stat: libc::stat = unsafe { std::mem::zeroed() };
+        if unsafe { libc::fstat(fd, &mut stat) } < 0 {
+            unsafe { libc::close(fd) };
+            return Err(io::Error::last_os_error());
+        }
+        let allowed_access = if stat.st_mode & libc::S_IFMT == libc::S_IFDIR {
+            *access
+        } else {
+            access & FILE_ACCESS
+        };
+        let beneath = PathBeneathAttr {
+            allowed_access,
+            parent_fd: fd,
+        };
+        let added = unsafe {
+            libc::syscall(
+                SYS_LANDLOCK_ADD_RULE,
+                ruleset,
+                RULE_PATH_BENEATH,
+                &beneath as *const PathBeneathAttr,
+                0u32,
+            )
+        };
+        unsafe { libc::close(fd) };
+        check(added)?;
+    }
+    check(unsafe { libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) } as libc::c_long)?;
+    let restricted = unsafe { libc::syscall(SYS_LANDLOCK_RESTRICT_SELF, ruleset, 0u32) };
+    unsafe { libc::close(ruleset) };
+    check(restricted)?;
+    if let Some(filter) = filter {
+        let program = libc::sock_fprog {
+            len: filter.len() as libc::c_ushort,
+            filter: filter.as_ptr() as *mut libc::sock_filter,
+        };
+        check(unsafe {
+            libc::prctl(
+                libc::PR_SET_SECCOMP,
+                libc::SECCOMP_MODE_FILTER,
+                &program as *const libc::sock_fprog,
+            )
+        } as libc::c_long)?;
+    }
+    Ok(())
+}
+
+const fn statement(code: u16, k: u32) -> libc::sock_filter {
+    libc::sock_filter {
+        code,
+        jt: 0,
+        jf: 0,
+        k,
+    }
+}
+const fn jump(code: u16, k: u32, jt: u8, jf: u8) -> libc::sock_filter {
+    libc::sock_filter { code, jt, jf, k }
+}
+
+/// seccomp program that only allows unix sockets, io_uring is refused because it can open sockets too
+fn network_filter() -> Result<Vec<libc::sock_filter>, ConfineError> {
+    const LOAD: u16 = (libc::BPF_LD | libc::BPF_W | libc::BPF_ABS) as u16;
+    const EQUAL: u16 = (libc::BPF_JMP | libc::BPF_JEQ | libc::BPF_K) as u16;
+    const AT_LEAST: u16 = (libc::BPF_JMP | libc::BPF_JGE | libc::BPF_K) as u16;
+    const RETURN: u16 = (libc::BPF_RET | libc::BPF_K) as u16;
+    // offsets into struct seccomp_data, args are little endian u64s
+    const NR: u32 = 0;
+    const ARCH: u32 = 4;
+    const FIRST_ARG: u32 = 16;
+    let arch = match AUDIT_ARCH {
+        Some(arch) => arch,
+        None => {
+            return Err(ConfineError::Unsupported(format!(
+                "no network filter for {}",
+                std::env::consts::ARCH
+            )))
+        }
+    };
+    let deny = libc::SECCOMP_RET_ERRNO | libc::EACCES as u32;
+    Ok(vec![
+        statement(LOAD, ARCH),
+        jump(EQUAL, arch, 1, 0),
+        statement(RETURN, libc::SECCOMP_RET_KILL_PROCESS),
+        statement(LOAD, NR),
+        jump(AT_LEAST, X32_SYSCALL_BIT, 3, 0),
+        jump(EQUAL, libc::SYS_socket as u32, 4, 0),
+        jump(EQUAL, libc::SYS_io_uring_setup as u32, 2, 0),
+        statement(RETURN, libc::SECCOMP_RET_ALLOW),
+        statement(RETURN, libc::SECCOMP_RET_KILL_PROCESS),
+        statement(RETURN, deny),
+        statement(LOAD, FIRST_ARG),
+        jump(EQUAL, libc::AF_UNIX as u32, 0, 1),
+        statement(RETURN, libc::SECCOMP_RET_ALLOW),
+        statement(RETURN, deny),
+    ])
+}
+
+#[test]
+fn grants() {
+    use ipnetwork::IpNetwork;
+
+    let confinement = Confinement::new(&[
+        Resource::Read(PathBuf::from("/data")),
+        Resource::ReadWriteExecute(PathBuf::from("/work")),
+    ]);
+    assert!(!confinement.network());
+    assert_eq!(
+        confinement.rules(),
+        &[
+            (PathBuf::from("/data"), Access::READ),
+            (
+                PathBuf::from("/work"),
+                Access {
+                    read: true,
+                    write: true,
+                    execute: true
+                }
+            )
+        ]
+    );
+    let network: IpNetwork = "10.0.0.0/8".parse().unwrap();
+    let confinement = Confinement::new(&[Resource::Network(network)]);
+    assert!(confinement.network());
+    assert!(confinement.rules().is_empty());
+
+    assert_eq!(Access::READ.landlock(1), ACCESS_READ_FILE | ACCESS_READ_DIR);
+    // refer and truncate only exist on later abi versions
+    let write = Access::READ_WRITE.landlock(1);
+    assert_eq!(write & (ACCESS_REFER | ACCESS_TRUNCATE), 0);
+    let write = Access::READ_WRITE.landlock(3);
+    assert_eq!(
+        write & (ACCESS_REFER | ACCESS_TRUNCATE),
+        ACCESS_REFER | ACCESS_TRUNCATE
+    );
+    assert_eq!(
+        Access::READ_EXECUTE.landlock(3) & ACCESS_EXECUTE,
+        ACCESS_EXECUTE
+    );
+}
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+#[test]
+fn network_filter_jumps() {
+    // runs the program the way the kernel would, on a struct seccomp_data
+    fn run(filter: &[libc::sock_filter], arch: u32, nr: u32, first_arg: u64) -> u32 {
+        let mut data = [0u8; 64];
+        data[0..4].copy_from_slice(&nr.to_le_bytes());
+        data[4..8].copy_from_slice(&arch.to_le_bytes());
+        data[16..24].copy_from_slice(&first_arg.to_le_bytes());
+        let (mut pc, mut accumulator) = (0, 0u32);
+        loop {
+            let op = filter[pc];
+            pc += 1;
+            match u32::from(op.code) {
+                code if code == libc::BPF_LD | libc::BPF_W | libc::BPF_ABS => {
+                    let offset = op.k as usize;
+                    accumulator = u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap());
+                }
+                code if code == libc::BPF_JMP | libc::BPF_JEQ | libc::BPF_K => {
+                    pc += usize::from(if accumulator == op.k { op.jt } else { op.jf });
+                }
+                code if code == libc::BPF_JMP | libc::BPF_JGE | libc::BPF_K => {
+                    pc += usize::from(if accumulator >= op.k { op.jt } else { op.jf });
+                }
+                code if code == libc::BPF_RET | libc::BPF_K => return op.k,
+                code => panic!("unexpected instruction {:#x}", code),
+            }
+        }
+    }
+    use std::convert::TryInto;
+
+    let filter = network_filter().unwrap();
+    let arch = AUDIT_ARCH.unwrap();
+    let deny = libc::SECCOMP_RET_ERRNO | libc::EACCES as u32;
+    let socket = libc::SYS_socket as u32;
+    let inet = libc::AF_INET as u64;
+    assert_eq!(run(&filter, arch, socket, inet), deny);
+    assert_eq!(run(&filter, arch, socket, libc::AF_INET6 as u64), deny);
+    assert_eq!(
+        run(&filter, arch, socket, libc::AF_UNIX as u64),
+        libc::SECCOMP_RET_ALLOW
+    );
+    let io_uring = libc::SYS_io_uring_setup as u32;
+    assert_eq!(run(&filter, arch, io_uring, 0), deny);
+    assert_eq!(
+        run(&filter, arch, libc::SYS_read as u32, 0),
+        libc::SECCOMP_RET_ALLOW
+    );
+    assert_eq!(
+        run(&filter, arch, X32_SYSCALL_BIT | socket, inet),
+        libc::SECCOMP_RET_KILL_PROCESS
+    );
+    assert_eq!(
+        run(&filter, 0, socket, inet),
+        libc::SECCOMP_RET_KILL_PROCESS
+    );
+}
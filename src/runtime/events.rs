@@ -0,0 +1,472 @@
+/*
+// domain events, so a job can be failed or retried when its guest crashes or shuts itself down
+// libvirt calls back from its event loop, which runs on a thread of its own, the callbacks only
+// forward into a channel that is read as a Stream
+// qemu run without libvirt reports the same events over QMP, which are translated into DomainEvents
+*/
+use serde_json::Value;
+use std::ffi::CStr;
+use std::os::raw::{c_char, c_int, c_void};
+use std::pin::Pin;
+use std::ptr;
+use std::sync::Once;
+use std::task::{Context, Poll};
+use std::thread;
+use tokio::stream::Stream;
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use virt::connect::sys::virConnectPtr;
+use virt::connect::Connect;
+use virt::domain::sys::virDomainPtr;
+
+type Callback = *const c_void;
+type FreeCallback = unsafe extern "C" fn(*mut c_void);
+
+// virt's handle types are empty structs, which libvirt only ever sees behind pointers
+#[allow(improper_ctypes)]
+#[link(name = "virt")]
+extern "C" {
+    fn virEventRegisterDefaultImpl() -> c_int;
+    fn virEventRunDefaultImpl() -> c_int;
+    fn virConnectDomainEventRegisterAny(
+        conn: virConnectPtr,
+        dom: virDomainPtr,
+        event_id: c_int,
+        cb: Callback,
+        opaque: *mut c_void,
+        freecb: Option<FreeCallback>,
+    ) -> c_int;
+    fn virConnectDomainEventDeregisterAny(conn: virConnectPtr, callback_id: c_int) -> c_int;
+    fn virConnectRef(conn: virConnectPtr) -> c_int;
+    fn virConnectClose(conn: virConnectPtr) -> c_int;
+    fn virDomainGetName(dom: virDomainPtr) -> *const c_char;
+}
+
+// VIR_DOMAIN_EVENT_ID_*
+const EVENT_LIFECYCLE: c_int = 0;
+const EVENT_REBOOT: c_int = 1;
+const EVENT_WATCHDOG: c_int = 3;
+const EVENT_IO_ERROR_REASON: c_int = 6;
+
+static EVENT_LOOP: Once = Once::new();
+
+/// registers libvirt's default event loop and runs it on a thread
+/// connections only receive events if they are opened after this, so QEMU calls it before connecting
+pub fn start_event_loop() {
+    EVENT_LOOP.call_once(|| {
+        if unsafe { virEventRegisterDefaultImpl() } < 0 {
+            return;
+        }
+        let _ = thread::Builder::new()
+            .name(String::from("libvirt-events"))
+            .spawn(|| while unsafe { virEventRunDefaultImpl() } >= 0 {});
+    });
+}
+
+/// VIR_DOMAIN_EVENT_*, what happened to the domain
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Lifecycle {
+    Defined,
+    Undefined,
+    Started,
+    Suspended,
+    Resumed,
+    Stopped,
+    Shutdown,
+    PMSuspended,
+    Crashed,
+}
+impl Lifecycle {
+    fn from_code(code: c_int) -> Option<Self> {
+        Some(match code {
+            0 => Lifecycle::Defined,
+            1 => Lifecycle::Undefined,
+            2 => Lifecycle::Started,
+            3 => Lifecycle::Suspended,
+            4 => Lifecycle::Resumed,
+            5 => Lifecycle::Stopped,
+            6 => Lifecycle::Shutdown,
+            7 => Lifecycle::PMSuspended,
+            8 => Lifecycle::Crashed,
+            _ => return None,
+        })
+    }
+}
+
+/// VIR_DOMAIN_EVENT_STOPPED_MIGRATED, details of a Stopped event
+pub const STOPPED_MIGRATED: i32 = 3;
+/// VIR_DOMAIN_EVENT_STOPPED_SAVED
+pub const STOPPED_SAVED: i32 = 4;
+
+/// what the hypervisor did when the guest's watchdog fired
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum WatchdogAction {
+    None,
+    Pause,
+    Reset,
+    Poweroff,
+    Shutdown,
+    Debug,
+    InjectNmi,
+}
+impl WatchdogAction {
+    fn from_code(code: c_int) -> Option<Self> {
+        Some(match code {
+            0 => WatchdogAction::None,
+            1 => WatchdogAction::Pause,
+            2 => WatchdogAction::Reset,
+            3 => WatchdogAction::Poweroff,
+            4 => WatchdogAction::Shutdown,
+            5 => WatchdogAction::Debug,
+            6 => WatchdogAction::InjectNmi,
+            _ => return None,
+        })
+    }
+    /// names used by qemu's WATCHDOG event
+    fn from_qmp(action: &str) -> Option<Self> {
+        Some(match action {
+            "none" => WatchdogAction::None,
+            "pause" => WatchdogAction::Pause,
+            "reset" => WatchdogAction::Reset,
+            "poweroff" => WatchdogAction::Poweroff,
+            "shutdown" => WatchdogAction::Shutdown,
+            "debug" => WatchdogAction::Debug,
+            "inject-nmi" => WatchdogAction::InjectNmi,
+            _ => return None,
+        })
+    }
+}
+
+/// what the hypervisor did after a disk returned an error
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum IoErrorAction {
+    None,
+    Pause,
+    Report,
+}
+impl IoErrorAction {
+    fn from_code(code: c_int) -> Option<Self> {
+        Some(match code {
+            0 => IoErrorAction::None,
+            1 => IoErrorAction::Pause,
+            2 => IoErrorAction::Report,
+            _ => return None,
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum DomainEvent {
+    /// detail is the VIR_DOMAIN_EVENT_*_* code of the event, such as why the domain stopped
+    Lifecycle {
+        domain: String,
+        event: Lifecycle,
+        detail: i32,
+    },
+    Reboot {
+        domain: String,
+    },
+    Watchdog {
+        domain: String,
+        action: WatchdogAction,
+    },
+    /// device is the alias of the disk, reason is "enospc" or another errno name
+    IoError {
+        domain: String,
+        device: String,
+        action: IoErrorAction,
+        reason: String,
+    },
+}
+impl DomainEvent {
+    pub fn domain(&self) -> &str {
+        match self {
+            DomainEvent::Lifecycle { domain, .. }
+            | DomainEvent::Reboot { domain }
+            | DomainEvent::Watchdog { domain, .. }
+            | DomainEvent::IoError { domain, .. } => domain,
+        }
+    }
+    /// the guest won't finish its job, because it crashed, shut itself down, stopped or its watchdog fired
+    /// a domain stopped to be saved or migrated isn't, it carries on once it's restored
+    pub fn is_fatal(&self) -> bool {
+        match self {
+            DomainEvent::Lifecycle {
+                event: Lifecycle::Stopped,
+                detail,
+                ..
+            } => !matches!(*detail, STOPPED_MIGRATED | STOPPED_SAVED),
+            DomainEvent::Lifecycle { event, .. } => {
+                matches!(event, Lifecycle::Shutdown | Lifecycle::Crashed)
+            }
+            DomainEvent::Watchdog { action, .. } => *action != WatchdogAction::None,
+            DomainEvent::IoError { action, .. } => *action == IoErrorAction::Pause,
+            DomainEvent::Reboot { .. } => false,
+        }
+    }
+    /// translates a QMP event, None for events that have no DomainEvent
+    /// details follow libvirt's, so that both sources can be handled the same way
+    pub fn from_qmp(domain: &str, event: &Value) -> Option<Self> {
+        let data = &event["data"];
+        let lifecycle = |event, detail| DomainEvent::Lifecycle {
+            domain: domain.to_string(),
+            event,
+            detail,
+        };
+        Some(match event["event"].as_str()? {
+            // VIR_DOMAIN_EVENT_SHUTDOWN_GUEST and _HOST
+            "SHUTDOWN" => match data["guest"].as_bool() {
+                Some(true) => lifecycle(Lifecycle::Shutdown, 1),
+                _ => lifecycle(Lifecycle::Shutdown, 2),
+            },
+            "STOP" => lifecycle(Lifecycle::Suspended, 0),
+            "RESUME" => lifecycle(Lifecycle::Resumed, 0),
+            "GUEST_PANICKED" => lifecycle(Lifecycle::Crashed, 0),
+            "RESET" => DomainEvent::Reboot {
+                domain: domain.to_string(),
+            },
+            "WATCHDOG" => DomainEvent::Watchdog {
+                domain: domain.to_string(),
+                action: WatchdogAction::from_qmp(data["action"].as_str()?)?,
+            },
+            "BLOCK_IO_ERROR" => DomainEvent::IoError {
+                domain: domain.to_string(),
+                device: data["device"]
+                    .as_str()
+                    .filter(|device| !device.is_empty())
+                    .or_else(|| data["node-name"].as_str())
+                    .unwrap_or_default()
+                    .to_string(),
+                action: match data["action"].as_str()? {
+                    "ignore" => IoErrorAction::None,
+                    "stop" => IoErrorAction::Pause,
+                    _ => IoErrorAction::Report,
+                },
+                reason: match data["nospace"].as_bool() {
+                    Some(true) => String::from("enospc"),
+                    _ => data["reason"].as_str().unwrap_or_default().to_string(),
+                },
+            },
+            _ => return None,
+        })
+    }
+}
+
+/// callbacks registered with libvirt, deregistered when dropped
+struct Registration {
+    connection: virConnectPtr,
+    callbacks: Vec<c_int>,
+}
+// libvirt connections can be used from any thread, the pointer is only used to deregister
+unsafe impl Send for Registration {}
+unsafe impl Sync for Registration {}
+impl Drop for Registration {
+    fn drop(&mut self) {
+        unsafe {
+            for id in self.callbacks.drain(..) {
+                virConnectDomainEventDeregisterAny(self.connection, id);
+            }
+            virConnectClose(self.connection);
+        }
+    }
+}
+
+/// events of all domains of a connection, or of one qemu process
+/// the stream ends once the qemu process exits, a libvirt stream only ends with its connection
+pub struct EventStream {
+    receiver: UnboundedReceiver<DomainEvent>,
+    registration: Option<Registration>,
+}
+impl EventStream {
+    /// registers for the events of every domain on connection, which must have been opened after start_event_loop
+    pub fn libvirt(connection: &Connect) -> Result<Self, virt::error::Error> {
+        let conn = connection.as_ptr();
+        let (sender, receiver) = mpsc::unbounded_channel();
+        unsafe { virConnectRef(conn) };
+        let mut registration = Registration {
+            connection: conn,
+            callbacks: Vec::new(),
+        };
+        let callbacks = [
+            (EVENT_LIFECYCLE, on_lifecycle as Callback),
+            (EVENT_REBOOT, on_reboot as Callback),
+            (EVENT_WATCHDOG, on_watchdog as Callback),
+            (EVENT_IO_ERROR_REASON, on_io_error as Callback),
+        ];
+        for (event_id, callback) in callbacks.iter() {
+            // each callback owns a sender, which libvirt frees when it's deregistered
+            let opaque = Box::into_raw(Box::new(sender.clone())) as *mut c_void;
+            let id = unsafe {
+                virConnectDomainEventRegisterAny(
+                    conn,
+                    ptr::null_mut(),
+                    *event_id,
+                    *callback,
+                    opaque,
+                    Some(free_sender),
+                )
+            };
+            if id < 0 {
+                unsafe { free_sender(opaque) };
+                return Err(virt::error::Error::new());
+            }
+            registration.callbacks.push(id);
+        }
+        Ok(Self {
+            receiver,
+            registration: Some(registration),
+        })
+    }
+    /// stream fed by a task, which ends when the task drops its sender
+    pub(crate) fn channel() -> (UnboundedSender<DomainEvent>, Self) {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let stream = Self {
+            receiver,
+            registration: None,
+        };
+        (sender, stream)
+    }
+}
+impl Stream for EventStream {
+    type Item = DomainEvent;
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<DomainEvent>> {
+        self.receiver.poll_recv(cx)
+    }
+}
+impl std::fmt::Debug for EventStream {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("EventStream")
+            .field("libvirt", &self.registration.is_some())
+            .finish()
+    }
+}
+
+unsafe extern "C" fn free_sender(opaque: *mut c_void) {
+    drop(Box::from_raw(opaque as *mut UnboundedSender<DomainEvent>));
+}
+/// the receiver may already be gone, in which case the event is dropped
+unsafe fn send(opaque: *mut c_void, event: DomainEvent) {
+    let sender = &*(opaque as *const UnboundedSender<DomainEvent>);
+    let _ = sender.send(event);
+}
+unsafe fn domain_name(dom: virDomainPtr) -> String {
+    let name = virDomainGetName(dom);
+    if name.is_null() {
+        return String::new();
+    }
+    CStr::from_ptr(name).to_string_lossy().into_owned()
+}
+unsafe extern "C" fn on_lifecycle(
+    _conn: virConnectPtr,
+    dom: virDomainPtr,
+    event: c_int,
+    detail: c_int,
+    opaque: *mut c_void,
+) -> c_int {
+    if let Some(event) = Lifecycle::from_code(event) {
+        let domain = domain_name(dom);
+        send(
+            opaque,
+            DomainEvent::Lifecycle {
+                domain,
+                event,
+                detail,
+            },
+        );
+    }
+    0
+}
+unsafe extern "C" fn on_reboot(_conn: virConnectPtr, dom: virDomainPtr, opaque: *mut c_void) {
+    let domain = domain_name(dom);
+    send(opaque, DomainEvent::Reboot { domain });
+}
+unsafe extern "C" fn on_watchdog(
+    _conn: virConnectPtr,
+    dom: virDomainPtr,
+    action: c_int,
+    opaque: *mut c_void,
+) {
+    if let Some(action) = WatchdogAction::from_code(action) {
+        let domain = domain_name(dom);
+        send(opaque, DomainEvent::Watchdog { domain, action });
+    }
+}
+unsafe extern "C" fn on_io_error(
+    _conn: virConnectPtr,
+    dom: virDomainPtr,
+    _src_path: *const c_char,
+    dev_alias: *const c_char,
+    action: c_int,
+    reason: *const c_char,
+    opaque: *mut c_void,
+) {
+    let text = |s: *const c_char| match s.is_null() {
+        true => String::new(),
+        false => CStr::from_ptr(s).to_string_lossy().into_owned(),
+    };
+    if let Some(action) = IoErrorAction::from_code(action) {
+        let event = DomainEvent::IoError {
+            domain: domain_name(dom),
+            device: text(dev_alias),
+            action,
+            reason: text(reason),
+        };
+        send(opaque, event);
+    }
+}
+
+#[test]
+fn qmp_events() {
+    use serde_json::json;
+
+    let event = |value| DomainEvent::from_qmp("guest", &value);
+    assert_eq!(
+        event(json!({"event": "SHUTDOWN", "data": {"guest": true, "reason": "guest-shutdown"}})),
+        Some(DomainEvent::Lifecycle {
+            domain: String::from("guest"),
+            event: Lifecycle::Shutdown,
+            detail: 1
+        })
+    );
+    assert_eq!(
+        event(json!({"event": "RESET", "data": {"guest": true}})),
+        Some(DomainEvent::Reboot {
+            domain: String::from("guest")
+        })
+    );
+    let watchdog = event(json!({"event": "WATCHDOG", "data": {"action": "reset"}})).unwrap();
+    assert_eq!(
+        watchdog,
+        DomainEvent::Watchdog {
+            domain: String::from("guest"),
+            action: WatchdogAction::Reset
+        }
+    );
+    assert!(watchdog.is_fatal());
+    let stopped = |detail| DomainEvent::Lifecycle {
+        domain: String::from("guest"),
+        event: Lifecycle::Stopped,
+        detail,
+    };
+    assert!(stopped(1).is_fatal());
+    assert!(!stopped(STOPPED_SAVED).is_fatal());
+    assert!(!stopped(STOPPED_MIGRATED).is_fatal());
+    assert!(event(json!({"event": "SHUTDOWN", "data": {"guest": true}})).unwrap().is_fatal());
+    let io_error = event(json!({"event": "BLOCK_IO_ERROR", "data": {
+        "device": "", "node-name": "disk0", "operation": "write", "action": "stop", "nospace": true
+    }}))
+    .unwrap();
+    assert_eq!(
+        io_error,
+        DomainEvent::IoError {
+            domain: String::from("guest"),
+            device: String::from("disk0"),
+            action: IoErrorAction::Pause,
+            reason: String::from("enospc")
+        }
+    );
+    assert!(io_error.is_fatal());
+    assert_eq!(
+        event(json!({"event": "RTC_CHANGE", "data": {"offset": 1}})),
+        None
+    );
+}
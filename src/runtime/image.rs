@@ -0,0 +1,395 @@
+/*
+// disk images for kvm guests, created with qemu-img
+// every image is created for a domain, and is deleted along with the other images of that domain
+// when the domain is released, jobs boot from a copy on write overlay of a shared base image
+// so that whatever a job writes to its disk is thrown away with the overlay
+*/
+use crate::runtime::kvm::{driver, Disk, Source, Target};
+use crate::runtime::{Quantity, UnitErr};
+use std::collections::HashMap;
+use std::error::Error;
+use std::ffi::OsString;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::str::FromStr;
+use std::sync::Mutex;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum ImageFormat {
+    Qcow2,
+    Raw,
+}
+impl ImageFormat {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Self::Qcow2 => "qcow2",
+            Self::Raw => "img",
+        }
+    }
+}
+impl fmt::Display for ImageFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Qcow2 => write!(f, "qcow2"),
+            Self::Raw => write!(f, "raw"),
+        }
+    }
+}
+impl FromStr for ImageFormat {
+    type Err = ImageError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "qcow2" => Ok(Self::Qcow2),
+            "raw" => Ok(Self::Raw),
+            other => Err(ImageError::Format(other.to_string())),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ImageError {
+    Io(io::Error),
+    /// qemu-img failed, with what it wrote to stderr
+    QemuImg(String),
+    Size(UnitErr),
+    /// image format other then qcow2 or raw
+    Format(String),
+    /// names can't contain path separators, or start with a dot
+    InvalidName(String),
+    Exists(PathBuf),
+}
+impl fmt::Display for ImageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Io(e) => write!(f, "{}", e),
+            Self::QemuImg(e) => write!(f, "qemu-img failed: {}", e),
+            Self::Size(e) => write!(f, "invalid image size: {}", e),
+            Self::Format(format) => write!(f, "unsupported image format {}", format),
+            Self::InvalidName(name) => write!(f, "invalid image name {}", name),
+            Self::Exists(path) => write!(f, "{} already exists", path.display()),
+        }
+    }
+}
+impl Error for ImageError {}
+impl From<io::Error> for ImageError {
+    fn from(error: io::Error) -> Self {
+        ImageError::Io(error)
+    }
+}
+impl From<UnitErr> for ImageError {
+    fn from(error: UnitErr) -> Self {
+        ImageError::Size(error)
+    }
+}
+
+/// image file created by an ImageManager
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Image {
+    path: PathBuf,
+    format: ImageFormat,
+}
+impl Image {
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+    pub fn format(&self) -> ImageFormat {
+        self.format
+    }
+    /// virtio disk backed by this image
+    ///
+    /// # Arguments
+    ///
+    /// dev: target device in the guest, such as vdb
+    pub fn disk(&self, dev: &str) -> Disk {
+        Disk {
+            r#type: Some(String::from("file")),
+            device: Some(String::from("disk")),
+            driver: Some(driver {
+                name: Some(String::from("qemu")),
+                r#type: Some(self.format.to_string()),
+            }),
+            source: Some(Source {
+                file: Some(self.path.to_string_lossy().into_owned()),
+            }),
+            target: Some(Target {
+                dev: Some(dev.to_string()),
+                bus: Some(String::from("virtio")),
+            }),
+            address: None,
+        }
+    }
+}
+
+/// what qemu-img info reports about an image
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ImageInfo {
+    pub format: ImageFormat,
+    /// bytes the guest sees
+    pub virtual_size: u64,
+    /// bytes used on the host
+    pub actual_size: Option<u64>,
+    pub backing_file: Option<PathBuf>,
+}
+
+/// creates images in one directory, and keeps track of which domain each image belongs to
+pub struct ImageManager {
+    dir: PathBuf,
+    qemu_img: PathBuf,
+    images: Mutex<HashMap<String, Vec<PathBuf>>>,
+}
+impl ImageManager {
+    pub fn new<P: AsRef<Path>>(dir: P) -> Self {
+        Self {
+            dir: dir.as_ref().to_path_buf(),
+            qemu_img: PathBuf::from("qemu-img"),
+            images: Mutex::new(HashMap::new()),
+        }
+    }
+    /// qemu-img from PATH by default
+    pub fn qemu_img<P: AsRef<Path>>(mut self, qemu_img: P) -> Self {
+        self.qemu_img = qemu_img.as_ref().to_path_buf();
+        self
+    }
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+    /// blank image of size bytes, sizes that aren't a whole number of bytes are rejected
+    ///
+    /// # Arguments
+    ///
+    /// domain: the image is deleted when this domain is released
+    /// name: file name of the image, without an extension
+    pub fn create(
+        &self,
+        domain: &str,
+        name: &str,
+        format: ImageFormat,
+        size: Quantity,
+    ) -> Result<Image, ImageError> {
+        let image = self.new_image(domain, name, format)?;
+        let args = create_args(&image, size.to_bytes()?);
+        self.run(&args)?;
+        self.track(domain, &image);
+        Ok(image)
+    }
+    /// qcow2 image that reads from base, and keeps writes to itself, base is never modified
+    pub fn overlay(&self, domain: &str, name: &str, base: &Path) -> Result<Image, ImageError> {
+        let base_format = self.info(base)?.format;
+        // relative backing files are resolved from the overlay's directory, not ours
+        let base = fs::canonicalize(base)?;
+        let image = self.new_image(domain, name, ImageFormat::Qcow2)?;
+        self.run(&overlay_args(&image, &base, base_format))?;
+        self.track(domain, &image);
+        Ok(image)
+    }
+    /// changes the size the guest sees, shrinking loses whatever is stored past the new size
+    pub fn resize(&self, image: &Image, size: Quantity) -> Result<(), ImageError> {
+        let bytes = size.to_bytes()?;
+        let shrink = bytes < self.info(&image.path)?.virtual_size;
+        self.run(&resize_args(image, bytes, shrink))?;
+        Ok(())
+    }
+    pub fn info(&self, path: &Path) -> Result<ImageInfo, ImageError> {
+        let mut args: Vec<OsString> = vec!["info".into(), "--output=json".into()];
+        args.push(path.into());
+        let output = self.run(&args)?;
+        parse_info(&output)
+    }
+    /// images created for domain that haven't been released
+    pub fn images(&self, domain: &str) -> Vec<PathBuf> {
+        self.images
+            .lock()
+            .unwrap()
+            .get(domain)
+            .cloned()
+            .unwrap_or_default()
+    }
+    /// deletes every image created for domain, images that are already gone are ignored
+    pub fn release(&self, domain: &str) -> Result<(), ImageError> {
+        let images = match self.images.lock().unwrap().remove(domain) {
+            Some(images) => images,
+            None => return Ok(()),
+        };
+        // overlays are removed before the images they could be backed by
+        for path in images.iter().rev() {
+            match fs::remove_file(path) {
+                Ok(()) => (),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => (),
+                Err(e) => return Err(e.into()),
+            }
+        }
+        Ok(())
+    }
+    fn new_image(
+        &self,
+        domain: &str,
+        name: &str,
+        format: ImageFormat,
+    ) -> Result<Image, ImageError> {
+        for part in [domain, name].iter() {
+            if part.is_empty() || part.starts_with('.') || part.contains('/') {
+                return Err(ImageError::InvalidName(part.to_string()));
+            }
+        }
+        fs::create_dir_all(&self.dir)?;
+        let path = self
+            .dir
+            .join(format!("{}-{}.{}", domain, name, format.extension()));
+        if path.exists() {
+            return Err(ImageError::Exists(path));
+        }
+        Ok(Image { path, format })
+    }
+    fn track(&self, domain: &str, image: &Image) {
+        self.images
+            .lock()
+            .unwrap()
+            .entry(domain.to_string())
+            .or_default()
+            .push(image.path.clone());
+    }
+    /// runs qemu-img and returns its stdout
+    fn run(&self, args: &[OsString]) -> Result<String, ImageError> {
+        let output = Command::new(&self.qemu_img).args(args).output()?;
+        if !output.status.success() {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            return Err(ImageError::QemuImg(stderr.trim().to_string()));
+        }
+        Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+    }
+}
+
+fn create_args(image: &Image, bytes: u64) -> Vec<OsString> {
+    vec![
+        "create".into(),
+        "-f".into(),
+        image.format.to_string().into(),
+        image.path.clone().into(),
+        bytes.to_string().into(),
+    ]
+}
+fn overlay_args(image: &Image, base: &Path, base_format: ImageFormat) -> Vec<OsString> {
+    vec![
+        "create".into(),
+        "-f".into(),
+        "qcow2".into(),
+        "-b".into(),
+        base.into(),
+        "-F".into(),
+        base_format.to_string().into(),
+        image.path.clone().into(),
+    ]
+}
+fn resize_args(image: &Image, bytes: u64, shrink: bool) -> Vec<OsString> {
+    let mut args: Vec<OsString> = vec!["resize".into(), "-f".into()];
+    args.push(image.format.to_string().into());
+    if shrink {
+        args.push("--shrink".into());
+    }
+    args.push(image.path.clone().into());
+    args.push(bytes.to_string().into());
+    args
+}
+fn parse_info(json: &str) -> Result<ImageInfo, ImageError> {
+    let info: serde_json::Value = match serde_json::from_str(json) {
+        Ok(info) => info,
+        Err(e) => return Err(ImageError::QemuImg(format!("invalid info output: {}", e))),
+    };
+    let format = match info["format"].as_str() {
+        Some(format) => format.parse()?,
+        None => return Err(ImageError::QemuImg(String::from("info has no format"))),
+    };
+    let virtual_size = match info["virtual-size"].as_u64() {
+        Some(size) => size,
+        None => return Err(ImageError::QemuImg(String::from("info has no size"))),
+    };
+    Ok(ImageInfo {
+        format,
+        virtual_size,
+        actual_size: info["actual-size"].as_u64(),
+        backing_file: info["backing-filename"].as_str().map(PathBuf::from),
+    })
+}
+
+#[test]
+fn qemu_img_args() {
+    let image = Image {
+        path: PathBuf::from("/images/job-0-root.qcow2"),
+        format: ImageFormat::Qcow2,
+    };
+    let args = |args: Vec<OsString>| {
+        let args: Vec<String> = args
+            .iter()
+            .map(|arg| arg.to_string_lossy().into_owned())
+            .collect();
+        args.join(" ")
+    };
+    assert_eq!(
+        args(create_args(&image, 1 << 30)),
+        "create -f qcow2 /images/job-0-root.qcow2 1073741824"
+    );
+    assert_eq!(
+        args(overlay_args(
+            &image,
+            Path::new("/base/debian.img"),
+            ImageFormat::Raw
+        )),
+        "create -f qcow2 -b /base/debian.img -F raw /images/job-0-root.qcow2"
+    );
+    assert_eq!(
+        args(resize_args(&image, 4096, true)),
+        "resize -f qcow2 --shrink /images/job-0-root.qcow2 4096"
+    );
+    let info = parse_info(
+        r#"{"virtual-size": 1073741824, "filename": "/images/job-0-root.qcow2",
+        "format": "qcow2", "actual-size": 200704, "backing-filename": "/base/debian.img",
+        "backing-filename-format": "raw", "dirty-flag": false}"#,
+    )
+    .unwrap();
+    assert_eq!(info.virtual_size, 1 << 30);
+    assert_eq!(info.backing_file, Some(PathBuf::from("/base/debian.img")));
+    assert_eq!(image.disk("vdb").target.unwrap().dev.unwrap(), "vdb");
+}
+#[test]
+fn images() {
+    use crate::runtime::Unit;
+
+    let dir = std::env::temp_dir().join(format!("artifice-images-{}", std::process::id()));
+    let manager = ImageManager::new(&dir);
+    let base = match manager.create(
+        "base",
+        "debian",
+        ImageFormat::Raw,
+        Quantity::new(1, Unit::MiB),
+    ) {
+        Ok(base) => base,
+        Err(ImageError::Io(e)) if e.kind() == io::ErrorKind::NotFound => {
+            println!("skipping, qemu-img isn't installed");
+            return;
+        }
+        Err(e) => panic!("{}", e),
+    };
+    let overlay = manager.overlay("job-0", "root", base.path()).unwrap();
+    let info = manager.info(overlay.path()).unwrap();
+    assert_eq!(info.format, ImageFormat::Qcow2);
+    assert_eq!(info.virtual_size, 1 << 20);
+    assert_eq!(info.backing_file.as_deref(), Some(base.path()));
+    assert!(matches!(
+        manager.overlay("job-0", "root", base.path()),
+        Err(ImageError::Exists(_))
+    ));
+    manager
+        .resize(&overlay, Quantity::new(2, Unit::MiB))
+        .unwrap();
+    assert_eq!(manager.info(overlay.path()).unwrap().virtual_size, 2 << 20);
+
+    manager.release("job-0").unwrap();
+    assert!(!overlay.path().exists());
+    assert!(base.path().exists());
+    assert!(manager.images("job-0").is_empty());
+    manager.release("base").unwrap();
+    fs::remove_dir(&dir).unwrap();
+}
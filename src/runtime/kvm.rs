@@ -5,30 +5,176 @@
 */
 use std::collections::HashMap;
 use std::error::Error;
-use strong_xml::{XmlRead, XmlWrite};
+use std::fmt;
+use std::fs;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::ffi::{CStr, CString};
+use std::os::raw::{c_char, c_int, c_uint};
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, Instant};
+use async_trait::async_trait;
+use ipnetwork::IpNetwork;
+use strong_xml::{XmlError, XmlRead, XmlWrite};
+use virt::connect::sys::virConnectPtr;
 use virt::connect::Connect;
-use virt::domain::Domain;
-use crate::runtime::Unit;
+use virt::domain::sys::virDomainPtr;
+use virt::domain::{self, Domain};
+use virt::domain_snapshot::sys::virDomainSnapshotPtr;
+use virt::domain_snapshot::DomainSnapshot;
+use virt::network::Network;
+use virt::storage_pool::sys::virStoragePoolPtr;
+use virt::storage_pool::StoragePool;
+use virt::storage_vol::sys::virStorageVolPtr;
+use virt::storage_vol::StorageVol;
+use crate::job::{CancelToken, JobError, JobId, JobOutcome, JobResult, JobStatus, JobTable};
+use crate::ledger::Usage;
+use crate::runtime::cloudinit::{
+    CloudInit, SeedError, SeedFormat, VmJob, OUTPUT_PORT, STATUS_PORT,
+};
+use crate::runtime::events::{self, EventStream};
+use crate::runtime::image::{Image, ImageError, ImageFormat, ImageManager};
+use crate::runtime::passthrough::{self, Passthrough};
+use crate::runtime::{Quantity, ResourceLimits, Unit, UnitErr};
+use crate::{EnvData, EnvType, ExecEnv, HostEnv, RemoteEnv};
+
+mod xml;
+pub use xml::*;
+
+#[derive(Debug)]
+pub enum KvmError {
+    Libvirt(virt::error::Error),
+    /// a DomainConfig couldn't be written as xml, or libvirt returned xml that couldn't be read
+    /// XmlError isn't Send, so only its message is kept
+    Xml(String),
+    /// no domain with this name has been loaded or created
+    UnknownDomain(String),
+    Image(ImageError),
+    Config(ConfigError),
+    Seed(SeedError),
+    Job(JobError),
+    Io(io::Error),
+    /// the vm of a job crashed before it powered off
+    Crashed(String),
+}
+impl fmt::Display for KvmError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Libvirt(e) => write!(f, "{}", e),
+            Self::Xml(e) => write!(f, "invalid domain xml: {}", e),
+            Self::UnknownDomain(name) => write!(f, "no domain named {}", name),
+            Self::Image(e) => write!(f, "{}", e),
+            Self::Config(e) => write!(f, "{}", e),
+            Self::Seed(e) => write!(f, "{}", e),
+            Self::Job(e) => write!(f, "{}", e),
+            Self::Io(e) => write!(f, "{}", e),
+            Self::Crashed(name) => write!(f, "{} crashed", name),
+        }
+    }
+}
+impl Error for KvmError {}
+impl From<virt::error::Error> for KvmError {
+    fn from(error: virt::error::Error) -> Self {
+        KvmError::Libvirt(error)
+    }
+}
+impl From<ImageError> for KvmError {
+    fn from(error: ImageError) -> Self {
+        KvmError::Image(error)
+    }
+}
+impl From<XmlError> for KvmError {
+    fn from(error: XmlError) -> Self {
+        KvmError::Xml(format!("{:?}", error))
+    }
+}
+impl From<ConfigError> for KvmError {
+    fn from(error: ConfigError) -> Self {
+        KvmError::Config(error)
+    }
+}
+impl From<SeedError> for KvmError {
+    fn from(error: SeedError) -> Self {
+        KvmError::Seed(error)
+    }
+}
+impl From<JobError> for KvmError {
+    fn from(error: JobError) -> Self {
+        KvmError::Job(error)
+    }
+}
+impl From<io::Error> for KvmError {
+    fn from(error: io::Error) -> Self {
+        KvmError::Io(error)
+    }
+}
+
+/// state of a domain as reported by libvirt
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum DomainState {
+    NoState,
+    Running,
+    /// blocked on a resource
+    Blocked,
+    /// paused by suspend
+    Paused,
+    /// shutdown has been requested but the guest is still running
+    ShuttingDown,
+    ShutOff,
+    Crashed,
+    /// suspended by the guest's own power management
+    PMSuspended,
+}
+impl From<domain::DomainState> for DomainState {
+    fn from(state: domain::DomainState) -> Self {
+        match state {
+            domain::VIR_DOMAIN_RUNNING => DomainState::Running,
+            domain::VIR_DOMAIN_BLOCKED => DomainState::Blocked,
+            domain::VIR_DOMAIN_PAUSED => DomainState::Paused,
+            domain::VIR_DOMAIN_SHUTDOWN => DomainState::ShuttingDown,
+            domain::VIR_DOMAIN_SHUTOFF => DomainState::ShutOff,
+            domain::VIR_DOMAIN_CRASHED => DomainState::Crashed,
+            domain::VIR_DOMAIN_PMSUSPENDED => DomainState::PMSuspended,
+            _ => DomainState::NoState,
+        }
+    }
+}
 
 pub struct QEMU {
     connection: Connect,
     active_domains: HashMap<String, Domain>,
+    images: Option<ImageManager>,
 }
 impl QEMU {
-    pub fn connect() -> Result<Self, Box<dyn Error>> {
-        let connection = Connect::open("qemu:///session")?;
+    pub fn connect() -> Result<Self, KvmError> {
+        Self::connect_uri("qemu:///session")
+    }
+    /// connects to any libvirt driver, such as "qemu:///system" or "test:///default"
+    pub fn connect_uri(uri: &str) -> Result<Self, KvmError> {
+        events::start_event_loop();
+        let connection = Connect::open(uri)?;
         let active_domains = HashMap::new();
         Ok(Self {
             connection,
             active_domains,
+            images: None,
         })
     }
-    pub fn load_by_name(&mut self, name: &str) -> Result<(), Box<dyn Error>> {
+    /// images created through the manager for a domain are deleted when it's destroyed or undefined
+    pub fn with_images(mut self, images: ImageManager) -> Self {
+        self.images = Some(images);
+        self
+    }
+    pub fn images(&self) -> Option<&ImageManager> {
+        self.images.as_ref()
+    }
+    pub fn load_by_name(&mut self, name: &str) -> Result<(), KvmError> {
         let domain = Domain::lookup_by_name(&self.connection, name)?;
         self.active_domains.insert(name.to_string(), domain);
         Ok(())
     }
-    pub fn load_all_domains(&mut self) -> Result<Vec<String>, Box<dyn Error>> {
+    pub fn load_all_domains(&mut self) -> Result<Vec<String>, KvmError> {
         let domain_list = self.connection.list_domains()?;
         let mut names = Vec::new();
         for id in domain_list.into_iter() {
@@ -42,14 +188,763 @@ impl QEMU {
     pub fn mut_domain(&mut self, key: &str) -> Option<&mut Domain> {
         self.active_domains.get_mut(key)
     }
-    pub fn create_domain(&mut self) {}
+    /// defines a persistent domain from config, and starts it if start is true
+    /// the domain is kept in active_domains under its name, if it fails to start it is undefined again
+    pub fn create_domain(
+        &mut self,
+        config: &DomainConfig,
+        start: bool,
+    ) -> Result<&mut Domain, KvmError> {
+        let xml = config.to_string()?;
+        let domain = Domain::define_xml(&self.connection, &xml)?;
+        if start {
+            if let Err(e) = domain.create() {
+                domain.undefine()?;
+                return Err(e.into());
+            }
+        }
+        let name = domain.get_name()?;
+        self.active_domains.insert(name.clone(), domain);
+        Ok(self.active_domains.get_mut(&name).unwrap())
+    }
+    fn domain(&self, name: &str) -> Result<&Domain, KvmError> {
+        self.active_domains
+            .get(name)
+            .ok_or_else(|| KvmError::UnknownDomain(name.to_string()))
+    }
+    /// boots a domain that is defined but not running
+    pub fn start(&self, name: &str) -> Result<(), KvmError> {
+        self.domain(name)?.create()?;
+        Ok(())
+    }
+    /// asks the guest to shut down, returns before the guest has stopped
+    pub fn shutdown(&self, name: &str) -> Result<(), KvmError> {
+        self.domain(name)?.shutdown()?;
+        Ok(())
+    }
+    /// stops the domain immediately, like pulling the power cord
+    pub fn destroy(&self, name: &str) -> Result<(), KvmError> {
+        self.domain(name)?.destroy()?;
+        self.release_images(name)
+    }
+    pub fn reboot(&self, name: &str) -> Result<(), KvmError> {
+        self.domain(name)?.reboot()?;
+        Ok(())
+    }
+    /// pauses the domain, its memory stays allocated
+    pub fn suspend(&self, name: &str) -> Result<(), KvmError> {
+        self.domain(name)?.suspend()?;
+        Ok(())
+    }
+    pub fn resume(&self, name: &str) -> Result<(), KvmError> {
+        self.domain(name)?.resume()?;
+        Ok(())
+    }
+    /// removes the domain's definition, a running domain keeps running as a transient domain
+    /// and stays in active_domains, otherwise it is removed
+    pub fn undefine(&mut self, name: &str) -> Result<(), KvmError> {
+        let domain = self.domain(name)?;
+        domain.undefine()?;
+        if !domain.is_active()? {
+            if let Some(domain) = self.active_domains.remove(name) {
+                free(domain);
+            }
+            self.release_images(name)?;
+        }
+        Ok(())
+    }
+    fn release_images(&self, name: &str) -> Result<(), KvmError> {
+        if let Some(images) = &self.images {
+            images.release(name)?;
+        }
+        Ok(())
+    }
+    pub fn state(&self, name: &str) -> Result<DomainState, KvmError> {
+        let (state, _reason) = self.domain(name)?.get_state()?;
+        Ok(state.into())
+    }
+    /// cpu time used by all of the domain's vcpus since it started
+    pub fn cpu_time(&self, name: &str) -> Result<Duration, KvmError> {
+        let info = self.domain(name)?.get_info()?;
+        Ok(Duration::from_nanos(info.cpu_time))
+    }
+    /// definition of the domain as libvirt has it, including what it filled in, such as addresses
+    pub fn config(&self, name: &str) -> Result<DomainConfig, KvmError> {
+        let xml = self.domain(name)?.get_xml_desc(0)?;
+        Ok(DomainConfig::from_str(&xml)?)
+    }
+    /// writes the memory and device state of a running domain to path, and stops it
+    /// the domain stays defined, and can be started from path again with restore
+    pub fn save(&self, name: &str, path: &Path) -> Result<(), KvmError> {
+        let domain = self.domain(name)?;
+        let path = c_path(path)?;
+        if unsafe { virDomainSave(domain.as_ptr(), path.as_ptr()) } == -1 {
+            return Err(virt::error::Error::new().into());
+        }
+        Ok(())
+    }
+    /// definition kept in a file written by save, which restore uses unless it's given another
+    pub fn saved_config(&self, path: &Path) -> Result<DomainConfig, KvmError> {
+        let path = c_path(path)?;
+        let conn = self.connection.as_ptr();
+        let xml = unsafe { virDomainSaveImageGetXMLDesc(conn, path.as_ptr(), 0) };
+        if xml.is_null() {
+            return Err(virt::error::Error::new().into());
+        }
+        let config = unsafe { CStr::from_ptr(xml) }.to_string_lossy().into_owned();
+        unsafe { libc::free(xml as *mut libc::c_void) };
+        Ok(DomainConfig::from_str(&config)?)
+    }
+    /// starts a domain from a file written by save, possibly on another host
+    ///
+    /// # Arguments
+    ///
+    /// config: replaces the definition kept in the file, such as to point its disks elsewhere,
+    /// only paths and other host specific parts may differ from the saved definition
+    pub fn restore(&mut self, path: &Path, config: Option<&DomainConfig>) -> Result<(), KvmError> {
+        let path = c_path(path)?;
+        let xml = match config {
+            Some(config) => Some(CString::new(config.to_string()?).map_err(|e| {
+                KvmError::Xml(e.to_string())
+            })?),
+            None => None,
+        };
+        let xml_ptr = xml.as_ref().map_or(std::ptr::null(), |xml| xml.as_ptr());
+        let conn = self.connection.as_ptr();
+        if unsafe { virDomainRestoreFlags(conn, path.as_ptr(), xml_ptr, 0) } == -1 {
+            return Err(virt::error::Error::new().into());
+        }
+        Ok(())
+    }
+    /// lifecycle, reboot, watchdog and io error events of every domain on the connection
+    pub fn events(&self) -> Result<EventStream, KvmError> {
+        Ok(EventStream::libvirt(&self.connection)?)
+    }
+    /// takes a snapshot of a domain, returns its name, which libvirt picks if config has none
+    pub fn create_snapshot(&self, name: &str, config: &SnapshotConfig) -> Result<String, KvmError> {
+        let flags = match config.is_disk_only() {
+            true => SNAPSHOT_CREATE_DISK_ONLY,
+            false => 0,
+        };
+        let xml = config.to_string()?;
+        let snapshot = DomainSnapshot::create_xml(self.domain(name)?, &xml, flags)?;
+        let result = snapshot.get_name();
+        free_snapshot(snapshot);
+        Ok(result?)
+    }
+    /// names of the domain's snapshots, oldest first
+    /// creation times are whole seconds, so snapshots taken in the same second are ordered by
+    /// their parent chain, a parent before its children
+    pub fn snapshots(&self, name: &str) -> Result<Vec<String>, KvmError> {
+        let mut snapshots = Vec::new();
+        let mut parents: HashMap<String, String> = HashMap::new();
+        for snapshot in self.domain(name)?.list_all_snapshots(0)? {
+            let config = snapshot.get_xml_desc(0);
+            let snapshot_name = snapshot.get_name();
+            free_snapshot(snapshot);
+            let config = SnapshotConfig::from_str(&config?)?;
+            let snapshot_name = snapshot_name?;
+            if let Some(parent) = &config.parent {
+                parents.insert(snapshot_name.clone(), parent.name.value().to_string());
+            }
+            snapshots.push((config.created(), snapshot_name));
+        }
+        let depth = |snapshot: &str| {
+            let mut depth = 0;
+            let mut current = snapshot;
+            // bounded, in case libvirt ever reports a cycle
+            while let Some(parent) = parents.get(current) {
+                if depth > parents.len() {
+                    break;
+                }
+                depth += 1;
+                current = parent.as_str();
+            }
+            depth
+        };
+        snapshots.sort_by_cached_key(|(created, name)| (*created, depth(name)));
+        Ok(snapshots.into_iter().map(|(_, name)| name).collect())
+    }
+    /// definition of a snapshot, including the domain as it was when the snapshot was taken
+    pub fn snapshot(&self, name: &str, snapshot: &str) -> Result<SnapshotConfig, KvmError> {
+        let snapshot = DomainSnapshot::lookup_by_name(self.domain(name)?, snapshot, 0)?;
+        let xml = snapshot.get_xml_desc(0);
+        free_snapshot(snapshot);
+        Ok(SnapshotConfig::from_str(&xml?)?)
+    }
+    /// returns the domain to the snapshot, running or paused if it was when the snapshot was taken
+    pub fn revert_snapshot(&self, name: &str, snapshot: &str) -> Result<(), KvmError> {
+        let snapshot = DomainSnapshot::lookup_by_name(self.domain(name)?, snapshot, 0)?;
+        let result = unsafe { virDomainRevertToSnapshot(snapshot.as_ptr(), 0) };
+        let error = match result {
+            -1 => Some(virt::error::Error::new()),
+            _ => None,
+        };
+        free_snapshot(snapshot);
+        match error {
+            Some(e) => Err(e.into()),
+            None => Ok(()),
+        }
+    }
+    /// deletes a snapshot, the images of an external snapshot are left for the caller to remove
+    pub fn delete_snapshot(&self, name: &str, snapshot: &str) -> Result<(), KvmError> {
+        let snapshot = DomainSnapshot::lookup_by_name(self.domain(name)?, snapshot, 0)?;
+        let result = snapshot.delete(0);
+        free_snapshot(snapshot);
+        result?;
+        Ok(())
+    }
+    /// defines a persistent network, and starts it if start is true
+    /// if it fails to start it is undefined again
+    pub fn create_network(&self, config: &NetworkConfig, start: bool) -> Result<(), KvmError> {
+        let network = Network::define_xml(&self.connection, &config.to_string()?)?;
+        let mut result = Ok(());
+        if start {
+            if let Err(e) = network.create() {
+                let _ = network.undefine();
+                result = Err(e);
+            }
+        }
+        free_network(network);
+        Ok(result?)
+    }
+    /// names of active and inactive networks
+    pub fn networks(&self) -> Result<Vec<String>, KvmError> {
+        let mut names = self.connection.list_networks()?;
+        names.extend(self.connection.list_defined_networks()?);
+        Ok(names)
+    }
+    pub fn network(&self, name: &str) -> Result<NetworkConfig, KvmError> {
+        let network = Network::lookup_by_name(&self.connection, name)?;
+        let xml = network.get_xml_desc(0);
+        free_network(network);
+        Ok(NetworkConfig::from_str(&xml?)?)
+    }
+    /// stops the network if it's running and removes its definition,
+    /// domains attached to it lose their interface's link
+    pub fn remove_network(&self, name: &str) -> Result<(), KvmError> {
+        let network = Network::lookup_by_name(&self.connection, name)?;
+        let result = match network.is_active() {
+            Ok(true) => network.destroy().and_then(|_| network.undefine()),
+            Ok(false) => network.undefine(),
+            Err(e) => Err(e),
+        };
+        free_network(network);
+        Ok(result?)
+    }
+    /// defines a persistent pool, builds it, which creates the directory of a dir pool,
+    /// and starts it if start is true, a pool has to be started before volumes can be created in it
+    pub fn create_pool(&self, config: &PoolConfig, start: bool) -> Result<(), KvmError> {
+        let pool = StoragePool::define_xml(&self.connection, &config.to_string()?, 0)?;
+        let mut result = pool.build(0).map(|_| ());
+        if start && result.is_ok() {
+            result = pool.create(0).map(|_| ());
+        }
+        if result.is_err() {
+            let _ = pool.undefine();
+        }
+        free_pool(pool);
+        Ok(result?)
+    }
+    /// names of active and inactive pools
+    pub fn pools(&self) -> Result<Vec<String>, KvmError> {
+        let mut names = self.connection.list_storage_pools()?;
+        names.extend(self.connection.list_defined_storage_pools()?);
+        Ok(names)
+    }
+    pub fn pool(&self, name: &str) -> Result<PoolConfig, KvmError> {
+        let pool = StoragePool::lookup_by_name(&self.connection, name)?;
+        let xml = pool.get_xml_desc(0);
+        free_pool(pool);
+        Ok(PoolConfig::from_str(&xml?)?)
+    }
+    /// stops the pool if it's running and removes its definition, its volumes are left on disk
+    pub fn remove_pool(&self, name: &str) -> Result<(), KvmError> {
+        let pool = StoragePool::lookup_by_name(&self.connection, name)?;
+        let result = match pool.is_active() {
+            Ok(true) => pool.destroy().and_then(|_| pool.undefine()),
+            Ok(false) => pool.undefine(),
+            Err(e) => Err(e),
+        };
+        free_pool(pool);
+        Ok(result?)
+    }
+    /// creates a volume in a started pool, returns the path of its image
+    pub fn create_volume(&self, pool: &str, config: &VolumeConfig) -> Result<PathBuf, KvmError> {
+        let pool = StoragePool::lookup_by_name(&self.connection, pool)?;
+        let result = StorageVol::create_xml(&pool, &config.to_string()?, 0).and_then(|volume| {
+            let path = volume.get_path();
+            free_volume(volume);
+            path
+        });
+        free_pool(pool);
+        Ok(PathBuf::from(result?))
+    }
+    pub fn volumes(&self, pool: &str) -> Result<Vec<String>, KvmError> {
+        let pool = StoragePool::lookup_by_name(&self.connection, pool)?;
+        let volumes = list_volumes(&pool);
+        free_pool(pool);
+        let mut names = Vec::new();
+        for volume in volumes? {
+            let name = volume.get_name();
+            free_volume(volume);
+            names.push(name?);
+        }
+        Ok(names)
+    }
+    pub fn volume(&self, pool: &str, name: &str) -> Result<VolumeConfig, KvmError> {
+        let pool = StoragePool::lookup_by_name(&self.connection, pool)?;
+        let xml = StorageVol::lookup_by_name(&pool, name).and_then(|volume| {
+            let xml = volume.get_xml_desc(0);
+            free_volume(volume);
+            xml
+        });
+        free_pool(pool);
+        Ok(VolumeConfig::from_str(&xml?)?)
+    }
+    /// virtio disk backed by a volume, like Image::disk
+    pub fn volume_disk(&self, pool: &str, name: &str, dev: &str) -> Result<Disk, KvmError> {
+        let volume = self.volume(pool, name)?;
+        let path = match volume.path() {
+            Some(path) => path.to_string(),
+            None => return Err(KvmError::Xml(format!("volume {} has no path", name))),
+        };
+        let format = volume.target.and_then(|target| target.format).map(|format| format.r#type);
+        Ok(Disk {
+            r#type: Some(String::from("file")),
+            device: Some(String::from("disk")),
+            driver: Some(driver {
+                name: Some(String::from("qemu")),
+                r#type: format,
+            }),
+            source: Some(Source { file: Some(path) }),
+            target: Some(Target {
+                dev: Some(dev.to_string()),
+                bus: Some(String::from("virtio")),
+            }),
+            address: None,
+        })
+    }
+    /// deletes a volume and its image
+    pub fn delete_volume(&self, pool: &str, name: &str) -> Result<(), KvmError> {
+        let pool = StoragePool::lookup_by_name(&self.connection, pool)?;
+        let result = StorageVol::lookup_by_name(&pool, name).and_then(|volume| {
+            let result = volume.delete(0);
+            free_volume(volume);
+            result
+        });
+        free_pool(pool);
+        Ok(result?)
+    }
 }
 impl Drop for QEMU {
     fn drop(&mut self) {
-        for (_name, domain) in self.active_domains.iter_mut() {
-            domain.free().unwrap();
+        for (_name, domain) in self.active_domains.drain() {
+            free(domain);
         }
-        self.connection.close().unwrap();
+        let _ = self.connection.close();
+    }
+}
+/// virt's handles panic when dropped if they can't be freed,
+/// in that case the handle is leaked instead
+macro_rules! free_handle {
+    ($($name:ident => $handle:ty),*) => {
+        $(
+            fn $name(mut handle: $handle) {
+                if handle.free().is_err() {
+                    std::mem::forget(handle);
+                }
+            }
+        )*
+    };
+}
+free_handle!(
+    free => Domain,
+    free_snapshot => DomainSnapshot,
+    free_network => Network,
+    free_pool => StoragePool,
+    free_volume => StorageVol
+);
+fn c_path(path: &Path) -> Result<CString, KvmError> {
+    match CString::new(path.to_string_lossy().into_owned()) {
+        Ok(path) => Ok(path),
+        Err(_) => Err(KvmError::Io(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "path contains a nul byte",
+        ))),
+    }
+}
+/// every volume in pool
+fn list_volumes(pool: &StoragePool) -> Result<Vec<StorageVol>, virt::error::Error> {
+    let mut list: *mut virStorageVolPtr = std::ptr::null_mut();
+    let count = unsafe { virStoragePoolListAllVolumes(pool.as_ptr(), &mut list, 0) };
+    if count < 0 {
+        return Err(virt::error::Error::new());
+    }
+    let mut volumes = Vec::new();
+    for index in 0..count as usize {
+        volumes.push(StorageVol::new(unsafe { *list.add(index) }));
+    }
+    // the volumes are owned by the StorageVols, only the array is freed here
+    unsafe { libc::free(list as *mut libc::c_void) };
+    Ok(volumes)
+}
+
+/// VIR_DOMAIN_SNAPSHOT_CREATE_DISK_ONLY
+const SNAPSHOT_CREATE_DISK_ONLY: u32 = 1 << 4;
+
+// virt doesn't wrap reverting, listing volumes, saving, restoring or reading saved definitions
+// its handle types are empty structs, which libvirt only ever sees behind pointers
+#[allow(improper_ctypes)]
+#[link(name = "virt")]
+extern "C" {
+    fn virDomainRevertToSnapshot(snapshot: virDomainSnapshotPtr, flags: c_uint) -> c_int;
+    fn virDomainSave(domain: virDomainPtr, to: *const c_char) -> c_int;
+    fn virDomainRestoreFlags(
+        conn: virConnectPtr,
+        from: *const c_char,
+        dxml: *const c_char,
+        flags: c_uint,
+    ) -> c_int;
+    fn virDomainSaveImageGetXMLDesc(
+        conn: virConnectPtr,
+        file: *const c_char,
+        flags: c_uint,
+    ) -> *mut c_char;
+    fn virStoragePoolListAllVolumes(
+        pool: virStoragePoolPtr,
+        vols: *mut *mut virStorageVolPtr,
+        flags: c_uint,
+    ) -> c_int;
+}
+
+/// every domain this crate creates is named with it, job domains as well as pool members
+pub const MANAGED_PREFIX: &str = "artifice-";
+/// whether a domain was created by this crate, rather then by someone else on the host
+pub fn managed(name: &str) -> bool {
+    name.starts_with(MANAGED_PREFIX)
+}
+
+/// how often the state of a job's domain is checked while waiting for it to power off
+pub const POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+/// what a job wrote to OUTPUT_PORT, and the exit code it sent to STATUS_PORT
+/// status is None when the guest stopped without sending one, such as when the job was cancelled
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct KvmOutput {
+    pub status: Option<i32>,
+    pub stdout: Vec<u8>,
+}
+/// how the vm of every job is built
+#[derive(Debug, Clone)]
+struct VmSettings {
+    uri: String,
+    base: PathBuf,
+    dir: PathBuf,
+    qemu_img: PathBuf,
+    domain_type: String,
+    memory: Quantity,
+    vcpus: u32,
+    seed_format: SeedFormat,
+    ssh_keys: Vec<String>,
+    policy: SharePolicy,
+    network: Option<String>,
+}
+/// runs every job inside of a disposable vm, booted from a copy on write overlay of a base image
+/// the base image has to run cloud-init, which runs the job from the seed and powers off once it's done
+/// unless a network is set the vm has no network interface,
+/// its only link to the host are the virtio-serial channels
+pub struct KvmEnv {
+    env: RemoteEnv,
+    vm: VmSettings,
+    jobs: JobTable,
+}
+impl KvmEnv {
+    /// # Arguments
+    ///
+    /// env: the host, the vm's memory and vcpus are reported instead of the host's
+    /// base: image every job's overlay is backed by, it's never written to
+    /// dir: where overlays, seeds and output of running jobs are kept
+    pub fn new<P: AsRef<Path>, D: AsRef<Path>>(env: RemoteEnv, base: P, dir: D) -> Self {
+        Self {
+            env,
+            vm: VmSettings {
+                uri: String::from("qemu:///session"),
+                base: base.as_ref().to_path_buf(),
+                dir: dir.as_ref().to_path_buf(),
+                qemu_img: PathBuf::from("qemu-img"),
+                domain_type: String::from("kvm"),
+                memory: Quantity::new(1, Unit::GiB),
+                vcpus: 1,
+                seed_format: SeedFormat::Iso,
+                ssh_keys: Vec::new(),
+                policy: SharePolicy::private(),
+                network: None,
+            },
+            jobs: JobTable::new(),
+        }
+    }
+    /// libvirt uri the vms are created through, "qemu:///session" by default
+    pub fn uri(mut self, uri: &str) -> Self {
+        self.vm.uri = uri.to_string();
+        self
+    }
+    pub fn qemu_img<P: AsRef<Path>>(mut self, qemu_img: P) -> Self {
+        self.vm.qemu_img = qemu_img.as_ref().to_path_buf();
+        self
+    }
+    /// "kvm" by default, see DomainBuilder::domain_type
+    pub fn domain_type(mut self, domain_type: &str) -> Self {
+        self.vm.domain_type = domain_type.to_string();
+        self
+    }
+    pub fn memory(mut self, value: u64, unit: Unit) -> Self {
+        self.vm.memory = Quantity::new(value, unit);
+        self
+    }
+    pub fn vcpus(mut self, vcpus: u32) -> Self {
+        self.vm.vcpus = vcpus;
+        self
+    }
+    pub fn seed_format(mut self, format: SeedFormat) -> Self {
+        self.vm.seed_format = format;
+        self
+    }
+    /// caps the limits of every job's vm, SharePolicy::private by default
+    pub fn policy(mut self, policy: SharePolicy) -> Self {
+        self.vm.policy = policy;
+        self
+    }
+    /// libvirt network every job's vm gets an interface on,
+    /// such as one made with QEMU::create_network
+    pub fn network(mut self, network: &str) -> Self {
+        self.vm.network = Some(network.to_string());
+        self
+    }
+    /// key allowed to log into every job's vm, for debugging a base image
+    pub fn ssh_key(mut self, key: &str) -> Self {
+        self.vm.ssh_keys.push(key.to_string());
+        self
+    }
+}
+impl VmSettings {
+    /// output and status are the files on the host that OUTPUT_PORT and STATUS_PORT are written to
+    fn domain_config(
+        &self,
+        name: &str,
+        overlay: &Image,
+        output: &Path,
+        status: &Path,
+    ) -> Result<DomainConfig, KvmError> {
+        let mut builder = DomainConfig::builder(name)
+            .uuid(&new_uuid()?)
+            .domain_type(&self.domain_type)
+            .memory(self.memory.value(), self.memory.unit())
+            .vcpus(self.vcpus)
+            .boot_disk(&overlay.path().to_string_lossy(), &overlay.format().to_string())
+            .console()
+            .channel(file_channel(OUTPUT_PORT, output))
+            .channel(file_channel(STATUS_PORT, status));
+        if let Some(network) = &self.network {
+            builder = builder.network(network);
+        }
+        Ok(builder.build()?)
+    }
+    /// boots the job's vm and waits for it to power off, blocks the calling thread
+    /// everything created for the job is kept in its own directory, which is removed afterwards
+    fn run(
+        &self,
+        name: &str,
+        job: &VmJob,
+        token: &CancelToken,
+    ) -> Result<(KvmOutput, Usage, JobOutcome), KvmError> {
+        let dir = self.dir.join(name);
+        fs::create_dir_all(&dir)?;
+        let result = self.run_in(&dir, name, job, token);
+        let removed = fs::remove_dir_all(&dir);
+        let result = result?;
+        removed?;
+        Ok(result)
+    }
+    fn run_in(
+        &self,
+        dir: &Path,
+        name: &str,
+        job: &VmJob,
+        token: &CancelToken,
+    ) -> Result<(KvmOutput, Usage, JobOutcome), KvmError> {
+        let images = ImageManager::new(dir).qemu_img(&self.qemu_img);
+        let overlay = images.overlay(name, "root", &self.base)?;
+        let mut cloud = CloudInit::new(name).job(job.clone());
+        for key in self.ssh_keys.iter() {
+            cloud = cloud.ssh_key(key);
+        }
+        let seed_path = match self.seed_format {
+            SeedFormat::Iso => dir.join("seed.iso"),
+            SeedFormat::Fat => dir.join("seed.img"),
+        };
+        let seed = cloud.write(&seed_path, self.seed_format)?;
+        let output = dir.join("output");
+        let status = dir.join("status");
+        let mut config = self.domain_config(name, &overlay, &output, &status)?;
+        config.tune(job.resource_limits(), &self.policy)?;
+        seed.attach(&mut config);
+        let mut qemu = QEMU::connect_uri(&self.uri)?.with_images(images);
+        let started = Instant::now();
+        qemu.create_domain(&config, true)?;
+        let waited = wait(&qemu, name, token);
+        // the domain has to go, even if waiting on it failed
+        let removed = match qemu.state(name) {
+            Ok(DomainState::ShutOff) => Ok(()),
+            _ => qemu.destroy(name),
+        }
+        .and_then(|_| qemu.undefine(name));
+        let (outcome, cpu_time) = waited?;
+        removed?;
+        let stdout = match fs::read(&output) {
+            Ok(stdout) => stdout,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(e.into()),
+        };
+        let status = fs::read_to_string(&status)
+            .ok()
+            .and_then(|status| status.trim().parse().ok());
+        let usage = Usage {
+            cpu_time: Some(cpu_time),
+            memory_peak: None,
+            wall_time: started.elapsed(),
+            bytes_in: job.payload_len(),
+            bytes_out: stdout.len() as u64,
+            fuel: None,
+        };
+        Ok((KvmOutput { status, stdout }, usage, outcome))
+    }
+}
+/// polls the domain until it has powered off or the job has to stop
+/// the cpu time is the last seen while it ran, libvirt doesn't report it once the domain is off
+fn wait(
+    qemu: &QEMU,
+    name: &str,
+    token: &CancelToken,
+) -> Result<(JobOutcome, Duration), KvmError> {
+    let mut cpu_time = Duration::from_secs(0);
+    loop {
+        if let Some(outcome) = token.stopped() {
+            return Ok((outcome, cpu_time));
+        }
+        match qemu.state(name)? {
+            DomainState::ShutOff => return Ok((JobOutcome::Completed, cpu_time)),
+            DomainState::Crashed => return Err(KvmError::Crashed(name.to_string())),
+            _ => cpu_time = qemu.cpu_time(name)?,
+        }
+        thread::sleep(POLL_INTERVAL);
+    }
+}
+/// virtio-serial port named port, that the host sees as the file path
+fn file_channel(port: &str, path: &Path) -> Channel {
+    Channel {
+        r#type: String::from("file"),
+        source: Some(CharSource {
+            mode: None,
+            path: Some(path.to_string_lossy().into_owned()),
+        }),
+        target: Some(CharTarget {
+            r#type: Some(String::from("virtio")),
+            port: None,
+            name: Some(port.to_string()),
+            model: None,
+        }),
+        address: None,
+    }
+}
+/// random, version 4 uuid
+pub(crate) fn new_uuid() -> io::Result<String> {
+    use std::io::Read;
+    let mut bytes = [0u8; 16];
+    fs::File::open("/dev/urandom")?.read_exact(&mut bytes)?;
+    // rfc 4122, the version is in the high nibble of byte 6 and the variant in the high bits of byte 8
+    bytes[6] = (bytes[6] & 0x0f) | 0x40;
+    bytes[8] = (bytes[8] & 0x3f) | 0x80;
+    let hex: String = bytes.iter().map(|byte| format!("{:02x}", byte)).collect();
+    Ok(format!(
+        "{}-{}-{}-{}-{}",
+        &hex[0..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..32]
+    ))
+}
+#[async_trait]
+impl ExecEnv for KvmEnv {
+    type Error = KvmError;
+    type Job = VmJob;
+    type Output = KvmOutput;
+    /// a job that was stopped has its vm destroyed, its output holds what it wrote until then
+    async fn execute(
+        &self,
+        id: JobId,
+        job: VmJob,
+        deadline: Option<Instant>,
+    ) -> Result<JobResult<KvmOutput>, KvmError> {
+        let token = self.jobs.start(id, deadline)?;
+        let vm = self.vm.clone();
+        let stop = token.clone();
+        // libvirt handles aren't Send, so the vm is managed from the blocking pool
+        let result = tokio::task::spawn_blocking(move || {
+            vm.run(&format!("{}job-{}", MANAGED_PREFIX, id.value()), &job, &stop)
+        })
+        .await;
+        let result = match result {
+            Ok(result) => result,
+            Err(e) => Err(KvmError::Io(io::Error::other(e))),
+        };
+        let status = match &result {
+            Ok((_, _, outcome)) if *outcome != JobOutcome::Completed => (*outcome).into(),
+            Ok((output, _, _)) if output.status == Some(0) => JobStatus::Completed,
+            _ => JobStatus::Failed,
+        };
+        self.jobs.finish(id, status);
+        let (output, usage, outcome) = result?;
+        Ok(JobResult {
+            id,
+            outcome,
+            output: Some(output),
+            usage,
+        })
+    }
+    /// the vm is destroyed the next time its state is polled
+    fn cancel(&self, id: JobId) -> Result<(), KvmError> {
+        self.jobs.cancel(id)?;
+        Ok(())
+    }
+    fn status(&self, id: JobId) -> JobStatus {
+        self.jobs.status(id)
+    }
+}
+impl EnvData for KvmEnv {
+    fn trusted(&self) -> bool {
+        self.env.trusted()
+    }
+    fn env_type(&self) -> &EnvType {
+        self.env.env_type()
+    }
+    fn os_name(&self) -> &str {
+        self.env.os_name()
+    }
+    fn arch_name(&self) -> &str {
+        self.env.arch_name()
+    }
+    /// memory of each job's vm, in KiB
+    fn total_mem(&self) -> u64 {
+        self.vm.memory.to_bytes().unwrap_or(u64::MAX) / 1024
+    }
+    /// vcpus of each job's vm
+    fn cpu_count(&self) -> u16 {
+        self.vm.vcpus.min(u16::MAX as u32) as u16
+    }
+    fn cpu_speed(&self) -> u16 {
+        self.env.cpu_speed()
     }
 }
 
@@ -91,298 +986,1098 @@ fn connect() {
     let name = name::from_str("<name>myGuest</name>").unwrap();
     panic!();
 }
-#[derive(XmlWrite, XmlRead, PartialEq, Eq, Debug, Clone, Serialize, Deserialize)]
-#[xml(tag = "boot")]
-pub struct boot {
-    #[xml(attr = "dev")]
-    dev: String,
-}
-/// this struct is designed for creating configurations so the types
-/// used in it should implement either Display or XmlRead & XmlWrite, as well as Serialize, and Deserialize
-macro_rules! construct_element {
-    ($element:ident, $tag_name:expr, [$($attribute:ident => $attrib_type:ty => $attrib_name:expr),*], [$($child:ident => $child_type:ty => $child_name:expr),*]) => {
-        #[derive(XmlWrite, XmlRead, PartialEq, Eq, Debug, Clone, Serialize, Deserialize)]
-        #[xml(tag = $tag_name)]
-        pub struct $element {
-            $(
-                #[xml(attr = $attrib_name)]
-                pub $attribute: $attrib_type,
-            )*
-            $(
-                #[xml(child = $child_name)]
-                pub $child: $child_type,
-            )*
-        }
-    };
-    ($element:ident, $tag_name:expr, $include_type:ident, $($attribute:ident => $attrib_type:ty => $attrib_name:expr),*) => {
-        #[derive(XmlRead, XmlWrite, PartialEq, Eq, Debug, Clone, Serialize, Deserialize)]
-        #[xml(tag = $tag_name)]
-        pub struct $element {
-            $(
-                #[xml($include_type = $attrib_name)]
-                pub $attribute: $attrib_type,
-            )*
-            #[xml(text)]
-            text: String,
-        }
-    };
-    ($($name:ident => $tag_name:expr),*) => {
-        $(
-            #[derive(XmlWrite, XmlRead, PartialEq, Eq, Debug, Clone, Serialize, Deserialize)]
-            #[xml(tag = $tag_name)]
-            pub struct $name {
-                #[xml(text)]
-                $name: String,
-            }
-            impl $name {
-                pub fn new(name: String) -> Self{
-                    Self {$name: name}
-                }
-            }
-        )*
-    };
-    ($no_text:ty, $($name:ident => $tag_name:expr),*) => {
-        $(
-            #[derive(XmlWrite, XmlRead, PartialEq, Eq, Debug, Clone, Serialize, Deserialize)]
-            #[xml(tag = $tag_name)]
-            pub struct $name {}
-            impl Default for $name {
-                fn default() -> Self {
-                    Self{}
-                }
-            }
-        )*
-    };
-    ($no_text:ty, $element:ident, $tag_name:expr, $include_type:ident, $($attribute:ident => $attrib_type:ty => $attrib_name:expr),*) => {
-        #[derive(XmlRead, XmlWrite, PartialEq, Eq, Debug, Clone, Serialize, Deserialize)]
-        #[xml(tag = $tag_name)]
-        pub struct $element {
-            $(
-                #[xml($include_type = $attrib_name)]
-                pub $attribute: $attrib_type,
-            )*
-        }
-    };
+#[test]
+fn devices() {
+    // taken from virsh dumpxml of a guest created by virt-install
+    let xml = "<devices>
+    <disk type='file' device='disk'>
+      <driver name='qemu' type='qcow2'/>
+      <source file='/var/lib/libvirt/images/guest.qcow2'/>
+      <target dev='vda' bus='virtio'/>
+      <address type='pci' domain='0x0000' bus='0x04' slot='0x00' function='0x0'/>
+    </disk>
+    <controller type='virtio-serial' index='0'>
+      <address type='pci' domain='0x0000' bus='0x03' slot='0x00' function='0x0'/>
+    </controller>
+    <interface type='network'>
+      <mac address='52:54:00:6d:90:02'/>
+      <source network='default'/>
+      <model type='virtio'/>
+      <address type='pci' domain='0x0000' bus='0x01' slot='0x00' function='0x0'/>
+    </interface>
+    <interface type='bridge'>
+      <mac address='52:54:00:22:c9:42'/>
+      <source bridge='br0'/>
+      <model type='e1000'/>
+    </interface>
+    <interface type='user'>
+      <mac address='52:54:00:8b:c5:1e'/>
+      <model type='virtio'/>
+    </interface>
+    <serial type='pty'>
+      <target type='isa-serial' port='0'>
+        <model name='isa-serial'/>
+      </target>
+    </serial>
+    <console type='pty'>
+      <target type='serial' port='0'/>
+    </console>
+    <channel type='unix'>
+      <source mode='bind' path='/var/lib/libvirt/qemu/channel/target/org.qemu.guest_agent.0'/>
+      <target type='virtio' name='org.qemu.guest_agent.0'/>
+      <address type='virtio-serial' controller='0' bus='0' port='1'/>
+    </channel>
+    <graphics type='vnc' port='-1' autoport='yes' listen='127.0.0.1'>
+      <listen type='address' address='127.0.0.1'/>
+    </graphics>
+    <rng model='virtio'>
+      <backend model='random'>/dev/urandom</backend>
+      <address type='pci' domain='0x0000' bus='0x06' slot='0x00' function='0x0'/>
+    </rng>
+  </devices>";
+    let devices = Devices::from_str(xml).unwrap();
+    assert_eq!(devices.disks.len(), 1);
+    assert_eq!(devices.controllers.len(), 1);
+    let sources: Vec<_> = devices
+        .interfaces
+        .iter()
+        .map(|interface| interface.source.clone())
+        .collect();
+    assert_eq!(sources[0].as_ref().unwrap().network.as_deref(), Some("default"));
+    assert_eq!(sources[1].as_ref().unwrap().bridge.as_deref(), Some("br0"));
+    assert!(sources[2].is_none());
+    assert_eq!(devices.interfaces[2].mac.as_ref().unwrap().address, "52:54:00:8b:c5:1e");
+    let serial_target = devices.serials[0].target.as_ref().unwrap();
+    assert_eq!(serial_target.model.as_ref().unwrap().name, "isa-serial");
+    assert_eq!(devices.consoles[0].target.as_ref().unwrap().port, Some(0));
+    let channel = &devices.channels[0];
+    assert_eq!(channel.address.as_ref().unwrap().port, Some(1));
+    assert_eq!(devices.graphics[0].port, Some(-1));
+    assert_eq!(devices.graphics[0].listens.len(), 1);
+    assert_eq!(devices.rngs[0].backend.text, "/dev/urandom");
+
+    // attributes are written in the order they are declared, so compare the parsed form
+    let written = devices.to_string().unwrap();
+    assert_eq!(Devices::from_str(&written).unwrap(), devices);
 }
+#[test]
+fn builder() {
+    let uuid = "4d6e0f4e-8bd1-4a2a-9d43-0c3f6bd1a004";
+    let config = DomainConfig::builder("artifice-guest")
+        .uuid(uuid)
+        .memory(2, Unit::GiB)
+        .vcpus(2)
+        .firmware("efi")
+        .boot_disk("/var/lib/artifice/guest.qcow2", "qcow2")
+        .network("default")
+        .console()
+        .build()
+        .unwrap();
+    assert_eq!(config.name.as_ref().unwrap().value(), "artifice-guest");
+    assert_eq!(config.memory.unit, Unit::GiB);
+    assert_eq!(config.os.boot.len(), 1);
+    let parsed = DomainConfig::from_str(&config.to_string().unwrap()).unwrap();
+    assert_eq!(parsed, config);
 
-// =============================
-//      Simple Elements
-// =============================
+    let missing = DomainConfig::builder("artifice-guest").build();
+    assert_eq!(missing.unwrap_err(), ConfigError::MissingUuid);
+    let invalid = DomainConfig::builder("artifice-guest").uuid("not-a-uuid").build();
+    assert!(matches!(invalid, Err(ConfigError::InvalidUuid(_))));
+    let empty = DomainConfig::builder("artifice-guest").uuid(uuid).memory(0, Unit::MiB).build();
+    assert_eq!(empty.unwrap_err(), ConfigError::NoMemory);
+    let twice = DomainConfig::builder("artifice-guest")
+        .uuid(uuid)
+        .boot_disk("/a.qcow2", "qcow2")
+        .boot_disk("/b.qcow2", "qcow2")
+        .build();
+    assert_eq!(twice.unwrap_err(), ConfigError::DuplicateTarget(String::from("vda")));
 
-construct_element!(title => "title",
-    description => "description",
-    genid => "genid",
-    uuid => "uuid",
-    name => "name",
-    emulator => "emulator",
-    on_poweroff => "on_powerof",
-    on_reboot => "on_reboot",
-    on_crash => "on_crash"
-);
-impl Default for on_poweroff {
-    fn default() -> Self {
-        Self {on_poweroff: String::from("destroy")}
-    }
+    let pci = |slot: Option<&str>, port: Option<u16>| Address {
+        r#type: Some(String::from("pci")),
+        domain: Some(String::from("0x0000")),
+        bus: Some(String::from("0x01")),
+        slot: slot.map(String::from),
+        function: Some(String::from("0x0")),
+        controller: None,
+        target: None,
+        unit: None,
+        port,
+    };
+    let mut config = config;
+    let devices = config.devices.as_mut().unwrap();
+    devices.interfaces[0].address = Some(pci(Some("0x00"), None));
+    assert_eq!(config.validate(), Ok(()));
+    let mut conflict = config.clone();
+    conflict.devices.as_mut().unwrap().disks[0].address = Some(pci(Some("0x00"), None));
+    assert!(matches!(conflict.validate(), Err(ConfigError::AddressConflict(_))));
+    let mut mixed = config.clone();
+    mixed.devices.as_mut().unwrap().disks[0].address = Some(pci(Some("0x02"), Some(1)));
+    assert!(matches!(mixed.validate(), Err(ConfigError::InvalidAddress(_))));
+    let mut no_slot = config;
+    no_slot.devices.as_mut().unwrap().disks[0].address = Some(pci(None, None));
+    assert!(matches!(no_slot.validate(), Err(ConfigError::InvalidAddress(_))));
 }
-
-impl Default for on_reboot {
-    fn default() -> Self {
-        Self {on_reboot: String::from("restart")}
-    }
+#[test]
+fn random_uuid() {
+    let uuid = new_uuid().unwrap();
+    assert!(valid_uuid(&uuid));
+    assert_eq!(&uuid[14..15], "4");
+    assert!(matches!(&uuid[19..20], "8" | "9" | "a" | "b"));
+    assert_ne!(uuid, new_uuid().unwrap());
 }
+#[test]
+fn allocate() {
+    let disk = |bus: &str| Disk {
+        r#type: Some(String::from("file")),
+        device: Some(String::from("disk")),
+        driver: None,
+        source: None,
+        target: Some(Target {
+            dev: None,
+            bus: Some(bus.to_string()),
+        }),
+        address: None,
+    };
+    let pci = |slot: &str| Address {
+        r#type: Some(String::from("pci")),
+        domain: Some(String::from("0x0000")),
+        bus: Some(String::from("0x00")),
+        slot: Some(slot.to_string()),
+        function: Some(String::from("0x0")),
+        controller: None,
+        target: None,
+        unit: None,
+        port: None,
+    };
+    let mut config = DomainConfig::builder("allocated")
+        .uuid("4d6e0f4e-8bd1-4a2a-9d43-0c3f6bd1a012")
+        .boot_disk("/a.qcow2", "qcow2")
+        .disk(disk("virtio"))
+        .disk(disk("sata"))
+        .disk(disk("sata"))
+        .network("default")
+        .network("default")
+        .build()
+        .unwrap();
+    let devices = config.devices.as_mut().unwrap();
+    devices.interfaces[0].address = Some(pci("0x2"));
+    devices.controllers.push(Controller {
+        attrib_type: Some(String::from("usb")),
+        index: 0,
+        model: None,
+        address: None,
+    });
+    config.allocate().unwrap();
+    config.validate().unwrap();
+    let devices = config.devices.as_ref().unwrap();
+    let devs: Vec<&str> = devices
+        .disks
+        .iter()
+        .map(|disk| disk.target.as_ref().unwrap().dev.as_deref().unwrap())
+        .collect();
+    assert_eq!(devs, vec!["vda", "vdb", "sda", "sdb"]);
+    let slot = |address: &Option<Address>| address.as_ref().unwrap().slot.clone().unwrap();
+    // slot 0x02 was taken by the first interface before anything was allocated
+    assert_eq!(slot(&devices.disks[0].address), "0x03");
+    assert_eq!(slot(&devices.disks[1].address), "0x04");
+    assert_eq!(devices.disks[3].address.as_ref().unwrap().unit, Some(1));
+    assert_eq!(slot(&devices.controllers[0].address), "0x05");
+    assert_eq!(slot(&devices.interfaces[0].address), "0x2");
+    assert_eq!(slot(&devices.interfaces[1].address), "0x06");
 
-impl Default for on_crash {
-    fn default() -> Self {
-        Self {on_crash: String::from("destroy")}
-    }
+    // the same slot written two ways is still a conflict
+    config.devices.as_mut().unwrap().interfaces[1].address = Some(pci("0x03"));
+    assert!(matches!(config.validate(), Err(ConfigError::AddressConflict(_))));
+    assert!(matches!(config.allocate(), Err(ConfigError::AddressConflict(_))));
+    assert_eq!(dev_name("vd", 25), "vdz");
+    assert_eq!(dev_name("vd", 26), "vdaa");
+    assert_eq!(dev_name("sd", 702), "sdaaa");
 }
+#[test]
+fn tune() {
+    let mut config = DomainConfig::builder("tuned")
+        .uuid("4d6e0f4e-8bd1-4a2a-9d43-0c3f6bd1a013")
+        .memory(4, Unit::GiB)
+        .vcpus(2)
+        .build()
+        .unwrap();
+    let limits = ResourceLimits::default()
+        .memory(1024 * 1024 * 1024)
+        .cpu_millis(1500);
+    config.tune(&limits, &SharePolicy::private()).unwrap();
+    let cputune = config.cputune.as_ref().unwrap();
+    assert_eq!(cputune.shares.as_ref().unwrap().value(), "1024");
+    assert_eq!(cputune.period.as_ref().unwrap().value(), "100000");
+    // 1.5 cpus split over 2 vcpus
+    assert_eq!(cputune.quota.as_ref().unwrap().value(), "75000");
+    assert_eq!(config.memory.text, "1048576");
+    let memtune = config.memtune.as_ref().unwrap();
+    assert_eq!(memtune.hard_limit.as_ref().unwrap().text, "1310720");
+    assert_eq!(memtune.swap_hard_limit.as_ref().unwrap().text, "1310720");
 
-// ==============================
-//      Tag Only
-// ==============================
-
-construct_element! (bool, 
-    readonly => "readonly",
-    acpi => "acpi",
-    apic => "apic"
-);
-
-// =========================
-//      Medium Elements
-// =========================
+    // the policy caps what the job asked for
+    let policy = SharePolicy::private()
+        .cpu_shares(256)
+        .io_weight(250)
+        .max_cpu_millis(500);
+    config.tune(&limits, &policy).unwrap();
+    let xml = config.to_string().unwrap();
+    assert!(xml.contains(
+        "<cputune><shares>256</shares><period>100000</period><quota>25000</quota></cputune>"
+    ));
+    assert!(xml.contains("<blkiotune><weight>250</weight></blkiotune>"));
+    assert_eq!(DomainConfig::from_str(&xml).unwrap(), config);
+    // without a memory limit the guest keeps its memory
+    config.tune(&ResourceLimits::default(), &SharePolicy::private()).unwrap();
+    assert_eq!(config.memtune, None);
+    assert_eq!(config.cputune.as_ref().unwrap().quota, None);
 
-construct_element! (
-    r#type, "type", attr, arch => Option<String> => "arch", machine => Option<String> => "machine"
-);
-construct_element! (
-    vmport, "vmport", attr, state => Option<String> => "state"
-);
-construct_element! (
-    driver, "driver", attr, name => Option<String> => "name", r#type => Option<String> => "type"
-);
-construct_element! (
-    Target, "target", attr, dev => Option<String> => "dev", bus => Option<String> => "bus"
-);
-construct_element! (
-    Source, "source", attr, file => Option<String> => "file"
-);
-construct_element! (
-    memory, "memory", attr,
-    unit => Unit => "unit"
-);
-construct_element! (
-    vcpu, "vcpu", attr,
-    placement => Option<String> => "placement"
-);
-construct_element! {
-    Timer, "timer", attr,
-    name => Option<String> => "name",
-    tickpolicy => Option<String> => "tickpolicy",
-    present => Option<String> => "present"
+    // a public host caps each guest at half of its memory and cpus
+    let host: RemoteEnv = serde_json::from_value(serde_json::json!({
+        "os_name": "linux",
+        "arch_name": "x86_64",
+        "total_mem": 2 * 1024 * 1024,
+        "cpu_count": 4,
+        "cpu_speed": 3000,
+        "env_type": "Inherit",
+        "trusted": false,
+    }))
+    .unwrap();
+    config.tune(&ResourceLimits::default(), &SharePolicy::public(&host)).unwrap();
+    assert_eq!(config.memory.text, "1048576");
+    let cputune = config.cputune.as_ref().unwrap();
+    assert_eq!(cputune.shares.as_ref().unwrap().value(), "256");
+    assert_eq!(cputune.quota.as_ref().unwrap().value(), "100000");
 }
+#[test]
+fn passthrough() {
+    use crate::runtime::passthrough::XmlNode;
 
-construct_element! (
-    Features, "features", child,
-    acpi => Option<acpi> => "acpi",
-    apic => Option<apic> => "apic",
-    vmport => Option<vmport> => "vmport"
-);
-construct_element! (
-    PM, "pm", child,
-    suspend_to_mem => Option<SuspendToMem> => "suspend-to-mem",
-    suspend_to_disk => Option<SuspendToDisk> => "suspend-to-disk"
-);
-construct_element! (
-    Clock, "clock", [
-        offset => Option<String> => "offset"
-    ], [
-        timers => Vec<Timer> => "timer"
-    ]
-);
-
-construct_element! (bool,
-    Model, "model", attr,
-    fallback => Option<String> => "fallback"
-);
-construct_element! (bool,
-    SuspendToMem, "suspend-to-mem", attr,
-    enabled => Option<String> => "enabled"
-);
-construct_element! (bool,
-    SuspendToDisk, "suspend-to-disk", attr,
-    enabled => Option<String> => "enabled"
-);
-
-construct_element! (
-    CPU, "cpu", [
-        mode => Option<String> => "mode",
-        check => Option<String> => "check"
-    ], [
-        model => Option<Model> => "model"
-    ]
-);
+    let xml = "
+<domain type='kvm' xmlns:qemu='http://libvirt.org/schemas/domain/qemu/1.0'>
+  <name>imported</name>
+  <uuid>4d6e0f4e-8bd1-4a2a-9d43-0c3f6bd1a010</uuid>
+  <metadata>
+    <app:job xmlns:app='https://artifice.example/job'>42</app:job>
+  </metadata>
+  <memory unit='KiB'>2097152</memory>
+  <currentMemory unit='KiB'>2097152</currentMemory>
+  <vcpu placement='static'>2</vcpu>
+  <resource>
+    <partition>/machine</partition>
+  </resource>
+  <os>
+    <type arch='x86_64' machine='pc-q35-6.2'>hvm</type>
+    <boot dev='hd'/>
+  </os>
+  <features>
+    <acpi/>
+    <apic/>
+  </features>
+  <on_poweroff>destroy</on_poweroff>
+  <devices>
+    <emulator>/usr/bin/qemu-system-x86_64</emulator>
+    <disk type='file' device='disk' snapshot='no'>
+      <driver name='qemu' type='qcow2' cache='none'/>
+      <source file='/images/imported.qcow2'/>
+      <target dev='vda' bus='virtio'/>
+    </disk>
+    <memballoon model='virtio'/>
+  </devices>
+  <seclabel type='dynamic' model='selinux' relabel='yes'/>
+  <qemu:commandline>
+    <qemu:arg value='-no-hpet'/>
+  </qemu:commandline>
+</domain>";
+    let mut config = DomainConfig::from_str(xml).unwrap();
+    assert!(config.passthrough.is_some());
+    config.memory = memory {
+        unit: Unit::GiB,
+        text: String::from("4"),
+    };
+    let mut expected = XmlNode::parse(xml).unwrap();
+    expected.children[3] = XmlNode::parse("<memory unit='GiB'>4</memory>")
+        .map(crate::runtime::passthrough::XmlContent::Element)
+        .unwrap();
+    let written = config.to_string().unwrap();
+    assert_eq!(XmlNode::parse(&written).unwrap(), expected);
+    assert_eq!(DomainConfig::from_str(&written).unwrap(), config);
 
-// =========================
-//      Address
-// =========================
+    // nothing is kept for a config that is fully modeled
+    let built = DomainConfig::builder("built")
+        .uuid("4d6e0f4e-8bd1-4a2a-9d43-0c3f6bd1a011")
+        .build()
+        .unwrap();
+    let parsed = DomainConfig::from_str(&built.to_string().unwrap()).unwrap();
+    assert_eq!(parsed.passthrough, None);
+    assert_eq!(parsed, built);
+}
+#[test]
+fn snapshot_config() {
+    let external = SnapshotConfig::new("before-job")
+        .description("clean")
+        .memory_file("/var/lib/artifice/before-job.mem")
+        .external_disk("vda", "/var/lib/artifice/before-job.qcow2")
+        .exclude_disk("sda");
+    assert!(external.is_external());
+    assert!(!external.is_disk_only());
+    let xml = external.to_string().unwrap();
+    let memory = "<memory snapshot=\"external\" file=\"/var/lib/artifice/before-job.mem\"/>";
+    assert!(xml.contains(memory));
+    assert_eq!(SnapshotConfig::from_str(&xml).unwrap(), external);
+    assert!(!SnapshotConfig::new("internal").is_external());
+    assert!(SnapshotConfig::new("disks").disk_only().is_disk_only());
 
-construct_element! (
-    Address, "address", attr, 
-    r#type => Option<String> => "type", 
-    domain => Option<String> => "domain", 
-    bus => Option<String> => "bus",
-    slot => Option<String> => "slot",
-    function => Option<String> => "function",
-    controller => Option<u16> => "controller",
-    target => Option<u16> => "target",
-    unit => Option<u32> => "unit",
-    port => Option<u16> => "port"
-);
+    // as libvirt describes a snapshot, with elements that aren't modeled
+    let described = "<domainsnapshot><name>clean</name><state>running</state>\
+        <parent><name>base</name></parent><creationTime>1600000000</creationTime>\
+        <memory snapshot='internal'/><disks><disk name='vda' snapshot='internal'/></disks>\
+        <domain type='test'><name>guest</name><uuid>4d6e0f4e-8bd1-4a2a-9d43-0c3f6bd1a020</uuid>\
+        <memory unit='KiB'>65536</memory><vcpu>1</vcpu><os><type>hvm</type></os></domain>\
+        <active>1</active></domainsnapshot>";
+    let snapshot = SnapshotConfig::from_str(described).unwrap();
+    assert_eq!(snapshot.created(), Some(1_600_000_000));
+    assert_eq!(snapshot.parent.as_ref().unwrap().name.value(), "base");
+    assert_eq!(snapshot.domain.as_ref().unwrap().name.as_ref().unwrap().value(), "guest");
+    assert_eq!(snapshot.passthrough.as_ref().map(Passthrough::len), Some(1));
+    assert!(snapshot.to_string().unwrap().contains("<active>1</active>"));
+}
+#[test]
+fn network_config() {
+    let subnet: IpNetwork = "192.168.100.0/24".parse().unwrap();
+    let nat = NetworkConfig::nat("artifice-nat", subnet)
+        .unwrap()
+        .bridge("virbr-artifice")
+        .dhcp_host("52:54:00:00:00:01", "192.168.100.10".parse().unwrap())
+        .unwrap();
+    assert!(!nat.is_isolated());
+    let ip = &nat.ips[0];
+    assert_eq!(ip.address, "192.168.100.1");
+    assert_eq!(ip.prefix, Some(24));
+    let dhcp = ip.dhcp.as_ref().unwrap();
+    assert_eq!(dhcp.ranges[0].start, "192.168.100.2");
+    assert_eq!(dhcp.ranges[0].end, "192.168.100.254");
+    assert_eq!(dhcp.hosts[0].ip, "192.168.100.10");
+    assert_eq!(NetworkConfig::from_str(&nat.to_string().unwrap()).unwrap(), nat);
+    assert!(matches!(
+        nat.dhcp_host("52:54:00:00:00:02", "10.0.0.2".parse().unwrap()),
+        Err(ConfigError::InvalidNetwork(_))
+    ));
 
-// ============================
-//         Disk
-// ============================
-construct_element! (
-    Disk, "disk", [
-        r#type => Option<String> => "type", 
-        device => Option<String> => "device"
-    ], [
-        driver => Option<driver> => "driver",
-        source => Option<Source> => "source",
-        target => Option<Target> => "target",
-        address => Option<Address> => "address"
-    ]
-);
+    let isolated = NetworkConfig::isolated("artifice-v6", "fd00:a::/64".parse().unwrap()).unwrap();
+    assert!(isolated.is_isolated());
+    let ip = &isolated.ips[0];
+    assert_eq!(ip.family.as_deref(), Some("ipv6"));
+    assert_eq!(ip.address, "fd00:a::1");
+    assert_eq!(ip.dhcp.as_ref().unwrap().ranges[0].end, "fd00:a::ffff:ffff:ffff:fffe");
+    // libvirt's stock network sets a netmask rather then a prefix
+    let default = NetworkConfig::from_str(
+        "<network><name>default</name><forward mode=\"nat\"/>\
+        <ip address=\"192.168.122.1\" netmask=\"255.255.255.0\"/></network>",
+    )
+    .unwrap();
+    assert_eq!(default.ips[0].subnet().unwrap().prefix(), 24);
+    assert!(matches!(
+        default.clone().dhcp_host("52:54:00:00:00:03", "10.0.0.2".parse().unwrap()),
+        Err(ConfigError::InvalidNetwork(_))
+    ));
+    let default = default
+        .dhcp_host("52:54:00:00:00:03", "192.168.122.10".parse().unwrap())
+        .unwrap();
+    assert_eq!(default.ips[0].dhcp.as_ref().unwrap().hosts.len(), 1);
+    let small = NetworkConfig::isolated("small", "10.0.0.0/31".parse().unwrap());
+    assert!(matches!(small, Err(ConfigError::InvalidNetwork(_))));
 
-// ================================
-//      OpSystem
-// ================================
+    let pool = PoolConfig::dir("artifice", "/var/lib/artifice/pool");
+    assert_eq!(PoolConfig::from_str(&pool.to_string().unwrap()).unwrap(), pool);
+    let volume = VolumeConfig::new("job.qcow2", Quantity::new(4, Unit::GiB), ImageFormat::Qcow2)
+        .backing("/var/lib/artifice/base.qcow2", ImageFormat::Qcow2);
+    let xml = volume.to_string().unwrap();
+    assert!(xml.contains("<backingStore><path>/var/lib/artifice/base.qcow2</path>"));
+    let parsed = VolumeConfig::from_str(&xml).unwrap();
+    assert_eq!(parsed.size().unwrap().to_bytes().unwrap(), 4 << 30);
+    assert_eq!(parsed, volume);
+}
+#[test]
+fn create_domain() {
+    // the test driver keeps its domains in memory, so nothing outlives the connection
+    let mut qemu = QEMU::connect_uri("test:///default").unwrap();
+    let config = |name: &str, uuid: &str| {
+        DomainConfig::from_str(&format!(
+            "<domain type=\"test\"><name>{}</name><uuid>{}</uuid>\
+            <memory unit=\"MiB\">64</memory><vcpu>1</vcpu>\
+            <os><type>hvm</type></os></domain>",
+            name, uuid
+        ))
+        .unwrap()
+    };
+    let running = config("artifice-running", "4d6e0f4e-8bd1-4a2a-9d43-0c3f6bd1a001");
+    let domain = qemu.create_domain(&running, true).unwrap();
+    assert_eq!(domain.get_name().unwrap(), "artifice-running");
+    assert!(domain.is_active().unwrap());
+    let defined = config("artifice-defined", "4d6e0f4e-8bd1-4a2a-9d43-0c3f6bd1a002");
+    let domain = qemu.create_domain(&defined, false).unwrap();
+    assert!(!domain.is_active().unwrap());
+    assert!(qemu.mut_domain("artifice-running").is_some());
+    assert!(qemu.mut_domain("artifice-defined").is_some());
+}
+#[test]
+fn lifecycle() {
+    let mut qemu = QEMU::connect_uri("test:///default").unwrap();
+    let config = DomainConfig::from_str(
+        "<domain type=\"test\"><name>artifice-lifecycle</name>\
+        <uuid>4d6e0f4e-8bd1-4a2a-9d43-0c3f6bd1a003</uuid>\
+        <memory unit=\"MiB\">64</memory><vcpu>1</vcpu>\
+        <os><type>hvm</type></os></domain>",
+    )
+    .unwrap();
+    let name = "artifice-lifecycle";
+    qemu.create_domain(&config, false).unwrap();
+    assert_eq!(qemu.state(name).unwrap(), DomainState::ShutOff);
+    qemu.start(name).unwrap();
+    assert_eq!(qemu.state(name).unwrap(), DomainState::Running);
+    qemu.suspend(name).unwrap();
+    assert_eq!(qemu.state(name).unwrap(), DomainState::Paused);
+    qemu.resume(name).unwrap();
+    qemu.reboot(name).unwrap();
+    assert_eq!(qemu.state(name).unwrap(), DomainState::Running);
+    qemu.shutdown(name).unwrap();
+    assert_eq!(qemu.state(name).unwrap(), DomainState::ShutOff);
+    qemu.start(name).unwrap();
+    qemu.destroy(name).unwrap();
+    assert_eq!(qemu.state(name).unwrap(), DomainState::ShutOff);
+    qemu.undefine(name).unwrap();
+    assert!(matches!(qemu.state(name), Err(KvmError::UnknownDomain(_))));
+    assert!(matches!(qemu.start("missing"), Err(KvmError::UnknownDomain(_))));
+}
+#[test]
+fn snapshots() {
+    let mut qemu = QEMU::connect_uri("test:///default").unwrap();
+    let config = DomainConfig::from_str(
+        "<domain type=\"test\"><name>artifice-snapshots</name>\
+        <uuid>4d6e0f4e-8bd1-4a2a-9d43-0c3f6bd1a005</uuid>\
+        <memory unit=\"MiB\">64</memory><vcpu>1</vcpu>\
+        <os><type>hvm</type></os></domain>",
+    )
+    .unwrap();
+    let name = "artifice-snapshots";
+    qemu.create_domain(&config, true).unwrap();
+    let running = SnapshotConfig::new("running").description("booted");
+    assert_eq!(qemu.create_snapshot(name, &running).unwrap(), "running");
+    qemu.suspend(name).unwrap();
+    qemu.create_snapshot(name, &SnapshotConfig::new("paused")).unwrap();
+    assert_eq!(qemu.snapshots(name).unwrap(), vec!["running", "paused"]);
+    let snapshot = qemu.snapshot(name, "running").unwrap();
+    assert_eq!(snapshot.description.unwrap().value(), "booted");
+    assert_eq!(snapshot.state.unwrap().value(), "running");
+    assert_eq!(snapshot.domain.unwrap().uuid, config.uuid);
+    qemu.revert_snapshot(name, "running").unwrap();
+    assert_eq!(qemu.state(name).unwrap(), DomainState::Running);
+    qemu.revert_snapshot(name, "paused").unwrap();
+    assert_eq!(qemu.state(name).unwrap(), DomainState::Paused);
+    qemu.delete_snapshot(name, "paused").unwrap();
+    assert_eq!(qemu.snapshots(name).unwrap(), vec!["running"]);
+    assert!(qemu.revert_snapshot(name, "paused").is_err());
+    qemu.delete_snapshot(name, "running").unwrap();
+    qemu.destroy(name).unwrap();
+    qemu.undefine(name).unwrap();
+}
+#[test]
+fn networks_and_storage() {
+    let qemu = QEMU::connect_uri("test:///default").unwrap();
+    let subnet: IpNetwork = "10.231.0.0/24".parse().unwrap();
+    let network = NetworkConfig::isolated("artifice-jobs", subnet).unwrap();
+    qemu.create_network(&network, true).unwrap();
+    assert!(qemu.networks().unwrap().contains(&String::from("artifice-jobs")));
+    let defined = qemu.network("artifice-jobs").unwrap();
+    assert!(defined.is_isolated());
+    assert_eq!(defined.ips[0].dhcp, network.ips[0].dhcp);
+    qemu.remove_network("artifice-jobs").unwrap();
+    assert!(!qemu.networks().unwrap().contains(&String::from("artifice-jobs")));
 
-construct_element! ();
-construct_element! (
-    OpSystem, "os", [
-        firmware => Option<String> => "firmware"
-    ], [
-        r#type => r#type => "type",
-        boot => Vec<boot> => "boot"
-    ]
-);
+    let pool = PoolConfig::dir("artifice-pool", "/var/lib/artifice/pool");
+    qemu.create_pool(&pool, true).unwrap();
+    assert!(qemu.pools().unwrap().contains(&String::from("artifice-pool")));
+    assert_eq!(qemu.pool("artifice-pool").unwrap().target, pool.target);
+    let volume = VolumeConfig::new("job.img", Quantity::new(64, Unit::MiB), ImageFormat::Raw);
+    let path = qemu.create_volume("artifice-pool", &volume).unwrap();
+    assert_eq!(path, Path::new("/var/lib/artifice/pool/job.img"));
+    assert_eq!(qemu.volumes("artifice-pool").unwrap(), vec!["job.img"]);
+    let disk = qemu.volume_disk("artifice-pool", "job.img", "vdb").unwrap();
+    assert_eq!(disk.source.unwrap().file.unwrap(), "/var/lib/artifice/pool/job.img");
+    qemu.delete_volume("artifice-pool", "job.img").unwrap();
+    assert!(qemu.volumes("artifice-pool").unwrap().is_empty());
+    qemu.remove_pool("artifice-pool").unwrap();
+}
+#[test]
+fn domain_events() {
+    use crate::runtime::events::{DomainEvent, Lifecycle};
+    use tokio::stream::StreamExt;
 
+    let mut qemu = QEMU::connect_uri("test:///default").unwrap();
+    let mut events = qemu.events().unwrap();
+    let config = DomainConfig::from_str(
+        "<domain type=\"test\"><name>artifice-events</name>\
+        <uuid>4d6e0f4e-8bd1-4a2a-9d43-0c3f6bd1a004</uuid>\
+        <memory unit=\"MiB\">64</memory><vcpu>1</vcpu>\
+        <os><type>hvm</type></os></domain>",
+    )
+    .unwrap();
+    let name = "artifice-events";
+    qemu.create_domain(&config, true).unwrap();
+    qemu.suspend(name).unwrap();
+    qemu.resume(name).unwrap();
+    qemu.destroy(name).unwrap();
+    qemu.undefine(name).unwrap();
+    let mut runtime = crate::runtime::test_runtime();
+    let mut seen = Vec::new();
+    runtime.block_on(async {
+        while seen.last() != Some(&Lifecycle::Undefined) {
+            let event = tokio::time::timeout(Duration::from_secs(5), events.next())
+                .await
+                .unwrap()
+                .unwrap();
+            // the connection is shared with other tests, so their domains' events show up too
+            if event.domain() != name {
+                continue;
+            }
+            if let DomainEvent::Lifecycle { event, .. } = event {
+                seen.push(event);
+            }
+        }
+    });
+    assert_eq!(
+        seen,
+        vec![
+            Lifecycle::Defined,
+            Lifecycle::Started,
+            Lifecycle::Suspended,
+            Lifecycle::Resumed,
+            Lifecycle::Stopped,
+            Lifecycle::Undefined
+        ]
+    );
+}
+#[test]
+fn kvm_env() {
+    let dir = std::env::temp_dir().join(format!("artifice-kvm-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let base = dir.join("base.qcow2");
+    let created = std::process::Command::new("qemu-img")
+        .args(["create", "-f", "qcow2"])
+        .arg(&base)
+        .arg("64M")
+        .output();
+    if created.is_err() {
+        println!("skipping, qemu-img isn't installed");
+        return;
+    }
+    // guests of the test driver never power off, so the job runs until its deadline
+    let env = KvmEnv::new(RemoteEnv::init(EnvType::Inherit).unwrap(), &base, dir.join("jobs"))
+        .uri("test:///default")
+        .domain_type("test")
+        .memory(64, Unit::MiB);
+    assert_eq!(env.total_mem(), 64 * 1024);
+    assert_eq!(env.cpu_count(), 1);
+    let job = VmJob::new("echo").arg(String::from("hello"));
+    let deadline = Instant::now() + Duration::from_secs(1);
+    let mut runtime = crate::runtime::test_runtime();
+    let result = match runtime.block_on(env.execute(JobId::new(0), job, Some(deadline))) {
+        Ok(result) => result,
+        Err(KvmError::Seed(SeedError::Io(e))) if e.kind() == io::ErrorKind::NotFound => {
+            println!("skipping, genisoimage isn't installed");
+            fs::remove_dir_all(&dir).unwrap();
+            return;
+        }
+        Err(e) => panic!("{}", e),
+    };
+    assert_eq!(result.outcome, JobOutcome::TimedOut);
+    assert_eq!(result.output.unwrap().status, None);
+    assert_eq!(env.status(JobId::new(0)), JobStatus::TimedOut);
+    // the job's directory was created under jobs, and removed once it finished
+    assert!(!dir.join("jobs").join("artifice-job-0").exists());
+    assert_eq!(fs::read_dir(dir.join("jobs")).unwrap().count(), 0);
+    fs::remove_dir_all(&dir).unwrap();
+}
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConfigError {
+    MissingName,
+    MissingUuid,
+    /// uuids have to be 32 hex digits in the 8-4-4-4-12 form
+    InvalidUuid(String),
+    /// memory doesn't fit in a u64 of bytes
+    Memory(UnitErr),
+    NoMemory,
+    NoVcpus,
+    /// two disks have the same target dev, such as vda
+    DuplicateTarget(String),
+    /// two devices share an address
+    AddressConflict(String),
+    /// an address is missing a field its type requires, or has one that belongs to another type
+    InvalidAddress(String),
+    /// every pci slot that allocate hands out is taken
+    NoFreeAddress,
+    /// a subnet that's too small for dhcp, or an address outside of the network
+    InvalidNetwork(String),
+}
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::MissingName => write!(f, "domain has no name"),
+            Self::MissingUuid => write!(f, "domain has no uuid"),
+            Self::InvalidUuid(uuid) => write!(f, "invalid uuid {}", uuid),
+            Self::Memory(e) => write!(f, "invalid memory size: {}", e),
+            Self::NoMemory => write!(f, "domain needs some memory"),
+            Self::NoVcpus => write!(f, "domain needs at least one vcpu"),
+            Self::DuplicateTarget(dev) => write!(f, "more then one disk targets {}", dev),
+            Self::AddressConflict(address) => write!(f, "address used twice: {}", address),
+            Self::InvalidAddress(reason) => write!(f, "invalid address: {}", reason),
+            Self::NoFreeAddress => write!(f, "no free pci slot left"),
+            Self::InvalidNetwork(reason) => write!(f, "invalid network: {}", reason),
+        }
+    }
+}
+impl Error for ConfigError {}
+impl From<UnitErr> for ConfigError {
+    fn from(error: UnitErr) -> Self {
+        ConfigError::Memory(error)
+    }
+}
 
+fn valid_uuid(uuid: &str) -> bool {
+    let groups: Vec<&str> = uuid.split('-').collect();
+    groups.len() == 5
+        && groups
+            .iter()
+            .zip([8, 4, 4, 4, 12].iter())
+            .all(|(group, len)| {
+                group.len() == *len && group.chars().all(|c| c.is_ascii_hexdigit())
+            })
+}
+fn check_address(address: &Address) -> Result<(), ConfigError> {
+    let invalid = |reason: &str| {
+        Err(ConfigError::InvalidAddress(format!(
+            "{} in {:?}",
+            reason, address
+        )))
+    };
+    let pci = address.domain.is_some()
+        || address.slot.is_some()
+        || address.function.is_some();
+    let drive = address.target.is_some() || address.unit.is_some();
+    match address.r#type.as_deref() {
+        None => invalid("no type"),
+        Some("pci") if address.slot.is_none() => invalid("pci address without a slot"),
+        Some("pci") if address.controller.is_some() || drive || address.port.is_some() => {
+            invalid("pci address with drive or port fields")
+        }
+        Some("drive") if address.unit.is_none() => invalid("drive address without a unit"),
+        Some("drive") if pci || address.port.is_some() => {
+            invalid("drive address with pci or port fields")
+        }
+        Some("virtio-serial") if address.port.is_none() => {
+            invalid("virtio-serial address without a port")
+        }
+        Some("virtio-serial") if pci || drive => {
+            invalid("virtio-serial address with pci or drive fields")
+        }
+        _ => Ok(()),
+    }
+}
 
-// ==================================
-//      Device
-// ==================================
+/// cpu period of cputune, in microseconds
+pub const TUNE_PERIOD: u64 = 100_000;
+/// smallest quota libvirt accepts, in microseconds
+pub const MIN_QUOTA: u64 = 1000;
+/// memory qemu itself uses besides the guest's, memtune limits leave room for it
+pub const QEMU_OVERHEAD: u64 = 256 * 1024 * 1024;
 
-construct_element! (
-    Controller, "controller", [
-        attrib_type => Option<String> => "type",
-        index => u32 => "index",
-        model => Option<String> => "model"
-    ], [
-        address => Address => "address",
-        
-    ]
-);
+/// how much of the host guests may take, so that a shared host stays usable by its owner
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SharePolicy {
+    cpu_shares: u32,
+    io_weight: u32,
+    cpu_millis: Option<u32>,
+    memory: Option<u64>,
+}
+impl SharePolicy {
+    /// guests weigh the same as the host's own processes, and are only limited by their job
+    pub fn private() -> Self {
+        Self {
+            cpu_shares: 1024,
+            io_weight: 500,
+            cpu_millis: None,
+            memory: None,
+        }
+    }
+    /// guests get a quarter of the cpu weight of the owner's processes and half of the io weight,
+    /// and each is capped at half of the host's cpus and memory
+    pub fn public<E: EnvData>(host: &E) -> Self {
+        Self {
+            cpu_shares: 256,
+            io_weight: 250,
+            cpu_millis: Some((u32::from(host.cpu_count()) * 500).max(1000)),
+            memory: Some(host.total_memory().to_bytes().unwrap_or(u64::MAX) / 2),
+        }
+    }
+    /// public if the host is shared with other peers
+    pub fn for_host(host: &HostEnv) -> Self {
+        match host.is_public() {
+            true => Self::public(host),
+            false => Self::private(),
+        }
+    }
+    /// weight against other guests and the host's processes, which weigh 1024
+    pub fn cpu_shares(mut self, shares: u32) -> Self {
+        self.cpu_shares = shares;
+        self
+    }
+    /// between 100 and 1000
+    pub fn io_weight(mut self, weight: u32) -> Self {
+        self.io_weight = weight;
+        self
+    }
+    /// most cpu a guest may use, in thousandths of a cpu
+    pub fn max_cpu_millis(mut self, millis: u32) -> Self {
+        self.cpu_millis = Some(millis);
+        self
+    }
+    /// most memory a guest may use, in bytes
+    pub fn max_memory(mut self, bytes: u64) -> Self {
+        self.memory = Some(bytes);
+        self
+    }
+}
+/// the lower of two limits, where None is no limit
+fn lowest<T: Ord>(a: Option<T>, b: Option<T>) -> Option<T> {
+    match (a, b) {
+        (Some(a), Some(b)) => Some(a.min(b)),
+        (a, b) => a.or(b),
+    }
+}
 
-construct_element! (
-    Devices, "devices", child,
-    disks => Vec<Disk> => "disk",
-    controllers => Vec<Controller> => "controller"
-);
+/// first pci slot handed out by allocate, slot 0 is the host bridge and slot 1 is left for video
+pub const FIRST_PCI_SLOT: u32 = 0x02;
+/// last pci slot handed out by allocate, q35 keeps slot 0x1f for its own controllers
+pub const LAST_PCI_SLOT: u32 = 0x1e;
 
-// ==================================
-//      DomainConfig
-// ==================================
+/// the location an address takes up, with its numbers parsed so "0x2" and "0x02" are the same slot
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum AddressKey {
+    Pci {
+        domain: u32,
+        bus: u32,
+        slot: u32,
+        function: u32,
+    },
+    Drive {
+        controller: u16,
+        bus: u32,
+        target: u16,
+        unit: u32,
+    },
+    Serial {
+        controller: u16,
+        bus: u32,
+        port: u16,
+    },
+    /// other types, such as usb or ccw, only conflict when they are identical
+    Other(String),
+}
+fn address_key(address: &Address) -> Result<AddressKey, ConfigError> {
+    check_address(address)?;
+    let number = |field: &Option<String>| match field.as_deref() {
+        None => Ok(0),
+        Some(value) => {
+            let parsed = match value.strip_prefix("0x") {
+                Some(hex) => u32::from_str_radix(hex, 16),
+                None => value.parse(),
+            };
+            parsed.map_err(|_| ConfigError::InvalidAddress(format!("{} isn't a number", value)))
+        }
+    };
+    Ok(match address.r#type.as_deref() {
+        Some("pci") => AddressKey::Pci {
+            domain: number(&address.domain)?,
+            bus: number(&address.bus)?,
+            slot: number(&address.slot)?,
+            function: number(&address.function)?,
+        },
+        Some("drive") => AddressKey::Drive {
+            controller: address.controller.unwrap_or(0),
+            bus: number(&address.bus)?,
+            target: address.target.unwrap_or(0),
+            unit: address.unit.unwrap_or(0),
+        },
+        Some("virtio-serial") => AddressKey::Serial {
+            controller: address.controller.unwrap_or(0),
+            bus: number(&address.bus)?,
+            port: address.port.unwrap_or(0),
+        },
+        _ => AddressKey::Other(format!("{:?}", address)),
+    })
+}
+fn next_pci(used: &mut Vec<AddressKey>) -> Result<Address, ConfigError> {
+    let slot = (FIRST_PCI_SLOT..=LAST_PCI_SLOT)
+        .find(|slot| {
+            !used.iter().any(|key| match key {
+                AddressKey::Pci {
+                    domain: 0,
+                    bus: 0,
+                    slot: taken,
+                    ..
+                } => taken == slot,
+                _ => false,
+            })
+        })
+        .ok_or(ConfigError::NoFreeAddress)?;
+    used.push(AddressKey::Pci {
+        domain: 0,
+        bus: 0,
+        slot,
+        function: 0,
+    });
+    Ok(Address {
+        r#type: Some(String::from("pci")),
+        domain: Some(String::from("0x0000")),
+        bus: Some(String::from("0x00")),
+        slot: Some(format!("0x{:02x}", slot)),
+        function: Some(String::from("0x0")),
+        controller: None,
+        target: None,
+        unit: None,
+        port: None,
+    })
+}
+/// the next free unit on the first controller that has one, ide controllers have two buses of two units
+fn next_drive(used: &mut Vec<AddressKey>, bus: &str) -> Address {
+    let (buses, units): (u32, u32) = match bus {
+        "ide" => (2, 2),
+        "sata" => (1, 6),
+        _ => (1, 7),
+    };
+    let drive = |controller: u16, bus: u32, unit: u32| AddressKey::Drive {
+        controller,
+        bus,
+        target: 0,
+        unit,
+    };
+    let (controller, bus, unit) = (0..)
+        .flat_map(|controller| {
+            (0..buses).flat_map(move |bus| (0..units).map(move |unit| (controller, bus, unit)))
+        })
+        .find(|(controller, bus, unit)| !used.contains(&drive(*controller, *bus, *unit)))
+        .unwrap();
+    used.push(drive(controller, bus, unit));
+    Address {
+        r#type: Some(String::from("drive")),
+        domain: None,
+        bus: Some(bus.to_string()),
+        slot: None,
+        function: None,
+        controller: Some(controller),
+        target: Some(0),
+        unit: Some(unit),
+        port: None,
+    }
+}
+fn dev_prefix(bus: &str) -> &'static str {
+    match bus {
+        "virtio" => "vd",
+        "ide" => "hd",
+        "xen" => "xvd",
+        "fdc" => "fd",
+        _ => "sd",
+    }
+}
+/// the name of the index'th disk, counting like spreadsheet columns: vda to vdz, then vdaa
+fn dev_name(prefix: &str, index: usize) -> String {
+    let mut letters = Vec::new();
+    let mut n = index + 1;
+    while n > 0 {
+        n -= 1;
+        letters.push((b'a' + (n % 26) as u8) as char);
+        n /= 26;
+    }
+    letters.reverse();
+    format!("{}{}", prefix, letters.into_iter().collect::<String>())
+}
 
-construct_element! (
-    DomainConfig, "domain", [
-        r#type => String => "type",
-        id => Option<u32> => "id"
-    ], [
-        name => Option<name> => "name",
-        uuid => uuid => "uuid",
-        genid => Option<genid> => "genid",
-        title => Option<title> => "title",
-        description => Option<description> => "description",
-        memory => memory => "memory",
-        vcpu => vcpu => "vcpu",
-        os => OpSystem => "os",
-        features => Option<Features> => "features",
-        cpu => Option<CPU> => "cpu",
-        clock => Option<Clock> => "clock",
-        on_poweroff => Option<on_poweroff> => "on_poweroff",
-        on_reboot => Option<on_reboot> => "on_reboot",
-        on_crash => Option<on_crash> => "on_crash",
-        pm => Option<PM> => "pm"
-    ]
-);
-impl DomainConfig {
-    pub fn mut_os(&mut self) -> &mut OpSystem {
-        &mut self.os
+/// builds a DomainConfig for a kvm guest, every value has a default except the uuid
+pub struct DomainBuilder {
+    name: String,
+    uuid: Option<String>,
+    domain_type: String,
+    memory: Quantity,
+    vcpus: u32,
+    firmware: Option<String>,
+    boot: Vec<boot>,
+    devices: Devices,
+}
+impl DomainBuilder {
+    /// 1 GiB of memory, 1 vcpu, and no devices
+    pub fn new(name: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            uuid: None,
+            domain_type: String::from("kvm"),
+            memory: Quantity::new(1, Unit::GiB),
+            vcpus: 1,
+            firmware: None,
+            boot: Vec::new(),
+            devices: Devices::default(),
+        }
+    }
+    pub fn uuid(mut self, uuid: &str) -> Self {
+        self.uuid = Some(uuid.to_string());
+        self
+    }
+    /// "kvm" by default, "qemu" for guests without hardware acceleration
+    pub fn domain_type(mut self, domain_type: &str) -> Self {
+        self.domain_type = domain_type.to_string();
+        self
+    }
+    pub fn memory(mut self, value: u64, unit: Unit) -> Self {
+        self.memory = Quantity::new(value, unit);
+        self
+    }
+    pub fn vcpus(mut self, vcpus: u32) -> Self {
+        self.vcpus = vcpus;
+        self
+    }
+    /// "efi" or "bios", left to libvirt if not set
+    pub fn firmware(mut self, firmware: &str) -> Self {
+        self.firmware = Some(firmware.to_string());
+        self
+    }
+    /// virtio disk at vda that the guest boots from
+    ///
+    /// # Arguments
+    ///
+    /// path: image file on the host
+    /// format: format of the image, such as "qcow2" or "raw"
+    pub fn boot_disk(mut self, path: &str, format: &str) -> Self {
+        self.boot.push(boot {
+            dev: String::from("hd"),
+        });
+        self.disk(Disk {
+            r#type: Some(String::from("file")),
+            device: Some(String::from("disk")),
+            driver: Some(driver {
+                name: Some(String::from("qemu")),
+                r#type: Some(format.to_string()),
+            }),
+            source: Some(Source {
+                file: Some(path.to_string()),
+            }),
+            target: Some(Target {
+                dev: Some(String::from("vda")),
+                bus: Some(String::from("virtio")),
+            }),
+            address: None,
+        })
+    }
+    pub fn disk(mut self, disk: Disk) -> Self {
+        self.devices.disks.push(disk);
+        self
+    }
+    /// virtio interface on a libvirt network, such as "default"
+    pub fn network(self, network: &str) -> Self {
+        self.interface(Interface {
+            r#type: String::from("network"),
+            mac: None,
+            source: Some(InterfaceSource {
+                network: Some(network.to_string()),
+                bridge: None,
+            }),
+            model: Some(InterfaceModel {
+                r#type: String::from("virtio"),
+            }),
+            address: None,
+        })
+    }
+    pub fn interface(mut self, interface: Interface) -> Self {
+        self.devices.interfaces.push(interface);
+        self
     }
-    pub fn os(&self) -> &OpSystem {
-        &self.os
+    pub fn channel(mut self, channel: Channel) -> Self {
+        self.devices.channels.push(channel);
+        self
+    }
+    /// serial port on a pty, used as the guest's console
+    pub fn console(mut self) -> Self {
+        self.devices.serials.push(Serial {
+            r#type: String::from("pty"),
+            source: None,
+            target: None,
+        });
+        self.devices.consoles.push(Console {
+            r#type: String::from("pty"),
+            source: None,
+            target: Some(CharTarget {
+                r#type: Some(String::from("serial")),
+                port: Some(0),
+                name: None,
+                model: None,
+            }),
+        });
+        self
+    }
+    pub fn build(self) -> Result<DomainConfig, ConfigError> {
+        let uuid = match self.uuid {
+            Some(uuid) => uuid,
+            None => return Err(ConfigError::MissingUuid),
+        };
+        if self.memory.to_bytes()? == 0 {
+            return Err(ConfigError::NoMemory);
+        }
+        if self.vcpus == 0 {
+            return Err(ConfigError::NoVcpus);
+        }
+        let config = DomainConfig {
+            r#type: self.domain_type,
+            id: None,
+            name: Some(name::new(self.name)),
+            uuid: uuid::new(uuid),
+            genid: None,
+            title: None,
+            description: None,
+            memory: memory {
+                unit: self.memory.unit(),
+                text: self.memory.value().to_string(),
+            },
+            vcpu: vcpu {
+                placement: None,
+                text: self.vcpus.to_string(),
+            },
+            cputune: None,
+            memtune: None,
+            blkiotune: None,
+            os: OpSystem {
+                firmware: self.firmware,
+                r#type: r#type {
+                    arch: None,
+                    machine: None,
+                    text: String::from("hvm"),
+                },
+                boot: self.boot,
+            },
+            features: Some(Features {
+                acpi: Some(acpi::default()),
+                apic: Some(apic::default()),
+                vmport: None,
+            }),
+            cpu: None,
+            clock: None,
+            on_poweroff: Some(on_poweroff::default()),
+            on_reboot: Some(on_reboot::default()),
+            on_crash: Some(on_crash::default()),
+            pm: None,
+            devices: Some(self.devices),
+            passthrough: None,
+        };
+        config.validate()?;
+        Ok(config)
     }
 }
 // difference between networking and ssh (other then pnet) is ssh can have leaked public key problems
@@ -0,0 +1,1143 @@
+/*
+// libvirt xml documents for domains, snapshots, networks and storage, and the elements they are made of
+// every type here derives XmlRead and XmlWrite, and is re-exported by runtime::kvm
+*/
+// strong-xml-derive (write/named.rs) borrows required child elements without using the borrow,
+// so the derived XmlWrite impls trip unused_must_use, the allow is kept to this module for that reason
+#![allow(unused_must_use)]
+use super::*;
+
+#[derive(XmlWrite, XmlRead, PartialEq, Eq, Debug, Clone, Serialize, Deserialize)]
+#[xml(tag = "boot")]
+pub struct boot {
+    #[xml(attr = "dev")]
+    pub dev: String,
+}
+/// this struct is designed for creating configurations so the types
+/// used in it should implement either Display or XmlRead & XmlWrite, as well as Serialize, and Deserialize
+macro_rules! construct_element {
+    ($element:ident, $tag_name:expr, [$($attribute:ident => $attrib_type:ident $(<$attrib_inner:ty>)? => $attrib_name:expr),*], [$($child:ident => $child_type:ident $(<$child_inner:ty>)? => $child_name:expr),*]) => {
+        #[derive(XmlWrite, XmlRead, PartialEq, Eq, Debug, Clone, Serialize, Deserialize)]
+        #[xml(tag = $tag_name)]
+        pub struct $element {
+            $(
+                #[xml(attr = $attrib_name)]
+                pub $attribute: $attrib_type $(<$attrib_inner>)?,
+            )*
+            $(
+                #[xml(child = $child_name)]
+                pub $child: $child_type $(<$child_inner>)?,
+            )*
+        }
+    };
+    ($element:ident, $tag_name:expr, $include_type:ident, $($attribute:ident => $attrib_type:ident $(<$attrib_inner:ty>)? => $attrib_name:expr),*) => {
+        #[derive(XmlRead, XmlWrite, PartialEq, Eq, Debug, Clone, Serialize, Deserialize)]
+        #[xml(tag = $tag_name)]
+        pub struct $element {
+            $(
+                #[xml($include_type = $attrib_name)]
+                pub $attribute: $attrib_type $(<$attrib_inner>)?,
+            )*
+            #[xml(text)]
+            pub text: String,
+        }
+    };
+    ($($name:ident => $tag_name:expr),*) => {
+        $(
+            #[derive(XmlWrite, XmlRead, PartialEq, Eq, Debug, Clone, Serialize, Deserialize)]
+            #[xml(tag = $tag_name)]
+            pub struct $name {
+                #[xml(text)]
+                $name: String,
+            }
+            impl $name {
+                pub fn new(name: String) -> Self{
+                    Self {$name: name}
+                }
+                pub fn value(&self) -> &str {
+                    &self.$name
+                }
+            }
+        )*
+    };
+    ($no_text:ty, $($name:ident => $tag_name:expr),*) => {
+        $(
+            #[derive(XmlWrite, XmlRead, PartialEq, Eq, Debug, Clone, Serialize, Deserialize)]
+            #[xml(tag = $tag_name)]
+            pub struct $name {}
+            impl Default for $name {
+                fn default() -> Self {
+                    Self{}
+                }
+            }
+        )*
+    };
+    ($no_text:ty, $element:ident, $tag_name:expr, $include_type:ident, $($attribute:ident => $attrib_type:ident $(<$attrib_inner:ty>)? => $attrib_name:expr),*) => {
+        #[derive(XmlRead, XmlWrite, PartialEq, Eq, Debug, Clone, Serialize, Deserialize)]
+        #[xml(tag = $tag_name)]
+        pub struct $element {
+            $(
+                #[xml($include_type = $attrib_name)]
+                pub $attribute: $attrib_type $(<$attrib_inner>)?,
+            )*
+        }
+    };
+}
+
+// =============================
+//      Simple Elements
+// =============================
+
+construct_element!(title => "title",
+    description => "description",
+    genid => "genid",
+    uuid => "uuid",
+    name => "name",
+    emulator => "emulator",
+    on_poweroff => "on_poweroff",
+    on_reboot => "on_reboot",
+    on_crash => "on_crash"
+);
+impl Default for on_poweroff {
+    fn default() -> Self {
+        Self {on_poweroff: String::from("destroy")}
+    }
+}
+
+impl Default for on_reboot {
+    fn default() -> Self {
+        Self {on_reboot: String::from("restart")}
+    }
+}
+
+impl Default for on_crash {
+    fn default() -> Self {
+        Self {on_crash: String::from("destroy")}
+    }
+}
+
+// ==============================
+//      Tag Only
+// ==============================
+
+construct_element! (bool, 
+    readonly => "readonly",
+    acpi => "acpi",
+    apic => "apic"
+);
+
+// =========================
+//      Medium Elements
+// =========================
+
+construct_element! (
+    r#type, "type", attr, arch => Option<String> => "arch", machine => Option<String> => "machine"
+);
+construct_element! (bool,
+    vmport, "vmport", attr, state => Option<String> => "state"
+);
+construct_element! (bool,
+    driver, "driver", attr, name => Option<String> => "name", r#type => Option<String> => "type"
+);
+construct_element! (bool,
+    Target, "target", attr, dev => Option<String> => "dev", bus => Option<String> => "bus"
+);
+construct_element! (bool,
+    Source, "source", attr, file => Option<String> => "file"
+);
+construct_element! (
+    memory, "memory", attr,
+    unit => Unit => "unit"
+);
+construct_element! (
+    vcpu, "vcpu", attr,
+    placement => Option<String> => "placement"
+);
+
+// ==================================
+//      Resource Tuning
+// ==================================
+
+construct_element!(shares => "shares",
+    period => "period",
+    quota => "quota",
+    weight => "weight"
+);
+construct_element! (
+    hard_limit, "hard_limit", attr,
+    unit => Unit => "unit"
+);
+construct_element! (
+    soft_limit, "soft_limit", attr,
+    unit => Unit => "unit"
+);
+construct_element! (
+    swap_hard_limit, "swap_hard_limit", attr,
+    unit => Unit => "unit"
+);
+construct_element! (
+    CpuTune, "cputune", [], [
+        shares => Option<shares> => "shares",
+        period => Option<period> => "period",
+        quota => Option<quota> => "quota"
+    ]
+);
+construct_element! (
+    MemTune, "memtune", [], [
+        hard_limit => Option<hard_limit> => "hard_limit",
+        soft_limit => Option<soft_limit> => "soft_limit",
+        swap_hard_limit => Option<swap_hard_limit> => "swap_hard_limit"
+    ]
+);
+construct_element! (
+    BlkioTune, "blkiotune", [], [
+        weight => Option<weight> => "weight"
+    ]
+);
+construct_element! {bool,
+    Timer, "timer", attr,
+    name => Option<String> => "name",
+    tickpolicy => Option<String> => "tickpolicy",
+    present => Option<String> => "present"
+}
+
+construct_element! (
+    Features, "features", [], [
+        acpi => Option<acpi> => "acpi",
+        apic => Option<apic> => "apic",
+        vmport => Option<vmport> => "vmport"
+    ]
+);
+construct_element! (
+    PM, "pm", [], [
+        suspend_to_mem => Option<SuspendToMem> => "suspend-to-mem",
+        suspend_to_disk => Option<SuspendToDisk> => "suspend-to-disk"
+    ]
+);
+construct_element! (
+    Clock, "clock", [
+        offset => Option<String> => "offset"
+    ], [
+        timers => Vec<Timer> => "timer"
+    ]
+);
+
+construct_element! (bool,
+    Model, "model", attr,
+    fallback => Option<String> => "fallback"
+);
+construct_element! (bool,
+    SuspendToMem, "suspend-to-mem", attr,
+    enabled => Option<String> => "enabled"
+);
+construct_element! (bool,
+    SuspendToDisk, "suspend-to-disk", attr,
+    enabled => Option<String> => "enabled"
+);
+
+construct_element! (
+    CPU, "cpu", [
+        mode => Option<String> => "mode",
+        check => Option<String> => "check"
+    ], [
+        model => Option<Model> => "model"
+    ]
+);
+
+// =========================
+//      Address
+// =========================
+
+construct_element! (bool,
+    Address, "address", attr,
+    r#type => Option<String> => "type", 
+    domain => Option<String> => "domain", 
+    bus => Option<String> => "bus",
+    slot => Option<String> => "slot",
+    function => Option<String> => "function",
+    controller => Option<u16> => "controller",
+    target => Option<u16> => "target",
+    unit => Option<u32> => "unit",
+    port => Option<u16> => "port"
+);
+
+// ============================
+//         Disk
+// ============================
+construct_element! (
+    Disk, "disk", [
+        r#type => Option<String> => "type", 
+        device => Option<String> => "device"
+    ], [
+        driver => Option<driver> => "driver",
+        source => Option<Source> => "source",
+        target => Option<Target> => "target",
+        address => Option<Address> => "address"
+    ]
+);
+
+// ================================
+//      OpSystem
+// ================================
+
+construct_element! (
+    OpSystem, "os", [
+        firmware => Option<String> => "firmware"
+    ], [
+        r#type => r#type => "type",
+        boot => Vec<boot> => "boot"
+    ]
+);
+
+
+
+// ==================================
+//      Interface
+// ==================================
+
+construct_element! (bool,
+    Mac, "mac", attr,
+    address => String => "address"
+);
+construct_element! (bool,
+    InterfaceSource, "source", attr,
+    network => Option<String> => "network",
+    bridge => Option<String> => "bridge"
+);
+construct_element! (bool,
+    InterfaceModel, "model", attr,
+    r#type => String => "type"
+);
+// type is one of "user", "network" or "bridge"
+construct_element! (
+    Interface, "interface", [
+        r#type => String => "type"
+    ], [
+        mac => Option<Mac> => "mac",
+        source => Option<InterfaceSource> => "source",
+        model => Option<InterfaceModel> => "model",
+        address => Option<Address> => "address"
+    ]
+);
+
+// ==================================
+//      Character Devices
+// ==================================
+
+construct_element! (bool,
+    CharSource, "source", attr,
+    mode => Option<String> => "mode",
+    path => Option<String> => "path"
+);
+construct_element! (bool,
+    CharModel, "model", attr,
+    name => String => "name"
+);
+construct_element! (
+    CharTarget, "target", [
+        r#type => Option<String> => "type",
+        port => Option<u16> => "port",
+        name => Option<String> => "name"
+    ], [
+        model => Option<CharModel> => "model"
+    ]
+);
+construct_element! (
+    Serial, "serial", [
+        r#type => String => "type"
+    ], [
+        source => Option<CharSource> => "source",
+        target => Option<CharTarget> => "target"
+    ]
+);
+construct_element! (
+    Console, "console", [
+        r#type => String => "type"
+    ], [
+        source => Option<CharSource> => "source",
+        target => Option<CharTarget> => "target"
+    ]
+);
+construct_element! (
+    Channel, "channel", [
+        r#type => String => "type"
+    ], [
+        source => Option<CharSource> => "source",
+        target => Option<CharTarget> => "target",
+        address => Option<Address> => "address"
+    ]
+);
+
+// ==================================
+//      Graphics
+// ==================================
+
+construct_element! (bool,
+    Listen, "listen", attr,
+    r#type => String => "type",
+    address => Option<String> => "address",
+    network => Option<String> => "network"
+);
+construct_element! (
+    Graphics, "graphics", [
+        r#type => String => "type",
+        port => Option<i32> => "port",
+        autoport => Option<String> => "autoport",
+        listen => Option<String> => "listen"
+    ], [
+        listens => Vec<Listen> => "listen"
+    ]
+);
+
+// ==================================
+//      Rng
+// ==================================
+
+// the text is the host device, such as /dev/urandom
+construct_element! (
+    Backend, "backend", attr,
+    model => String => "model"
+);
+construct_element! (
+    Rng, "rng", [
+        model => String => "model"
+    ], [
+        backend => Backend => "backend",
+        address => Option<Address> => "address"
+    ]
+);
+
+// ==================================
+//      Device
+// ==================================
+
+construct_element! (
+    Controller, "controller", [
+        attrib_type => Option<String> => "type",
+        index => u32 => "index",
+        model => Option<String> => "model"
+    ], [
+        address => Option<Address> => "address"
+    ]
+);
+
+construct_element! (
+    Devices, "devices", [], [
+        disks => Vec<Disk> => "disk",
+        controllers => Vec<Controller> => "controller",
+        interfaces => Vec<Interface> => "interface",
+        serials => Vec<Serial> => "serial",
+        consoles => Vec<Console> => "console",
+        channels => Vec<Channel> => "channel",
+        graphics => Vec<Graphics> => "graphics",
+        rngs => Vec<Rng> => "rng"
+    ]
+);
+// the macro can't derive Default, since other elements have required children
+#[allow(clippy::derivable_impls)]
+impl Default for Devices {
+    fn default() -> Self {
+        Self {
+            disks: Vec::new(),
+            controllers: Vec::new(),
+            interfaces: Vec::new(),
+            serials: Vec::new(),
+            consoles: Vec::new(),
+            channels: Vec::new(),
+            graphics: Vec::new(),
+            rngs: Vec::new(),
+        }
+    }
+}
+
+// ==================================
+//      DomainConfig
+// ==================================
+
+construct_element! (
+    DomainConfig, "domain", [
+        r#type => String => "type",
+        id => Option<u32> => "id"
+    ], [
+        name => Option<name> => "name",
+        uuid => uuid => "uuid",
+        genid => Option<genid> => "genid",
+        title => Option<title> => "title",
+        description => Option<description> => "description",
+        memory => memory => "memory",
+        vcpu => vcpu => "vcpu",
+        cputune => Option<CpuTune> => "cputune",
+        memtune => Option<MemTune> => "memtune",
+        blkiotune => Option<BlkioTune> => "blkiotune",
+        os => OpSystem => "os",
+        features => Option<Features> => "features",
+        cpu => Option<CPU> => "cpu",
+        clock => Option<Clock> => "clock",
+        on_poweroff => Option<on_poweroff> => "on_poweroff",
+        on_reboot => Option<on_reboot> => "on_reboot",
+        on_crash => Option<on_crash> => "on_crash",
+        pm => Option<PM> => "pm",
+        devices => Option<Devices> => "devices",
+        passthrough => Option<Passthrough> => "#passthrough"
+    ]
+);
+impl DomainConfig {
+    /// parses a domain definition, anything that isn't modeled is kept in passthrough
+    /// this shadows XmlRead::from_str, which drops it
+    #[allow(clippy::should_implement_trait)]
+    pub fn from_str(xml: &str) -> Result<Self, XmlError> {
+        let (mut config, passthrough) = passthrough::read::<Self>(xml)?;
+        config.passthrough = passthrough;
+        Ok(config)
+    }
+    /// writes the domain definition, with anything kept in passthrough merged back in
+    /// this shadows XmlWrite::to_string, which leaves it out
+    pub fn to_string(&self) -> Result<String, XmlError> {
+        passthrough::write(self, self.passthrough.as_ref())
+    }
+    pub fn mut_os(&mut self) -> &mut OpSystem {
+        &mut self.os
+    }
+    pub fn os(&self) -> &OpSystem {
+        &self.os
+    }
+    pub fn builder(name: &str) -> DomainBuilder {
+        DomainBuilder::new(name)
+    }
+    /// checks the rules libvirt would otherwise reject the domain for, or silently work around
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        match &self.name {
+            Some(name) if !name.value().is_empty() => (),
+            _ => return Err(ConfigError::MissingName),
+        }
+        if !valid_uuid(self.uuid.value()) {
+            return Err(ConfigError::InvalidUuid(self.uuid.value().to_string()));
+        }
+        let devices = match &self.devices {
+            Some(devices) => devices,
+            None => return Ok(()),
+        };
+        let mut targets: Vec<&str> = Vec::new();
+        for disk in devices.disks.iter() {
+            if let Some(dev) = disk.target.as_ref().and_then(|target| target.dev.as_deref()) {
+                if targets.contains(&dev) {
+                    return Err(ConfigError::DuplicateTarget(dev.to_string()));
+                }
+                targets.push(dev);
+            }
+        }
+        let addresses = devices
+            .disks
+            .iter()
+            .filter_map(|disk| disk.address.as_ref())
+            .chain(devices.controllers.iter().filter_map(|c| c.address.as_ref()))
+            .chain(devices.interfaces.iter().filter_map(|i| i.address.as_ref()))
+            .chain(devices.channels.iter().filter_map(|c| c.address.as_ref()))
+            .chain(devices.rngs.iter().filter_map(|rng| rng.address.as_ref()));
+        let mut used: Vec<AddressKey> = Vec::new();
+        for address in addresses {
+            let key = address_key(address)?;
+            if used.contains(&key) {
+                return Err(ConfigError::AddressConflict(format!("{:?}", address)));
+            }
+            used.push(key);
+        }
+        Ok(())
+    }
+    /// fills cputune, memtune and blkiotune from a job's limits, capped by what policy allows a guest
+    /// guest memory above the limit is lowered to it, since the guest would be killed once it used it
+    pub fn tune(&mut self, limits: &ResourceLimits, policy: &SharePolicy) -> Result<(), ConfigError> {
+        let vcpus: u64 = match self.vcpu.text.trim().parse() {
+            Ok(vcpus) if vcpus > 0 => vcpus,
+            _ => return Err(ConfigError::NoVcpus),
+        };
+        let mut cputune = CpuTune {
+            shares: Some(shares::new(policy.cpu_shares.to_string())),
+            period: None,
+            quota: None,
+        };
+        if let Some(millis) = lowest(limits.cpu_limit(), policy.cpu_millis) {
+            // quota is per vcpu, so the limit is split between them
+            let total = u64::from(millis) * TUNE_PERIOD / 1000;
+            let quota = total.div_ceil(vcpus).max(MIN_QUOTA);
+            cputune.period = Some(period::new(TUNE_PERIOD.to_string()));
+            cputune.quota = Some(quota::new(quota.to_string()));
+        }
+        self.cputune = Some(cputune);
+        self.memtune = match lowest(limits.memory_limit(), policy.memory) {
+            Some(limit) => {
+                let text = self.memory.text.trim();
+                let value = text.parse().map_err(|_| UnitErr::InvalidNumber)?;
+                let mut guest = Quantity::new(value, self.memory.unit).to_bytes()?;
+                if guest > limit {
+                    guest = limit;
+                    self.memory = memory {
+                        unit: Unit::KiB,
+                        text: (limit / 1024).to_string(),
+                    };
+                }
+                let kib = (guest + QEMU_OVERHEAD) / 1024;
+                // swap_hard_limit counts memory and swap together, so the guest can't swap
+                Some(MemTune {
+                    hard_limit: Some(hard_limit {
+                        unit: Unit::KiB,
+                        text: kib.to_string(),
+                    }),
+                    soft_limit: None,
+                    swap_hard_limit: Some(swap_hard_limit {
+                        unit: Unit::KiB,
+                        text: kib.to_string(),
+                    }),
+                })
+            }
+            None => None,
+        };
+        self.blkiotune = Some(BlkioTune {
+            weight: Some(weight::new(policy.io_weight.to_string())),
+        });
+        Ok(())
+    }
+    /// gives disks without a target dev the next free name for their bus, such as vdb,
+    /// and gives disks, controllers and interfaces without an address the next free one
+    /// virtio devices are placed on pci bus 0, disks on other buses get a drive address on their controller
+    /// addresses that are already set are kept, but they have to be valid and unique
+    pub fn allocate(&mut self) -> Result<(), ConfigError> {
+        let devices = match &mut self.devices {
+            Some(devices) => devices,
+            None => return Ok(()),
+        };
+        let mut used: Vec<AddressKey> = Vec::new();
+        let set = devices
+            .disks
+            .iter()
+            .filter_map(|disk| disk.address.as_ref())
+            .chain(devices.controllers.iter().filter_map(|c| c.address.as_ref()))
+            .chain(devices.interfaces.iter().filter_map(|i| i.address.as_ref()))
+            .chain(devices.channels.iter().filter_map(|c| c.address.as_ref()))
+            .chain(devices.rngs.iter().filter_map(|rng| rng.address.as_ref()));
+        for address in set {
+            let key = address_key(address)?;
+            if used.contains(&key) {
+                return Err(ConfigError::AddressConflict(format!("{:?}", address)));
+            }
+            used.push(key);
+        }
+        let mut names: Vec<String> = Vec::new();
+        for disk in devices.disks.iter() {
+            if let Some(dev) = disk.target.as_ref().and_then(|target| target.dev.as_ref()) {
+                if names.contains(dev) {
+                    return Err(ConfigError::DuplicateTarget(dev.clone()));
+                }
+                names.push(dev.clone());
+            }
+        }
+        for disk in devices.disks.iter_mut() {
+            let target = disk.target.get_or_insert(Target {
+                dev: None,
+                bus: Some(String::from("virtio")),
+            });
+            let bus = target.bus.clone().unwrap_or_else(|| String::from("virtio"));
+            if target.dev.is_none() {
+                let prefix = dev_prefix(&bus);
+                let dev = (0..)
+                    .map(|index| dev_name(prefix, index))
+                    .find(|dev| !names.contains(dev))
+                    .unwrap();
+                names.push(dev.clone());
+                target.dev = Some(dev);
+            }
+            if disk.address.is_none() {
+                disk.address = match bus.as_str() {
+                    "virtio" => Some(next_pci(&mut used)?),
+                    "sata" | "scsi" | "ide" => Some(next_drive(&mut used, &bus)),
+                    // usb and floppy disks aren't addressed by the guest's bus
+                    _ => None,
+                };
+            }
+        }
+        for controller in devices.controllers.iter_mut() {
+            let builtin = matches!(
+                controller.attrib_type.as_deref(),
+                Some("pci") | Some("ide") | Some("fdc")
+            );
+            if !builtin && controller.address.is_none() {
+                controller.address = Some(next_pci(&mut used)?);
+            }
+        }
+        for interface in devices.interfaces.iter_mut() {
+            if interface.address.is_none() {
+                interface.address = Some(next_pci(&mut used)?);
+            }
+        }
+        Ok(())
+    }
+}
+
+// ==================================
+//      DomainSnapshot
+// ==================================
+
+construct_element!(state => "state",
+    creation_time => "creationTime"
+);
+// snapshot is "no", "internal" or "external", an external memory snapshot is saved to file
+construct_element! (bool,
+    SnapshotMemory, "memory", attr,
+    snapshot => String => "snapshot",
+    file => Option<String> => "file"
+);
+// name is the target dev of a disk of the domain, snapshot is "no", "internal" or "external"
+// an external snapshot's source is the new overlay the domain writes to from then on
+construct_element! (
+    SnapshotDisk, "disk", [
+        name => String => "name",
+        snapshot => Option<String> => "snapshot"
+    ], [
+        driver => Option<driver> => "driver",
+        source => Option<Source> => "source"
+    ]
+);
+construct_element! (
+    SnapshotDisks, "disks", [], [
+        disks => Vec<SnapshotDisk> => "disk"
+    ]
+);
+construct_element! (
+    SnapshotParent, "parent", [], [
+        name => name => "name"
+    ]
+);
+// state, parent, creation_time and domain are filled in by libvirt,
+// and are ignored when creating a snapshot
+construct_element! (
+    SnapshotConfig, "domainsnapshot", [], [
+        name => Option<name> => "name",
+        description => Option<description> => "description",
+        state => Option<state> => "state",
+        parent => Option<SnapshotParent> => "parent",
+        creation_time => Option<creation_time> => "creationTime",
+        memory => Option<SnapshotMemory> => "memory",
+        disks => Option<SnapshotDisks> => "disks",
+        domain => Option<DomainConfig> => "domain",
+        passthrough => Option<Passthrough> => "#passthrough"
+    ]
+);
+impl SnapshotConfig {
+    /// internal snapshot of the disks, and of memory if the domain is running,
+    /// stored in the domain's qcow2 images
+    pub fn new(name: &str) -> Self {
+        Self {
+            name: Some(name::new(name.to_string())),
+            description: None,
+            state: None,
+            parent: None,
+            creation_time: None,
+            memory: None,
+            disks: None,
+            domain: None,
+            passthrough: None,
+        }
+    }
+    /// parses a snapshot definition, anything that isn't modeled is kept in passthrough
+    #[allow(clippy::should_implement_trait)]
+    pub fn from_str(xml: &str) -> Result<Self, XmlError> {
+        let (mut config, passthrough) = passthrough::read::<Self>(xml)?;
+        config.passthrough = passthrough;
+        Ok(config)
+    }
+    pub fn to_string(&self) -> Result<String, XmlError> {
+        passthrough::write(self, self.passthrough.as_ref())
+    }
+    pub fn description(mut self, text: &str) -> Self {
+        self.description = Some(description::new(text.to_string()));
+        self
+    }
+    /// saves memory to file rather then into the images, which makes the snapshot external
+    pub fn memory_file<P: AsRef<Path>>(mut self, file: P) -> Self {
+        self.memory = Some(SnapshotMemory {
+            snapshot: String::from("external"),
+            file: Some(file.as_ref().to_string_lossy().into_owned()),
+        });
+        self
+    }
+    /// only snapshots the disks, a running domain keeps running without its memory being saved
+    pub fn disk_only(mut self) -> Self {
+        self.memory = Some(SnapshotMemory {
+            snapshot: String::from("no"),
+            file: None,
+        });
+        self
+    }
+    /// writes to dev go to a new qcow2 overlay at file from now on,
+    /// the image as it was becomes the overlay's backing file
+    pub fn external_disk<P: AsRef<Path>>(self, dev: &str, file: P) -> Self {
+        let file = file.as_ref().to_string_lossy().into_owned();
+        self.disk(SnapshotDisk {
+            name: dev.to_string(),
+            snapshot: Some(String::from("external")),
+            driver: Some(driver {
+                name: None,
+                r#type: Some(String::from("qcow2")),
+            }),
+            source: Some(Source { file: Some(file) }),
+        })
+    }
+    /// leaves dev out of the snapshot, such as a scratch disk or a read only seed image
+    pub fn exclude_disk(self, dev: &str) -> Self {
+        self.disk(SnapshotDisk {
+            name: dev.to_string(),
+            snapshot: Some(String::from("no")),
+            driver: None,
+            source: None,
+        })
+    }
+    fn disk(mut self, disk: SnapshotDisk) -> Self {
+        let disks = &mut self
+            .disks
+            .get_or_insert_with(|| SnapshotDisks { disks: Vec::new() })
+            .disks;
+        disks.retain(|existing| existing.name != disk.name);
+        disks.push(disk);
+        self
+    }
+    pub fn is_disk_only(&self) -> bool {
+        self.memory.as_ref().map(|memory| memory.snapshot.as_str()) == Some("no")
+    }
+    /// memory or any disk is kept outside of the domain's images
+    pub fn is_external(&self) -> bool {
+        let external = |snapshot: Option<&str>| snapshot == Some("external");
+        external(self.memory.as_ref().map(|memory| memory.snapshot.as_str()))
+            || self.disks.iter().flat_map(|disks| disks.disks.iter()).any(|disk| {
+                external(disk.snapshot.as_deref())
+            })
+    }
+    /// seconds since the epoch, as set by libvirt
+    pub fn created(&self) -> Option<u64> {
+        self.creation_time.as_ref()?.value().trim().parse().ok()
+    }
+}
+
+// ==================================
+//      Network
+// ==================================
+
+// mode is "nat", "route" or "open", a network without forward is isolated from the host's networks
+construct_element! (bool,
+    Forward, "forward", attr,
+    mode => Option<String> => "mode"
+);
+construct_element! (bool,
+    NetworkBridge, "bridge", attr,
+    name => Option<String> => "name",
+    stp => Option<String> => "stp",
+    delay => Option<String> => "delay"
+);
+construct_element! (bool,
+    DhcpRange, "range", attr,
+    start => String => "start",
+    end => String => "end"
+);
+// fixed address for the guest with mac
+construct_element! (bool,
+    DhcpHost, "host", attr,
+    mac => Option<String> => "mac",
+    name => Option<String> => "name",
+    ip => String => "ip"
+);
+construct_element! (
+    Dhcp, "dhcp", [], [
+        ranges => Vec<DhcpRange> => "range",
+        hosts => Vec<DhcpHost> => "host"
+    ]
+);
+// address is the host's address on the network, which guests use as their gateway
+construct_element! (
+    NetworkIp, "ip", [
+        family => Option<String> => "family",
+        address => String => "address",
+        netmask => Option<String> => "netmask",
+        prefix => Option<u8> => "prefix"
+    ], [
+        dhcp => Option<Dhcp> => "dhcp"
+    ]
+);
+construct_element! (
+    NetworkConfig, "network", [], [
+        name => name => "name",
+        uuid => Option<uuid> => "uuid",
+        forward => Option<Forward> => "forward",
+        bridge => Option<NetworkBridge> => "bridge",
+        ips => Vec<NetworkIp> => "ip",
+        passthrough => Option<Passthrough> => "#passthrough"
+    ]
+);
+impl NetworkIp {
+    /// the first address of subnet for the host, and the rest of it leased out over dhcp
+    pub fn dhcp(subnet: IpNetwork) -> Result<Self, ConfigError> {
+        let network = subnet.network();
+        // ipv6 has no broadcast address, but the last address is left out all the same
+        let last = subnet.broadcast();
+        // the network address, the host, one lease and the last address
+        let bits = match subnet {
+            IpNetwork::V4(_) => 32,
+            IpNetwork::V6(_) => 128,
+        };
+        if subnet.prefix() + 2 > bits {
+            return Err(ConfigError::InvalidNetwork(format!(
+                "{} has no room for leases",
+                subnet
+            )));
+        }
+        Ok(Self {
+            family: match subnet {
+                IpNetwork::V4(_) => None,
+                IpNetwork::V6(_) => Some(String::from("ipv6")),
+            },
+            address: offset(network, 1).to_string(),
+            netmask: None,
+            prefix: Some(subnet.prefix()),
+            dhcp: Some(Dhcp {
+                ranges: vec![DhcpRange {
+                    start: offset(network, 2).to_string(),
+                    end: offset(last, -1).to_string(),
+                }],
+                hosts: Vec::new(),
+            }),
+        })
+    }
+    /// subnet the address is in, from prefix or from netmask, which libvirt's own networks use
+    /// None if neither is set, rather then guessing at the subnet the way libvirt does
+    pub fn subnet(&self) -> Option<IpNetwork> {
+        let address: IpAddr = self.address.parse().ok()?;
+        match (self.prefix, &self.netmask) {
+            (Some(prefix), _) => IpNetwork::new(address, prefix).ok(),
+            (None, Some(netmask)) => IpNetwork::with_netmask(address, netmask.parse().ok()?).ok(),
+            (None, None) => None,
+        }
+    }
+}
+/// address delta away from ip, which has to stay within the same subnet
+fn offset(ip: IpAddr, delta: i32) -> IpAddr {
+    match ip {
+        IpAddr::V4(ip) => IpAddr::V4(Ipv4Addr::from(u32::from(ip).wrapping_add(delta as u32))),
+        IpAddr::V6(ip) => {
+            IpAddr::V6(Ipv6Addr::from(u128::from(ip).wrapping_add(delta as i128 as u128)))
+        }
+    }
+}
+impl NetworkConfig {
+    /// network that guests can reach the outside through, with the host translating their addresses
+    pub fn nat(name: &str, subnet: IpNetwork) -> Result<Self, ConfigError> {
+        let mut config = Self::isolated(name, subnet)?;
+        config.forward = Some(Forward {
+            mode: Some(String::from("nat")),
+        });
+        Ok(config)
+    }
+    /// network where guests can only reach each other and the host
+    pub fn isolated(name: &str, subnet: IpNetwork) -> Result<Self, ConfigError> {
+        Ok(Self {
+            name: name::new(name.to_string()),
+            uuid: None,
+            forward: None,
+            bridge: None,
+            ips: vec![NetworkIp::dhcp(subnet)?],
+            passthrough: None,
+        })
+    }
+    #[allow(clippy::should_implement_trait)]
+    pub fn from_str(xml: &str) -> Result<Self, XmlError> {
+        let (mut config, passthrough) = passthrough::read::<Self>(xml)?;
+        config.passthrough = passthrough;
+        Ok(config)
+    }
+    pub fn to_string(&self) -> Result<String, XmlError> {
+        passthrough::write(self, self.passthrough.as_ref())
+    }
+    /// name of the bridge created on the host, libvirt picks a free virbrN if it isn't set
+    pub fn bridge(mut self, name: &str) -> Self {
+        self.bridge = Some(NetworkBridge {
+            name: Some(name.to_string()),
+            stp: Some(String::from("on")),
+            delay: Some(String::from("0")),
+        });
+        self
+    }
+    /// always leases ip to the guest with mac, ip must be in one of the network's subnets
+    pub fn dhcp_host(mut self, mac: &str, ip: IpAddr) -> Result<Self, ConfigError> {
+        let network = self.ips.iter_mut().find(|network| match network.subnet() {
+            Some(subnet) => subnet.contains(ip),
+            None => false,
+        });
+        let network = match network {
+            Some(network) => network,
+            None => return Err(ConfigError::InvalidNetwork(format!("{} isn't in any subnet", ip))),
+        };
+        network
+            .dhcp
+            .get_or_insert_with(|| Dhcp {
+                ranges: Vec::new(),
+                hosts: Vec::new(),
+            })
+            .hosts
+            .push(DhcpHost {
+                mac: Some(mac.to_string()),
+                name: None,
+                ip: ip.to_string(),
+            });
+        Ok(self)
+    }
+    pub fn is_isolated(&self) -> bool {
+        self.forward.is_none()
+    }
+}
+
+// ==================================
+//      Storage
+// ==================================
+
+construct_element!(path => "path");
+construct_element! (
+    capacity, "capacity", attr,
+    unit => Unit => "unit"
+);
+construct_element! (
+    allocation, "allocation", attr,
+    unit => Unit => "unit"
+);
+construct_element! (
+    available, "available", attr,
+    unit => Unit => "unit"
+);
+// type is an image format, such as qcow2 or raw
+construct_element! (bool,
+    VolumeFormat, "format", attr,
+    r#type => String => "type"
+);
+construct_element! (
+    PoolTarget, "target", [], [
+        path => path => "path"
+    ]
+);
+// type is "dir" for a directory of image files,
+// other types need a source, which is kept in passthrough
+// capacity, allocation and available are filled in by libvirt
+construct_element! (
+    PoolConfig, "pool", [
+        r#type => String => "type"
+    ], [
+        name => name => "name",
+        uuid => Option<uuid> => "uuid",
+        capacity => Option<capacity> => "capacity",
+        allocation => Option<allocation> => "allocation",
+        available => Option<available> => "available",
+        target => PoolTarget => "target",
+        passthrough => Option<Passthrough> => "#passthrough"
+    ]
+);
+// path is filled in by libvirt, from the pool's path and the volume's name
+construct_element! (
+    VolumeTarget, "target", [], [
+        path => Option<path> => "path",
+        format => Option<VolumeFormat> => "format"
+    ]
+);
+construct_element! (
+    BackingStore, "backingStore", [], [
+        path => path => "path",
+        format => Option<VolumeFormat> => "format"
+    ]
+);
+construct_element! (
+    VolumeConfig, "volume", [
+        r#type => Option<String> => "type"
+    ], [
+        name => name => "name",
+        capacity => capacity => "capacity",
+        allocation => Option<allocation> => "allocation",
+        target => Option<VolumeTarget> => "target",
+        backing_store => Option<BackingStore> => "backingStore",
+        passthrough => Option<Passthrough> => "#passthrough"
+    ]
+);
+impl PoolConfig {
+    /// pool of image files in dir, which libvirt creates when the pool is built
+    pub fn dir<P: AsRef<Path>>(name: &str, dir: P) -> Self {
+        Self {
+            r#type: String::from("dir"),
+            name: name::new(name.to_string()),
+            uuid: None,
+            capacity: None,
+            allocation: None,
+            available: None,
+            target: PoolTarget {
+                path: path::new(dir.as_ref().to_string_lossy().into_owned()),
+            },
+            passthrough: None,
+        }
+    }
+    #[allow(clippy::should_implement_trait)]
+    pub fn from_str(xml: &str) -> Result<Self, XmlError> {
+        let (mut config, passthrough) = passthrough::read::<Self>(xml)?;
+        config.passthrough = passthrough;
+        Ok(config)
+    }
+    pub fn to_string(&self) -> Result<String, XmlError> {
+        passthrough::write(self, self.passthrough.as_ref())
+    }
+}
+impl VolumeConfig {
+    /// # Arguments
+    ///
+    /// size: the size the guest sees, the file only grows as far as it's written to
+    pub fn new(name: &str, size: Quantity, format: ImageFormat) -> Self {
+        Self {
+            r#type: Some(String::from("file")),
+            name: name::new(name.to_string()),
+            capacity: capacity {
+                unit: size.unit(),
+                text: size.value().to_string(),
+            },
+            allocation: Some(allocation {
+                unit: Unit::B,
+                text: String::from("0"),
+            }),
+            target: Some(VolumeTarget {
+                path: None,
+                format: Some(VolumeFormat {
+                    r#type: format.to_string(),
+                }),
+            }),
+            backing_store: None,
+            passthrough: None,
+        }
+    }
+    /// reads from base and keeps writes to itself, like ImageManager::overlay
+    pub fn backing<P: AsRef<Path>>(mut self, base: P, format: ImageFormat) -> Self {
+        self.backing_store = Some(BackingStore {
+            path: path::new(base.as_ref().to_string_lossy().into_owned()),
+            format: Some(VolumeFormat {
+                r#type: format.to_string(),
+            }),
+        });
+        self
+    }
+    #[allow(clippy::should_implement_trait)]
+    pub fn from_str(xml: &str) -> Result<Self, XmlError> {
+        let (mut config, passthrough) = passthrough::read::<Self>(xml)?;
+        config.passthrough = passthrough;
+        Ok(config)
+    }
+    pub fn to_string(&self) -> Result<String, XmlError> {
+        passthrough::write(self, self.passthrough.as_ref())
+    }
+    pub fn size(&self) -> Result<Quantity, UnitErr> {
+        match self.capacity.text.trim().parse() {
+            Ok(value) => Ok(Quantity::new(value, self.capacity.unit)),
+            Err(_) => Err(UnitErr::InvalidNumber),
+        }
+    }
+    pub fn path(&self) -> Option<&str> {
+        Some(self.target.as_ref()?.path.as_ref()?.value())
+    }
+}
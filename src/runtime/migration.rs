@@ -0,0 +1,632 @@
+/*
+// moves managed domains to another host, so a donating host can take its machine back without losing jobs
+// the domain is saved to a state file, which is sent along with its definition and disk images,
+// and restored on the other end, the guest is paused from the save until the restore
+// the base images overlays are backed by aren't sent, the other host has to have them at the same paths
+*/
+use crate::protocol::{Migration, ProtocolError, Transport};
+use crate::runtime::kvm::{self, CharSource, DomainConfig, DomainState, KvmError, QEMU};
+use async_trait::async_trait;
+use networking::asyncronous::AsyncStream;
+use networking::NetworkError;
+use std::error::Error;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::UnixStream;
+
+/// bumped whenever a message changes, peers with another version are refused
+pub const MIGRATION_VERSION: u32 = 1;
+/// largest message file contents are sent in
+pub const CHUNK_SIZE: usize = 64 * 1024;
+/// largest message a unix socket accepts, a definition is far smaller
+const MAX_MESSAGE: usize = 16 * 1024 * 1024;
+
+#[derive(Debug)]
+pub enum MigrationError {
+    Kvm(KvmError),
+    Io(io::Error),
+    Network(NetworkError),
+    /// the other end sent something out of order, or that couldn't be read
+    Protocol(String),
+    /// the other end couldn't restore the domain, it was restored here instead
+    Rejected(String),
+    /// the definition sent would give the domain parts of this host other then the files sent with it
+    Refused(String),
+}
+impl fmt::Display for MigrationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Kvm(e) => write!(f, "{}", e),
+            Self::Io(e) => write!(f, "{}", e),
+            Self::Network(e) => write!(f, "{}", e),
+            Self::Protocol(reason) => write!(f, "migration protocol error: {}", reason),
+            Self::Rejected(reason) => write!(f, "migration rejected: {}", reason),
+            Self::Refused(reason) => write!(f, "refused to restore domain: {}", reason),
+        }
+    }
+}
+impl Error for MigrationError {}
+impl From<KvmError> for MigrationError {
+    fn from(error: KvmError) -> Self {
+        Self::Kvm(error)
+    }
+}
+impl From<io::Error> for MigrationError {
+    fn from(error: io::Error) -> Self {
+        Self::Io(error)
+    }
+}
+impl From<NetworkError> for MigrationError {
+    fn from(error: NetworkError) -> Self {
+        Self::Network(error)
+    }
+}
+impl From<ProtocolError> for MigrationError {
+    fn from(error: ProtocolError) -> Self {
+        match error {
+            // receive stops once the other end hangs up
+            ProtocolError::Closed => Self::Io(io::ErrorKind::UnexpectedEof.into()),
+            ProtocolError::Io(e) => Self::Io(e),
+            ProtocolError::Network(e) => Self::Network(e),
+            other => Self::Protocol(other.to_string()),
+        }
+    }
+}
+impl From<serde_json::Error> for MigrationError {
+    fn from(error: serde_json::Error) -> Self {
+        Self::Protocol(error.to_string())
+    }
+}
+
+/// carries messages in order, each message sent arrives whole as one message on the other end
+/// libvirt handles aren't Send, so neither are migrations
+#[async_trait(?Send)]
+pub trait MigrationStream {
+    async fn send_message(&mut self, message: &[u8]) -> Result<(), MigrationError>;
+    async fn recv_message(&mut self) -> Result<Vec<u8>, MigrationError>;
+}
+#[async_trait(?Send)]
+impl MigrationStream for AsyncStream {
+    async fn send_message(&mut self, message: &[u8]) -> Result<(), MigrationError> {
+        self.send(message).await?;
+        Ok(())
+    }
+    async fn recv_message(&mut self) -> Result<Vec<u8>, MigrationError> {
+        let mut message = Vec::new();
+        self.recv(&mut message).await?;
+        Ok(message)
+    }
+}
+/// messages are MigrationData frames, so a peer's channel stays usable for other requests
+#[async_trait(?Send)]
+impl<S: Transport + Send> MigrationStream for Migration<'_, S> {
+    async fn send_message(&mut self, message: &[u8]) -> Result<(), MigrationError> {
+        Ok(self.send(message).await?)
+    }
+    async fn recv_message(&mut self) -> Result<Vec<u8>, MigrationError> {
+        Ok(self.recv().await?)
+    }
+}
+/// messages are prefixed with their length, for migrating between connections on one host
+#[async_trait(?Send)]
+impl MigrationStream for UnixStream {
+    async fn send_message(&mut self, message: &[u8]) -> Result<(), MigrationError> {
+        self.write_all(&(message.len() as u32).to_be_bytes())
+            .await?;
+        self.write_all(message).await?;
+        Ok(())
+    }
+    async fn recv_message(&mut self) -> Result<Vec<u8>, MigrationError> {
+        let mut len = [0; 4];
+        self.read_exact(&mut len).await?;
+        let len = u32::from_be_bytes(len) as usize;
+        if len > MAX_MESSAGE {
+            return Err(MigrationError::Protocol(format!("{} byte message", len)));
+        }
+        let mut message = vec![0; len];
+        self.read_exact(&mut message).await?;
+        Ok(message)
+    }
+}
+
+/// sent as json, file contents follow a Domain message as raw chunks, files first and the state last
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+enum Message {
+    Domain {
+        version: u32,
+        /// definition of the domain, as libvirt had it before it was saved
+        xml: String,
+        /// disk images, with their path on the sending host and their size
+        files: Vec<(String, u64)>,
+        /// size of the state file
+        state: u64,
+    },
+    /// every domain has been sent
+    Done,
+    Restored {
+        name: String,
+    },
+    Failed {
+        reason: String,
+    },
+}
+async fn send<S: MigrationStream>(stream: &mut S, message: &Message) -> Result<(), MigrationError> {
+    stream.send_message(&serde_json::to_vec(message)?).await
+}
+async fn recv<S: MigrationStream>(stream: &mut S) -> Result<Message, MigrationError> {
+    Ok(serde_json::from_slice(&stream.recv_message().await?)?)
+}
+
+/// moves a domain to the host at the other end of stream, which has to be running receive
+/// the domain is undefined here once the other host has restored it, and restored here if it couldn't
+///
+/// # Arguments
+///
+/// dir: where the state file is kept while it's sent
+pub async fn send_domain<S: MigrationStream>(
+    qemu: &mut QEMU,
+    name: &str,
+    dir: &Path,
+    stream: &mut S,
+) -> Result<(), MigrationError> {
+    let config = qemu.config(name)?;
+    let files = disk_files(&config)
+        .into_iter()
+        .filter(|path| path.is_file())
+        .map(|path| {
+            let len = fs::metadata(&path)?.len();
+            Ok((path.to_string_lossy().into_owned(), len))
+        })
+        .collect::<Result<Vec<_>, io::Error>>()?;
+    fs::create_dir_all(dir)?;
+    let state = dir.join(format!("{}.state", name));
+    qemu.save(name, &state)?;
+    let result = send_saved(&config, files, &state, stream).await;
+    let result = match result {
+        Ok(()) => qemu.undefine(name).map_err(MigrationError::from),
+        Err(e) => {
+            // the domain keeps running here, as it would have if it was never sent
+            if let Err(restore) = qemu.restore(&state, None) {
+                let _ = fs::remove_file(&state);
+                return Err(restore.into());
+            }
+            Err(e)
+        }
+    };
+    let _ = fs::remove_file(&state);
+    result
+}
+async fn send_saved<S: MigrationStream>(
+    config: &DomainConfig,
+    files: Vec<(String, u64)>,
+    state: &Path,
+    stream: &mut S,
+) -> Result<(), MigrationError> {
+    let message = Message::Domain {
+        version: MIGRATION_VERSION,
+        xml: config.to_string().map_err(KvmError::from)?,
+        files: files.clone(),
+        state: fs::metadata(state)?.len(),
+    };
+    send(stream, &message).await?;
+    for (path, _) in files.iter() {
+        send_file(Path::new(path), stream).await?;
+    }
+    send_file(state, stream).await?;
+    match recv(stream).await? {
+        Message::Restored { .. } => Ok(()),
+        Message::Failed { reason } => Err(MigrationError::Rejected(reason)),
+        other => Err(MigrationError::Protocol(format!("unexpected {:?}", other))),
+    }
+}
+async fn send_file<S: MigrationStream>(path: &Path, stream: &mut S) -> Result<(), MigrationError> {
+    let mut file = File::open(path)?;
+    let mut chunk = vec![0; CHUNK_SIZE];
+    loop {
+        let read = file.read(&mut chunk)?;
+        if read == 0 {
+            return Ok(());
+        }
+        stream.send_message(&chunk[..read]).await?;
+    }
+}
+/// file backed disks of a domain, in the order they are sent
+fn disk_files(config: &DomainConfig) -> Vec<PathBuf> {
+    config
+        .devices
+        .iter()
+        .flat_map(|devices| devices.disks.iter())
+        .filter_map(|disk| disk.source.as_ref()?.file.as_ref())
+        .map(PathBuf::from)
+        .collect()
+}
+
+/// moves every running or paused domain of qemu this crate manages to the other end of stream,
+/// then tells it that's all, domains of the host's owner are never moved
+/// returns the names of the domains that were moved, the others keep running here
+pub async fn evacuate<S: MigrationStream>(
+    qemu: &mut QEMU,
+    dir: &Path,
+    stream: &mut S,
+) -> Result<Vec<String>, MigrationError> {
+    let mut moved = Vec::new();
+    for name in qemu.load_all_domains()? {
+        if !kvm::managed(&name) {
+            continue;
+        }
+        if !matches!(
+            qemu.state(&name)?,
+            DomainState::Running | DomainState::Paused
+        ) {
+            continue;
+        }
+        match send_domain(qemu, &name, dir, stream).await {
+            Ok(()) => moved.push(name),
+            Err(MigrationError::Rejected(_)) => (),
+            Err(e) => return Err(e),
+        }
+    }
+    send(stream, &Message::Done).await?;
+    Ok(moved)
+}
+
+/// restores domains sent by send_domain or evacuate, until the other end is done or hangs up
+/// returns the names of the domains that were restored
+///
+/// # Arguments
+///
+/// dir: where disk images and state files are written, disks are restored from there
+pub async fn receive<S: MigrationStream>(
+    qemu: &mut QEMU,
+    dir: &Path,
+    stream: &mut S,
+) -> Result<Vec<String>, MigrationError> {
+    let mut restored = Vec::new();
+    loop {
+        let message = match stream.recv_message().await {
+            Ok(message) => serde_json::from_slice(&message)?,
+            // a peer sending one domain with send_domain hangs up rather then sending Done
+            Err(MigrationError::Io(e)) if e.kind() == io::ErrorKind::UnexpectedEof => break,
+            Err(e) => return Err(e),
+        };
+        let (xml, files, state) = match message {
+            Message::Domain {
+                version,
+                xml,
+                files,
+                state,
+            } if version == MIGRATION_VERSION => (xml, files, state),
+            Message::Domain { version, .. } => {
+                return Err(MigrationError::Protocol(format!("version {}", version)));
+            }
+            Message::Done => break,
+            other => return Err(MigrationError::Protocol(format!("unexpected {:?}", other))),
+        };
+        let config = DomainConfig::from_str(&xml).map_err(KvmError::from)?;
+        let name = match &config.name {
+            Some(name) => name.value().to_string(),
+            None => return Err(MigrationError::Protocol(String::from("domain has no name"))),
+        };
+        let domain_dir = domain_dir(dir, &name)?;
+        fs::create_dir_all(&domain_dir)?;
+        let result = receive_domain(qemu, config, &domain_dir, files, state, stream).await;
+        match result {
+            Ok(()) => {
+                send(stream, &Message::Restored { name: name.clone() }).await?;
+                restored.push(name);
+            }
+            // the other end has already sent everything, so it can restore the domain itself
+            Err(e @ MigrationError::Kvm(_))
+            | Err(e @ MigrationError::Io(_))
+            | Err(e @ MigrationError::Refused(_)) => {
+                let _ = fs::remove_dir_all(&domain_dir);
+                let reason = e.to_string();
+                send(stream, &Message::Failed { reason }).await?;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(restored)
+}
+/// directory the files of a received domain are written to
+/// the name comes from the peer, so it has to be a single component that stays inside of dir
+fn domain_dir(dir: &Path, name: &str) -> Result<PathBuf, MigrationError> {
+    let hostile = name.is_empty()
+        || name.starts_with('.')
+        || name.contains('/')
+        || name.contains("..")
+        || name.contains('\0');
+    if hostile {
+        return Err(MigrationError::Protocol(format!("invalid domain name {:?}", name)));
+    }
+    Ok(dir.join(name))
+}
+async fn receive_domain<S: MigrationStream>(
+    qemu: &mut QEMU,
+    config: DomainConfig,
+    dir: &Path,
+    files: Vec<(String, u64)>,
+    state: u64,
+    stream: &mut S,
+) -> Result<(), MigrationError> {
+    let mut written = Vec::new();
+    let mut failed = None;
+    // every chunk is read, even after a write fails, so the stream stays in step
+    for (index, (path, len)) in files.iter().enumerate() {
+        let file_name = Path::new(path)
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        let local = dir.join(format!("{}-{}", index, file_name));
+        receive_file(&local, *len, stream, &mut failed).await?;
+        written.push((path.clone(), local));
+    }
+    let state_file = dir.join("domain.state");
+    receive_file(&state_file, state, stream, &mut failed).await?;
+    if let Some(e) = failed {
+        return Err(e.into());
+    }
+    let config = confine(config, &written, dir)?;
+    // restoring without a replacement uses the definition in the state file, which comes from the peer as well
+    let saved = qemu.saved_config(&state_file)?;
+    // the definition is only replaced when it has to be, not every driver allows replacing it
+    let replaced = match confine(saved.clone(), &written, dir)? == saved {
+        true => None,
+        false => Some(&config),
+    };
+    qemu.restore(&state_file, replaced)?;
+    let _ = fs::remove_file(&state_file);
+    // a restored domain is transient until it's defined again, which leaves it running
+    qemu.create_domain(&config, false)?;
+    Ok(())
+}
+/// unknown parts of a received definition that only describe the sending host, this host fills them in itself
+const HOST_SPECIFIC: [&str; 6] = [
+    "resource",
+    "seclabel",
+    "devices/emulator",
+    "devices/disk/backingStore",
+    "devices/interface/target",
+    "devices/console/@tty",
+];
+/// whether an unknown part of a received definition can be kept, none of them refer to anything on the host
+fn harmless(location: &str) -> bool {
+    let alias = location.starts_with("devices/") && location.ends_with("/alias");
+    alias
+        || matches!(
+            location,
+            "currentMemory"
+                | "metadata"
+                | "devices/memballoon"
+                | "devices/input"
+                | "devices/video"
+                | "devices/disk/source/@index"
+                | "devices/serial/@tty"
+        )
+}
+/// the definition of a received domain, limited to the files that were received with it
+/// disks must be one of the received files, and are pointed at where they were written,
+/// file and socket character devices are moved into dir, anything else that isn't harmless is refused
+///
+/// # Arguments
+///
+/// written: the path of each received file on the peer, and where it was written here
+fn confine(
+    mut config: DomainConfig,
+    written: &[(String, PathBuf)],
+    dir: &Path,
+) -> Result<DomainConfig, MigrationError> {
+    if let Some(passthrough) = config.passthrough.as_mut() {
+        passthrough.retain(|location| !HOST_SPECIFIC.contains(&location));
+        if let Some(location) = passthrough.locations().find(|location| !harmless(location)) {
+            return Err(MigrationError::Refused(format!("unknown {}", location)));
+        }
+        if passthrough.is_empty() {
+            config.passthrough = None;
+        }
+    }
+    let devices = match config.devices.as_mut() {
+        Some(devices) => devices,
+        None => return Ok(config),
+    };
+    for disk in devices.disks.iter_mut() {
+        let file = match disk.source.as_mut().and_then(|source| source.file.as_mut()) {
+            Some(file) => file,
+            None => continue,
+        };
+        match written.iter().find(|(path, _)| path == file) {
+            Some((_, local)) => *file = local.to_string_lossy().into_owned(),
+            None => {
+                return Err(MigrationError::Refused(format!(
+                    "disk {} wasn't sent",
+                    file
+                )))
+            }
+        }
+    }
+    let chars = devices
+        .serials
+        .iter_mut()
+        .map(|serial| (&serial.r#type, &mut serial.source))
+        .chain(
+            devices
+                .consoles
+                .iter_mut()
+                .map(|console| (&console.r#type, &mut console.source)),
+        )
+        .chain(
+            devices
+                .channels
+                .iter_mut()
+                .map(|channel| (&channel.r#type, &mut channel.source)),
+        );
+    for (index, (kind, source)) in chars.enumerate() {
+        confine_char(kind, source, &dir.join(format!("char-{}", index)))?;
+    }
+    Ok(config)
+}
+fn confine_char(
+    kind: &str,
+    source: &mut Option<CharSource>,
+    local: &Path,
+) -> Result<(), MigrationError> {
+    let path = match source.as_mut().and_then(|source| source.path.as_mut()) {
+        Some(path) => path,
+        None => return Ok(()),
+    };
+    match kind {
+        "file" | "unix" => *path = local.to_string_lossy().into_owned(),
+        // the host allocates the pty, the path only reports which one it was
+        "pty" => *source = None,
+        _ => return Err(MigrationError::Refused(format!("{} device {}", kind, path))),
+    }
+    Ok(())
+}
+/// writes len bytes of chunks to path, once writing fails the rest is read and dropped
+async fn receive_file<S: MigrationStream>(
+    path: &Path,
+    len: u64,
+    stream: &mut S,
+    failed: &mut Option<io::Error>,
+) -> Result<(), MigrationError> {
+    let mut file = match File::create(path) {
+        Ok(file) => Some(file),
+        Err(e) => {
+            failed.get_or_insert(e);
+            None
+        }
+    };
+    let mut remaining = len;
+    while remaining > 0 {
+        let chunk = stream.recv_message().await?;
+        if chunk.is_empty() || chunk.len() as u64 > remaining {
+            return Err(MigrationError::Protocol(format!(
+                "{} byte chunk with {} bytes left",
+                chunk.len(),
+                remaining
+            )));
+        }
+        remaining -= chunk.len() as u64;
+        if let Some(writer) = file.as_mut() {
+            if let Err(e) = writer.write_all(&chunk) {
+                failed.get_or_insert(e);
+                file = None;
+            }
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn hostile_names() {
+    let dir = Path::new("/var/lib/artifice/received");
+    assert_eq!(domain_dir(dir, "guest-1").unwrap(), dir.join("guest-1"));
+    for name in ["", ".", "..", ".hidden", "../../home/x", "a/b", "a..b", "/etc", "a\0b"].iter() {
+        assert!(matches!(domain_dir(dir, name), Err(MigrationError::Protocol(_))), "{}", name);
+    }
+}
+#[test]
+fn confined() {
+    let dir = Path::new("/var/lib/artifice/received/guest");
+    let written = vec![(String::from("/images/guest.qcow2"), dir.join("0-guest.qcow2"))];
+    let domain = |devices: &str| {
+        DomainConfig::from_str(&format!(
+            "<domain type=\"kvm\"><name>guest</name>\
+            <uuid>8a3c1f52-6e0b-4c4f-b3a9-2d7e5f9c0b17</uuid>\
+            <memory unit=\"MiB\">64</memory><currentMemory unit=\"MiB\">64</currentMemory>\
+            <vcpu>1</vcpu><os><type>hvm</type></os><devices>\
+            <emulator>/usr/bin/qemu-system-x86_64</emulator>\
+            <disk type=\"file\" device=\"disk\"><source file=\"/images/guest.qcow2\"/>\
+            <target dev=\"vda\" bus=\"virtio\"/></disk>{}</devices></domain>",
+            devices
+        ))
+        .unwrap()
+    };
+
+    let serial = "<serial type=\"file\"><source path=\"/jobs/1/out\"/></serial>";
+    let config = confine(domain(serial), &written, dir).unwrap();
+    let devices = config.devices.as_ref().unwrap();
+    let disk = devices.disks[0].source.as_ref().unwrap().file.as_ref().unwrap();
+    assert_eq!(Path::new(disk), dir.join("0-guest.qcow2"));
+    let serial = devices.serials[0].source.as_ref().unwrap().path.as_ref().unwrap();
+    assert_eq!(Path::new(serial), dir.join("char-0"));
+    let xml = config.to_string().unwrap();
+    assert!(xml.contains("currentMemory") && !xml.contains("emulator"));
+
+    let hostile = [
+        "<disk type=\"file\" device=\"disk\"><source file=\"/etc/shadow\"/></disk>",
+        "<disk type=\"block\" device=\"disk\"><source dev=\"/dev/sda\"/></disk>",
+        "<hostdev mode=\"subsystem\" type=\"pci\"/>",
+        "<serial type=\"dev\"><source path=\"/dev/ttyS0\"/></serial>",
+    ];
+    for devices in hostile.iter() {
+        let result = confine(domain(devices), &written, dir);
+        assert!(matches!(result, Err(MigrationError::Refused(_))), "{}", devices);
+    }
+}
+#[test]
+fn migrate() {
+    use std::thread;
+
+    let dir = std::env::temp_dir().join(format!("artifice-migration-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    // a file backed test driver is its own host, unlike test:///default which other tests share,
+    // and which evacuate would empty
+    let host = |name: &str| {
+        let node = dir.join(format!("{}.xml", name));
+        fs::write(&node, "<node></node>").unwrap();
+        format!("test://{}", node.display())
+    };
+    let (source_uri, destination_uri) = (host("source"), host("destination"));
+    let (source, destination) = std::os::unix::net::UnixStream::pair().unwrap();
+
+    let receive_dir = dir.join("received");
+    let receiver = thread::spawn(move || {
+        let mut runtime = crate::runtime::test_runtime();
+        runtime.block_on(async {
+            let mut qemu = QEMU::connect_uri(&destination_uri).unwrap();
+            let mut stream = UnixStream::from_std(destination).unwrap();
+            let restored = receive(&mut qemu, &receive_dir, &mut stream).await.unwrap();
+            let states = restored
+                .iter()
+                .map(|name| qemu.state(name).unwrap())
+                .collect::<Vec<_>>();
+            (restored, states)
+        })
+    });
+
+    let mut qemu = QEMU::connect_uri(&source_uri).unwrap();
+    let config = DomainConfig::from_str(
+        "<domain type=\"test\"><name>artifice-migrate</name>\
+        <uuid>8a3c1f52-6e0b-4c4f-b3a9-2d7e5f9c0b17</uuid>\
+        <memory unit=\"MiB\">64</memory><vcpu>1</vcpu>\
+        <os><type>hvm</type></os></domain>",
+    )
+    .unwrap();
+    qemu.create_domain(&config, true).unwrap();
+    // the owner's own domains stay where they are
+    let owner = DomainConfig::from_str(
+        "<domain type=\"test\"><name>desktop</name>\
+        <uuid>8a3c1f52-6e0b-4c4f-b3a9-2d7e5f9c0b18</uuid>\
+        <memory unit=\"MiB\">64</memory><vcpu>1</vcpu>\
+        <os><type>hvm</type></os></domain>",
+    )
+    .unwrap();
+    qemu.create_domain(&owner, true).unwrap();
+    let mut runtime = crate::runtime::test_runtime();
+    let moved = runtime.block_on(async {
+        let mut stream = UnixStream::from_std(source).unwrap();
+        evacuate(&mut qemu, &dir, &mut stream).await.unwrap()
+    });
+    assert_eq!(moved, vec![String::from("artifice-migrate")]);
+    assert!(qemu.state("artifice-migrate").is_err());
+    assert_eq!(qemu.state("desktop").unwrap(), DomainState::Running);
+
+    let (restored, states) = receiver.join().unwrap();
+    assert_eq!(restored, moved);
+    assert!(states.iter().all(|state| *state == DomainState::Running));
+    fs::remove_dir_all(&dir).unwrap();
+}
@@ -1,26 +1,121 @@
 #![allow(non_camel_case_types)]
 #[cfg(feature = "kvm")]
 pub mod kvm;
+#[cfg(target_os = "linux")]
+pub mod cgroup;
+#[cfg(feature = "kvm")]
+pub mod cloudinit;
+#[cfg(target_os = "linux")]
+pub mod confine;
+#[cfg(feature = "kvm")]
+pub mod events;
+#[cfg(feature = "kvm")]
+pub mod image;
+#[cfg(feature = "kvm")]
+pub mod migration;
+pub mod paillier;
+#[cfg(feature = "kvm")]
+pub mod passthrough;
+#[cfg(feature = "kvm")]
+pub mod pool;
+#[cfg(feature = "kvm")]
+pub mod qemu;
+pub mod registry;
+pub mod sandbox;
 use derive_more::{Display};
 
+use crate::job::{CancelToken, JobError, JobId, JobOutcome, JobResult, JobStatus, JobTable};
+use crate::ledger::Usage;
+use crate::permissions::Resource;
 use crate::{EnvData, EnvType, ExecEnv, RemoteEnv};
+use async_trait::async_trait;
+#[cfg(target_os = "linux")]
+use cgroup::{CgroupError, CgroupRoot, CgroupUsage};
+#[cfg(target_os = "linux")]
+use confine::{Access, ConfineError, Confinement};
+use ferrisvm::error::FerrisError;
+use ferrisvm::Machine;
 use networking::NetworkError;
+use sandbox::{Sandbox, SandboxError};
 use std::convert::TryInto;
 use std::error::Error;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::process::{Command, ExitStatus, Output, Stdio};
+use std::mem::size_of;
 use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+/// number of memory cells given to each ferrisvm machine
+pub const VM_MEMORY: usize = 65536;
+/// number of instructions a ferrisvm job runs between checks of its deadline
+pub const DEADLINE_INTERVAL: u32 = 4096;
 
 pub struct VirtualEnv {
     key: [u8; 16],
     code: Vec<u8>,
     env: RemoteEnv,
+    jobs: JobTable,
+}
+/// input for the program loaded into a VirtualEnv
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct VirtualJob {
+    input: Vec<i64>,
+}
+impl VirtualJob {
+    pub fn new(input: Vec<i64>) -> Self {
+        Self { input }
+    }
+}
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct VirtualOutput {
+    pub output: Vec<i64>,
+}
+#[derive(Debug)]
+pub enum VirtualError {
+    Network(NetworkError),
+    Ferris(FerrisError),
+    Job(JobError),
+    Io(std::io::Error),
+}
+impl fmt::Display for VirtualError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Network(e) => write!(f, "{}", e),
+            Self::Ferris(e) => write!(f, "{}", e),
+            Self::Job(e) => write!(f, "{}", e),
+            Self::Io(e) => write!(f, "{}", e),
+        }
+    }
+}
+impl Error for VirtualError {}
+impl From<NetworkError> for VirtualError {
+    fn from(error: NetworkError) -> Self {
+        VirtualError::Network(error)
+    }
+}
+impl From<FerrisError> for VirtualError {
+    fn from(error: FerrisError) -> Self {
+        VirtualError::Ferris(error)
+    }
+}
+impl From<JobError> for VirtualError {
+    fn from(error: JobError) -> Self {
+        VirtualError::Job(error)
+    }
 }
 impl VirtualEnv {
     pub fn empty(env_type: EnvType) -> Result<Self, NetworkError> {
-        Ok(Self {
+        Ok(Self::new(RemoteEnv::init(env_type)?))
+    }
+    /// same as empty, but with data on the environment passed in
+    pub fn new(env: RemoteEnv) -> Self {
+        Self {
             key: [0; 16],
             code: Vec::new(),
-            env: RemoteEnv::init(env_type)?,
-        })
+            env,
+            jobs: JobTable::new(),
+        }
     }
     pub fn load(mut self, code: &[u8], key: &[u8]) -> Result<Self, NetworkError> {
         self.key = key.try_into()?;
@@ -33,6 +128,9 @@ impl EnvData for VirtualEnv {
     fn trusted(&self) -> bool {
         self.env.trusted()
     }
+    fn env_type(&self) -> &EnvType {
+        self.env.env_type()
+    }
     fn os_name(&self) -> &str {
         self.env.os_name()
     }
@@ -49,20 +147,523 @@ impl EnvData for VirtualEnv {
         self.env.cpu_speed()
     }
 }
+#[async_trait]
 impl ExecEnv for VirtualEnv {
-    type Error = NetworkError;
+    type Error = VirtualError;
+    type Job = VirtualJob;
+    type Output = VirtualOutput;
+    async fn execute(
+        &self,
+        id: JobId,
+        job: VirtualJob,
+        deadline: Option<Instant>,
+    ) -> Result<JobResult<VirtualOutput>, VirtualError> {
+        let token = self.jobs.start(id, deadline)?;
+        let mut machine = match Machine::new(&self.code, VM_MEMORY) {
+            Ok(machine) => machine,
+            Err(e) => {
+                self.jobs.finish(id, JobStatus::Failed);
+                return Err(e.into());
+            }
+        };
+        machine.input(&job.input);
+        let started = Instant::now();
+        // the machine doesn't yield, so it is run on the blocking pool
+        let stop = token.clone();
+        let result = tokio::task::spawn_blocking(move || {
+            let cpu = thread_cpu_time();
+            let mut steps: u32 = 0;
+            // reading the clock costs more than an instruction, so the deadline isn't checked every time
+            let halted = machine.run_while(|| {
+                steps = steps.wrapping_add(1);
+                match steps % DEADLINE_INTERVAL {
+                    0 => stop.stopped().is_none(),
+                    _ => !stop.is_stopped(),
+                }
+            })?;
+            let usage = Usage {
+                cpu_time: Some(thread_cpu_time() - cpu),
+                memory_peak: Some(machine.memory_peak() as u64),
+                fuel: Some(machine.executed()),
+                ..Usage::default()
+            };
+            Ok((halted, usage, machine.into_output()))
+        })
+        .await;
+        let result = match result {
+            Ok(result) => result,
+            Err(e) => Err(VirtualError::Io(std::io::Error::other(e))),
+        };
+        match result {
+            Ok((halted, mut usage, output)) => {
+                let outcome = match halted {
+                    true => JobOutcome::Completed,
+                    false => token.outcome(),
+                };
+                self.jobs.finish(id, outcome.into());
+                usage.wall_time = started.elapsed();
+                usage.bytes_in = (job.input.len() * size_of::<i64>()) as u64;
+                usage.bytes_out = (output.len() * size_of::<i64>()) as u64;
+                Ok(JobResult {
+                    id,
+                    outcome,
+                    output: Some(VirtualOutput { output }),
+                    usage,
+                })
+            }
+            Err(e) => {
+                self.jobs.finish(id, JobStatus::Failed);
+                Err(e)
+            }
+        }
+    }
+    fn cancel(&self, id: JobId) -> Result<(), VirtualError> {
+        self.jobs.cancel(id)?;
+        Ok(())
+    }
+    fn status(&self, id: JobId) -> JobStatus {
+        self.jobs.status(id)
+    }
+}
+/// cpu time used by the calling thread, measures jobs that run on a thread of the blocking pool
+pub(crate) fn thread_cpu_time() -> Duration {
+    let mut time = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    unsafe { libc::clock_gettime(libc::CLOCK_THREAD_CPUTIME_ID, &mut time) };
+    Duration::new(time.tv_sec as u64, time.tv_nsec as u32)
+}
+/// resources a job requests, None means no limit
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ResourceLimits {
+    memory: Option<u64>,
+    cpu_millis: Option<u32>,
+    pids: Option<u64>,
+}
+impl ResourceLimits {
+    /// maximum memory in bytes
+    pub fn memory(mut self, bytes: u64) -> Self {
+        self.memory = Some(bytes);
+        self
+    }
+    /// cpu time in thousandths of a cpu, 1500 is one and a half cores
+    pub fn cpu_millis(mut self, millis: u32) -> Self {
+        self.cpu_millis = Some(millis);
+        self
+    }
+    /// maximum number of processes and threads
+    pub fn pids(mut self, pids: u64) -> Self {
+        self.pids = Some(pids);
+        self
+    }
+    pub fn memory_limit(&self) -> Option<u64> {
+        self.memory
+    }
+    pub fn cpu_limit(&self) -> Option<u32> {
+        self.cpu_millis
+    }
+    pub fn pid_limit(&self) -> Option<u64> {
+        self.pids
+    }
+}
+/// program, arguments and environment of a job that is run directly on the host
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct NativeJob {
+    program: PathBuf,
+    args: Vec<String>,
+    envs: Vec<(String, String)>,
+    limits: ResourceLimits,
+    grants: Vec<Resource>,
+}
+impl NativeJob {
+    pub fn new<P: AsRef<Path>>(program: P) -> Self {
+        Self {
+            program: program.as_ref().to_path_buf(),
+            args: Vec::new(),
+            envs: Vec::new(),
+            limits: ResourceLimits::default(),
+            grants: Vec::new(),
+        }
+    }
+    /// only enforced when the NativeEnv has a cgroup root
+    pub fn limits(mut self, limits: ResourceLimits) -> Self {
+        self.limits = limits;
+        self
+    }
+    /// resources granted to the application this job belongs to, see permissions::granted
+    /// only enforced when the NativeEnv confines jobs
+    pub fn grants(mut self, grants: &[Resource]) -> Self {
+        self.grants.extend_from_slice(grants);
+        self
+    }
+    pub fn arg(mut self, arg: String) -> Self {
+        self.args.push(arg);
+        self
+    }
+    pub fn args(mut self, args: &[String]) -> Self {
+        self.args.extend_from_slice(args);
+        self
+    }
+    pub fn env(mut self, key: String, value: String) -> Self {
+        self.envs.push((key, value));
+        self
+    }
+    pub fn program(&self) -> &Path {
+        &self.program
+    }
+    pub fn resource_limits(&self) -> &ResourceLimits {
+        &self.limits
+    }
+    pub fn granted(&self) -> &[Resource] {
+        &self.grants
+    }
+}
+/// exit status and output of a native job
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NativeOutput {
+    pub status: ExitStatus,
+    pub stdout: Vec<u8>,
+    pub stderr: Vec<u8>,
+    /// only available when the job ran in a cgroup
+    #[cfg(target_os = "linux")]
+    pub usage: Option<CgroupUsage>,
+}
+impl NativeOutput {
+    /// cpu time and peak memory are only known when the job ran in a cgroup
+    fn accounted(&self, wall_time: Duration) -> Usage {
+        #[cfg(target_os = "linux")]
+        let (cpu_time, memory_peak) = match &self.usage {
+            Some(usage) => (Some(usage.cpu_usage), usage.memory_peak),
+            None => (None, None),
+        };
+        #[cfg(not(target_os = "linux"))]
+        let (cpu_time, memory_peak) = (None, None);
+        Usage {
+            cpu_time,
+            memory_peak,
+            wall_time,
+            bytes_in: 0,
+            bytes_out: (self.stdout.len() + self.stderr.len()) as u64,
+            fuel: None,
+        }
+    }
+}
+#[derive(Debug)]
+pub enum NativeError {
+    Sandbox(SandboxError),
+    #[cfg(target_os = "linux")]
+    Cgroup(CgroupError),
+    #[cfg(target_os = "linux")]
+    Confine(ConfineError),
+    Network(NetworkError),
+    Job(JobError),
+    Io(std::io::Error),
+}
+impl fmt::Display for NativeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Sandbox(e) => write!(f, "{}", e),
+            #[cfg(target_os = "linux")]
+            Self::Cgroup(e) => write!(f, "{}", e),
+            #[cfg(target_os = "linux")]
+            Self::Confine(e) => write!(f, "{}", e),
+            Self::Network(e) => write!(f, "{}", e),
+            Self::Job(e) => write!(f, "{}", e),
+            Self::Io(e) => write!(f, "{}", e),
+        }
+    }
+}
+impl Error for NativeError {}
+impl From<SandboxError> for NativeError {
+    fn from(error: SandboxError) -> Self {
+        NativeError::Sandbox(error)
+    }
+}
+#[cfg(target_os = "linux")]
+impl From<CgroupError> for NativeError {
+    fn from(error: CgroupError) -> Self {
+        NativeError::Cgroup(error)
+    }
+}
+#[cfg(target_os = "linux")]
+impl From<ConfineError> for NativeError {
+    fn from(error: ConfineError) -> Self {
+        NativeError::Confine(error)
+    }
+}
+impl From<NetworkError> for NativeError {
+    fn from(error: NetworkError) -> Self {
+        NativeError::Network(error)
+    }
+}
+impl From<JobError> for NativeError {
+    fn from(error: JobError) -> Self {
+        NativeError::Job(error)
+    }
+}
+impl From<std::io::Error> for NativeError {
+    fn from(error: std::io::Error) -> Self {
+        NativeError::Io(error)
+    }
 }
 pub struct NativeEnv {
     env: RemoteEnv,
+    sandbox: Option<Sandbox>,
+    #[cfg(target_os = "linux")]
+    cgroup: Option<CgroupRoot>,
+    confine: bool,
+    jobs: JobTable,
+}
+/// cgroup a job runs in, if any, usage is read back from it once the job exited
+struct Accounting {
+    #[cfg(target_os = "linux")]
+    leaf: Option<cgroup::Cgroup>,
 }
-impl NativeEnv {}
+impl Accounting {
+    #[cfg(target_os = "linux")]
+    fn finish(self, output: Output) -> Result<NativeOutput, NativeError> {
+        let usage = match &self.leaf {
+            Some(leaf) => Some(leaf.usage()?),
+            None => None,
+        };
+        Ok(NativeOutput {
+            status: output.status,
+            stdout: output.stdout,
+            stderr: output.stderr,
+            usage,
+        })
+    }
+    #[cfg(not(target_os = "linux"))]
+    fn finish(self, output: Output) -> Result<NativeOutput, NativeError> {
+        Ok(NativeOutput {
+            status: output.status,
+            stdout: output.stdout,
+            stderr: output.stderr,
+        })
+    }
+}
+impl NativeEnv {
+    pub fn init(env_type: EnvType) -> Result<Self, NetworkError> {
+        Ok(Self::new(RemoteEnv::init(env_type)?))
+    }
+    /// jobs run without a sandbox until one is set
+    pub fn new(env: RemoteEnv) -> Self {
+        Self {
+            env,
+            sandbox: None,
+            #[cfg(target_os = "linux")]
+            cgroup: None,
+            confine: false,
+            jobs: JobTable::new(),
+        }
+    }
+    /// run every job inside of a fresh set of namespaces, see runtime::sandbox
+    pub fn sandbox(mut self, sandbox: Sandbox) -> Self {
+        self.sandbox = Some(sandbox);
+        self
+    }
+    pub fn sandbox_config(&self) -> &Option<Sandbox> {
+        &self.sandbox
+    }
+    /// enforce the resources granted to each job with landlock and seccomp
+    /// granted paths are also mounted into the sandbox, and a Network grant shares the host network
+    pub fn confine(mut self, confine: bool) -> Self {
+        self.confine = confine;
+        self
+    }
+    /// build the command for a job, the host environment is not inherited
+    /// fails with SandboxError::Unsupported if a sandbox is set but namespaces are not available
+    pub fn command(&self, job: &NativeJob) -> Result<Command, NativeError> {
+        let mut command = Command::new(&job.program);
+        self.configure(&mut command, job)?;
+        Ok(command)
+    }
+    fn configure(&self, command: &mut Command, job: &NativeJob) -> Result<(), NativeError> {
+        use std::os::unix::process::CommandExt;
+        // own process group, so that cancelling a job also kills everything it started
+        command
+            .process_group(0)
+            .args(&job.args)
+            .env_clear()
+            .envs(job.envs.iter().map(|(k, v)| (k, v)))
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped());
+        if !self.confine {
+            if let Some(sandbox) = &self.sandbox {
+                sandbox.apply(command)?;
+            }
+            return Ok(());
+        }
+        self.confined(command, job)
+    }
+    /// sandbox (if any) has to be entered before the landlock rules are built
+    #[cfg(target_os = "linux")]
+    fn confined(&self, command: &mut Command, job: &NativeJob) -> Result<(), NativeError> {
+        let mut confinement = Confinement::new(&job.grants);
+        let system = match &self.sandbox {
+            Some(sandbox) => sandbox.read_only_paths().to_vec(),
+            None => sandbox::DEFAULT_READ_ONLY.iter().map(PathBuf::from).collect(),
+        };
+        for path in system.iter() {
+            confinement = confinement.allow(path, Access::READ_EXECUTE);
+        }
+        for device in sandbox::DEVICES.iter() {
+            confinement = confinement.allow(device, Access::READ_WRITE);
+        }
+        if let Some(sandbox) = &self.sandbox {
+            let mut sandbox = sandbox.clone().share_network(confinement.network());
+            for grant in job.grants.iter() {
+                sandbox = match grant.path() {
+                    Some(path) if grant.writable() => sandbox.read_write(path),
+                    Some(path) => sandbox.read_only(path),
+                    None => sandbox,
+                };
+            }
+            sandbox.apply(command)?;
+            // both are private to the sandbox
+            confinement = confinement
+                .allow("/tmp", Access::READ_WRITE)
+                .allow("/proc", Access::READ);
+        }
+        confinement.apply(command)?;
+        Ok(())
+    }
+    #[cfg(not(target_os = "linux"))]
+    fn confined(&self, _command: &mut Command, _job: &NativeJob) -> Result<(), NativeError> {
+        Err(SandboxError::Unsupported(String::from("landlock is only available on linux")).into())
+    }
+    /// place every job in its own leaf under root, limited by the job's ResourceLimits
+    #[cfg(target_os = "linux")]
+    pub fn cgroup(mut self, root: CgroupRoot) -> Self {
+        self.cgroup = Some(root);
+        self
+    }
+    fn prepare(&self, job: &NativeJob) -> Result<(Command, Accounting), NativeError> {
+        let mut command = Command::new(&job.program);
+        #[cfg(target_os = "linux")]
+        let leaf = match &self.cgroup {
+            Some(root) => Some(root.leaf(&job.limits)?),
+            None => None,
+        };
+        // the job has to join the cgroup before it enters the sandbox
+        #[cfg(target_os = "linux")]
+        {
+            if let Some(leaf) = &leaf {
+                leaf.attach(&mut command)?;
+            }
+        }
+        self.configure(&mut command, job)?;
+        let accounting = Accounting {
+            #[cfg(target_os = "linux")]
+            leaf,
+        };
+        Ok((command, accounting))
+    }
+    /// run a job to completion, blocking the current thread
+    pub fn run(&self, job: &NativeJob) -> Result<NativeOutput, NativeError> {
+        let (mut command, accounting) = self.prepare(job)?;
+        let output = command.output()?;
+        accounting.finish(output)
+    }
+    async fn spawn(
+        &self,
+        id: JobId,
+        job: &NativeJob,
+        token: &CancelToken,
+    ) -> Result<JobResult<NativeOutput>, NativeError> {
+        let (command, accounting) = self.prepare(job)?;
+        let started = Instant::now();
+        let child = tokio::process::Command::from(command)
+            .kill_on_drop(true)
+            .spawn()?;
+        let pid = child.id();
+        self.jobs.set_pid(id, pid);
+        // cancel could have run before the pid was known
+        if token.is_stopped() {
+            kill_group(pid);
+        }
+        // cancel kills the process group itself, so only the deadline has to be waited on
+        let mut wait = Box::pin(child.wait_with_output());
+        let output = match token.deadline() {
+            Some(deadline) => {
+                let deadline = tokio::time::Instant::from_std(deadline);
+                match tokio::time::timeout_at(deadline, &mut wait).await {
+                    Ok(output) => output?,
+                    Err(_) => {
+                        token.expire();
+                        kill_group(pid);
+                        wait.await?
+                    }
+                }
+            }
+            None => wait.await?,
+        };
+        let outcome = match token.is_stopped() {
+            true => token.outcome(),
+            false => JobOutcome::Completed,
+        };
+        let output = accounting.finish(output)?;
+        let mut usage = output.accounted(started.elapsed());
+        // stdin is closed, so arguments and environment are all the input a job gets
+        usage.bytes_in = job.args.iter().map(String::len).sum::<usize>() as u64
+            + job.envs.iter().map(|(k, v)| k.len() + v.len()).sum::<usize>() as u64;
+        Ok(JobResult {
+            id,
+            outcome,
+            output: Some(output),
+            usage,
+        })
+    }
+}
+/// the job leads its own process group, see NativeEnv::configure
+fn kill_group(pid: u32) {
+    unsafe { libc::killpg(pid as libc::pid_t, libc::SIGKILL) };
+}
+#[async_trait]
 impl ExecEnv for NativeEnv {
-    type Error = NetworkError;
+    type Error = NativeError;
+    type Job = NativeJob;
+    type Output = NativeOutput;
+    /// output of a job that was stopped holds what it wrote until it was killed
+    async fn execute(
+        &self,
+        id: JobId,
+        job: NativeJob,
+        deadline: Option<Instant>,
+    ) -> Result<JobResult<NativeOutput>, NativeError> {
+        let token = self.jobs.start(id, deadline)?;
+        let result = self.spawn(id, &job, &token).await;
+        let status = match &result {
+            Ok(result) if !result.is_completed() => result.outcome.into(),
+            Ok(JobResult {
+                output: Some(output),
+                ..
+            }) if output.status.success() => JobStatus::Completed,
+            _ => JobStatus::Failed,
+        };
+        self.jobs.finish(id, status);
+        result
+    }
+    /// kills the process group of a running job
+    fn cancel(&self, id: JobId) -> Result<(), NativeError> {
+        if let Some(pid) = self.jobs.cancel(id)? {
+            kill_group(pid);
+        }
+        Ok(())
+    }
+    fn status(&self, id: JobId) -> JobStatus {
+        self.jobs.status(id)
+    }
 }
 impl EnvData for NativeEnv {
     fn trusted(&self) -> bool {
         self.env.trusted()
     }
+    fn env_type(&self) -> &EnvType {
+        self.env.env_type()
+    }
     fn os_name(&self) -> &str {
         self.env.os_name()
     }
@@ -83,6 +684,8 @@ impl EnvData for NativeEnv {
 /// contains definitions for various data sizes
 #[derive(Debug, Display, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
 pub enum Unit {
+    /// bytes
+    B,
     /// kibibytes
     KiB,
     /// membibytes
@@ -108,6 +711,7 @@ impl FromStr for Unit {
     type Err = UnitErr;
     fn from_str(s: &str) -> Result<Self, Self::Err>{
         Ok(match s {
+            "B" | "bytes" => Unit::B,
             "KiB" => Unit::KiB,
             "MiB" => Unit::MiB,
             "GiB" => Unit::GiB,
@@ -122,8 +726,315 @@ impl FromStr for Unit {
         })
     }
 }
+impl Unit {
+    /// binary units from largest to smallest, B is in both lists
+    pub const BINARY: [Unit; 6] = [Unit::PiB, Unit::TiB, Unit::GiB, Unit::MiB, Unit::KiB, Unit::B];
+    /// decimal units from largest to smallest
+    pub const DECIMAL: [Unit; 6] = [Unit::PB, Unit::TB, Unit::GB, Unit::MB, Unit::KB, Unit::B];
+    /// number of bytes in one of this unit
+    pub fn bytes(&self) -> u64 {
+        match self {
+            Unit::B => 1,
+            Unit::KiB => 1 << 10,
+            Unit::MiB => 1 << 20,
+            Unit::GiB => 1 << 30,
+            Unit::TiB => 1 << 40,
+            Unit::PiB => 1 << 50,
+            Unit::KB => 1_000,
+            Unit::MB => 1_000_000,
+            Unit::GB => 1_000_000_000,
+            Unit::TB => 1_000_000_000_000,
+            Unit::PB => 1_000_000_000_000_000,
+        }
+    }
+    pub fn is_decimal(&self) -> bool {
+        matches!(self, Unit::KB | Unit::MB | Unit::GB | Unit::TB | Unit::PB)
+    }
+}
 #[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Display)]
 pub enum UnitErr{
     UnknownFormat,
+    /// the number in front of the unit isn't a whole number
+    InvalidNumber,
+    /// doesn't fit in a u64 number of bytes
+    Overflow,
+    /// can't be expressed as a whole number of the requested unit
+    Inexact,
+}
+impl Error for UnitErr {}
+
+/// an amount of data, such as "4 GiB", compared and added by its exact number of bytes
+/// so 1 KiB == 1024 B, the unit is kept so that values can be shown as they were given
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct Quantity {
+    value: u64,
+    unit: Unit,
+}
+impl Quantity {
+    pub fn new(value: u64, unit: Unit) -> Self {
+        Self { value, unit }
+    }
+    /// uses the largest binary unit that holds bytes exactly, 4294967296 becomes 4 GiB
+    pub fn from_bytes(bytes: u64) -> Self {
+        for unit in Unit::BINARY.iter() {
+            if bytes.is_multiple_of(unit.bytes()) {
+                return Self::new(bytes / unit.bytes(), *unit);
+            }
+        }
+        Self::new(bytes, Unit::B)
+    }
+    pub fn value(&self) -> u64 {
+        self.value
+    }
+    pub fn unit(&self) -> Unit {
+        self.unit
+    }
+    /// never overflows, even for values that don't fit in a u64 number of bytes
+    fn exact(&self) -> u128 {
+        u128::from(self.value) * u128::from(self.unit.bytes())
+    }
+    pub fn to_bytes(&self) -> Result<u64, UnitErr> {
+        self.value
+            .checked_mul(self.unit.bytes())
+            .ok_or(UnitErr::Overflow)
+    }
+    /// fails with UnitErr::Inexact if the quantity isn't a whole number of unit, 1536 KiB in MiB for example
+    pub fn to_unit(&self, unit: Unit) -> Result<Self, UnitErr> {
+        let bytes = self.exact();
+        let per_unit = u128::from(unit.bytes());
+        if !bytes.is_multiple_of(per_unit) {
+            return Err(UnitErr::Inexact);
+        }
+        match (bytes / per_unit).try_into() {
+            Ok(value) => Ok(Self::new(value, unit)),
+            Err(_) => Err(UnitErr::Overflow),
+        }
+    }
+    /// the sum is expressed in the smaller of the two units, or in bytes when mixing
+    /// binary and decimal units leaves a remainder, so it stays exact
+    pub fn checked_add(self, other: Self) -> Result<Self, UnitErr> {
+        let sum = self.exact() + other.exact();
+        let unit = match self.unit.bytes() < other.unit.bytes() {
+            true => self.unit,
+            false => other.unit,
+        };
+        let unit = match sum.is_multiple_of(u128::from(unit.bytes())) {
+            true => unit,
+            false => Unit::B,
+        };
+        match (sum / u128::from(unit.bytes())).try_into() {
+            Ok(value) => Ok(Self::new(value, unit)),
+            Err(_) => Err(UnitErr::Overflow),
+        }
+    }
+}
+impl From<u64> for Quantity {
+    fn from(bytes: u64) -> Self {
+        Self::new(bytes, Unit::B)
+    }
+}
+impl std::ops::Add for Quantity {
+    type Output = Quantity;
+    /// panics if the sum doesn't fit, like integer addition, see checked_add
+    fn add(self, other: Self) -> Self {
+        self.checked_add(other).expect("quantity overflow")
+    }
+}
+impl PartialEq for Quantity {
+    fn eq(&self, other: &Self) -> bool {
+        self.exact() == other.exact()
+    }
+}
+impl Eq for Quantity {}
+impl PartialOrd for Quantity {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+impl Ord for Quantity {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        self.exact().cmp(&other.exact())
+    }
+}
+impl std::hash::Hash for Quantity {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        self.exact().hash(state);
+    }
+}
+/// accepts "4 GiB", "512MB" and plain byte counts such as "1024"
+impl FromStr for Quantity {
+    type Err = UnitErr;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let split = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+        let (value, unit) = s.split_at(split);
+        if value.is_empty() {
+            return Err(UnitErr::InvalidNumber);
+        }
+        let unit = match unit.trim() {
+            "" => Unit::B,
+            unit if unit.starts_with(|c: char| c.is_ascii_digit() || c == '.' || c == ',') => {
+                return Err(UnitErr::InvalidNumber)
+            }
+            unit => unit.parse()?,
+        };
+        match value.parse() {
+            Ok(value) => Ok(Self::new(value, unit)),
+            Err(_) => Err(UnitErr::Overflow),
+        }
+    }
+}
+/// uses the largest unit of the same kind (binary or decimal) that the quantity is at least one of,
+/// with up to two decimals, 1536 MiB is shown as "1.5 GiB"
+impl fmt::Display for Quantity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let units = match self.unit.is_decimal() {
+            true => &Unit::DECIMAL,
+            false => &Unit::BINARY,
+        };
+        let bytes = self.exact();
+        let unit = units
+            .iter()
+            .find(|unit| bytes >= u128::from(unit.bytes()))
+            .unwrap_or(&Unit::B);
+        let per_unit = u128::from(unit.bytes());
+        let hundredths = bytes * 100 / per_unit;
+        let (whole, fraction) = (hundredths / 100, hundredths % 100);
+        match fraction {
+            0 => write!(f, "{} {}", whole, unit),
+            fraction if fraction.is_multiple_of(10) => write!(f, "{}.{} {}", whole, fraction / 10, unit),
+            fraction => write!(f, "{}.{:02} {}", whole, fraction, unit),
+        }
+    }
+}
+
+#[test]
+fn quantity() {
+    let four: Quantity = "4 GiB".parse().unwrap();
+    assert_eq!(four.to_bytes(), Ok(4 << 30));
+    assert_eq!(Quantity::from_bytes(4 << 30), four);
+    assert_eq!("512MB".parse::<Quantity>().unwrap().to_bytes(), Ok(512_000_000));
+    assert_eq!("1024".parse::<Quantity>().unwrap(), Quantity::new(1, Unit::KiB));
+    assert_eq!("1.5 GiB".parse::<Quantity>(), Err(UnitErr::InvalidNumber));
+    assert_eq!("4 GB ".parse::<Quantity>().unwrap().unit(), Unit::GB);
+    assert_eq!("4 gallons".parse::<Quantity>(), Err(UnitErr::UnknownFormat));
+
+    assert!(Quantity::new(1, Unit::GB) < Quantity::new(1, Unit::GiB));
+    assert!(Quantity::new(1000, Unit::MiB) < four);
+    let sum = Quantity::new(1, Unit::GiB) + Quantity::new(512, Unit::MiB);
+    assert_eq!(sum.unit(), Unit::MiB);
+    assert_eq!(sum.value(), 1536);
+    assert_eq!(sum.to_unit(Unit::GiB), Err(UnitErr::Inexact));
+    assert_eq!(sum.to_unit(Unit::KiB).unwrap().value(), 1536 * 1024);
+    let mixed = Quantity::new(1, Unit::KiB) + Quantity::new(1, Unit::KB);
+    assert_eq!(mixed.unit(), Unit::B);
+    assert_eq!(mixed.value(), 2024);
+    assert_eq!((Quantity::new(1, Unit::MB) + Quantity::new(3, Unit::KiB)).unit(), Unit::B);
+    assert_eq!(
+        Quantity::new(u64::MAX, Unit::B).checked_add(Quantity::new(1, Unit::B)),
+        Err(UnitErr::Overflow)
+    );
+    assert_eq!(Quantity::new(16384, Unit::PiB).to_bytes(), Err(UnitErr::Overflow));
+
+    assert_eq!(sum.to_string(), "1.5 GiB");
+    assert_eq!(Quantity::new(1234, Unit::MB).to_string(), "1.23 GB");
+    assert_eq!(Quantity::from(1023).to_string(), "1023 B");
+    assert_eq!(Quantity::new(0, Unit::TiB).to_string(), "0 B");
+}
+/// single threaded runtime with io and timers, shared by the tests of every module
+#[cfg(test)]
+pub(crate) fn test_runtime() -> tokio::runtime::Runtime {
+    tokio::runtime::Builder::new()
+        .basic_scheduler()
+        .enable_all()
+        .build()
+        .unwrap()
+}
+#[test]
+fn virtual_stops() {
+    use ferrisvm::opcode::Opcode;
+    let code = Opcode::encode(&[Opcode::Jump(0)]);
+    let env = VirtualEnv::new(RemoteEnv::init(EnvType::Inherit).unwrap())
+        .load(&code, &[0; 16])
+        .unwrap();
+    let mut runtime = test_runtime();
+    let deadline = Instant::now() + Duration::from_millis(50);
+    let result = runtime
+        .block_on(env.execute(JobId::new(0), VirtualJob::new(Vec::new()), Some(deadline)))
+        .unwrap();
+    assert_eq!(result.outcome, JobOutcome::TimedOut);
+    assert_eq!(env.status(JobId::new(0)), JobStatus::TimedOut);
+
+    // the loop never halts, so only the token can stop it between instructions
+    let id = JobId::new(1);
+    let result = std::thread::scope(|scope| {
+        scope.spawn(|| {
+            while env.status(id) != JobStatus::Running {
+                std::thread::yield_now();
+            }
+            std::thread::sleep(Duration::from_millis(20));
+            env.cancel(id).unwrap();
+        });
+        runtime.block_on(env.execute(id, VirtualJob::new(Vec::new()), None))
+    })
+    .unwrap();
+    assert_eq!(result.outcome, JobOutcome::Cancelled);
+    assert!(result.usage.fuel.unwrap() > 0);
+    assert_eq!(env.status(id), JobStatus::Cancelled);
+}
+#[test]
+fn native_deadline() {
+    let env = NativeEnv::new(RemoteEnv::init(EnvType::Inherit).unwrap());
+    let job = NativeJob::new("/bin/sleep").arg(String::from("30"));
+    let started = Instant::now();
+    let deadline = started + Duration::from_millis(100);
+    let result = test_runtime()
+        .block_on(env.execute(JobId::new(0), job, Some(deadline)))
+        .unwrap();
+    assert_eq!(result.outcome, JobOutcome::TimedOut);
+    assert!(started.elapsed() < Duration::from_secs(10));
+    assert_eq!(env.status(JobId::new(0)), JobStatus::TimedOut);
+}
+#[test]
+fn native_group_kill() {
+    // true while the process exists and hasn't exited, a killed orphan can linger as a zombie
+    fn alive(pid: &str) -> bool {
+        match std::fs::read_to_string(format!("/proc/{}/stat", pid)) {
+            Ok(stat) => !stat.rsplit(')').next().unwrap().trim_start().starts_with('Z'),
+            Err(_) => false,
+        }
+    }
+    let env = NativeEnv::new(RemoteEnv::init(EnvType::Inherit).unwrap());
+    // the shell forks a grandchild, then waits on it
+    let job = || {
+        NativeJob::new("/bin/sh")
+            .arg(String::from("-c"))
+            .arg(String::from("/bin/sleep 30 & echo $!; wait"))
+    };
+    let mut runtime = test_runtime();
+    let deadline = Instant::now() + Duration::from_millis(200);
+    let result = runtime
+        .block_on(env.execute(JobId::new(0), job(), Some(deadline)))
+        .unwrap();
+    assert_eq!(result.outcome, JobOutcome::TimedOut);
+    let stdout = String::from_utf8(result.output.unwrap().stdout).unwrap();
+    assert!(!alive(stdout.trim()));
+
+    let id = JobId::new(1);
+    let started = Instant::now();
+    let result = std::thread::scope(|scope| {
+        scope.spawn(|| {
+            while env.status(id) != JobStatus::Running {
+                std::thread::yield_now();
+            }
+            std::thread::sleep(Duration::from_millis(200));
+            env.cancel(id).unwrap();
+        });
+        runtime.block_on(env.execute(id, job(), None))
+    })
+    .unwrap();
+    assert_eq!(result.outcome, JobOutcome::Cancelled);
+    assert!(started.elapsed() < Duration::from_secs(10));
+    let stdout = String::from_utf8(result.output.unwrap().stdout).unwrap();
+    assert!(!alive(stdout.trim()));
 }
-impl Error for UnitErr {}
\ No newline at end of file
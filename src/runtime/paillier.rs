@@ -0,0 +1,340 @@
+/*
+// MeSHE execution using the paillier cryptosystem
+// the submitter keeps the decryption key, and only sends the encryption key along with encrypted vectors
+// ciphertexts can be added together and multiplied by plaintext constants, which is enough for the peer
+// to evaluate sums, weighted sums and scalar products without learning the inputs or the result
+*/
+use crate::job::{CancelToken, JobError, JobId, JobResult, JobStatus, JobTable};
+use crate::ledger::Usage;
+use crate::runtime::thread_cpu_time;
+use crate::{EnvData, EnvType, ExecEnv, RemoteEnv};
+use ::paillier::{
+    Add, Decrypt, DecryptionKey, EncodedCiphertext, Encrypt, EncryptionKey, KeyGeneration, Mul,
+    Paillier,
+};
+use async_trait::async_trait;
+use networking::NetworkError;
+use std::error::Error;
+use std::fmt;
+use std::time::Instant;
+
+/// vector encrypted element by element, so that elements can be combined independently
+/// values are u64, results are only meaningful while they don't overflow a u64
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EncryptedVector {
+    values: Vec<EncodedCiphertext<u64>>,
+}
+impl EncryptedVector {
+    pub fn len(&self) -> usize {
+        self.values.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.values.is_empty()
+    }
+}
+/// linear computations a peer can evaluate on ciphertexts
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum LinearOp {
+    /// element wise sum of every input
+    Sum,
+    /// element wise sum of every input multiplied by its weight, one weight per input
+    WeightedSum(Vec<u64>),
+    /// scalar product of each input with a plaintext vector, one value per input
+    ScalarProduct(Vec<u64>),
+}
+/// everything the peer needs to evaluate a computation, but not to decrypt it
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PaillierJob {
+    key: EncryptionKey,
+    inputs: Vec<EncryptedVector>,
+    op: LinearOp,
+}
+impl PaillierJob {
+    pub fn op(&self) -> &LinearOp {
+        &self.op
+    }
+    pub fn inputs(&self) -> &[EncryptedVector] {
+        &self.inputs
+    }
+}
+/// key pair of the submitter, the decryption key never leaves this struct
+pub struct PaillierKeys {
+    encryption: EncryptionKey,
+    decryption: DecryptionKey,
+}
+impl PaillierKeys {
+    /// generating a key takes a while, the keys should be reused for many jobs
+    pub fn generate() -> Self {
+        let (encryption, decryption) = Paillier::keypair().keys();
+        Self {
+            encryption,
+            decryption,
+        }
+    }
+    pub fn encryption_key(&self) -> &EncryptionKey {
+        &self.encryption
+    }
+    pub fn encrypt(&self, values: &[u64]) -> EncryptedVector {
+        EncryptedVector {
+            values: values
+                .iter()
+                .map(|value| Paillier::encrypt(&self.encryption, *value))
+                .collect(),
+        }
+    }
+    pub fn decrypt(&self, vector: &EncryptedVector) -> Vec<u64> {
+        vector
+            .values
+            .iter()
+            .map(|value| Paillier::decrypt(&self.decryption, value))
+            .collect()
+    }
+    pub fn job(&self, inputs: Vec<EncryptedVector>, op: LinearOp) -> PaillierJob {
+        PaillierJob {
+            key: self.encryption.clone(),
+            inputs,
+            op,
+        }
+    }
+}
+#[derive(Debug)]
+pub enum PaillierError {
+    /// inputs or operands of a job don't have matching lengths
+    Shape(String),
+    Network(NetworkError),
+    Job(JobError),
+    Io(std::io::Error),
+}
+impl fmt::Display for PaillierError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Shape(reason) => write!(f, "invalid paillier job: {}", reason),
+            Self::Network(e) => write!(f, "{}", e),
+            Self::Job(e) => write!(f, "{}", e),
+            Self::Io(e) => write!(f, "{}", e),
+        }
+    }
+}
+impl Error for PaillierError {}
+impl From<NetworkError> for PaillierError {
+    fn from(error: NetworkError) -> Self {
+        PaillierError::Network(error)
+    }
+}
+impl From<JobError> for PaillierError {
+    fn from(error: JobError) -> Self {
+        PaillierError::Job(error)
+    }
+}
+
+/// evaluates LinearOps for other peers, never holds a decryption key
+pub struct PaillierEnv {
+    env: RemoteEnv,
+    jobs: JobTable,
+}
+impl PaillierEnv {
+    pub fn init() -> Result<Self, NetworkError> {
+        Ok(Self::new(RemoteEnv::init(EnvType::Paillier)?))
+    }
+    pub fn new(env: RemoteEnv) -> Self {
+        Self {
+            env,
+            jobs: JobTable::new(),
+        }
+    }
+}
+impl EnvData for PaillierEnv {
+    fn trusted(&self) -> bool {
+        self.env.trusted()
+    }
+    fn env_type(&self) -> &EnvType {
+        self.env.env_type()
+    }
+    fn os_name(&self) -> &str {
+        self.env.os_name()
+    }
+    fn arch_name(&self) -> &str {
+        self.env.arch_name()
+    }
+    fn total_mem(&self) -> u64 {
+        self.env.total_mem()
+    }
+    fn cpu_count(&self) -> u16 {
+        self.env.cpu_count()
+    }
+    fn cpu_speed(&self) -> u16 {
+        self.env.cpu_speed()
+    }
+}
+#[async_trait]
+impl ExecEnv for PaillierEnv {
+    type Error = PaillierError;
+    type Job = PaillierJob;
+    type Output = EncryptedVector;
+    /// a partial result is useless to the submitter, so stopped jobs have no output
+    async fn execute(
+        &self,
+        id: JobId,
+        job: PaillierJob,
+        deadline: Option<Instant>,
+    ) -> Result<JobResult<EncryptedVector>, PaillierError> {
+        let token = self.jobs.start(id, deadline)?;
+        let stop = token.clone();
+        let started = Instant::now();
+        // every operation is a big integer exponentiation, too slow to run on the executor
+        let result = tokio::task::spawn_blocking(move || {
+            let cpu = thread_cpu_time();
+            let output = evaluate(&job, &stop)?;
+            let usage = Usage {
+                cpu_time: Some(thread_cpu_time() - cpu),
+                bytes_in: serialized_len(&job.inputs),
+                bytes_out: output.as_ref().map_or(0, serialized_len),
+                ..Usage::default()
+            };
+            Ok((output, usage))
+        })
+        .await;
+        let result = match result {
+            Ok(result) => result,
+            Err(e) => Err(PaillierError::Io(std::io::Error::other(e))),
+        };
+        let result = match result {
+            Ok((output, mut usage)) => {
+                usage.wall_time = started.elapsed();
+                match output {
+                    Some(output) => Ok(JobResult::completed(id, output, usage)),
+                    None => Ok(JobResult {
+                        id,
+                        outcome: token.outcome(),
+                        output: None,
+                        usage,
+                    }),
+                }
+            }
+            Err(e) => Err(e),
+        };
+        match &result {
+            Ok(result) => self.jobs.finish(id, result.outcome.into()),
+            Err(_) => self.jobs.finish(id, JobStatus::Failed),
+        }
+        result
+    }
+    fn cancel(&self, id: JobId) -> Result<(), PaillierError> {
+        self.jobs.cancel(id)?;
+        Ok(())
+    }
+    fn status(&self, id: JobId) -> JobStatus {
+        self.jobs.status(id)
+    }
+}
+
+/// size of the value when it is sent to a peer
+fn serialized_len<T: serde::Serialize>(value: &T) -> u64 {
+    serde_json::to_vec(value).map_or(0, |bytes| bytes.len() as u64)
+}
+/// returns None once the token stops
+fn evaluate(
+    job: &PaillierJob,
+    token: &CancelToken,
+) -> Result<Option<EncryptedVector>, PaillierError> {
+    let first = match job.inputs.first() {
+        Some(first) => first,
+        None => return Err(PaillierError::Shape(String::from("no inputs"))),
+    };
+    let mut values = Vec::new();
+    match &job.op {
+        LinearOp::Sum | LinearOp::WeightedSum(_) => {
+            let weights = match &job.op {
+                LinearOp::WeightedSum(weights) => weights.clone(),
+                _ => vec![1; job.inputs.len()],
+            };
+            if weights.len() != job.inputs.len() {
+                return Err(PaillierError::Shape(format!(
+                    "{} weights for {} inputs",
+                    weights.len(),
+                    job.inputs.len()
+                )));
+            }
+            if job.inputs.iter().any(|input| input.len() != first.len()) {
+                return Err(PaillierError::Shape(String::from(
+                    "inputs have different lengths",
+                )));
+            }
+            for index in 0..first.len() {
+                let terms = job.inputs.iter().map(|input| &input.values[index]);
+                match weighted(&job.key, terms.zip(weights.iter()), token) {
+                    Some(value) => values.push(value),
+                    None => return Ok(None),
+                }
+            }
+        }
+        LinearOp::ScalarProduct(plain) => {
+            if plain.is_empty() || job.inputs.iter().any(|input| input.len() != plain.len()) {
+                return Err(PaillierError::Shape(format!(
+                    "every input needs {} values",
+                    plain.len()
+                )));
+            }
+            for input in job.inputs.iter() {
+                match weighted(&job.key, input.values.iter().zip(plain.iter()), token) {
+                    Some(value) => values.push(value),
+                    None => return Ok(None),
+                }
+            }
+        }
+    }
+    Ok(Some(EncryptedVector { values }))
+}
+/// sum of each ciphertext multiplied by its weight, terms can't be empty
+fn weighted<'a, I: Iterator<Item = (&'a EncodedCiphertext<u64>, &'a u64)>>(
+    key: &EncryptionKey,
+    terms: I,
+    token: &CancelToken,
+) -> Option<EncodedCiphertext<u64>> {
+    let mut sum: Option<EncodedCiphertext<u64>> = None;
+    for (value, weight) in terms {
+        if token.stopped().is_some() {
+            return None;
+        }
+        let term = Paillier::mul(key, value, *weight);
+        sum = Some(match sum {
+            Some(sum) => Paillier::add(key, &sum, &term),
+            None => term,
+        });
+    }
+    sum
+}
+
+#[test]
+fn loopback() {
+    let keys = PaillierKeys::generate();
+    let env = PaillierEnv::init().unwrap();
+    let inputs = vec![keys.encrypt(&[1, 2, 3]), keys.encrypt(&[10, 20, 30])];
+    let mut runtime = crate::runtime::test_runtime();
+    let mut run = |op: LinearOp| {
+        // the job and result go through serde, as they would when sent to a peer
+        let job = serde_json::to_string(&keys.job(inputs.clone(), op)).unwrap();
+        let job: PaillierJob = serde_json::from_str(&job).unwrap();
+        let result = runtime
+            .block_on(env.execute(JobId::new(0), job, None))
+            .unwrap();
+        assert!(result.is_completed());
+        let output = serde_json::to_string(&result.output.unwrap()).unwrap();
+        keys.decrypt(&serde_json::from_str(&output).unwrap())
+    };
+    assert_eq!(run(LinearOp::Sum), vec![11, 22, 33]);
+    assert_eq!(run(LinearOp::WeightedSum(vec![3, 2])), vec![23, 46, 69]);
+    assert_eq!(run(LinearOp::ScalarProduct(vec![1, 0, 2])), vec![7, 70]);
+
+    let job = keys.job(inputs.clone(), LinearOp::WeightedSum(vec![1]));
+    let result = runtime.block_on(env.execute(JobId::new(1), job, None));
+    assert!(matches!(result, Err(PaillierError::Shape(_))));
+    assert_eq!(env.status(JobId::new(1)), JobStatus::Failed);
+
+    let job = keys.job(inputs, LinearOp::Sum);
+    let result = runtime
+        .block_on(env.execute(JobId::new(2), job, Some(Instant::now())))
+        .unwrap();
+    assert_eq!(result.outcome, crate::job::JobOutcome::TimedOut);
+    assert!(result.output.is_none());
+}
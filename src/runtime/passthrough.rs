@@ -0,0 +1,342 @@
+/*
+// keeps the parts of a libvirt definition that kvm.rs doesn't model
+// the typed elements are written back out, parsed again as a generic tree and compared with the original,
+// whatever only the original has is kept in a Passthrough, and merged back in when the config is written
+// elements are matched by their tag and their position among siblings with the same tag
+*/
+use std::collections::HashMap;
+use strong_xml::utils::xml_unescape;
+use strong_xml::xmlparser::{ElementEnd, Token};
+use strong_xml::{XmlError, XmlRead, XmlReader, XmlResult, XmlWrite, XmlWriter};
+
+/// tag of the field Passthrough is stored in, it isn't a valid xml name so no element is ever read into it
+pub const PASSTHROUGH_TAG: &str = "#passthrough";
+
+/// element of a generic xml tree
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct XmlNode {
+    pub tag: String,
+    pub attributes: Vec<(String, String)>,
+    pub children: Vec<XmlContent>,
+}
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum XmlContent {
+    Element(XmlNode),
+    Text(String),
+}
+impl XmlNode {
+    pub fn new(tag: &str) -> Self {
+        Self {
+            tag: tag.to_string(),
+            attributes: Vec::new(),
+            children: Vec::new(),
+        }
+    }
+    /// parses the root element of xml, comments and whitespace between elements are dropped
+    pub fn parse(xml: &str) -> XmlResult<Self> {
+        let mut reader = XmlReader::new(xml);
+        let mut open: Vec<XmlNode> = Vec::new();
+        while let Some(token) = reader.next() {
+            match token? {
+                Token::ElementStart { prefix, local, .. } => {
+                    open.push(XmlNode::new(&qualified(prefix.as_str(), local.as_str())));
+                }
+                Token::Attribute {
+                    prefix,
+                    local,
+                    value,
+                    ..
+                } => {
+                    let node = open.last_mut().ok_or(XmlError::UnexpectedEof)?;
+                    let value = xml_unescape(value.as_str())?.into_owned();
+                    node.attributes
+                        .push((qualified(prefix.as_str(), local.as_str()), value));
+                }
+                Token::ElementEnd {
+                    end: ElementEnd::Open,
+                    ..
+                } => (),
+                Token::ElementEnd { end, .. } => {
+                    let node = open.pop().ok_or(XmlError::UnexpectedEof)?;
+                    if let ElementEnd::Close(prefix, local) = end {
+                        let tag = qualified(prefix.as_str(), local.as_str());
+                        if tag != node.tag {
+                            return Err(XmlError::TagMismatch {
+                                expected: node.tag,
+                                found: tag,
+                            });
+                        }
+                    }
+                    match open.last_mut() {
+                        Some(parent) => parent.children.push(XmlContent::Element(node)),
+                        None => return Ok(node),
+                    }
+                }
+                Token::Text { text } if !text.as_str().trim().is_empty() => {
+                    if let Some(node) = open.last_mut() {
+                        let text = xml_unescape(text.as_str())?.into_owned();
+                        node.children.push(XmlContent::Text(text));
+                    }
+                }
+                Token::Cdata { text, .. } => {
+                    if let Some(node) = open.last_mut() {
+                        node.children
+                            .push(XmlContent::Text(text.as_str().to_string()));
+                    }
+                }
+                _ => (),
+            }
+        }
+        Err(XmlError::UnexpectedEof)
+    }
+    pub fn attribute(&self, name: &str) -> Option<&str> {
+        self.attributes
+            .iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.as_str())
+    }
+    /// child elements, without text
+    pub fn elements(&self) -> impl Iterator<Item = &XmlNode> {
+        self.children.iter().filter_map(|child| match child {
+            XmlContent::Element(element) => Some(element),
+            XmlContent::Text(_) => None,
+        })
+    }
+    fn has_text(&self) -> bool {
+        self.children
+            .iter()
+            .any(|child| matches!(child, XmlContent::Text(_)))
+    }
+    /// nth child element with tag
+    fn child_mut(&mut self, tag: &str, n: usize) -> Option<&mut XmlNode> {
+        self.children
+            .iter_mut()
+            .filter_map(|child| match child {
+                XmlContent::Element(element) if element.tag == tag => Some(element),
+                _ => None,
+            })
+            .nth(n)
+    }
+    fn find_mut(&mut self, path: &[(String, usize)]) -> Option<&mut XmlNode> {
+        let mut node = self;
+        for (tag, n) in path.iter() {
+            node = node.child_mut(tag, *n)?;
+        }
+        Some(node)
+    }
+    /// inserts element so that it's the nth child element, or the last one if there are fewer
+    fn insert_element(&mut self, n: usize, element: XmlNode) {
+        let index = self
+            .children
+            .iter()
+            .enumerate()
+            .filter(|(_, child)| matches!(child, XmlContent::Element(_)))
+            .nth(n)
+            .map(|(index, _)| index)
+            .unwrap_or(self.children.len());
+        self.children.insert(index, XmlContent::Element(element));
+    }
+}
+impl XmlWrite for XmlNode {
+    fn to_writer<W: std::io::Write>(&self, writer: &mut XmlWriter<W>) -> XmlResult<()> {
+        writer.write_element_start(&self.tag)?;
+        for (key, value) in self.attributes.iter() {
+            writer.write_attribute(key, value)?;
+        }
+        if self.children.is_empty() {
+            writer.write_element_end_empty()?;
+            return Ok(());
+        }
+        writer.write_element_end_open()?;
+        for child in self.children.iter() {
+            match child {
+                XmlContent::Element(element) => element.to_writer(writer)?,
+                XmlContent::Text(text) => writer.write_text(text)?,
+            }
+        }
+        writer.write_element_end_close(&self.tag)?;
+        Ok(())
+    }
+}
+fn qualified(prefix: &str, local: &str) -> String {
+    match prefix.is_empty() {
+        true => local.to_string(),
+        false => format!("{}:{}", prefix, local),
+    }
+}
+
+/// path from the root to an element, as the tag and index among siblings with that tag of each element on the way
+type NodePath = Vec<(String, usize)>;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+enum Unknown {
+    Attribute {
+        path: NodePath,
+        name: String,
+        value: String,
+    },
+    /// position is the index among the parent's child elements in the original document
+    Element {
+        path: NodePath,
+        position: usize,
+        element: XmlNode,
+    },
+    Text {
+        path: NodePath,
+        text: String,
+    },
+}
+
+impl Unknown {
+    /// tags from the root down to the unknown part separated by '/', ending with the tag of an unknown element,
+    /// '@' and the name of an unknown attribute, or "#text", such as "devices/disk/source/@index"
+    fn location(&self) -> String {
+        let (path, last) = match self {
+            Unknown::Attribute { path, name, .. } => (path, format!("@{}", name)),
+            Unknown::Element { path, element, .. } => (path, element.tag.clone()),
+            Unknown::Text { path, .. } => (path, String::from("#text")),
+        };
+        let mut tags: Vec<&str> = path.iter().map(|(tag, _)| tag.as_str()).collect();
+        tags.push(&last);
+        tags.join("/")
+    }
+}
+
+/// elements, attributes and text of a document that weren't read into its typed elements
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Passthrough {
+    unknown: Vec<Unknown>,
+}
+impl Passthrough {
+    /// # Arguments
+    ///
+    /// original: the document as it was read
+    /// known: the typed elements read from original, written back out
+    pub fn diff(original: &XmlNode, known: &XmlNode) -> Self {
+        let mut passthrough = Self::default();
+        passthrough.diff_node(original, known, &mut Vec::new());
+        passthrough
+    }
+    fn diff_node(&mut self, original: &XmlNode, known: &XmlNode, path: &mut NodePath) {
+        for (name, value) in original.attributes.iter() {
+            if known.attribute(name).is_none() {
+                self.unknown.push(Unknown::Attribute {
+                    path: path.clone(),
+                    name: name.clone(),
+                    value: value.clone(),
+                });
+            }
+        }
+        if !known.has_text() {
+            for child in original.children.iter() {
+                if let XmlContent::Text(text) = child {
+                    self.unknown.push(Unknown::Text {
+                        path: path.clone(),
+                        text: text.clone(),
+                    });
+                }
+            }
+        }
+        let mut seen: HashMap<&str, usize> = HashMap::new();
+        for (position, element) in original.elements().enumerate() {
+            let count = seen.entry(&element.tag).or_insert(0);
+            let n = *count;
+            *count += 1;
+            match known.elements().filter(|e| e.tag == element.tag).nth(n) {
+                Some(known) => {
+                    path.push((element.tag.clone(), n));
+                    self.diff_node(element, known, path);
+                    path.pop();
+                }
+                None => self.unknown.push(Unknown::Element {
+                    path: path.clone(),
+                    position,
+                    element: element.clone(),
+                }),
+            }
+        }
+    }
+    pub fn is_empty(&self) -> bool {
+        self.unknown.is_empty()
+    }
+    /// number of unknown elements, attributes and texts
+    pub fn len(&self) -> usize {
+        self.unknown.len()
+    }
+    /// location of every unknown part, the root element's own tag isn't included
+    pub fn locations(&self) -> impl Iterator<Item = String> + '_ {
+        self.unknown.iter().map(Unknown::location)
+    }
+    /// drops the unknown parts whose location keep returns false for
+    pub fn retain<F: FnMut(&str) -> bool>(&mut self, mut keep: F) {
+        self.unknown.retain(|unknown| keep(&unknown.location()));
+    }
+    /// adds everything that was unknown back into root
+    /// parts whose parent element no longer exists are dropped, attributes that are now known aren't replaced
+    pub fn merge(&self, root: &mut XmlNode) {
+        for unknown in self.unknown.iter() {
+            match unknown {
+                Unknown::Attribute { path, name, value } => {
+                    if let Some(node) = root.find_mut(path) {
+                        if node.attribute(name).is_none() {
+                            node.attributes.push((name.clone(), value.clone()));
+                        }
+                    }
+                }
+                Unknown::Element {
+                    path,
+                    position,
+                    element,
+                } => {
+                    if let Some(node) = root.find_mut(path) {
+                        node.insert_element(*position, element.clone());
+                    }
+                }
+                Unknown::Text { path, text } => {
+                    if let Some(node) = root.find_mut(path) {
+                        if !node.has_text() {
+                            node.children.push(XmlContent::Text(text.clone()));
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+/// reads a typed element from xml, along with whatever it doesn't model
+/// passthrough is None when everything was read into the element
+pub fn read<'a, T: XmlRead<'a> + XmlWrite>(xml: &'a str) -> XmlResult<(T, Option<Passthrough>)> {
+    let element = T::from_str(xml)?;
+    let known = XmlNode::parse(&element.to_string()?)?;
+    let passthrough = Passthrough::diff(&XmlNode::parse(xml)?, &known);
+    match passthrough.is_empty() {
+        true => Ok((element, None)),
+        false => Ok((element, Some(passthrough))),
+    }
+}
+/// writes a typed element, with passthrough merged back in
+pub fn write<T: XmlWrite>(element: &T, passthrough: Option<&Passthrough>) -> XmlResult<String> {
+    let xml = element.to_string()?;
+    match passthrough {
+        Some(passthrough) if !passthrough.is_empty() => {
+            let mut root = XmlNode::parse(&xml)?;
+            passthrough.merge(&mut root);
+            root.to_string()
+        }
+        _ => Ok(xml),
+    }
+}
+/// Passthrough is held in a child field of a typed element, which writes nothing
+impl XmlWrite for Passthrough {
+    fn to_writer<W: std::io::Write>(&self, _writer: &mut XmlWriter<W>) -> XmlResult<()> {
+        Ok(())
+    }
+}
+/// never called for valid xml, since no element has PASSTHROUGH_TAG, the element is skipped
+impl<'a> XmlRead<'a> for Passthrough {
+    fn from_reader(reader: &mut XmlReader<'a>) -> XmlResult<Self> {
+        reader.read_till_element_start(PASSTHROUGH_TAG)?;
+        reader.read_to_end(PASSTHROUGH_TAG)?;
+        Ok(Self::default())
+    }
+}
@@ -0,0 +1,215 @@
+/*
+// keeps domains booted and snapshotted, so that a job starts by taking one rather then booting one
+// each domain has an internal snapshot of itself running, taken once it has booted,
+// and is reverted to it when a job hands the domain back, which is much faster then a fresh boot
+*/
+use crate::runtime::kvm::{self, DomainConfig, DomainState, KvmError, SnapshotConfig, QEMU};
+use std::path::Path;
+use std::thread;
+use std::time::Duration;
+
+/// name of the snapshot each domain of a pool is reverted to
+pub const CLEAN_SNAPSHOT: &str = "clean";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Member {
+    name: String,
+    busy: bool,
+}
+
+/// domains copied from a template, each kept running at its clean snapshot until a job takes it
+pub struct WarmPool {
+    qemu: QEMU,
+    template: DomainConfig,
+    size: usize,
+    warmup: Duration,
+    members: Vec<Member>,
+    /// number of domains booted so far, so names aren't reused
+    booted: u64,
+}
+impl WarmPool {
+    /// # Arguments
+    ///
+    /// template: each domain is a copy with its own name and uuid, and if qemu has an ImageManager,
+    /// its own overlays of the template's disks, otherwise the disks are shared
+    /// size: how many domains fill boots
+    pub fn new(qemu: QEMU, template: DomainConfig, size: usize) -> Self {
+        Self {
+            qemu,
+            template,
+            size,
+            warmup: Duration::from_secs(0),
+            members: Vec::new(),
+            booted: 0,
+        }
+    }
+    /// how long a domain gets to boot before its snapshot is taken
+    pub fn warmup(mut self, warmup: Duration) -> Self {
+        self.warmup = warmup;
+        self
+    }
+    pub fn qemu(&self) -> &QEMU {
+        &self.qemu
+    }
+    /// domains in the pool, including taken ones
+    pub fn len(&self) -> usize {
+        self.members.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.members.is_empty()
+    }
+    /// domains that can be taken right away
+    pub fn idle(&self) -> usize {
+        self.members.iter().filter(|member| !member.busy).count()
+    }
+    /// boots domains until there are size of them, returns how many were added
+    pub fn fill(&mut self) -> Result<usize, KvmError> {
+        let mut added = 0;
+        while self.members.len() < self.size {
+            let name = self.boot()?;
+            self.members.push(Member { name, busy: false });
+            added += 1;
+        }
+        Ok(added)
+    }
+    /// name of an idle domain, which belongs to the caller until it's released, None if all are taken
+    pub fn acquire(&mut self) -> Option<String> {
+        let member = self.members.iter_mut().find(|member| !member.busy)?;
+        member.busy = true;
+        Some(member.name.clone())
+    }
+    /// reverts a domain to its clean snapshot, after which it can be taken again
+    /// a domain that can't be reverted is removed from the pool, and replaced by the next fill
+    pub fn release(&mut self, name: &str) -> Result<(), KvmError> {
+        let index = match self.members.iter().position(|member| member.name == name) {
+            Some(index) => index,
+            None => return Err(KvmError::UnknownDomain(name.to_string())),
+        };
+        if let Err(e) = self.qemu.revert_snapshot(name, CLEAN_SNAPSHOT) {
+            self.members.remove(index);
+            let _ = self.remove(name);
+            return Err(e);
+        }
+        self.members[index].busy = false;
+        Ok(())
+    }
+    /// destroys every domain of the pool, including taken ones
+    pub fn drain(&mut self) -> Result<(), KvmError> {
+        let mut result = Ok(());
+        for member in std::mem::take(&mut self.members) {
+            if let Err(e) = self.remove(&member.name) {
+                result = Err(e);
+            }
+        }
+        result
+    }
+    /// defines and starts a copy of the template, and snapshots it once it has booted
+    fn boot(&mut self) -> Result<String, KvmError> {
+        // the prefix marks the domain as managed, so evacuate moves it
+        let prefix = match &self.template.name {
+            Some(name) if kvm::managed(name.value()) => name.value().to_string(),
+            Some(name) => format!("{}{}", kvm::MANAGED_PREFIX, name.value()),
+            None => format!("{}pool", kvm::MANAGED_PREFIX),
+        };
+        let name = format!("{}-{}", prefix, self.booted);
+        self.booted += 1;
+        let mut config = self.template.clone();
+        config.name = Some(kvm::name::new(name.clone()));
+        config.uuid = kvm::uuid::new(kvm::new_uuid()?);
+        let created = match self.overlay_disks(&name, &mut config) {
+            Ok(()) => self.qemu.create_domain(&config, true).map(|_| ()),
+            Err(e) => Err(e),
+        };
+        if let Err(e) = created {
+            if let Some(images) = self.qemu.images() {
+                let _ = images.release(&name);
+            }
+            return Err(e);
+        }
+        thread::sleep(self.warmup);
+        let snapshot =
+            SnapshotConfig::new(CLEAN_SNAPSHOT).description("booted, before any job ran");
+        if let Err(e) = self.qemu.create_snapshot(&name, &snapshot) {
+            let _ = self.remove(&name);
+            return Err(e);
+        }
+        Ok(name)
+    }
+    /// points the file backed disks of config at overlays of their images
+    fn overlay_disks(&self, name: &str, config: &mut DomainConfig) -> Result<(), KvmError> {
+        let images = match self.qemu.images() {
+            Some(images) => images,
+            None => return Ok(()),
+        };
+        let disks = config
+            .devices
+            .iter_mut()
+            .flat_map(|devices| devices.disks.iter_mut());
+        for (index, disk) in disks.enumerate() {
+            if disk.device.as_deref().unwrap_or("disk") != "disk" {
+                continue;
+            }
+            let base = match disk.source.as_ref().and_then(|source| source.file.clone()) {
+                Some(base) => base,
+                None => continue,
+            };
+            let dev = disk.target.as_ref().and_then(|target| target.dev.clone());
+            let dev = dev.unwrap_or_else(|| format!("disk{}", index));
+            let overlay = images.overlay(name, &dev, Path::new(&base))?;
+            let overlay_disk = overlay.disk(&dev);
+            disk.source = overlay_disk.source;
+            disk.driver = overlay_disk.driver;
+        }
+        Ok(())
+    }
+    /// deletes the clean snapshot, then destroys and undefines the domain
+    fn remove(&mut self, name: &str) -> Result<(), KvmError> {
+        let _ = self.qemu.delete_snapshot(name, CLEAN_SNAPSHOT);
+        let state = self.qemu.state(name)?;
+        if !matches!(state, DomainState::ShutOff | DomainState::Crashed) {
+            self.qemu.destroy(name)?;
+        }
+        self.qemu.undefine(name)
+    }
+}
+/// domains of a pool are disposable, and don't outlive it
+impl Drop for WarmPool {
+    fn drop(&mut self) {
+        let _ = self.drain();
+    }
+}
+
+#[test]
+fn warm_pool() {
+    let qemu = QEMU::connect_uri("test:///default").unwrap();
+    let template = DomainConfig::from_str(
+        "<domain type=\"test\"><name>artifice-warm</name>\
+        <uuid>4d6e0f4e-8bd1-4a2a-9d43-0c3f6bd1a006</uuid>\
+        <memory unit=\"MiB\">64</memory><vcpu>1</vcpu>\
+        <os><type>hvm</type></os></domain>",
+    )
+    .unwrap();
+    let mut pool = WarmPool::new(qemu, template, 2);
+    assert_eq!(pool.fill().unwrap(), 2);
+    assert_eq!(pool.fill().unwrap(), 0);
+    let first = pool.acquire().unwrap();
+    let second = pool.acquire().unwrap();
+    assert_ne!(first, second);
+    assert_eq!(pool.acquire(), None);
+    // a job leaves its domain paused, releasing it goes back to the running snapshot
+    pool.qemu().suspend(&first).unwrap();
+    pool.release(&first).unwrap();
+    assert_eq!(pool.qemu().state(&first).unwrap(), DomainState::Running);
+    assert_eq!(pool.idle(), 1);
+    assert_eq!(pool.acquire(), Some(first));
+    assert!(matches!(
+        pool.release("missing"),
+        Err(KvmError::UnknownDomain(_))
+    ));
+    pool.drain().unwrap();
+    assert!(pool.is_empty());
+    assert!(matches!(
+        pool.qemu().state(&second),
+        Err(KvmError::UnknownDomain(_))
+    ));
+}
@@ -0,0 +1,792 @@
+/*
+// runs DomainConfigs directly with qemu-system, without libvirt
+// the config is turned into an argv, and the running process is controlled through QMP,
+// qemu's json protocol, over a unix socket passed to it with -qmp
+// only what a DomainConfig can describe is translated, anything qemu can't do without libvirt,
+// such as attaching to a libvirt network, is an error rather then being silently dropped
+*/
+use crate::runtime::events::{DomainEvent, EventStream};
+use crate::runtime::kvm::{ConfigError, Devices, Disk, DomainConfig, Interface};
+use crate::runtime::{Quantity, Unit, UnitErr};
+use serde_json::{json, Value};
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::{ExitStatus, Stdio};
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::UnixStream;
+use tokio::process::{Child, Command};
+
+/// how long qemu gets to create its QMP socket
+const STARTUP_TIMEOUT: Duration = Duration::from_secs(10);
+const STARTUP_INTERVAL: Duration = Duration::from_millis(50);
+
+#[derive(Debug)]
+pub enum QemuError {
+    Io(io::Error),
+    Config(ConfigError),
+    Memory(UnitErr),
+    /// part of the config that can't be expressed on the qemu command line
+    Unsupported(String),
+    /// qemu exited before its QMP socket was ready, with what it wrote to stderr
+    Exited(ExitStatus, String),
+    /// an error reply to a QMP command, or a reply that isn't QMP
+    Qmp(String),
+    Json(serde_json::Error),
+}
+impl fmt::Display for QemuError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Io(e) => write!(f, "{}", e),
+            Self::Config(e) => write!(f, "{}", e),
+            Self::Memory(e) => write!(f, "invalid memory size: {}", e),
+            Self::Unsupported(what) => write!(f, "qemu backend doesn't support {}", what),
+            Self::Exited(status, stderr) => write!(f, "qemu exited with {}: {}", status, stderr),
+            Self::Qmp(e) => write!(f, "qmp: {}", e),
+            Self::Json(e) => write!(f, "{}", e),
+        }
+    }
+}
+impl Error for QemuError {}
+impl From<io::Error> for QemuError {
+    fn from(error: io::Error) -> Self {
+        QemuError::Io(error)
+    }
+}
+impl From<ConfigError> for QemuError {
+    fn from(error: ConfigError) -> Self {
+        QemuError::Config(error)
+    }
+}
+impl From<UnitErr> for QemuError {
+    fn from(error: UnitErr) -> Self {
+        QemuError::Memory(error)
+    }
+}
+impl From<serde_json::Error> for QemuError {
+    fn from(error: serde_json::Error) -> Self {
+        QemuError::Json(error)
+    }
+}
+
+/// translates DomainConfigs into qemu-system invocations
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct QemuCommand {
+    binary: PathBuf,
+    firmware: PathBuf,
+}
+impl Default for QemuCommand {
+    fn default() -> Self {
+        Self {
+            binary: PathBuf::from("qemu-system-x86_64"),
+            firmware: PathBuf::from("/usr/share/OVMF/OVMF_CODE.fd"),
+        }
+    }
+}
+impl QemuCommand {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// qemu-system-x86_64 from PATH by default
+    pub fn binary<P: AsRef<Path>>(mut self, binary: P) -> Self {
+        self.binary = binary.as_ref().to_path_buf();
+        self
+    }
+    /// uefi code image used for domains with firmware "efi"
+    pub fn firmware<P: AsRef<Path>>(mut self, firmware: P) -> Self {
+        self.firmware = firmware.as_ref().to_path_buf();
+        self
+    }
+    /// arguments for qemu, not including the binary itself
+    ///
+    /// # Arguments
+    ///
+    /// config: validated before it's translated
+    /// qmp: path qemu creates its QMP socket at, a second socket only used for events is created at event_socket(qmp)
+    pub fn argv(&self, config: &DomainConfig, qmp: &Path) -> Result<Vec<String>, QemuError> {
+        config.validate()?;
+        let mut args = Vec::new();
+        let mut arg = |flag: &str, value: String| {
+            args.push(flag.to_string());
+            args.push(value);
+        };
+        if let Some(name) = &config.name {
+            arg("-name", escape(name.value()));
+        }
+        arg("-uuid", config.uuid.value().to_string());
+        let accel = match config.r#type.as_str() {
+            "kvm" => "kvm",
+            "qemu" => "tcg",
+            other => return Err(QemuError::Unsupported(format!("domain type {}", other))),
+        };
+        let machine = config.os.r#type.machine.as_deref().unwrap_or("q35");
+        arg("-machine", format!("{},accel={}", escape(machine), accel));
+        if accel == "kvm" {
+            arg("-cpu", String::from("host"));
+        }
+        arg("-m", format!("{}M", memory_mib(config)?));
+        arg("-smp", config.vcpu.text.trim().to_string());
+        // libvirt enforces tuning through cgroups, qemu has no options for it
+        let tunes = [
+            ("cputune", config.cputune.is_some()),
+            ("memtune", config.memtune.is_some()),
+            ("blkiotune", config.blkiotune.is_some()),
+        ];
+        if let Some((tune, _)) = tunes.iter().find(|(_, set)| *set) {
+            return Err(QemuError::Unsupported(tune.to_string()));
+        }
+        // anything the config doesn't model would otherwise be dropped without a word
+        if let Some(passthrough) = &config.passthrough {
+            if let Some(location) = passthrough.locations().next() {
+                return Err(QemuError::Unsupported(location));
+            }
+        }
+        match config.os.firmware.as_deref() {
+            None | Some("bios") => (),
+            Some("efi") => arg(
+                "-drive",
+                format!(
+                    "if=pflash,format=raw,readonly=on,file={}",
+                    escape(&self.firmware.to_string_lossy())
+                ),
+            ),
+            Some(other) => return Err(QemuError::Unsupported(format!("firmware {}", other))),
+        }
+        let order: String = config
+            .os
+            .boot
+            .iter()
+            .map(|boot| match boot.dev.as_str() {
+                "hd" => Ok('c'),
+                "cdrom" => Ok('d'),
+                "network" => Ok('n'),
+                "fd" => Ok('a'),
+                other => Err(QemuError::Unsupported(format!("boot device {}", other))),
+            })
+            .collect::<Result<_, _>>()?;
+        if !order.is_empty() {
+            arg("-boot", format!("order={}", order));
+        }
+        if config.on_reboot.as_ref().map(|on| on.value()) == Some("destroy") {
+            args.push(String::from("-no-reboot"));
+        }
+        // only the devices in the config, and no monitor other then QMP
+        args.push(String::from("-nodefaults"));
+        args.push(String::from("-no-user-config"));
+        args.push(String::from("-qmp"));
+        args.push(format!(
+            "unix:{},server=on,wait=off",
+            escape(&qmp.to_string_lossy())
+        ));
+        // a QMP socket serves one client at a time, so events get their own
+        args.push(String::from("-qmp"));
+        args.push(format!(
+            "unix:{},server=on,wait=off",
+            escape(&event_socket(qmp).to_string_lossy())
+        ));
+        if let Some(devices) = &config.devices {
+            args.extend(device_args(devices)?);
+        }
+        if !args.iter().any(|arg| arg == "-vnc") {
+            args.push(String::from("-display"));
+            args.push(String::from("none"));
+        }
+        Ok(args)
+    }
+    /// starts qemu and connects to its QMP socket, qemu is killed when the returned QemuVm is dropped
+    pub async fn launch(&self, config: &DomainConfig, qmp: &Path) -> Result<QemuVm, QemuError> {
+        let args = self.argv(config, qmp)?;
+        // a socket left by an earlier run would be connected to before qemu replaces it
+        let _ = std::fs::remove_file(qmp);
+        let _ = std::fs::remove_file(event_socket(qmp));
+        let mut child = Command::new(&self.binary)
+            .args(&args)
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()?;
+        let started = std::time::Instant::now();
+        loop {
+            if qmp.exists() {
+                if let Ok(connection) = Qmp::connect(qmp).await {
+                    let name = match &config.name {
+                        Some(name) => name.value().to_string(),
+                        None => config.uuid.value().to_string(),
+                    };
+                    return Ok(QemuVm {
+                        child,
+                        qmp: connection,
+                        events: event_socket(qmp),
+                        name,
+                    });
+                }
+            }
+            // the child is only polled for as long as the interval, to notice qemu failing to start
+            if let Ok(status) = tokio::time::timeout(STARTUP_INTERVAL, &mut child).await {
+                let mut stderr = String::new();
+                if let Some(mut pipe) = child.stderr.take() {
+                    use tokio::io::AsyncReadExt;
+                    let _ = pipe.read_to_string(&mut stderr).await;
+                }
+                return Err(QemuError::Exited(status?, stderr.trim().to_string()));
+            }
+            if started.elapsed() > STARTUP_TIMEOUT {
+                let _ = child.kill();
+                return Err(QemuError::Qmp(String::from(
+                    "timed out waiting for the socket",
+                )));
+            }
+        }
+    }
+}
+
+/// memory of the domain in MiB, qemu allocates memory in whole pages so smaller units aren't useful
+fn memory_mib(config: &DomainConfig) -> Result<u64, QemuError> {
+    let value: u64 = match config.memory.text.trim().parse() {
+        Ok(value) => value,
+        Err(_) => return Err(UnitErr::InvalidNumber.into()),
+    };
+    Ok(Quantity::new(value, config.memory.unit)
+        .to_unit(Unit::MiB)?
+        .value())
+}
+/// path of the QMP socket events are read from, next to the one for commands
+pub fn event_socket(qmp: &Path) -> PathBuf {
+    qmp.with_extension("events")
+}
+/// commas separate options on the qemu command line, so commas in values are doubled
+fn escape(value: &str) -> String {
+    value.replace(',', ",,")
+}
+fn device_args(devices: &Devices) -> Result<Vec<String>, QemuError> {
+    let mut args = Vec::new();
+    // qemu adds the controllers a machine type needs, and picks addresses itself
+    if !devices.controllers.is_empty() {
+        return Err(QemuError::Unsupported(String::from("controllers")));
+    }
+    let addressed = devices.disks.iter().any(|disk| disk.address.is_some())
+        || devices
+            .interfaces
+            .iter()
+            .any(|interface| interface.address.is_some());
+    if addressed {
+        return Err(QemuError::Unsupported(String::from("device addresses")));
+    }
+    for disk in devices.disks.iter() {
+        args.push(String::from("-drive"));
+        args.push(drive(disk)?);
+    }
+    for (index, interface) in devices.interfaces.iter().enumerate() {
+        let (netdev, device) = netdev(index, interface)?;
+        args.extend(vec![
+            String::from("-netdev"),
+            netdev,
+            String::from("-device"),
+            device,
+        ]);
+    }
+    for (index, serial) in devices.serials.iter().enumerate() {
+        let path = serial
+            .source
+            .as_ref()
+            .and_then(|source| source.path.as_deref());
+        let chardev = match (serial.r#type.as_str(), path) {
+            ("pty", _) => String::from("pty"),
+            ("stdio", _) => String::from("stdio"),
+            ("null", _) => String::from("null"),
+            // the file: shorthand takes the rest of the argument as the path, so commas can't be escaped
+            ("file", Some(path)) => {
+                args.push(String::from("-chardev"));
+                args.push(format!("file,id=serial{},path={}", index, escape(path)));
+                format!("chardev:serial{}", index)
+            }
+            ("unix", Some(path)) => format!("unix:{},server=on,wait=off", escape(path)),
+            (other, _) => return Err(QemuError::Unsupported(format!("serial type {}", other))),
+        };
+        args.push(String::from("-serial"));
+        args.push(chardev);
+    }
+    // a console on a serial target is the serial port itself
+    for console in devices.consoles.iter() {
+        let target = console
+            .target
+            .as_ref()
+            .and_then(|target| target.r#type.as_deref());
+        if target != Some("serial") {
+            return Err(QemuError::Unsupported(String::from(
+                "consoles not on a serial port",
+            )));
+        }
+    }
+    if !devices.channels.is_empty() {
+        return Err(QemuError::Unsupported(String::from("channels")));
+    }
+    for graphics in devices.graphics.iter() {
+        if graphics.r#type != "vnc" {
+            return Err(QemuError::Unsupported(format!(
+                "{} graphics",
+                graphics.r#type
+            )));
+        }
+        let listen = graphics.listen.as_deref().unwrap_or("127.0.0.1");
+        // vnc display numbers are offsets from port 5900
+        let display = match graphics.port {
+            Some(port) if port >= 5900 => format!("{}:{}", listen, port - 5900),
+            _ => format!("{}:0,to=99", listen),
+        };
+        args.push(String::from("-vnc"));
+        args.push(display);
+    }
+    for (index, rng) in devices.rngs.iter().enumerate() {
+        if rng.model != "virtio" || rng.backend.model != "random" {
+            return Err(QemuError::Unsupported(format!("{} rng", rng.backend.model)));
+        }
+        let file = match rng.backend.text.trim() {
+            "" => "/dev/urandom",
+            file => file,
+        };
+        args.push(String::from("-object"));
+        args.push(format!(
+            "rng-random,id=rng{},filename={}",
+            index,
+            escape(file)
+        ));
+        args.push(String::from("-device"));
+        args.push(format!("virtio-rng-pci,rng=rng{}", index));
+    }
+    Ok(args)
+}
+fn drive(disk: &Disk) -> Result<String, QemuError> {
+    let file = match disk
+        .source
+        .as_ref()
+        .and_then(|source| source.file.as_deref())
+    {
+        Some(file) => file,
+        None => {
+            return Err(QemuError::Unsupported(String::from(
+                "disks without a source file",
+            )))
+        }
+    };
+    let bus = disk
+        .target
+        .as_ref()
+        .and_then(|target| target.bus.as_deref());
+    let interface = match bus {
+        None | Some("virtio") => "virtio",
+        Some("sata") | Some("ide") => "ide",
+        Some("scsi") => "scsi",
+        Some(other) => return Err(QemuError::Unsupported(format!("disk bus {}", other))),
+    };
+    let mut drive = format!("file={},if={}", escape(file), interface);
+    if let Some(format) = disk
+        .driver
+        .as_ref()
+        .and_then(|driver| driver.r#type.as_deref())
+    {
+        drive.push_str(&format!(",format={}", format));
+    }
+    if disk.device.as_deref() == Some("cdrom") {
+        drive.push_str(",media=cdrom,readonly=on");
+    }
+    Ok(drive)
+}
+fn netdev(index: usize, interface: &Interface) -> Result<(String, String), QemuError> {
+    let id = format!("net{}", index);
+    let netdev = match interface.r#type.as_str() {
+        "user" => format!("user,id={}", id),
+        "bridge" => match interface.source.as_ref().and_then(|s| s.bridge.as_deref()) {
+            Some(bridge) => format!("bridge,id={},br={}", id, bridge),
+            None => {
+                return Err(QemuError::Unsupported(String::from(
+                    "bridges without a name",
+                )))
+            }
+        },
+        other => return Err(QemuError::Unsupported(format!("{} interfaces", other))),
+    };
+    let model = match interface.model.as_ref().map(|model| model.r#type.as_str()) {
+        None | Some("virtio") => "virtio-net-pci",
+        Some(model) => model,
+    };
+    let mut device = format!("{},netdev={}", model, id);
+    if let Some(mac) = &interface.mac {
+        device.push_str(&format!(",mac={}", mac.address));
+    }
+    Ok((netdev, device))
+}
+
+/// QMP connection, events that arrive while waiting for a reply are kept until they're taken
+pub struct Qmp {
+    stream: BufReader<UnixStream>,
+    events: Vec<Value>,
+}
+impl Qmp {
+    /// reads the greeting and leaves capabilities negotiation mode, after which commands can be sent
+    pub async fn connect<P: AsRef<Path>>(path: P) -> Result<Self, QemuError> {
+        let stream = UnixStream::connect(path).await?;
+        let mut qmp = Self {
+            stream: BufReader::new(stream),
+            events: Vec::new(),
+        };
+        let greeting = qmp.read().await?;
+        if greeting.get("QMP").is_none() {
+            return Err(QemuError::Qmp(format!("unexpected greeting {}", greeting)));
+        }
+        qmp.execute("qmp_capabilities", None).await?;
+        Ok(qmp)
+    }
+    async fn read(&mut self) -> Result<Value, QemuError> {
+        let mut line = String::new();
+        if self.stream.read_line(&mut line).await? == 0 {
+            return Err(QemuError::Qmp(String::from("connection closed")));
+        }
+        Ok(serde_json::from_str(&line)?)
+    }
+    /// sends a command and waits for its reply, returning the value of "return"
+    pub async fn execute(
+        &mut self,
+        command: &str,
+        arguments: Option<Value>,
+    ) -> Result<Value, QemuError> {
+        let mut request = json!({ "execute": command });
+        if let Some(arguments) = arguments {
+            request["arguments"] = arguments;
+        }
+        let mut line = serde_json::to_vec(&request)?;
+        line.push(b'\n');
+        self.stream.get_mut().write_all(&line).await?;
+        loop {
+            let mut reply = self.read().await?;
+            if reply.get("event").is_some() {
+                self.events.push(reply);
+            } else if let Some(value) = reply.get_mut("return") {
+                return Ok(value.take());
+            } else if let Some(error) = reply.get("error") {
+                return Err(QemuError::Qmp(format!(
+                    "{}: {}",
+                    error["class"].as_str().unwrap_or("Error"),
+                    error["desc"].as_str().unwrap_or_default()
+                )));
+            } else {
+                return Err(QemuError::Qmp(format!("unexpected reply {}", reply)));
+            }
+        }
+    }
+    /// events received so far, such as SHUTDOWN or RESET
+    pub fn take_events(&mut self) -> Vec<Value> {
+        std::mem::take(&mut self.events)
+    }
+    /// the oldest event that hasn't been taken, waiting for one if there is none
+    pub async fn next_event(&mut self) -> Result<Value, QemuError> {
+        if !self.events.is_empty() {
+            return Ok(self.events.remove(0));
+        }
+        loop {
+            let message = self.read().await?;
+            if message.get("event").is_some() {
+                return Ok(message);
+            }
+        }
+    }
+    /// turns the connection into a stream of the events it receives, which ends when qemu exits
+    pub fn into_events(mut self, domain: &str) -> EventStream {
+        let (sender, stream) = EventStream::channel();
+        let domain = domain.to_string();
+        tokio::spawn(async move {
+            while let Ok(event) = self.next_event().await {
+                if let Some(event) = DomainEvent::from_qmp(&domain, &event) {
+                    if sender.send(event).is_err() {
+                        break;
+                    }
+                }
+            }
+        });
+        stream
+    }
+}
+
+/// qemu process started by QemuCommand::launch
+pub struct QemuVm {
+    child: Child,
+    qmp: Qmp,
+    /// path of the event socket
+    events: PathBuf,
+    /// name of the domain, or its uuid if it has none
+    name: String,
+}
+impl QemuVm {
+    pub fn pid(&self) -> u32 {
+        self.child.id()
+    }
+    pub fn qmp(&mut self) -> &mut Qmp {
+        &mut self.qmp
+    }
+    /// connects to the event socket, only one stream can be open at a time
+    /// must be called from within a tokio runtime, which the events are read on
+    pub async fn events(&self) -> Result<EventStream, QemuError> {
+        Ok(Qmp::connect(&self.events).await?.into_events(&self.name))
+    }
+    /// "running", "paused", "shutdown" and so on, as reported by query-status
+    pub async fn status(&mut self) -> Result<String, QemuError> {
+        let status = self.qmp.execute("query-status", None).await?;
+        match status["status"].as_str() {
+            Some(status) => Ok(status.to_string()),
+            None => Err(QemuError::Qmp(format!("unexpected status {}", status))),
+        }
+    }
+    /// presses the power button, the guest decides when to shut down
+    pub async fn shutdown(&mut self) -> Result<(), QemuError> {
+        self.qmp.execute("system_powerdown", None).await?;
+        Ok(())
+    }
+    pub async fn reset(&mut self) -> Result<(), QemuError> {
+        self.qmp.execute("system_reset", None).await?;
+        Ok(())
+    }
+    pub async fn pause(&mut self) -> Result<(), QemuError> {
+        self.qmp.execute("stop", None).await?;
+        Ok(())
+    }
+    pub async fn resume(&mut self) -> Result<(), QemuError> {
+        self.qmp.execute("cont", None).await?;
+        Ok(())
+    }
+    /// stops qemu immediately and waits for it to exit
+    pub async fn quit(mut self) -> Result<ExitStatus, QemuError> {
+        // qemu can exit before it replies to quit
+        match self.qmp.execute("quit", None).await {
+            Ok(_) | Err(QemuError::Qmp(_)) | Err(QemuError::Io(_)) => (),
+            Err(e) => return Err(e),
+        }
+        Ok((&mut self.child).await?)
+    }
+    /// waits for the guest to shut itself down
+    pub async fn wait(mut self) -> Result<ExitStatus, QemuError> {
+        Ok((&mut self.child).await?)
+    }
+}
+
+#[test]
+fn argv() {
+    use crate::runtime::kvm::{CharSource, InterfaceModel, Mac, MemTune, Serial};
+
+    let qmp = Path::new("/run/artifice/guest.qmp");
+    let has = |args: &[String], flag: &str, value: &str| {
+        args.windows(2)
+            .any(|pair| pair[0] == flag && pair[1] == value)
+    };
+    let config = DomainConfig::builder("guest,1")
+        .uuid("4d6e0f4e-8bd1-4a2a-9d43-0c3f6bd1a005")
+        .memory(2, Unit::GiB)
+        .vcpus(4)
+        .firmware("efi")
+        .boot_disk("/images/guest.qcow2", "qcow2")
+        .console()
+        .build()
+        .unwrap();
+    let args = QemuCommand::new()
+        .firmware("/ovmf/code.fd")
+        .argv(&config, qmp)
+        .unwrap();
+    assert!(has(&args, "-name", "guest,,1"));
+    assert!(has(&args, "-machine", "q35,accel=kvm"));
+    assert!(has(&args, "-cpu", "host"));
+    assert!(has(&args, "-m", "2048M"));
+    assert!(has(&args, "-smp", "4"));
+    assert!(has(
+        &args,
+        "-drive",
+        "if=pflash,format=raw,readonly=on,file=/ovmf/code.fd"
+    ));
+    assert!(has(
+        &args,
+        "-drive",
+        "file=/images/guest.qcow2,if=virtio,format=qcow2"
+    ));
+    assert!(has(&args, "-boot", "order=c"));
+    assert!(has(
+        &args,
+        "-qmp",
+        "unix:/run/artifice/guest.qmp,server=on,wait=off"
+    ));
+    assert!(has(
+        &args,
+        "-qmp",
+        "unix:/run/artifice/guest.events,server=on,wait=off"
+    ));
+    assert!(has(&args, "-serial", "pty"));
+    assert!(has(&args, "-display", "none"));
+
+    // user networking and a serial log need nothing from the host
+    let config = DomainConfig::builder("guest")
+        .uuid("4d6e0f4e-8bd1-4a2a-9d43-0c3f6bd1a005")
+        .domain_type("qemu")
+        .memory(512, Unit::MiB)
+        .interface(Interface {
+            r#type: String::from("user"),
+            mac: Some(Mac {
+                address: String::from("52:54:00:8b:c5:1e"),
+            }),
+            source: None,
+            model: Some(InterfaceModel {
+                r#type: String::from("e1000"),
+            }),
+            address: None,
+        })
+        .build()
+        .unwrap();
+    let mut config = config;
+    config.devices.as_mut().unwrap().serials.push(Serial {
+        r#type: String::from("file"),
+        source: Some(CharSource {
+            mode: None,
+            path: Some(String::from("/tmp/serial,0.log")),
+        }),
+        target: None,
+    });
+    let args = QemuCommand::new().argv(&config, qmp).unwrap();
+    assert!(has(&args, "-machine", "q35,accel=tcg"));
+    assert!(!args.iter().any(|arg| arg == "-cpu"));
+    assert!(has(&args, "-m", "512M"));
+    assert!(has(&args, "-netdev", "user,id=net0"));
+    assert!(has(
+        &args,
+        "-device",
+        "e1000,netdev=net0,mac=52:54:00:8b:c5:1e"
+    ));
+    assert!(has(&args, "-chardev", "file,id=serial0,path=/tmp/serial,,0.log"));
+    assert!(has(&args, "-serial", "chardev:serial0"));
+
+    // tuning and device addresses need libvirt
+    let mut tuned = config.clone();
+    tuned.memtune = Some(MemTune {
+        hard_limit: None,
+        soft_limit: None,
+        swap_hard_limit: None,
+    });
+    assert!(matches!(
+        QemuCommand::new().argv(&tuned, qmp),
+        Err(QemuError::Unsupported(what)) if what == "memtune"
+    ));
+    // a machine type can't add options of its own
+    let mut machine = config.clone();
+    machine.os.r#type.machine = Some(String::from("q35,accel=xen"));
+    let args = QemuCommand::new().argv(&machine, qmp).unwrap();
+    assert!(has(&args, "-machine", "q35,,accel=xen,accel=tcg"));
+    let xml = config.to_string().unwrap().replace(
+        "</devices>",
+        "<hostdev mode=\"subsystem\" type=\"pci\"/></devices>",
+    );
+    assert!(matches!(
+        QemuCommand::new().argv(&DomainConfig::from_str(&xml).unwrap(), qmp),
+        Err(QemuError::Unsupported(what)) if what == "devices/hostdev"
+    ));
+    let mut addressed = config.clone();
+    addressed.allocate().unwrap();
+    assert!(matches!(
+        QemuCommand::new().argv(&addressed, qmp),
+        Err(QemuError::Unsupported(_))
+    ));
+
+    // libvirt networks only exist under libvirt
+    let network = DomainConfig::builder("guest")
+        .uuid("4d6e0f4e-8bd1-4a2a-9d43-0c3f6bd1a005")
+        .network("default")
+        .build()
+        .unwrap();
+    assert!(matches!(
+        QemuCommand::new().argv(&network, qmp),
+        Err(QemuError::Unsupported(_))
+    ));
+    let inexact = DomainConfig::builder("guest")
+        .uuid("4d6e0f4e-8bd1-4a2a-9d43-0c3f6bd1a005")
+        .memory(1536, Unit::KiB)
+        .build()
+        .unwrap();
+    assert!(matches!(
+        QemuCommand::new().argv(&inexact, qmp),
+        Err(QemuError::Memory(UnitErr::Inexact))
+    ));
+}
+#[test]
+fn qmp() {
+    use crate::runtime::events::Lifecycle;
+    use std::io::{BufRead, Write};
+    use std::os::unix::net::UnixListener;
+    use tokio::stream::StreamExt;
+
+    let path = std::env::temp_dir().join(format!("artifice-qmp-{}", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let listener = UnixListener::bind(&path).unwrap();
+    // answers the way qemu does, with an event before the reply to query-status,
+    // and events after the reply to stop, after which it hangs up like qemu exiting
+    let server = std::thread::spawn(move || {
+        let (stream, _) = listener.accept().unwrap();
+        let mut reader = std::io::BufReader::new(stream.try_clone().unwrap());
+        let mut writer = stream;
+        writeln!(
+            writer,
+            r#"{{"QMP": {{"version": {{}}, "capabilities": []}}}}"#
+        )
+        .unwrap();
+        let mut commands = Vec::new();
+        let mut line = String::new();
+        while reader.read_line(&mut line).unwrap() > 0 {
+            let command: Value = serde_json::from_str(&line).unwrap();
+            let reply = match command["execute"].as_str().unwrap() {
+                "qmp_capabilities" => String::from(r#"{"return": {}}"#),
+                "query-status" => String::from(
+                    "{\"event\": \"RESUME\", \"timestamp\": {}}\n\
+                    {\"return\": {\"status\": \"running\", \"running\": true}}",
+                ),
+                "stop" => String::from(
+                    "{\"return\": {}}\n\
+                    {\"event\": \"STOP\", \"timestamp\": {}}\n\
+                    {\"event\": \"SHUTDOWN\", \"data\": {\"guest\": true}, \"timestamp\": {}}",
+                ),
+                other => format!(
+                    r#"{{"error": {{"class": "CommandNotFound", "desc": "{}"}}}}"#,
+                    other
+                ),
+            };
+            writeln!(writer, "{}", reply).unwrap();
+            if command["execute"] == "stop" {
+                commands.push(command);
+                break;
+            }
+            commands.push(command);
+            line.clear();
+        }
+        commands
+    });
+    let mut runtime = crate::runtime::test_runtime();
+    runtime.block_on(async {
+        let mut qmp = Qmp::connect(&path).await.unwrap();
+        let status = qmp.execute("query-status", None).await.unwrap();
+        assert_eq!(status["status"], "running");
+        let events = qmp.take_events();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0]["event"], "RESUME");
+        let missing = qmp.execute("missing", Some(json!({ "a": 1 }))).await;
+        assert!(matches!(missing, Err(QemuError::Qmp(_))));
+        qmp.execute("stop", None).await.unwrap();
+        let events: Vec<_> = qmp.into_events("guest").collect().await;
+        let lifecycle: Vec<_> = events
+            .iter()
+            .map(|event| match event {
+                DomainEvent::Lifecycle { event, .. } => *event,
+                other => panic!("unexpected event {:?}", other),
+            })
+            .collect();
+        assert_eq!(lifecycle, vec![Lifecycle::Suspended, Lifecycle::Shutdown]);
+    });
+    let commands = server.join().unwrap();
+    assert_eq!(commands.len(), 4);
+    assert_eq!(commands[2]["arguments"]["a"], 1);
+    std::fs::remove_file(&path).unwrap();
+}
@@ -0,0 +1,271 @@
+/*
+// registry of backends for EnvType::Other
+// a backend is registered under a name along with a description of what it can run, and a factory
+// since every ExecEnv has its own job and output types, created environments are used through DynEnv,
+// which takes jobs and returns output serialized as json, the same form they arrive in from peers
+*/
+use crate::job::{JobId, JobResult, JobStatus};
+use crate::{EnvData, EnvType, ExecEnv, RemoteEnv};
+use async_trait::async_trait;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::time::Instant;
+
+/// what a backend can run, advertised to other peers
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Capabilities {
+    name: String,
+    description: String,
+    features: Vec<String>,
+}
+impl Capabilities {
+    /// # Arguments
+    ///
+    /// name: the string used in EnvType::Other
+    /// description: human readable summary of the backend
+    pub fn new(name: &str, description: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            description: description.to_string(),
+            features: Vec::new(),
+        }
+    }
+    /// languages, formats or instruction sets the backend accepts, such as "python3" or "wasm32"
+    pub fn feature(mut self, feature: &str) -> Self {
+        self.features.push(feature.to_string());
+        self
+    }
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+    pub fn description(&self) -> &str {
+        &self.description
+    }
+    pub fn features(&self) -> &[String] {
+        &self.features
+    }
+    pub fn supports(&self, feature: &str) -> bool {
+        self.features.iter().any(|f| f == feature)
+    }
+}
+
+#[derive(Debug)]
+pub enum RegistryError {
+    /// no backend is registered under the name, or the EnvType isn't Other
+    Unknown(String),
+    Duplicate(String),
+    /// a job couldn't be deserialized, or output couldn't be serialized
+    Format(serde_json::Error),
+    /// returned by the factory, or by the environment itself
+    Env(Box<dyn Error + Send>),
+}
+impl fmt::Display for RegistryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Unknown(name) => write!(f, "no backend registered for {}", name),
+            Self::Duplicate(name) => write!(f, "a backend is already registered for {}", name),
+            Self::Format(e) => write!(f, "{}", e),
+            Self::Env(e) => write!(f, "{}", e),
+        }
+    }
+}
+impl Error for RegistryError {}
+impl From<serde_json::Error> for RegistryError {
+    fn from(error: serde_json::Error) -> Self {
+        RegistryError::Format(error)
+    }
+}
+
+/// ExecEnv with its job and output types erased, jobs and output are json
+#[async_trait]
+pub trait DynEnv: EnvData + Send + Sync {
+    async fn execute_json(
+        &self,
+        id: JobId,
+        job: &[u8],
+        deadline: Option<Instant>,
+    ) -> Result<JobResult<Vec<u8>>, RegistryError>;
+    fn cancel(&self, id: JobId) -> Result<(), RegistryError>;
+    fn status(&self, id: JobId) -> JobStatus;
+}
+#[async_trait]
+impl<E> DynEnv for E
+where
+    E: ExecEnv,
+    E::Job: DeserializeOwned,
+    E::Output: Serialize,
+    E::Error: 'static,
+{
+    async fn execute_json(
+        &self,
+        id: JobId,
+        job: &[u8],
+        deadline: Option<Instant>,
+    ) -> Result<JobResult<Vec<u8>>, RegistryError> {
+        let bytes_in = job.len() as u64;
+        let job: E::Job = serde_json::from_slice(job)?;
+        let result = match ExecEnv::execute(self, id, job, deadline).await {
+            Ok(result) => result,
+            Err(e) => return Err(RegistryError::Env(Box::new(e))),
+        };
+        let output = match &result.output {
+            Some(output) => Some(serde_json::to_vec(output)?),
+            None => None,
+        };
+        // what was actually received and sent, rather then the backend's own estimate
+        let mut usage = result.usage;
+        usage.bytes_in = bytes_in;
+        usage.bytes_out = output.as_ref().map_or(0, |output| output.len() as u64);
+        Ok(JobResult {
+            id: result.id,
+            outcome: result.outcome,
+            output,
+            usage,
+        })
+    }
+    fn cancel(&self, id: JobId) -> Result<(), RegistryError> {
+        match ExecEnv::cancel(self, id) {
+            Ok(()) => Ok(()),
+            Err(e) => Err(RegistryError::Env(Box::new(e))),
+        }
+    }
+    fn status(&self, id: JobId) -> JobStatus {
+        ExecEnv::status(self, id)
+    }
+}
+
+type Factory = Box<dyn Fn(RemoteEnv) -> Result<Box<dyn DynEnv>, RegistryError> + Send + Sync>;
+struct Backend {
+    capabilities: Capabilities,
+    factory: Factory,
+}
+/// maps the names used in EnvType::Other to the backends that implement them
+#[derive(Default)]
+pub struct Registry {
+    backends: HashMap<String, Backend>,
+}
+impl Registry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// # Arguments
+    ///
+    /// capabilities: registered under capabilities.name()
+    /// factory: creates the environment, the RemoteEnv passed in has EnvType::Other(name)
+    pub fn register<E, F>(
+        &mut self,
+        capabilities: Capabilities,
+        factory: F,
+    ) -> Result<(), RegistryError>
+    where
+        E: ExecEnv + 'static,
+        E::Job: DeserializeOwned,
+        E::Output: Serialize,
+        E::Error: 'static,
+        F: Fn(RemoteEnv) -> Result<E, E::Error> + Send + Sync + 'static,
+    {
+        let name = capabilities.name().to_string();
+        if self.backends.contains_key(&name) {
+            return Err(RegistryError::Duplicate(name));
+        }
+        let factory: Factory = Box::new(move |env| match factory(env) {
+            Ok(env) => Ok(Box::new(env) as Box<dyn DynEnv>),
+            Err(e) => Err(RegistryError::Env(Box::new(e))),
+        });
+        self.backends.insert(
+            name,
+            Backend {
+                capabilities,
+                factory,
+            },
+        );
+        Ok(())
+    }
+    pub fn unregister(&mut self, name: &str) -> Option<Capabilities> {
+        self.backends
+            .remove(name)
+            .map(|backend| backend.capabilities)
+    }
+    pub fn capabilities(&self, name: &str) -> Option<&Capabilities> {
+        self.backends.get(name).map(|backend| &backend.capabilities)
+    }
+    pub fn supports(&self, env_type: &EnvType) -> bool {
+        match env_type {
+            EnvType::Other(name) => self.backends.contains_key(name),
+            _ => false,
+        }
+    }
+    /// every registered backend sorted by name, for hosts to send to other peers
+    pub fn advertise(&self) -> Vec<Capabilities> {
+        let mut capabilities: Vec<Capabilities> = self
+            .backends
+            .values()
+            .map(|backend| backend.capabilities.clone())
+            .collect();
+        capabilities.sort_by(|a, b| a.name.cmp(&b.name));
+        capabilities
+    }
+    /// create the backend named by env.env_type(), which has to be EnvType::Other
+    pub fn create(&self, env: RemoteEnv) -> Result<Box<dyn DynEnv>, RegistryError> {
+        let backend = match env.env_type() {
+            EnvType::Other(name) => match self.backends.get(name) {
+                Some(backend) => backend,
+                None => return Err(RegistryError::Unknown(name.clone())),
+            },
+            other => return Err(RegistryError::Unknown(format!("{:?}", other))),
+        };
+        (backend.factory)(env)
+    }
+}
+
+#[test]
+fn virtual_backend() {
+    use crate::runtime::{VirtualEnv, VirtualJob, VirtualOutput};
+    use ferrisvm::opcode::Opcode;
+
+    let code = Opcode::encode(&[Opcode::Input, Opcode::Input, Opcode::Mul, Opcode::Output]);
+    let mut registry = Registry::new();
+    let capabilities =
+        Capabilities::new("ferris-mul", "multiplies two numbers").feature("ferrisvm");
+    registry
+        .register(capabilities.clone(), move |env| {
+            Ok(VirtualEnv::new(env).load(&code, &[0; 16])?)
+        })
+        .unwrap();
+    assert!(matches!(
+        registry.register(capabilities.clone(), |env| Ok(VirtualEnv::new(env))),
+        Err(RegistryError::Duplicate(_))
+    ));
+    assert_eq!(registry.advertise(), vec![capabilities]);
+    assert!(registry
+        .capabilities("ferris-mul")
+        .unwrap()
+        .supports("ferrisvm"));
+
+    let name = EnvType::Other(String::from("ferris-mul"));
+    assert!(registry.supports(&name));
+    let env = registry
+        .create(RemoteEnv::init(name.clone()).unwrap())
+        .unwrap();
+    assert_eq!(env.env_type(), &name);
+    let job = serde_json::to_vec(&VirtualJob::new(vec![6, 7])).unwrap();
+    let mut runtime = crate::runtime::test_runtime();
+    let result = runtime
+        .block_on(env.execute_json(JobId::new(0), &job, None))
+        .unwrap();
+    let output: VirtualOutput = serde_json::from_slice(&result.output.unwrap()).unwrap();
+    assert_eq!(output.output, vec![42]);
+    assert!(matches!(
+        runtime.block_on(env.execute_json(JobId::new(1), b"[", None)),
+        Err(RegistryError::Format(_))
+    ));
+
+    let unknown = RemoteEnv::init(EnvType::Other(String::from("python3"))).unwrap();
+    assert!(matches!(
+        registry.create(unknown),
+        Err(RegistryError::Unknown(_))
+    ));
+}
@@ -0,0 +1,538 @@
+/*
+// namespace sandbox for native jobs
+// each job is started in a fresh user, mount, pid, network, ipc and uts namespace
+// the new root is a read only tmpfs with the host system directories bind mounted read only
+// and a private tmpfs on /tmp, nothing else from the host is visible
+// this only needs unprivileged user namespaces, so root is not required
+*/
+use std::error::Error;
+use std::ffi::CString;
+use std::fmt;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// host directories that are visible (read only) inside the sandbox by default
+pub const DEFAULT_READ_ONLY: [&str; 5] = ["/usr", "/bin", "/lib", "/lib64", "/etc"];
+/// device nodes bound into the sandbox /dev
+pub const DEVICES: [&str; 4] = ["/dev/null", "/dev/zero", "/dev/random", "/dev/urandom"];
+
+/// configuration for the namespaces a native job is started in
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Sandbox {
+    read_only: Vec<PathBuf>,
+    read_write: Vec<PathBuf>,
+    share_network: bool,
+    hostname: String,
+    tmp_size: u64,
+    workdir: PathBuf,
+}
+impl Default for Sandbox {
+    fn default() -> Self {
+        Self {
+            read_only: DEFAULT_READ_ONLY.iter().map(PathBuf::from).collect(),
+            read_write: Vec::new(),
+            share_network: false,
+            hostname: String::from("artifice"),
+            // 64 MiB
+            tmp_size: 64 * 1024 * 1024,
+            workdir: PathBuf::from("/tmp"),
+        }
+    }
+}
+impl Sandbox {
+    /// sandbox that exposes nothing from the host, use read_only to add directories
+    pub fn empty() -> Self {
+        Self {
+            read_only: Vec::new(),
+            ..Self::default()
+        }
+    }
+    /// expose a host directory read only at the same path inside the sandbox
+    pub fn read_only<P: AsRef<Path>>(mut self, path: P) -> Self {
+        self.read_only.push(path.as_ref().to_path_buf());
+        self
+    }
+    /// expose a host file or directory writable at the same path inside the sandbox
+    pub fn read_write<P: AsRef<Path>>(mut self, path: P) -> Self {
+        self.read_write.push(path.as_ref().to_path_buf());
+        self
+    }
+    /// keep the host network instead of an empty network namespace
+    pub fn share_network(mut self, share: bool) -> Self {
+        self.share_network = share;
+        self
+    }
+    pub fn hostname(mut self, hostname: String) -> Self {
+        self.hostname = hostname;
+        self
+    }
+    /// size of the private /tmp in bytes
+    pub fn tmp_size(mut self, bytes: u64) -> Self {
+        self.tmp_size = bytes;
+        self
+    }
+    /// directory (inside the sandbox) the job is started in
+    pub fn workdir<P: AsRef<Path>>(mut self, path: P) -> Self {
+        self.workdir = path.as_ref().to_path_buf();
+        self
+    }
+    pub fn read_only_paths(&self) -> &[PathBuf] {
+        &self.read_only
+    }
+    pub fn read_write_paths(&self) -> &[PathBuf] {
+        &self.read_write
+    }
+    pub fn shares_network(&self) -> bool {
+        self.share_network
+    }
+    /// checks if unprivileged user namespaces can be created on this host
+    pub fn probe() -> Result<(), SandboxError> {
+        probe()
+    }
+    /// configure command so that it is executed inside of the sandbox
+    /// note that the program path is resolved inside of the new root
+    pub fn apply(&self, command: &mut Command) -> Result<(), SandboxError> {
+        probe()?;
+        apply(self, command)
+    }
+}
+
+#[derive(Debug)]
+pub enum SandboxError {
+    /// user namespaces are missing or disabled on this host
+    Unsupported(String),
+    /// a path or name could not be passed to the kernel (contains a nul byte)
+    InvalidPath(PathBuf),
+    Io(io::Error),
+}
+impl fmt::Display for SandboxError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Unsupported(reason) => write!(f, "sandbox unsupported: {}", reason),
+            Self::InvalidPath(path) => write!(f, "invalid path: {}", path.display()),
+            Self::Io(error) => write!(f, "{}", error),
+        }
+    }
+}
+impl Error for SandboxError {}
+impl From<io::Error> for SandboxError {
+    fn from(error: io::Error) -> Self {
+        SandboxError::Io(error)
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn probe() -> Result<(), SandboxError> {
+    Err(SandboxError::Unsupported(String::from(
+        "namespaces are only available on linux",
+    )))
+}
+#[cfg(not(target_os = "linux"))]
+fn apply(_sandbox: &Sandbox, _command: &mut Command) -> Result<(), SandboxError> {
+    probe()
+}
+
+#[cfg(target_os = "linux")]
+fn probe() -> Result<(), SandboxError> {
+    // debian and ubuntu patch in a switch for unprivileged user namespaces
+    let switches = [
+        "/proc/sys/user/max_user_namespaces",
+        "/proc/sys/kernel/unprivileged_userns_clone",
+    ];
+    probe_host(Path::new("/proc/self/ns/user"), &switches)
+}
+/// user_ns has to exist, and none of the switches may be 0, switches that don't exist are ignored
+#[cfg(target_os = "linux")]
+fn probe_host<P: AsRef<Path>>(user_ns: &Path, switches: &[P]) -> Result<(), SandboxError> {
+    if !user_ns.exists() {
+        return Err(SandboxError::Unsupported(String::from(
+            "kernel built without user namespaces",
+        )));
+    }
+    for switch in switches.iter() {
+        if let Ok(value) = std::fs::read_to_string(switch) {
+            if value.trim() == "0" {
+                return Err(SandboxError::Unsupported(format!(
+                    "{} is 0",
+                    switch.as_ref().display()
+                )));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// host path mounted at the same path under the staging root
+#[cfg(target_os = "linux")]
+struct Bind {
+    source: CString,
+    target: CString,
+    read_only: bool,
+    file: bool,
+}
+
+/// everything the child needs, allocated before fork so the pre_exec hook doesn't allocate
+#[cfg(target_os = "linux")]
+struct Plan {
+    flags: nix::sched::CloneFlags,
+    uid_map: CString,
+    gid_map: CString,
+    hostname: CString,
+    tmp_options: CString,
+    binds: Vec<Bind>,
+    devices: Vec<(CString, CString)>,
+    dirs: Vec<CString>,
+    tmp: CString,
+    proc: CString,
+    workdir: CString,
+}
+
+#[cfg(target_os = "linux")]
+const STAGE: &str = "/tmp";
+
+#[cfg(target_os = "linux")]
+fn cstring<P: AsRef<Path>>(path: P) -> Result<CString, SandboxError> {
+    CString::new(path.as_ref().as_os_str().as_bytes())
+        .map_err(|_| SandboxError::InvalidPath(path.as_ref().to_path_buf()))
+}
+
+#[cfg(target_os = "linux")]
+fn staged<P: AsRef<Path>>(path: P) -> Result<CString, SandboxError> {
+    let relative = path.as_ref().strip_prefix("/").unwrap_or(path.as_ref());
+    cstring(Path::new(STAGE).join(relative))
+}
+
+#[cfg(target_os = "linux")]
+impl Plan {
+    fn new(sandbox: &Sandbox) -> Result<Self, SandboxError> {
+        use nix::sched::CloneFlags;
+        let mut flags = CloneFlags::CLONE_NEWUSER
+            | CloneFlags::CLONE_NEWNS
+            | CloneFlags::CLONE_NEWPID
+            | CloneFlags::CLONE_NEWIPC
+            | CloneFlags::CLONE_NEWUTS;
+        if !sandbox.share_network {
+            flags |= CloneFlags::CLONE_NEWNET;
+        }
+        let uid = nix::unistd::getuid();
+        let gid = nix::unistd::getgid();
+        let mut binds = Vec::new();
+        let tmp = staged("/tmp")?;
+        let proc = staged("/proc")?;
+        let mut dirs = vec![
+            tmp.clone(),
+            proc.clone(),
+            staged("/dev")?,
+            staged("/.old_root")?,
+        ];
+        let read_only = sandbox.read_only.iter().map(|path| (path, true));
+        let read_write = sandbox.read_write.iter().map(|path| (path, false));
+        for (path, read_only) in read_only.chain(read_write) {
+            // missing paths such as /lib64 on some distributions are skipped
+            if !path.exists() {
+                continue;
+            }
+            let file = !path.is_dir();
+            let mut parent = PathBuf::from("/");
+            let mut components = path.strip_prefix("/").unwrap_or(path).components().peekable();
+            while let Some(component) = components.next() {
+                parent.push(component);
+                // a file is mounted over an empty file, not a directory
+                if file && components.peek().is_none() {
+                    break;
+                }
+                let dir = staged(&parent)?;
+                if !dirs.contains(&dir) {
+                    dirs.push(dir);
+                }
+            }
+            binds.push(Bind {
+                source: cstring(path)?,
+                target: staged(path)?,
+                read_only,
+                file,
+            });
+        }
+        let mut devices = Vec::new();
+        for device in DEVICES.iter() {
+            if Path::new(device).exists() {
+                devices.push((cstring(device)?, staged(device)?));
+            }
+        }
+        Ok(Self {
+            flags,
+            uid_map: CString::new(format!("0 {} 1", uid)).unwrap(),
+            gid_map: CString::new(format!("0 {} 1", gid)).unwrap(),
+            hostname: cstring(&sandbox.hostname)?,
+            tmp_options: CString::new(format!("size={},mode=1777", sandbox.tmp_size)).unwrap(),
+            binds,
+            devices,
+            dirs,
+            tmp,
+            proc,
+            workdir: cstring(&sandbox.workdir)?,
+        })
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn apply(sandbox: &Sandbox, command: &mut Command) -> Result<(), SandboxError> {
+    use std::os::unix::process::CommandExt;
+    let plan = Plan::new(sandbox)?;
+    unsafe {
+        command.pre_exec(move || enter(&plan));
+    }
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+fn sys(error: nix::Error) -> io::Error {
+    match error.as_errno() {
+        Some(errno) => io::Error::from_raw_os_error(errno as i32),
+        None => io::Error::from(io::ErrorKind::Other),
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn write_file(path: &[u8], data: &[u8]) -> io::Result<()> {
+    // path is nul terminated by the caller, std::fs would allocate
+    let fd = unsafe { libc::open(path.as_ptr() as *const libc::c_char, libc::O_WRONLY) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let written = unsafe { libc::write(fd, data.as_ptr() as *const libc::c_void, data.len()) };
+    unsafe { libc::close(fd) };
+    if written < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// runs in the forked child before exec
+#[cfg(target_os = "linux")]
+fn enter(plan: &Plan) -> io::Result<()> {
+    use nix::sched::unshare;
+    use nix::unistd::{fork, ForkResult};
+
+    // own process group so the whole job can be signaled at once
+    nix::unistd::setpgid(nix::unistd::Pid::from_raw(0), nix::unistd::Pid::from_raw(0))
+        .map_err(sys)?;
+    unshare(plan.flags).map_err(sys)?;
+    write_file(b"/proc/self/setgroups\0", b"deny")?;
+    write_file(b"/proc/self/uid_map\0", plan.uid_map.as_bytes())?;
+    write_file(b"/proc/self/gid_map\0", plan.gid_map.as_bytes())?;
+
+    // only children of this process enter the new pid namespace,
+    // so this process stays behind and forwards the exit status of the job
+    match unsafe { fork() }.map_err(sys)? {
+        ForkResult::Parent { child } => supervise(child),
+        ForkResult::Child => {
+            unsafe { libc::prctl(libc::PR_SET_PDEATHSIG, libc::SIGKILL) };
+            build_root(plan)
+        }
+    }
+}
+
+/// waits for pid 1 of the sandbox, then exits with its status, never returns
+#[cfg(target_os = "linux")]
+fn supervise(child: nix::unistd::Pid) -> ! {
+    use nix::sys::wait::{waitpid, WaitStatus};
+    // don't hold the pipe std uses to detect exec failure, or spawn would block until the job ends
+    if unsafe { libc::syscall(libc::SYS_close_range, 3u32, u32::MAX, 0u32) } != 0 {
+        // close_range is only available since linux 5.9
+        for fd in 3..1024 {
+            unsafe { libc::close(fd) };
+        }
+    }
+    let code = loop {
+        match waitpid(child, None) {
+            Ok(WaitStatus::Exited(_, code)) => break code,
+            Ok(WaitStatus::Signaled(_, signal, _)) => break 128 + signal as i32,
+            Ok(_) => continue,
+            Err(nix::Error::Sys(nix::errno::Errno::EINTR)) => continue,
+            Err(_) => break 127,
+        }
+    };
+    unsafe { libc::_exit(code) }
+}
+
+#[cfg(target_os = "linux")]
+fn build_root(plan: &Plan) -> io::Result<()> {
+    use nix::mount::{mount, umount2, MntFlags, MsFlags};
+    use nix::sys::stat::Mode;
+    use nix::unistd::{chdir, mkdir, pivot_root, sethostname};
+    const NONE: Option<&'static str> = None;
+
+    sethostname(std::ffi::OsStr::from_bytes(plan.hostname.as_bytes())).map_err(sys)?;
+    // stop mount events from propagating back to the host
+    mount(NONE, "/", NONE, MsFlags::MS_REC | MsFlags::MS_PRIVATE, NONE).map_err(sys)?;
+    mount(
+        Some("tmpfs"),
+        STAGE,
+        Some("tmpfs"),
+        MsFlags::MS_NOSUID | MsFlags::MS_NODEV,
+        Some("mode=0755"),
+    )
+    .map_err(sys)?;
+    for dir in plan.dirs.iter() {
+        mkdir(dir.as_c_str(), Mode::from_bits_truncate(0o755)).map_err(sys)?;
+    }
+    for bind in plan.binds.iter() {
+        if bind.file {
+            touch(&bind.target)?;
+        }
+        if bind.read_only {
+            bind_read_only(&bind.source, &bind.target)?;
+        } else {
+            mount(
+                Some(bind.source.as_c_str()),
+                bind.target.as_c_str(),
+                NONE,
+                MsFlags::MS_BIND | MsFlags::MS_REC,
+                NONE,
+            )
+            .map_err(sys)?;
+        }
+    }
+    for (source, target) in plan.devices.iter() {
+        touch(target)?;
+        mount(Some(source.as_c_str()), target.as_c_str(), NONE, MsFlags::MS_BIND, NONE)
+            .map_err(sys)?;
+    }
+    mount(
+        Some("tmpfs"),
+        plan.tmp.as_c_str(),
+        Some("tmpfs"),
+        MsFlags::MS_NOSUID | MsFlags::MS_NODEV,
+        Some(plan.tmp_options.as_c_str()),
+    )
+    .map_err(sys)?;
+    mount(
+        Some("proc"),
+        plan.proc.as_c_str(),
+        Some("proc"),
+        MsFlags::MS_NOSUID | MsFlags::MS_NODEV | MsFlags::MS_NOEXEC,
+        NONE,
+    )
+    .map_err(sys)?;
+
+    pivot_root(STAGE, "/tmp/.old_root").map_err(sys)?;
+    chdir("/").map_err(sys)?;
+    umount2("/.old_root", MntFlags::MNT_DETACH).map_err(sys)?;
+    unsafe { libc::rmdir(b"/.old_root\0".as_ptr() as *const libc::c_char) };
+    mount(
+        NONE,
+        "/",
+        NONE,
+        MsFlags::MS_REMOUNT | MsFlags::MS_RDONLY | MsFlags::MS_NOSUID | MsFlags::MS_NODEV,
+        NONE,
+    )
+    .map_err(sys)?;
+    chdir(plan.workdir.as_c_str()).map_err(sys)?;
+    Ok(())
+}
+
+/// bind mount targets have to exist
+#[cfg(target_os = "linux")]
+fn touch(path: &CString) -> io::Result<()> {
+    let fd = unsafe { libc::open(path.as_ptr(), libc::O_CREAT | libc::O_WRONLY, 0o644) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    unsafe { libc::close(fd) };
+    Ok(())
+}
+
+/// bind mount then remount read only, flags the host locked (nosuid, nodev, ...) have to be kept
+#[cfg(target_os = "linux")]
+fn bind_read_only(source: &CString, target: &CString) -> io::Result<()> {
+    use nix::mount::{mount, MsFlags};
+    use nix::sys::statvfs::{statvfs, FsFlags};
+    const NONE: Option<&'static str> = None;
+
+    mount(
+        Some(source.as_c_str()),
+        target.as_c_str(),
+        NONE,
+        MsFlags::MS_BIND | MsFlags::MS_REC,
+        NONE,
+    )
+    .map_err(sys)?;
+    let locked = statvfs(source.as_c_str()).map_err(sys)?.flags();
+    let mut flags = MsFlags::MS_BIND | MsFlags::MS_REMOUNT | MsFlags::MS_RDONLY;
+    for (fs_flag, ms_flag) in [
+        (FsFlags::ST_NOSUID, MsFlags::MS_NOSUID),
+        (FsFlags::ST_NODEV, MsFlags::MS_NODEV),
+        (FsFlags::ST_NOEXEC, MsFlags::MS_NOEXEC),
+        (FsFlags::ST_NOATIME, MsFlags::MS_NOATIME),
+        (FsFlags::ST_NODIRATIME, MsFlags::MS_NODIRATIME),
+        (FsFlags::ST_RELATIME, MsFlags::MS_RELATIME),
+    ]
+    .iter()
+    {
+        if locked.contains(*fs_flag) {
+            flags |= *ms_flag;
+        }
+    }
+    mount(NONE, target.as_c_str(), NONE, flags, NONE).map_err(sys)
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn unsupported_hosts() {
+    let dir = std::env::temp_dir().join(format!("artifice-sandbox-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let enabled = dir.join("enabled");
+    let disabled = dir.join("disabled");
+    std::fs::write(&enabled, "15000\n").unwrap();
+    std::fs::write(&disabled, "0\n").unwrap();
+    let missing = dir.join("missing");
+
+    assert!(probe_host(&dir, &[&enabled, &missing]).is_ok());
+    match probe_host(&dir, &[&enabled, &disabled]) {
+        Err(SandboxError::Unsupported(reason)) => assert!(reason.contains("disabled is 0")),
+        other => panic!("expected unsupported, got {:?}", other),
+    }
+    match probe_host(&missing, &[&enabled]) {
+        Err(SandboxError::Unsupported(_)) => (),
+        other => panic!("expected unsupported, got {:?}", other),
+    }
+    std::fs::remove_dir_all(&dir).unwrap();
+
+    // a name the kernel can't take is refused before anything is started
+    let mut command = Command::new("/bin/true");
+    let sandbox = Sandbox::default().hostname(String::from("job\0"));
+    match sandbox.apply(&mut command) {
+        Err(SandboxError::InvalidPath(_)) | Err(SandboxError::Unsupported(_)) => (),
+        other => panic!("expected a clean error, got {:?}", other),
+    }
+}
+#[cfg(target_os = "linux")]
+#[test]
+fn isolation() {
+    if let Err(e) = Sandbox::probe() {
+        eprintln!("skipping, {}", e);
+        return;
+    }
+    // left on the host /tmp, the sandbox has its own
+    let marker = std::env::temp_dir().join(format!("artifice-marker-{}", std::process::id()));
+    std::fs::write(&marker, "host").unwrap();
+    let script = format!(
+        "test ! -e {marker} || exit 10; \
+        touch /tmp/job || exit 11; \
+        touch /root-write 2>/dev/null && exit 12; \
+        touch /usr/job-write 2>/dev/null && exit 13; \
+        test \"$(grep -c : /proc/net/dev)\" = 1 || exit 14; \
+        grep -q lo: /proc/net/dev || exit 15; \
+        test \"$$\" = 1 || exit 16",
+        marker = marker.display()
+    );
+    let mut command = Command::new("/bin/sh");
+    command.arg("-c").arg(script);
+    Sandbox::default().apply(&mut command).unwrap();
+    let status = command.status().unwrap();
+    std::fs::remove_file(&marker).unwrap();
+    assert_eq!(status.code(), Some(0));
+}
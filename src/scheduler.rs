@@ -0,0 +1,550 @@
+/*
+// chooses which peer a job is sent to
+// peers that can't run the job are skipped, such as peers of another architecture or without enough
+// free memory, the rest are ranked by a strategy, and the best ranked peer is chosen
+// every peer gets a decision saying why it was chosen, ranked lower or skipped, so placements can be
+// shown to users, or logged to investigate why a peer never gets any work
+*/
+use crate::runtime::Quantity;
+use crate::{EnvData, EnvType, RemoteEnv};
+use networking::NetworkHash;
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::fmt;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// what a job needs from the peer it runs on, anything left as None is accepted
+#[derive(Debug, Clone, Default)]
+pub struct Requirements {
+    pub arch: Option<String>,
+    pub os: Option<String>,
+    /// free memory, after what jobs already assigned to the peer reserved
+    pub memory: Option<Quantity>,
+    pub env_type: Option<EnvType>,
+    pub trusted: bool,
+}
+impl Requirements {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    pub fn arch(mut self, arch: &str) -> Self {
+        self.arch = Some(arch.to_string());
+        self
+    }
+    pub fn os(mut self, os: &str) -> Self {
+        self.os = Some(os.to_string());
+        self
+    }
+    pub fn memory(mut self, memory: Quantity) -> Self {
+        self.memory = Some(memory);
+        self
+    }
+    pub fn env_type(mut self, env_type: EnvType) -> Self {
+        self.env_type = Some(env_type);
+        self
+    }
+    pub fn trusted(mut self, trusted: bool) -> Self {
+        self.trusted = trusted;
+        self
+    }
+    /// bytes, a requirement too large to count in bytes can't be met
+    fn memory_bytes(&self) -> u64 {
+        self.memory
+            .map_or(0, |memory| memory.to_bytes().unwrap_or(u64::MAX))
+    }
+    fn check(&self, env: &RemoteEnv, load: &PeerLoad) -> Result<(), Skip> {
+        if let Some(arch) = &self.arch {
+            if arch != env.arch_name() {
+                return Err(Skip::Arch {
+                    wanted: arch.clone(),
+                    found: env.arch_name().to_string(),
+                });
+            }
+        }
+        if let Some(os) = &self.os {
+            if os != env.os_name() {
+                return Err(Skip::Os {
+                    wanted: os.clone(),
+                    found: env.os_name().to_string(),
+                });
+            }
+        }
+        if let Some(env_type) = &self.env_type {
+            if env_type != env.env_type() {
+                return Err(Skip::EnvType {
+                    wanted: env_type.clone(),
+                    found: env.env_type().clone(),
+                });
+            }
+        }
+        if self.trusted && !env.trusted() {
+            return Err(Skip::Untrusted);
+        }
+        let free = load.free_memory(env);
+        if self.memory_bytes() > free {
+            return Err(Skip::Memory {
+                needed: self.memory.unwrap_or_else(|| Quantity::from(0)),
+                free: Quantity::from_bytes(free),
+            });
+        }
+        Ok(())
+    }
+}
+
+/// what this host knows about work it sent to a peer, kept by the scheduler
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PeerLoad {
+    /// jobs assigned to the peer that haven't been released
+    pub jobs: u32,
+    /// bytes of memory the assigned jobs required
+    pub reserved: u64,
+    /// last measured round trip, None until one is recorded
+    pub latency: Option<Duration>,
+}
+impl PeerLoad {
+    /// bytes of the peer's memory no assigned job has reserved
+    pub fn free_memory(&self, env: &RemoteEnv) -> u64 {
+        let total = env.total_memory().to_bytes().unwrap_or(u64::MAX);
+        total.saturating_sub(self.reserved)
+    }
+    /// assigned jobs per cpu
+    pub fn jobs_per_cpu(&self, env: &RemoteEnv) -> f64 {
+        f64::from(self.jobs) / f64::from(env.cpu_count().max(1))
+    }
+}
+
+/// a peer that meets a job's requirements, as handed to a strategy
+#[derive(Debug, Clone, Copy)]
+pub struct Candidate<'a> {
+    pub peer: &'a NetworkHash,
+    pub env: &'a RemoteEnv,
+    pub load: &'a PeerLoad,
+    pub requirements: &'a Requirements,
+}
+/// score a strategy gave a peer, lower is better, with what the score was based on
+#[derive(Debug, Clone, PartialEq)]
+pub struct Rank {
+    pub score: f64,
+    pub reason: String,
+}
+/// ranks the peers that meet a job's requirements, peers with equal scores are ordered by their hash
+pub trait Strategy: Send + Sync {
+    fn name(&self) -> &str;
+    fn rank(&mut self, candidate: &Candidate) -> Rank;
+}
+
+/// prefers the peer with the fewest assigned jobs per cpu, spreading jobs over every peer
+#[derive(Debug, Clone, Copy, Default)]
+pub struct LeastLoaded;
+impl Strategy for LeastLoaded {
+    fn name(&self) -> &str {
+        "least-loaded"
+    }
+    fn rank(&mut self, candidate: &Candidate) -> Rank {
+        Rank {
+            score: candidate.load.jobs_per_cpu(candidate.env),
+            reason: format!(
+                "{} jobs on {} cpus",
+                candidate.load.jobs,
+                candidate.env.cpu_count()
+            ),
+        }
+    }
+}
+/// prefers the peer with the least memory left once the job is placed, filling peers one at a time
+/// so the others stay free for jobs that need more memory
+#[derive(Debug, Clone, Copy, Default)]
+pub struct BinPacking;
+impl Strategy for BinPacking {
+    fn name(&self) -> &str {
+        "bin-packing"
+    }
+    fn rank(&mut self, candidate: &Candidate) -> Rank {
+        let left = candidate
+            .load
+            .free_memory(candidate.env)
+            .saturating_sub(candidate.requirements.memory_bytes());
+        Rank {
+            score: left as f64,
+            reason: format!("{} left after placing", Quantity::from_bytes(left)),
+        }
+    }
+}
+/// ranks peers in a random order, a seeded strategy always gives the same order
+#[derive(Debug, Clone, Copy)]
+pub struct Random {
+    state: u64,
+}
+impl Random {
+    pub fn new() -> Self {
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos() as u64;
+        Self::seeded(nanos ^ u64::from(std::process::id()).rotate_left(32))
+    }
+    pub fn seeded(seed: u64) -> Self {
+        // xorshift never leaves 0
+        Self { state: seed.max(1) }
+    }
+    fn next(&mut self) -> u64 {
+        self.state ^= self.state << 13;
+        self.state ^= self.state >> 7;
+        self.state ^= self.state << 17;
+        self.state
+    }
+}
+impl Default for Random {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+impl Strategy for Random {
+    fn name(&self) -> &str {
+        "random"
+    }
+    fn rank(&mut self, _candidate: &Candidate) -> Rank {
+        // the top 53 bits fit exactly in a f64
+        let score = (self.next() >> 11) as f64 / (1u64 << 53) as f64;
+        Rank {
+            score,
+            reason: String::from("drawn at random"),
+        }
+    }
+}
+/// mixes round trip time and load, a peer's score is latency * its latency in milliseconds
+/// plus load * its jobs per cpu, so with the default weights one job per cpu costs as much as 100ms
+#[derive(Debug, Clone, Copy)]
+pub struct LatencyWeighted {
+    pub latency: f64,
+    pub load: f64,
+    /// assumed for peers that haven't had their latency measured
+    pub unknown: Duration,
+}
+impl LatencyWeighted {
+    pub fn new(latency: f64, load: f64) -> Self {
+        Self {
+            latency,
+            load,
+            unknown: Duration::from_millis(500),
+        }
+    }
+    pub fn unknown(mut self, latency: Duration) -> Self {
+        self.unknown = latency;
+        self
+    }
+}
+impl Default for LatencyWeighted {
+    fn default() -> Self {
+        Self::new(1.0, 100.0)
+    }
+}
+impl Strategy for LatencyWeighted {
+    fn name(&self) -> &str {
+        "latency-weighted"
+    }
+    fn rank(&mut self, candidate: &Candidate) -> Rank {
+        let (latency, measured) = match candidate.load.latency {
+            Some(latency) => (latency, "measured"),
+            None => (self.unknown, "assumed"),
+        };
+        let millis = latency.as_secs_f64() * 1000.0;
+        let jobs_per_cpu = candidate.load.jobs_per_cpu(candidate.env);
+        Rank {
+            score: self.latency * millis + self.load * jobs_per_cpu,
+            reason: format!(
+                "{:.1}ms {} latency, {:.2} jobs per cpu",
+                millis, measured, jobs_per_cpu
+            ),
+        }
+    }
+}
+
+/// why a peer can't run a job
+#[derive(Debug, Clone, PartialEq)]
+pub enum Skip {
+    Arch { wanted: String, found: String },
+    Os { wanted: String, found: String },
+    EnvType { wanted: EnvType, found: EnvType },
+    Untrusted,
+    Memory { needed: Quantity, free: Quantity },
+}
+impl fmt::Display for Skip {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Arch { wanted, found } => write!(f, "needs {} but peer is {}", wanted, found),
+            Self::Os { wanted, found } => write!(f, "needs {} but peer runs {}", wanted, found),
+            Self::EnvType { wanted, found } => {
+                write!(
+                    f,
+                    "needs a {:?} environment but peer has {:?}",
+                    wanted, found
+                )
+            }
+            Self::Untrusted => write!(f, "needs a trusted peer"),
+            Self::Memory { needed, free } => write!(f, "needs {} but {} is free", needed, free),
+        }
+    }
+}
+#[derive(Debug, Clone, PartialEq)]
+pub enum Verdict {
+    Chosen(Rank),
+    /// met the requirements, but another peer ranked better, position 1 is the runner up
+    Ranked {
+        position: usize,
+        rank: Rank,
+    },
+    Skipped(Skip),
+}
+#[derive(Debug, Clone, PartialEq)]
+pub struct Decision {
+    pub peer: NetworkHash,
+    pub verdict: Verdict,
+}
+impl fmt::Display for Decision {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for byte in self.peer.iter().take(8) {
+            write!(f, "{:02x}", byte)?;
+        }
+        match &self.verdict {
+            Verdict::Chosen(rank) => write!(f, " chosen, score {:.3}: {}", rank.score, rank.reason),
+            Verdict::Ranked { position, rank } => write!(
+                f,
+                " ranked {}, score {:.3}: {}",
+                position + 1,
+                rank.score,
+                rank.reason
+            ),
+            Verdict::Skipped(skip) => write!(f, " skipped: {}", skip),
+        }
+    }
+}
+/// a decision for every peer that was considered, the chosen peer first, then the other ranked peers
+/// from best to worst, then skipped peers
+#[derive(Debug, Clone, PartialEq)]
+pub struct Placement {
+    pub strategy: String,
+    pub decisions: Vec<Decision>,
+}
+impl Placement {
+    /// None if every peer was skipped
+    pub fn chosen(&self) -> Option<&NetworkHash> {
+        match self.decisions.first() {
+            Some(Decision {
+                peer,
+                verdict: Verdict::Chosen(_),
+            }) => Some(peer),
+            _ => None,
+        }
+    }
+    /// peers that met the requirements, best first, for falling back when the chosen peer rejects the job
+    pub fn ranked(&self) -> Vec<&NetworkHash> {
+        self.decisions
+            .iter()
+            .filter(|decision| !matches!(decision.verdict, Verdict::Skipped(_)))
+            .map(|decision| &decision.peer)
+            .collect()
+    }
+    pub fn skipped(&self) -> Vec<(&NetworkHash, &Skip)> {
+        self.decisions
+            .iter()
+            .filter_map(|decision| match &decision.verdict {
+                Verdict::Skipped(skip) => Some((&decision.peer, skip)),
+                _ => None,
+            })
+            .collect()
+    }
+}
+impl fmt::Display for Placement {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "placed by {}", self.strategy)?;
+        for decision in self.decisions.iter() {
+            write!(f, "\n{}", decision)?;
+        }
+        Ok(())
+    }
+}
+
+/// places jobs on peers with a strategy, and keeps track of the load it assigned to each peer
+pub struct Scheduler {
+    strategy: Box<dyn Strategy>,
+    loads: HashMap<NetworkHash, PeerLoad>,
+}
+impl Scheduler {
+    pub fn new<S: Strategy + 'static>(strategy: S) -> Self {
+        Self {
+            strategy: Box::new(strategy),
+            loads: HashMap::new(),
+        }
+    }
+    pub fn strategy_name(&self) -> &str {
+        self.strategy.name()
+    }
+    /// replaces the strategy, the load of each peer is kept
+    pub fn set_strategy<S: Strategy + 'static>(&mut self, strategy: S) {
+        self.strategy = Box::new(strategy);
+    }
+    pub fn load(&self, peer: &NetworkHash) -> PeerLoad {
+        self.loads.get(peer).copied().unwrap_or_default()
+    }
+    pub fn record_latency(&mut self, peer: &NetworkHash, latency: Duration) {
+        self.loads.entry(*peer).or_default().latency = Some(latency);
+    }
+    /// counts a job sent to peer, and reserves the memory it requires
+    pub fn assign(&mut self, peer: &NetworkHash, requirements: &Requirements) {
+        let load = self.loads.entry(*peer).or_default();
+        load.jobs += 1;
+        load.reserved = load.reserved.saturating_add(requirements.memory_bytes());
+    }
+    /// undoes assign once the job has finished, with the same requirements
+    pub fn release(&mut self, peer: &NetworkHash, requirements: &Requirements) {
+        let load = self.loads.entry(*peer).or_default();
+        load.jobs = load.jobs.saturating_sub(1);
+        load.reserved = load.reserved.saturating_sub(requirements.memory_bytes());
+    }
+    /// decides where a job with requirements should run, doesn't assign it
+    pub fn place<'a, I>(&mut self, peers: I, requirements: &Requirements) -> Placement
+    where
+        I: IntoIterator<Item = (&'a NetworkHash, &'a RemoteEnv)>,
+    {
+        let mut ranked = Vec::new();
+        let mut skipped = Vec::new();
+        let mut peers = peers.into_iter().collect::<Vec<_>>();
+        // ranking in a fixed order keeps seeded strategies repeatable
+        peers.sort_by_key(|(peer, _)| *peer);
+        for (peer, env) in peers {
+            let load = self.load(peer);
+            match requirements.check(env, &load) {
+                Ok(()) => {
+                    let candidate = Candidate {
+                        peer,
+                        env,
+                        load: &load,
+                        requirements,
+                    };
+                    ranked.push((*peer, self.strategy.rank(&candidate)));
+                }
+                Err(skip) => skipped.push(Decision {
+                    peer: *peer,
+                    verdict: Verdict::Skipped(skip),
+                }),
+            }
+        }
+        ranked.sort_by(|(a_peer, a), (b_peer, b)| {
+            a.score
+                .partial_cmp(&b.score)
+                .unwrap_or(Ordering::Equal)
+                .then_with(|| a_peer.cmp(b_peer))
+        });
+        let mut decisions = ranked
+            .into_iter()
+            .enumerate()
+            .map(|(position, (peer, rank))| Decision {
+                peer,
+                verdict: match position {
+                    0 => Verdict::Chosen(rank),
+                    _ => Verdict::Ranked { position, rank },
+                },
+            })
+            .collect::<Vec<_>>();
+        decisions.append(&mut skipped);
+        Placement {
+            strategy: self.strategy.name().to_string(),
+            decisions,
+        }
+    }
+}
+impl Default for Scheduler {
+    fn default() -> Self {
+        Self::new(LeastLoaded)
+    }
+}
+impl fmt::Debug for Scheduler {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Scheduler")
+            .field("strategy", &self.strategy.name())
+            .field("loads", &self.loads)
+            .finish()
+    }
+}
+
+#[test]
+fn placement() {
+    use crate::runtime::Unit;
+
+    let env = |arch: &str, mem_gib: u64, cpus: u16, trusted: bool| -> RemoteEnv {
+        serde_json::from_value(serde_json::json!({
+            "os_name": "linux",
+            "arch_name": arch,
+            "total_mem": mem_gib * 1024 * 1024,
+            "cpu_count": cpus,
+            "cpu_speed": 3000,
+            "env_type": "Inherit",
+            "trusted": trusted,
+        }))
+        .unwrap()
+    };
+    let (small, large, arm) = ([1; 32], [2; 32], [3; 32]);
+    let peers = [
+        (small, env("x86_64", 4, 2, true)),
+        (large, env("x86_64", 16, 8, false)),
+        (arm, env("aarch64", 8, 4, true)),
+    ];
+    let peers = || peers.iter().map(|(hash, env)| (hash, env));
+    let requirements = Requirements::new()
+        .arch("x86_64")
+        .memory(Quantity::new(2, Unit::GiB));
+
+    let mut scheduler = Scheduler::default();
+    let placement = scheduler.place(peers(), &requirements);
+    assert_eq!(placement.chosen(), Some(&small));
+    assert_eq!(placement.ranked(), vec![&small, &large]);
+    assert_eq!(
+        placement.skipped(),
+        vec![(
+            &arm,
+            &Skip::Arch {
+                wanted: String::from("x86_64"),
+                found: String::from("aarch64")
+            }
+        )]
+    );
+    // a job per cpu on the small peer makes the large one less loaded
+    scheduler.assign(&small, &requirements);
+    scheduler.assign(&small, &requirements);
+    assert_eq!(
+        scheduler.place(peers(), &requirements).chosen(),
+        Some(&large)
+    );
+    // both jobs reserved all of the small peer's memory
+    match &scheduler.place(peers(), &requirements).skipped()[..] {
+        [(peer, Skip::Memory { .. }), (_, Skip::Arch { .. })] => assert_eq!(**peer, small),
+        other => panic!("expected small to be skipped for memory, got {:?}", other),
+    }
+    scheduler.release(&small, &requirements);
+    scheduler.release(&small, &requirements);
+    assert_eq!(scheduler.load(&small).reserved, 0);
+
+    let trusted = requirements.clone().trusted(true);
+    scheduler.set_strategy(BinPacking);
+    assert_eq!(scheduler.place(peers(), &trusted).chosen(), Some(&small));
+    assert_eq!(
+        scheduler.place(peers(), &Requirements::new()).chosen(),
+        Some(&small)
+    );
+
+    scheduler.set_strategy(LatencyWeighted::default());
+    scheduler.record_latency(&small, Duration::from_millis(300));
+    scheduler.record_latency(&large, Duration::from_millis(20));
+    let placement = scheduler.place(peers(), &requirements);
+    assert_eq!(placement.chosen(), Some(&large));
+    assert!(placement.to_string().contains("20.0ms measured latency"));
+
+    let mut first = Scheduler::new(Random::seeded(7));
+    let mut second = Scheduler::new(Random::seeded(7));
+    let requirements = Requirements::new();
+    assert_eq!(
+        first.place(peers(), &requirements),
+        second.place(peers(), &requirements)
+    );
+}